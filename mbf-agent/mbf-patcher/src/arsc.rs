@@ -0,0 +1,370 @@
+//! Reads the IDs of attribute resources (i.e. the `attr` type) from a resource table (`resources.arsc`).
+//! This is used to read the attribute IDs of the Android framework from `framework-res.apk`, rather than relying
+//! on the table bundled with MBF, which may be missing attributes.
+
+use std::{collections::HashMap, io::{Read, Seek, SeekFrom}, rc::Rc};
+
+use anyhow::{anyhow, Context, Result};
+use byteorder::{ReadBytesExt, LE};
+
+use crate::axml::load_string_pool;
+
+const TABLE_CHUNK: u16 = 0x0002;
+const STRING_POOL_CHUNK: u16 = 0x0001;
+const PACKAGE_CHUNK: u16 = 0x0200;
+const TYPE_CHUNK: u16 = 0x0201;
+
+// The ID of the `android` package, which holds the framework resources.
+const ANDROID_PACKAGE_ID: u32 = 0x01;
+// The number of bytes taken by the name of a package within its chunk header, which is 128 UTF-16 characters.
+const PACKAGE_NAME_LEN: i64 = 256;
+
+// Set on a type chunk if its entries are given as pairs of entry index and offset, rather than an offset for each entry.
+const TYPE_FLAG_SPARSE: u8 = 0x01;
+// Set on a type chunk if its entry offsets are 16 bits (in units of 4 bytes), rather than 32 bits.
+const TYPE_FLAG_OFFSET16: u8 = 0x02;
+// Set on an entry if its key is given in 16 bits, rather than after the entry flags.
+const ENTRY_FLAG_COMPACT: u16 = 0x0008;
+const NO_ENTRY: u32 = 0xFFFFFFFF;
+const NO_ENTRY_16: u16 = 0xFFFF;
+
+struct ChunkHeader {
+    // The offset of the start of the chunk within the file.
+    start: u64,
+    chunk_type: u16,
+    header_len: u16,
+    len: u32
+}
+
+impl ChunkHeader {
+    fn read(data: &mut (impl Read + Seek)) -> Result<Self> {
+        Ok(Self {
+            start: data.stream_position()?,
+            chunk_type: data.read_u16::<LE>()?,
+            header_len: data.read_u16::<LE>()?,
+            len: data.read_u32::<LE>()?
+        })
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.len as u64
+    }
+}
+
+/// Reads the names and resource IDs of the attributes in the `android` package of a resource table.
+pub fn read_attribute_ids(data: &mut (impl Read + Seek)) -> Result<HashMap<Rc<str>, u32>> {
+    let table = ChunkHeader::read(data)?;
+    if table.chunk_type != TABLE_CHUNK {
+        return Err(anyhow!("Initial chunk was not a resource table"));
+    }
+
+    let mut ids = HashMap::new();
+    let mut next_chunk = table.start + table.header_len as u64;
+    while next_chunk < table.end() {
+        data.seek(SeekFrom::Start(next_chunk))?;
+        let chunk = ChunkHeader::read(data)?;
+        if chunk.len == 0 {
+            return Err(anyhow!("Resource table contained an empty chunk"));
+        }
+
+        // The global string pool holds the values of resources, which aren't needed, so only packages are read.
+        if chunk.chunk_type == PACKAGE_CHUNK {
+            read_package(data, &chunk, &mut ids).context("Failed to read package")?;
+        }
+        next_chunk = chunk.end();
+    }
+
+    if ids.is_empty() {
+        return Err(anyhow!("Resource table contained no android attributes"));
+    }
+    Ok(ids)
+}
+
+// Adds the attributes in the package with the given header to `ids`, if it is the `android` package.
+fn read_package(data: &mut (impl Read + Seek), package: &ChunkHeader, ids: &mut HashMap<Rc<str>, u32>) -> Result<()> {
+    let package_id = data.read_u32::<LE>()?;
+    if package_id != ANDROID_PACKAGE_ID {
+        return Ok(());
+    }
+
+    data.seek(SeekFrom::Current(PACKAGE_NAME_LEN))?;
+    let type_strings_offset = data.read_u32::<LE>()?;
+    let _last_public_type = data.read_u32::<LE>()?;
+    let key_strings_offset = data.read_u32::<LE>()?;
+
+    let type_names = read_string_pool_at(data, package.start + type_strings_offset as u64).context("Failed to read type names")?;
+    let key_names = read_string_pool_at(data, package.start + key_strings_offset as u64).context("Failed to read key names")?;
+
+    // Type IDs start from 1, and are the index of the type name within the type string pool plus 1.
+    let attr_type_id = match type_names.iter().position(|name| &**name == "attr") {
+        Some(idx) => idx as u32 + 1,
+        None => return Err(anyhow!("Package had no attr type"))
+    };
+
+    let mut next_chunk = package.start + package.header_len as u64;
+    while next_chunk < package.end() {
+        data.seek(SeekFrom::Start(next_chunk))?;
+        let chunk = ChunkHeader::read(data)?;
+        if chunk.len == 0 {
+            return Err(anyhow!("Package contained an empty chunk"));
+        }
+
+        if chunk.chunk_type == TYPE_CHUNK {
+            let type_id = data.read_u8()? as u32;
+            if type_id == attr_type_id {
+                let base_id = (package_id << 24) | (type_id << 16);
+                read_type_entries(data, &chunk, base_id, &key_names, ids)?;
+            }
+        }
+        next_chunk = chunk.end();
+    }
+
+    Ok(())
+}
+
+// Adds the entries of the type chunk with the given header to `ids`, giving each the ID `base_id` plus its index.
+// The type ID of the chunk must already have been read.
+fn read_type_entries(data: &mut (impl Read + Seek),
+    chunk: &ChunkHeader,
+    base_id: u32,
+    key_names: &[Rc<str>],
+    ids: &mut HashMap<Rc<str>, u32>) -> Result<()> {
+    let flags = data.read_u8()?;
+    let _reserved = data.read_u16::<LE>()?;
+    let entry_count = data.read_u32::<LE>()?;
+    let entries_start = chunk.start + data.read_u32::<LE>()? as u64;
+
+    // Find the index and offset (from `entries_start`) of each entry that exists.
+    data.seek(SeekFrom::Start(chunk.start + chunk.header_len as u64))?;
+    let mut entries = Vec::new();
+    for idx in 0..entry_count {
+        if flags & TYPE_FLAG_SPARSE != 0 {
+            let idx = data.read_u16::<LE>()? as u32;
+            entries.push((idx, data.read_u16::<LE>()? as u64 * 4));
+        }   else if flags & TYPE_FLAG_OFFSET16 != 0 {
+            let offset = data.read_u16::<LE>()?;
+            if offset != NO_ENTRY_16 {
+                entries.push((idx, offset as u64 * 4));
+            }
+        }   else {
+            let offset = data.read_u32::<LE>()?;
+            if offset != NO_ENTRY {
+                entries.push((idx, offset as u64));
+            }
+        }
+    }
+
+    for (idx, offset) in entries {
+        data.seek(SeekFrom::Start(entries_start + offset))?;
+        let size_or_key = data.read_u16::<LE>()?;
+        let entry_flags = data.read_u16::<LE>()?;
+        let key = if entry_flags & ENTRY_FLAG_COMPACT != 0 {
+            size_or_key as u32
+        }   else {
+            data.read_u32::<LE>()?
+        };
+
+        let name = key_names.get(key as usize)
+            .ok_or(anyhow!("Entry key {key} was outside the key string pool"))?;
+        // Each configuration of a type has its own chunk, but gives the same names to the same IDs.
+        ids.entry(name.clone()).or_insert(base_id | idx);
+    }
+
+    Ok(())
+}
+
+fn read_string_pool_at(data: &mut (impl Read + Seek), offset: u64) -> Result<Vec<Rc<str>>> {
+    data.seek(SeekFrom::Start(offset))?;
+    let chunk = ChunkHeader::read(data)?;
+    if chunk.chunk_type != STRING_POOL_CHUNK {
+        return Err(anyhow!("Expected string pool"));
+    }
+
+    let (strings, _was_utf8) = load_string_pool(data)?;
+    Ok(strings)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use byteorder::WriteBytesExt;
+
+    use super::*;
+
+    const STRING_POOL_UTF8: u32 = 0x00000100;
+    const ENTRY_FLAG_COMPLEX: u16 = 0x0001;
+    const ATTR_TYPE_ID: u8 = 1;
+
+    // Wraps `header` (the fields after the type, header length and length) and `body` in a chunk of the given type.
+    fn chunk(chunk_type: u16, header: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.write_u16::<LE>(chunk_type).unwrap();
+        data.write_u16::<LE>(8 + header.len() as u16).unwrap();
+        data.write_u32::<LE>((8 + header.len() + body.len()) as u32).unwrap();
+        data.extend_from_slice(header);
+        data.extend_from_slice(body);
+        data
+    }
+
+    // A UTF-8 string pool of short strings, padded to a multiple of 4 bytes as in `aapt2` output.
+    fn string_pool(strings: &[&str]) -> Vec<u8> {
+        let mut header = Vec::new();
+        header.write_u32::<LE>(strings.len() as u32).unwrap();
+        header.write_u32::<LE>(0).unwrap();
+        header.write_u32::<LE>(STRING_POOL_UTF8).unwrap();
+        header.write_u32::<LE>(28 + 4 * strings.len() as u32).unwrap();
+        header.write_u32::<LE>(0).unwrap();
+
+        let (mut offsets, mut string_data) = (Vec::new(), Vec::new());
+        for string in strings {
+            offsets.write_u32::<LE>(string_data.len() as u32).unwrap();
+            string_data.extend_from_slice(&[string.len() as u8, string.len() as u8]);
+            string_data.extend_from_slice(string.as_bytes());
+            string_data.push(0);
+        }
+        string_data.resize(string_data.len().next_multiple_of(4), 0);
+        chunk(STRING_POOL_CHUNK, &header, &[offsets, string_data].concat())
+    }
+
+    // A type chunk for the given type ID, holding an entry with the given key (as an index into the key strings) at each index.
+    // Entries are located in the way that `flags` specifies, and are compact if `compact` is true.
+    fn type_chunk(type_id: u8, flags: u8, compact: bool, entries: &[(u32, u32)]) -> Vec<u8> {
+        let entry_count = if flags & TYPE_FLAG_SPARSE != 0 {
+            entries.len() as u32
+        }   else {
+            entries.iter().map(|(idx, _)| idx + 1).max().unwrap_or(0)
+        };
+        let offset_len = if flags & (TYPE_FLAG_SPARSE | TYPE_FLAG_OFFSET16) == TYPE_FLAG_OFFSET16 { 2 } else { 4 };
+        // After the type ID, flags, reserved field, entry count, entries offset and a configuration of only its size.
+        let header_len = 8 + 12 + 4;
+        let entry_len = 16;
+
+        let mut header = vec![type_id, flags];
+        header.write_u16::<LE>(0).unwrap();
+        header.write_u32::<LE>(entry_count).unwrap();
+        header.write_u32::<LE>(header_len + entry_count * offset_len).unwrap();
+        header.write_u32::<LE>(4).unwrap();
+
+        let mut offsets = Vec::new();
+        if flags & TYPE_FLAG_SPARSE != 0 {
+            for (position, (idx, _)) in entries.iter().enumerate() {
+                offsets.write_u16::<LE>(*idx as u16).unwrap();
+                offsets.write_u16::<LE>((position as u32 * entry_len / 4) as u16).unwrap();
+            }
+        }   else {
+            for idx in 0..entry_count {
+                let position = entries.iter().position(|(entry_idx, _)| *entry_idx == idx);
+                match (position, offset_len) {
+                    (Some(position), 2) => offsets.write_u16::<LE>((position as u32 * entry_len / 4) as u16).unwrap(),
+                    (Some(position), _) => offsets.write_u32::<LE>(position as u32 * entry_len).unwrap(),
+                    (None, 2) => offsets.write_u16::<LE>(NO_ENTRY_16).unwrap(),
+                    (None, _) => offsets.write_u32::<LE>(NO_ENTRY).unwrap()
+                }
+            }
+        }
+
+        // Each entry is a map entry (as attributes are) with no values.
+        let mut entry_data = Vec::new();
+        for (_, key) in entries {
+            if compact {
+                entry_data.write_u16::<LE>(*key as u16).unwrap();
+                entry_data.write_u16::<LE>(ENTRY_FLAG_COMPLEX | ENTRY_FLAG_COMPACT).unwrap();
+                entry_data.write_u32::<LE>(0).unwrap();
+            }   else {
+                entry_data.write_u16::<LE>(16).unwrap();
+                entry_data.write_u16::<LE>(ENTRY_FLAG_COMPLEX).unwrap();
+                entry_data.write_u32::<LE>(*key).unwrap();
+            }
+            entry_data.write_u32::<LE>(0).unwrap();
+            entry_data.write_u32::<LE>(0).unwrap();
+        }
+        chunk(TYPE_CHUNK, &header, &[offsets, entry_data].concat())
+    }
+
+    fn package(package_id: u32, type_names: &[&str], key_names: &[&str], type_chunks: &[Vec<u8>]) -> Vec<u8> {
+        let type_strings = string_pool(type_names);
+        let key_strings = string_pool(key_names);
+        let header_len = 8 + 4 + PACKAGE_NAME_LEN as u32 + 20;
+
+        let mut header = Vec::new();
+        header.write_u32::<LE>(package_id).unwrap();
+        header.extend_from_slice(&[0; PACKAGE_NAME_LEN as usize]);
+        header.write_u32::<LE>(header_len).unwrap();
+        header.write_u32::<LE>(type_names.len() as u32).unwrap();
+        header.write_u32::<LE>(header_len + type_strings.len() as u32).unwrap();
+        header.write_u32::<LE>(key_names.len() as u32).unwrap();
+        header.write_u32::<LE>(0).unwrap();
+        chunk(PACKAGE_CHUNK, &header, &[type_strings, key_strings, type_chunks.concat()].concat())
+    }
+
+    fn table(chunks: &[Vec<u8>]) -> Vec<u8> {
+        chunk(TABLE_CHUNK, &(chunks.len() as u32).to_le_bytes(), &chunks.concat())
+    }
+
+    /// Gives a minimal resource table with the given attributes in the `android` package, each with its name and entry index.
+    pub(crate) fn android_attribute_table(attributes: &[(&str, u32)]) -> Vec<u8> {
+        let names: Vec<&str> = attributes.iter().map(|(name, _)| *name).collect();
+        let entries: Vec<(u32, u32)> = attributes.iter().enumerate().map(|(key, (_, idx))| (*idx, key as u32)).collect();
+        table(&[
+            string_pool(&["a value"]),
+            package(ANDROID_PACKAGE_ID, &["attr"], &names, &[type_chunk(ATTR_TYPE_ID, 0, false, &entries)])
+        ])
+    }
+
+    fn read(table: &[u8]) -> Result<HashMap<Rc<str>, u32>> {
+        read_attribute_ids(&mut Cursor::new(table))
+    }
+
+    #[test]
+    fn attributes_are_read_from_every_kind_of_type_chunk() {
+        let key_names = ["name", "label", "icon", "theme", "versionCode", "appName"];
+        let android = package(ANDROID_PACKAGE_ID, &["attr", "id"], &key_names, &[
+            type_chunk(ATTR_TYPE_ID, 0, false, &[(0, 0), (2, 1)]),
+            type_chunk(ATTR_TYPE_ID, TYPE_FLAG_SPARSE, false, &[(5, 2)]),
+            type_chunk(ATTR_TYPE_ID, TYPE_FLAG_OFFSET16, true, &[(7, 3)]),
+            // Another configuration, which gives an existing name to a different index.
+            type_chunk(ATTR_TYPE_ID, 0, false, &[(3, 0)]),
+            // Not an attribute, so not read.
+            type_chunk(2, 0, false, &[(0, 4)])
+        ]);
+        // Attributes of the app itself, rather than the framework.
+        let app = package(0x7f, &["attr"], &key_names, &[type_chunk(ATTR_TYPE_ID, 0, false, &[(0, 5)])]);
+
+        let ids = read(&table(&[string_pool(&["a value"]), app, android])).unwrap();
+        let mut ids: Vec<(&str, u32)> = ids.iter().map(|(name, id)| (&**name, *id)).collect();
+        ids.sort();
+        assert_eq!(ids, [("icon", 0x01010005), ("label", 0x01010002), ("name", 0x01010000), ("theme", 0x01010007)]);
+    }
+
+    #[test]
+    fn minimal_table_gives_its_attributes() {
+        let ids = read(&android_attribute_table(&[("name", 3), ("label", 1)])).unwrap();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(ids["name"], 0x01010003);
+        assert_eq!(ids["label"], 0x01010001);
+    }
+
+    #[test]
+    fn invalid_tables_are_errors() {
+        let valid = android_attribute_table(&[("name", 3)]);
+        // The value of the last entry isn't needed, so only truncating before it is an error.
+        for len in 0..valid.len() - 8 {
+            assert!(read(&valid[..len]).is_err(), "table truncated to {len} bytes should be an error");
+        }
+
+        let err = |table: &[u8]| read(table).unwrap_err().to_string();
+        assert_eq!(err(&string_pool(&["name"])), "Initial chunk was not a resource table");
+        assert_eq!(err(&table(&[string_pool(&["a value"])])), "Resource table contained no android attributes");
+        assert_eq!(err(&table(&[package(0x7f, &["attr"], &["name"], &[type_chunk(ATTR_TYPE_ID, 0, false, &[(0, 0)])])])),
+            "Resource table contained no android attributes");
+        // A string pool chunk with a header but a length of zero.
+        let empty_chunk = vec![0x01, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(err(&table(&[empty_chunk])), "Resource table contained an empty chunk");
+
+        let no_attr_type = table(&[package(ANDROID_PACKAGE_ID, &["id"], &["name"], &[])]);
+        assert_eq!(format!("{:#}", read(&no_attr_type).unwrap_err()), "Failed to read package: Package had no attr type");
+        let missing_key = table(&[package(ANDROID_PACKAGE_ID, &["attr"], &["name"], &[type_chunk(ATTR_TYPE_ID, 0, false, &[(0, 1)])])]);
+        assert_eq!(format!("{:#}", read(&missing_key).unwrap_err()), "Failed to read package: Entry key 1 was outside the key string pool");
+    }
+}
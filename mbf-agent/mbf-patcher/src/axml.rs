@@ -0,0 +1,1577 @@
+//! Module to parse and save the Android binary XML format
+//! Used for modifying the APK manifest
+//! 
+//! LICENSING
+//! Although the MBF project is licensed under the GNU Affero General Public License,
+//! this file AND THIS FILE ONLY is also available under the MIT License.
+//! Copyright 2024 Laurie ?
+//! 
+//! Permission is hereby granted, free of charge, to any person obtaining a copy of this software and 
+//! associated documentation files (the “Software”), to deal in the Software without restriction,
+//! including without limitation the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//! and/or sell copies of the Software, and to permit persons to whom the Software is furnished to do so, subject to the following conditions:
+//! 
+//! The above copyright notice and this permission notice shall be included
+//! in all copies or substantial portions of the Software.
+//! 
+//! THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED,
+//! INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT.
+//! IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY,
+//! WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, 
+//! OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use std::{collections::{BTreeMap, HashMap}, io::{Cursor, Read, Seek, SeekFrom, Write}, rc::Rc};
+
+use anyhow::{Result, anyhow, Context};
+use byteorder::{ReadBytesExt, WriteBytesExt, BE, LE};
+
+// The maximum number of elements preallocated for a list whose length is given in the file.
+// Lists longer than this are still read, but the lengths given in malformed files cannot cause huge allocations.
+const MAX_PREALLOCATED: usize = 4096;
+// The length of the type and length fields at the start of every chunk.
+const CHUNK_HEADER_LEN: u32 = 8;
+
+/// An XML event within the main body of an AXML file.
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// An event that this implementation does not parse/understand, typically CData
+    /// The contents are written back unchanged, so any string indices within them refer to the string pool of the original file.
+    Unknown {
+        contents: Vec<u8>,
+        res_type: u32
+    },
+    StartNamespace(Namespace),
+    EndNamespace(Namespace),
+    StartElement {
+        attributes: Vec<Attribute>,
+        name: Rc<str>,
+        namespace: Option<Rc<str>>,
+        line_num: u32,
+    },
+    EndElement {
+        line_num: u32,
+        namespace: Option<Rc<str>>,
+        name: Rc<str>
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Namespace {
+    prefix: Option<Rc<str>>,
+    uri: Rc<str>
+}
+
+#[derive(Debug, Clone)]
+pub struct Attribute {
+    pub name: Rc<str>,
+    pub namespace: Option<Rc<str>>,
+    pub resource_id: Option<u32>,
+    pub value: AttributeValue
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    String(Rc<str>),
+    Boolean(bool),
+    Integer(i32),
+    // An integer written in hexadecimal, which aapt uses for flags, e.g. `android:configChanges`.
+    Hex(u32),
+    Float(f32),
+    Reference(u32), // Reference ID
+    // A value of any other type, e.g. a dimension or colour, which is kept as is.
+    Other {
+        type_id: u8,
+        data: u32
+    }
+}
+
+pub struct AxmlReader<'r, R: Read + Seek> {
+    data: &'r mut R,
+
+    string_pool: Vec<Rc<str>>,
+
+    // Map of resource IDs to resource map indices
+    res_map: Vec<u32>,
+
+    end_file_offset: u64,
+    // If true, chunks of unknown types cause an error, rather than being given as `Event::Unknown`.
+    strict: bool
+}
+
+impl<'r, R: Read + Seek> AxmlReader<'r, R> {
+    pub fn new(data: &'r mut R) -> Result<Self> {
+        // The initial structure of the AXML document is an XML tag, which contains, in order:
+        // The StringPool, then the XmlResourceMap (if any attributes have resource IDs), then all of the tags within a file
+
+        if ChunkType::parse(data.read_u32::<LE>()?) != Some(ChunkType::Xml) {
+            return Err(anyhow!("Initial chunk was not XML"));
+        }
+    
+        let file_size = data.read_u32::<LE>()?;
+        if ChunkType::parse(data.read_u32::<LE>()?) != Some(ChunkType::StringPool) {
+            return Err(anyhow!("Expected string pool after first XML tag"));
+        }
+        let post_string_pool = data.read_u32::<LE>()? as u64 + data.stream_position()? - 8;
+        let (string_pool, _was_utf8) = load_string_pool(data).context("Failed to load string pool")?;
+        data.seek(SeekFrom::Start(post_string_pool))?;
+
+        let res_map = Self::read_res_map(data, post_string_pool)?;
+        Ok(Self {
+            data,
+            string_pool,
+            res_map,
+            end_file_offset: file_size as u64,
+            strict: false
+        })
+    }
+
+    // Reads the resource map, which should follow the string pool that ends at `post_string_pool`.
+    // The resource map is left out by some tools if no attributes have resource IDs, in which case it is empty.
+    fn read_res_map(data: &mut R, post_string_pool: u64) -> Result<Vec<u32>> {
+        let c_type = ChunkType::parse(data.read_u32::<LE>()?);
+        if c_type != Some(ChunkType::XmlResourceMap) {
+            data.seek(SeekFrom::Start(post_string_pool))?;
+            return Ok(Vec::new());
+        }
+        let res_map_len = data.read_u32::<LE>()?;
+        let post_resource_map = data.stream_position()? + res_map_len as u64 - 8;
+
+        // Number of integers within the resource map. Subtract 2 due to the chunk type and length
+        let res_map_size = (res_map_len >> 2).checked_sub(2)
+            .ok_or(anyhow!("Resource map length {res_map_len} was too short"))?;
+        let mut res_map = Vec::with_capacity((res_map_size as usize).min(MAX_PREALLOCATED));
+        for _ in 0..res_map_size {
+            res_map.push(data.read_u32::<LE>()?);
+        }
+        
+        data.seek(SeekFrom::Start(post_resource_map))?;
+        Ok(res_map)
+    }
+
+    /// Sets whether chunks of unknown types cause `read_next_event` to fail. By default, they are given as `Event::Unknown`.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Reads the next event from the file.
+    pub fn read_next_event(&mut self) -> Result<Option<Event>> {
+        if self.data.stream_position()? >= self.end_file_offset {
+            return Ok(None)
+        }
+
+        let raw_res_type = self.data.read_u32::<LE>()?;
+        let length = self.data.read_u32::<LE>()?;
+        // Otherwise, seeking to the next chunk could go backwards, and the same chunks would be read forever.
+        if length < CHUNK_HEADER_LEN {
+            return Err(anyhow!("Chunk length {length} was shorter than its header"));
+        }
+        let post_ev_offset = self.data.stream_position()? - 8 + length as u64;
+
+        match ChunkType::parse(raw_res_type) {
+            Some(known) => {
+                let result = match known {
+                    ChunkType::StringPool 
+                    | ChunkType::Xml
+                    | ChunkType::XmlResourceMap => return Err(anyhow!("Invalid res type {raw_res_type} for main file contents")),
+                    ChunkType::XmlStartNamespace => Event::StartNamespace(self.read_namespace()?),
+                    ChunkType::XmlEndNamespace => Event::EndNamespace(self.read_namespace()?),
+                    ChunkType::XmlStartElement => self.read_element()?,
+                    ChunkType::XmlEndElement => self.read_end_element()?,
+                };
+
+                // Make sure to seek to the start of the next element
+                // (in case reading this element fails, we can continue from the next element)
+                self.data.seek(SeekFrom::Start(post_ev_offset))?;
+                
+                Ok(Some(result))
+            },
+            None if self.strict => Err(anyhow!("Unknown chunk type {raw_res_type:#010x}")),
+            None => {
+                // The length includes the chunk header. Reading with `take` means the buffer only grows as data is actually read.
+                let content_len = (length - CHUNK_HEADER_LEN) as u64;
+                let mut contents = Vec::new();
+                (&mut self.data).take(content_len).read_to_end(&mut contents)?;
+                if contents.len() as u64 != content_len {
+                    return Err(anyhow!("Chunk of type {raw_res_type} was truncated"));
+                }
+
+                Ok(Some(Event::Unknown { contents, res_type: raw_res_type }))
+            }
+        }
+    }
+
+    fn read_element(&mut self) -> Result<Event> {
+        let line_num = self.data.read_u32::<LE>()?;
+
+        if self.data.read_u32::<LE>()? != 0xFFFFFFFF {
+            return Err(anyhow!("Expected -1"));
+        }
+
+        let ns_idx = self.data.read_i32::<LE>()?;
+        let namespace = if ns_idx == -1 {
+            None
+        }   else    {
+            Some(self.get_pooled_string(ns_idx as u32)?.to_owned())
+        };
+
+        let name_idx = self.data.read_u32::<LE>()?;
+        let name = self.get_pooled_string(name_idx)?.to_owned();
+        if self.data.read_u32::<LE>()? != 0x00140014 {
+            return Err(anyhow!("Expected 0x00140014"));
+        }
+
+        // TODO: Can these be used in manifests? If so, we should add support for them.
+        let num_attributes = self.data.read_u16::<LE>()?;
+        let id_attr_idx = self.data.read_u16::<LE>()?;
+        let class_attr_idx = self.data.read_u16::<LE>()?;
+        let style_attr_idx = self.data.read_u16::<LE>()?;
+        if id_attr_idx != 0 || class_attr_idx != 0 || style_attr_idx != 0 {
+           return Err(anyhow!("Element indicated presence of style, class or ID attributes, which are not supported"))
+        }
+
+        let mut attributes = Vec::with_capacity(num_attributes as usize);
+        for _ in 0..num_attributes {
+            attributes.push(self.read_attribute()?);
+        }
+
+        Ok(Event::StartElement {
+            attributes,
+            name,
+            namespace,
+            line_num
+        })
+    }
+
+    fn read_attribute(&mut self) -> Result<Attribute> {
+        let attr_ns = self.string_pool.get(self.data.read_u32::<LE>()? as usize);
+        let name_and_res_id = self.data.read_u32::<LE>()?;
+
+        let _raw_string_idx = self.data.read_u32::<LE>()?; // Only used for id, class and style attributes
+        let type_id = self.data.read_u32::<LE>()?;
+        let raw_value = self.data.read_u32::<LE>()?;
+
+        let value = match AttributeTypeId::parse(type_id) {
+            Some(AttributeTypeId::Boolean) => AttributeValue::Boolean(raw_value > 0),
+            Some(AttributeTypeId::Int) => AttributeValue::Integer(raw_value as i32),
+            Some(AttributeTypeId::Hex) => AttributeValue::Hex(raw_value),
+            Some(AttributeTypeId::Float) => AttributeValue::Float(f32::from_bits(raw_value)),
+            Some(AttributeTypeId::String) => AttributeValue::String(self.get_pooled_string(raw_value)?),
+            Some(AttributeTypeId::Reference) => AttributeValue::Reference(raw_value),
+            None => AttributeValue::Other { type_id: (type_id >> 24) as u8, data: raw_value }
+        };
+
+        Ok(Attribute {
+            name: self.get_pooled_string(name_and_res_id)?,
+            namespace: attr_ns.cloned(),
+            resource_id: self.res_map.get(name_and_res_id as usize).copied(),
+            value,
+        })
+    }
+
+    fn read_end_element(&mut self) -> Result<Event> {
+        let line_num = self.data.read_u32::<LE>()?;
+        if self.data.read_u32::<LE>()? != 0xFFFFFFFF {
+            return Err(anyhow!("Expected -1"));
+        }
+
+        let ns_idx = self.data.read_i32::<LE>()?;
+        let namespace = if ns_idx == -1 {
+            None
+        }   else    {
+            Some(self.get_pooled_string(ns_idx as u32)?.to_owned())
+        };
+
+        let name_idx = self.data.read_u32::<LE>()?;
+        let name = self.get_pooled_string(name_idx)?;
+        Ok(Event::EndElement { line_num, namespace, name })
+    }
+
+    fn read_namespace(&mut self) -> Result<Namespace> {
+        let _line_num = self.data.read_u32::<LE>()?;
+
+        let _unknown = self.data.read_u32::<LE>()?;
+        let prefix_id = self.data.read_u32::<LE>()?;
+        let uri_id = self.data.read_u32::<LE>()?;
+
+        let prefix = if prefix_id == 0xFFFFFFFF {
+            None
+        }   else    {
+            Some(self.get_pooled_string(prefix_id)?.to_owned())
+        };
+
+        let uri = self.get_pooled_string(uri_id)?;
+        Ok(Namespace { prefix, uri })
+    }
+
+    fn get_pooled_string(&self, id: u32) -> Result<Rc<str>> {
+        match self.string_pool.get(id as usize) {
+            Some(s) => Ok(s.clone()),
+            None => Err(anyhow!("Invalid string index {id}"))
+        }
+    }
+}
+
+/// Reads the remaining events of `reader` and converts them to indented text XML, for debugging.
+/// This isn't guaranteed to be valid XML: every attribute and element is kept as is (including duplicates), and chunks
+/// that can't be parsed are written as comments. References are written as `@0x<resource ID>`, and values of other types
+/// that aren't understood are written with their type ID and raw data.
+pub fn to_readable_xml<R: Read + Seek>(reader: &mut AxmlReader<R>) -> Result<String> {
+    use std::fmt::Write;
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    // The prefixes of the namespaces that have been started, by URI.
+    let mut prefixes: HashMap<Rc<str>, Option<Rc<str>>> = HashMap::new();
+    // The namespaces started since the last element, which are declared on the next element.
+    let mut new_namespaces = Vec::new();
+    let mut depth = 0;
+    // True if the last element started has no children (yet), so can be closed with `/>` if it ends next.
+    let mut open_tag = false;
+
+    while let Some(event) = reader.read_next_event()? {
+        if open_tag && !matches!(event, Event::EndElement { .. } | Event::EndNamespace(_)) {
+            xml.push_str(">\n");
+            open_tag = false;
+        }
+
+        let indent = "    ".repeat(depth);
+        match event {
+            Event::StartNamespace(ns) => {
+                prefixes.insert(ns.uri.clone(), ns.prefix.clone());
+                new_namespaces.push(ns);
+            },
+            Event::EndNamespace(_) => {},
+            Event::StartElement { attributes, name, namespace, .. } => {
+                write!(xml, "{indent}<{}", qualified_name(&prefixes, namespace.as_ref(), &name))?;
+                for ns in new_namespaces.drain(..) {
+                    match &ns.prefix {
+                        Some(prefix) => write!(xml, " xmlns:{prefix}=\"{}\"", escape_xml(&ns.uri))?,
+                        None => write!(xml, " xmlns=\"{}\"", escape_xml(&ns.uri))?
+                    }
+                }
+                for attr in attributes {
+                    let value = match &attr.value {
+                        AttributeValue::String(s) => escape_xml(s),
+                        AttributeValue::Boolean(b) => b.to_string(),
+                        AttributeValue::Integer(i) => i.to_string(),
+                        AttributeValue::Hex(i) => format!("0x{i:08x}"),
+                        AttributeValue::Float(f) => f.to_string(),
+                        AttributeValue::Reference(id) => format!("@0x{id:08x}"),
+                        AttributeValue::Other { type_id, data } => format!("(type 0x{type_id:02x}) 0x{data:08x}")
+                    };
+                    write!(xml, "\n{indent}    {}=\"{value}\"", qualified_name(&prefixes, attr.namespace.as_ref(), &attr.name))?;
+                }
+
+                depth += 1;
+                open_tag = true;
+            },
+            Event::EndElement { name, namespace, .. } => {
+                depth = depth.saturating_sub(1);
+                if open_tag {
+                    xml.push_str(" />\n");
+                    open_tag = false;
+                }   else {
+                    writeln!(xml, "{}</{}>", "    ".repeat(depth), qualified_name(&prefixes, namespace.as_ref(), &name))?;
+                }
+            },
+            Event::Unknown { contents, res_type } => {
+                writeln!(xml, "{indent}<!-- Unknown chunk of type 0x{res_type:08x} ({} bytes) -->", contents.len())?;
+            }
+        }
+    }
+
+    Ok(xml)
+}
+
+// Gives the name of an element or attribute, prefixed with its namespace (or the URI of the namespace, if it has no known prefix).
+fn qualified_name(prefixes: &HashMap<Rc<str>, Option<Rc<str>>>, namespace: Option<&Rc<str>>, name: &str) -> String {
+    match namespace {
+        Some(uri) => match prefixes.get(uri) {
+            Some(Some(prefix)) => format!("{prefix}:{name}"),
+            _ => format!("{{{uri}}}{name}")
+        },
+        None => name.to_string()
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub struct AxmlWriter<'w, W: Write> {
+    data: &'w mut W,
+
+    // The index of the first entry for each string in the string pool.
+    string_pool: HashMap<Rc<str>, u32>,
+    // The index of entries for the names of attributes without a resource ID, for names that are also the name of an attribute
+    // with a resource ID. These need a separate entry, as every entry at the start of the pool has a resource ID.
+    unmapped_names: HashMap<Rc<str>, u32>,
+    linear_string_pool: Vec<Rc<str>>,
+
+    // The names of the attributes with resource IDs, by resource ID, which are added to the front of the string pool once every event
+    // has been written, in order of resource ID.
+    res_names: BTreeMap<u32, Rc<str>>,
+    // Set if an attribute was written with the same resource ID as another attribute with a different name.
+    res_name_conflict: Option<(u32, Rc<str>, Rc<str>)>,
+    res_map: HashMap<u32, u32>, // Key is resource ID, value is res map index
+    linear_res_map: Vec<u32>,
+
+    events: Vec<Event>,
+    main_contents: Cursor<Vec<u8>>
+}
+
+impl<'w, W: Write> AxmlWriter<'w, W> {
+    pub fn new(data: &'w mut W) -> Self {
+        Self {
+            data,
+            string_pool: HashMap::new(),
+            unmapped_names: HashMap::new(),
+            linear_string_pool: Vec::new(),
+            res_names: BTreeMap::new(),
+            res_name_conflict: None,
+            res_map: HashMap::new(),
+            linear_res_map: Vec::new(),
+            main_contents: Cursor::new(Vec::new()),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn write_event(&mut self, event: Event) {
+        if let Event::StartElement { attributes, .. } = &event {
+            self.prepare_res_map(attributes);
+        }
+
+        self.events.push(event);
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        // Write the contained events into the main chunk
+        // These are saved to a buffer as we need to know the total file length before we can write the start of the file
+        // (yes, this could be manually calculated as the elements are passed in, 
+        // we would also have to add all elements to the string pool at the same time, however)
+        //
+        // It's also not possible to carry out this process as the events are written, since we need attribute names and resource IDs to match
+        // (see prepare_res_map) 
+        if let Some((res_id, first_name, second_name)) = self.res_name_conflict.take() {
+            return Err(anyhow!("Attributes {first_name} and {second_name} had the same resource ID {res_id}.
+                Resource IDs must correspond one-to-one with attribute names"));
+        }
+
+        // The names of attributes with resource IDs come first, so that the index of each within the string pool matches the
+        // index of its resource ID within the resource map.
+        for (res_id, name) in std::mem::take(&mut self.res_names) {
+            let idx = self.linear_string_pool.len().try_into().context("Resource map too large")?;
+            self.res_map.insert(res_id, idx);
+            self.linear_res_map.push(res_id);
+            self.string_pool.entry(name.clone()).or_insert(idx);
+            self.linear_string_pool.push(name);
+        }
+
+        let mut events = Vec::new();
+        std::mem::swap(&mut self.events, &mut events);
+        for event in events {
+            self.write_event_internal(event)?;
+        }
+    
+        // The string pool must be padded to a multiple of 4 bytes
+        let str_pool_len: u32 = self.get_total_str_pool_len().try_into().context("String pool too large")?;
+        let str_pool_padding = (4 - str_pool_len % 4) % 4;
+        let res_pool_len = self.linear_res_map.len() as u32 * 4;
+
+        // The "XML" chunk is the parent chunk of the entire file
+        let total_xml_chunk_length = str_pool_len 
+            + str_pool_padding 
+            + res_pool_len 
+            + 16 // String pool and resource map headers
+            + self.main_contents.position() as u32;
+        Self::write_chunk_header(self.data, ChunkType::Xml, total_xml_chunk_length)?;
+
+        Self::write_chunk_header(self.data, ChunkType::StringPool, str_pool_len + str_pool_padding)?;
+        self.write_string_pool()?;
+        for _ in 0..str_pool_padding {
+            self.data.write_u8(0)?;
+        }
+
+        Self::write_chunk_header(self.data, ChunkType::XmlResourceMap, res_pool_len)?;
+        for res_id in self.linear_res_map {
+            self.data.write_u32::<LE>(res_id)?;
+        }
+
+        self.data.write_all(self.main_contents.get_mut())?;
+        Ok(())
+    }
+
+    fn prepare_res_map(&mut self, attributes: &[Attribute]) {
+        for attribute in attributes {
+            // For all attributes with resource IDs, record these and the respective attribute names NOW so that they can be
+            // added to the res map/string pool before any other strings, and so that the resource pool index matches with the
+            // string pool index for each. These must match as one field in the attribute corresponds to both indices.
+            if let Some(res_id) = attribute.resource_id {
+                let name = self.res_names.entry(res_id).or_insert(attribute.name.clone());
+                if *name != attribute.name && self.res_name_conflict.is_none() {
+                    self.res_name_conflict = Some((res_id, name.clone(), attribute.name.clone()));
+                }
+            }
+        }
+    }
+
+
+    fn write_event_internal(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Unknown { contents, res_type } => {
+                self.main_contents.write_u32::<LE>(res_type)?;
+                self.main_contents.write_u32::<LE>(u32::try_from(contents.len()).ok()
+                    .and_then(|len| len.checked_add(CHUNK_HEADER_LEN))
+                    .ok_or(anyhow!("Unknown chunk too long"))?)?;
+                self.main_contents.write_all(&contents)?;
+            },
+            Event::StartNamespace(ns) => self.write_start_namespace(ns)?,
+            Event::EndNamespace(ns) => self.write_end_namespace(ns)?,
+            Event::StartElement { attributes, name, namespace, line_num } =>
+                 self.write_start_element(attributes, name, namespace, line_num)?,
+            Event::EndElement { line_num, namespace, name } => 
+                self.write_end_element(line_num, namespace, name)?,
+        }
+
+        Ok(())
+    }
+
+    fn write_start_namespace(&mut self, ns: Namespace) -> Result<()> {
+        Self::write_chunk_header(&mut self.main_contents, ChunkType::XmlStartNamespace, 16)?;
+        self.write_ns_chunk_contents(ns)?;
+        Ok(())
+    }
+
+    fn write_end_namespace(&mut self, ns: Namespace) -> Result<()> {
+        Self::write_chunk_header(&mut self.main_contents, ChunkType::XmlEndNamespace, 16)?;
+        self.write_ns_chunk_contents(ns)?;
+        Ok(())
+    }
+
+    // Gets the length of an XmlStartElement chunk, not including the header
+    fn get_start_element_len(num_attributes: u32) -> u32 {
+        28 + 20 * num_attributes
+    }
+    
+    fn write_start_element(&mut self, mut attributes: Vec<Attribute>,
+        name: Rc<str>,
+        namespace: Option<Rc<str>>,
+        line_num: u32) -> Result<()> {
+        Self::write_chunk_header(&mut self.main_contents,
+            ChunkType::XmlStartElement,
+            Self::get_start_element_len(attributes.len()
+                .try_into().context("Too many attributes for element")?)
+        )?;
+
+        self.main_contents.write_u32::<LE>(line_num)?;
+        self.main_contents.write_i32::<LE>(-1)?;
+        if let Some(uri) = namespace {
+            let uri_idx = self.get_string_idx(uri)?;
+            self.main_contents.write_u32::<LE>(uri_idx)?;
+        }   else {
+            self.main_contents.write_i32::<LE>(-1)?;
+        }
+
+        let name_idx = self.get_string_idx(name)?;
+        self.main_contents.write_u32::<LE>(name_idx)?;
+        self.main_contents.write_u32::<LE>(0x00140014)?;
+
+        self.main_contents.write_u16::<LE>(attributes.len()
+            .try_into()
+            .context("Too many attributes")?)?;
+        self.main_contents.write_u16::<LE>(0)?;
+        self.main_contents.write_u16::<LE>(0)?;
+        self.main_contents.write_u16::<LE>(0)?;
+
+        // Attributes must be sorted in the order of increasing resource ID
+        // Otherwise, certain attributes are not properly detected by android
+        attributes.sort_by_key(|attr| attr.resource_id);
+
+        for attribute in attributes {
+            self.write_attribute(attribute)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_end_element(&mut self,
+        line_number: u32,
+        namespace: Option<Rc<str>>,
+        name: Rc<str>) -> Result<()> {
+        Self::write_chunk_header(&mut self.main_contents, ChunkType::XmlEndElement, 16)?;
+        self.main_contents.write_u32::<LE>(line_number)?;
+        self.main_contents.write_i32::<LE>(-1)?;
+        
+        if let Some(uri) = namespace {
+            let uri_idx = self.get_string_idx(uri)?;
+            self.main_contents.write_u32::<LE>(uri_idx)?;
+        }   else {
+            self.main_contents.write_i32::<LE>(-1)?;
+        }
+
+        let name_idx = self.get_string_idx(name)?;
+        self.main_contents.write_u32::<LE>(name_idx)?;
+
+        Ok(())
+    }
+
+    fn write_ns_chunk_contents(&mut self, ns: Namespace) -> Result<()> {
+        self.main_contents.write_i32::<LE>(-1)?;
+        self.main_contents.write_i32::<LE>(-1)?;
+
+        let prefix_idx = if let Some(prefix) = ns.prefix {
+            self.get_string_idx(prefix)?
+        }   else    {
+            0xFFFFFFFF
+        };
+
+        let uri_idx = self.get_string_idx(ns.uri)?;
+
+        self.main_contents.write_u32::<LE>(prefix_idx)?;
+        self.main_contents.write_u32::<LE>(uri_idx)?;
+        Ok(())
+    }
+
+    fn write_attribute(&mut self, attribute: Attribute) -> Result<()> {
+        if let Some(namespace) = attribute.namespace {
+            let ns_idx = self.get_string_idx(namespace)?;
+            self.main_contents.write_u32::<LE>(ns_idx)?;
+        }   else {
+            self.main_contents.write_i32::<LE>(-1)?;
+        }
+    
+        let name_idx = if let Some(res_id) = attribute.resource_id {
+            self.get_res_map_and_string_pool_idx(res_id, attribute.name)
+        }   else    {
+            self.get_unmapped_name_idx(attribute.name)
+        }?;
+        self.main_contents.write_u32::<LE>(name_idx)?;
+
+        // The purpose of the raw_str field seems to be unknown - it is -1, 
+        // except for strings where it takes the same value as raw_value
+        let (raw_value, raw_str, value_type) = match attribute.value {
+            AttributeValue::Boolean(true) => (-1, -1, AttributeTypeId::Boolean.save()),
+            AttributeValue::Boolean(false) => (0, -1, AttributeTypeId::Boolean.save()),
+            AttributeValue::Integer(i) => (i, -1, AttributeTypeId::Int.save()),
+            AttributeValue::Hex(i) => (i as i32, -1, AttributeTypeId::Hex.save()),
+            AttributeValue::Float(f) => (f.to_bits() as i32, -1, AttributeTypeId::Float.save()),
+            AttributeValue::Reference(link) => (link as i32, -1, AttributeTypeId::Reference.save()),
+            AttributeValue::String(str_value) => {
+                let str_idx = self.get_string_idx(str_value)?;
+                (str_idx as i32, str_idx as i32, AttributeTypeId::String.save())
+            },
+            AttributeValue::Other { type_id, data } => (data as i32, -1, AttributeTypeId::save_raw(type_id))
+        };
+
+        self.main_contents.write_i32::<LE>(raw_str)?;
+        self.main_contents.write_u32::<LE>(value_type)?;
+        self.main_contents.write_i32::<LE>(raw_value)?;
+        Ok(())
+    }
+
+    // Writes the header for a chunk.
+    // The `length` does not include the chunk header (8 bytes).
+    fn write_chunk_header(to: &mut impl Write, chunk_type: ChunkType, length: u32) -> Result<()> {
+        to.write_u32::<LE>(chunk_type.save())?;
+        to.write_u32::<LE>(length + 8)?; // Account for the chunk length and chunk type, each of which is 4 bytes.
+
+        Ok(())
+    }
+
+    // Gets the index of a string within the string pool, adding it if it isn't already in the pool.
+    // Each string is only added once, so this may give the index of the name of an attribute with a resource ID.
+    fn get_string_idx(&mut self, s: Rc<str>) -> Result<u32> {
+        match self.string_pool.get(&s) {
+            Some(idx) => Ok(*idx),
+            None => self.push_string(s)
+        }
+    }
+
+    // Gets the index of the name of an attribute without a resource ID within the string pool.
+    // This is never the index of the name of an attribute with a resource ID, since the attribute would then be given that ID.
+    fn get_unmapped_name_idx(&mut self, name: Rc<str>) -> Result<u32> {
+        match self.string_pool.get(&name) {
+            Some(idx) if *idx as usize >= self.linear_res_map.len() => Ok(*idx),
+            Some(_) => match self.unmapped_names.get(&name) {
+                Some(idx) => Ok(*idx),
+                None => {
+                    let idx = self.push_string(name.clone())?;
+                    self.unmapped_names.insert(name, idx);
+                    Ok(idx)
+                }
+            },
+            None => self.push_string(name)
+        }
+    }
+
+    // Adds a new entry to the end of the string pool, giving its index.
+    fn push_string(&mut self, s: Rc<str>) -> Result<u32> {
+        let new_idx = self.linear_string_pool.len().try_into()
+            .context("String pool too large")?;
+
+        self.string_pool.entry(s.clone()).or_insert(new_idx);
+        self.linear_string_pool.push(s);
+        Ok(new_idx)
+    }
+
+    // For resource IDs, the resource ID in the resource map and the attribute name must
+    // have matching indices in the string pool/resource map.
+    // This function verifies that this is the case and then returns the ID to use.
+    fn get_res_map_and_string_pool_idx(&mut self, res_id: u32, attr_name: Rc<str>) -> Result<u32> {
+        match self.res_map.get(&res_id) {
+            Some(res_idx) => if self.linear_string_pool[*res_idx as usize] == attr_name {
+                Ok(*res_idx)
+            }   else    {
+                Err(anyhow!("Attribute with name {attr_name} and ID {res_id} does not match previous usage of {res_id}.
+                    Resource IDs must correspond one-to-one with attribute names"))
+            },
+            None => panic!("Resource ID {res_id} for attribute {attr_name} was not prepared before save phase")
+        }
+    }
+    
+    // Gets the total length of the string pool chunk, not including the chunk header/chunk length bytes
+    fn get_total_str_pool_len(&self) -> usize {
+        let strings_len: usize = self.linear_string_pool.iter()
+            .map(|str| Self::get_pooled_str_len(str))
+            .sum();
+
+        20 + self.linear_string_pool.len() * 4 + strings_len
+    }
+
+    // Calculates the length of the given string within the string pool: its length in UTF-16 code units,
+    // its length in UTF-8 bytes, its UTF-8 bytes and then a null terminator.
+    fn get_pooled_str_len(str: &str) -> usize {
+        let varint_len = |len: usize| if len > 0x7F { 2 } else { 1 };
+        varint_len(str.encode_utf16().count()) + varint_len(str.len()) + str.len() + 1
+    }
+
+    // Saves the AXML string pool, as UTF-8
+    fn write_string_pool(&mut self) -> Result<()> {
+        self.data.write_u32::<LE>(self.linear_string_pool.len()
+            .try_into().context("String pool length too large")?)?;
+        self.data.write_u32::<LE>(0)?; // Style count, not implemented
+        self.data.write_u32::<LE>(UTF8_FLAG)?; // Strings are saved as UTF-8
+
+        // Offset from the start of the chunk to the first byte of the first string
+        let strings_offset = 7 * 4 + self.linear_string_pool.len() * 4;
+        self.data.write_u32::<LE>(strings_offset.try_into().context("String pool too large")?)?;
+        self.data.write_u32::<LE>(0)?; // Purpose unknown
+
+        // Write out the offset to each string within the pool
+        let mut curr_str_offset = 0;
+        for str in self.linear_string_pool.iter() {
+            self.data.write_u32::<LE>(curr_str_offset.try_into().context("String pool too large")?)?;
+            curr_str_offset += Self::get_pooled_str_len(str);
+        }
+
+        // Now write each string within the pool
+        for str in self.linear_string_pool.iter() {
+            write_utf8_len(self.data, str.encode_utf16().count())?;
+            write_utf8_len(self.data, str.len())?;
+            self.data.write_all(str.as_bytes())?;
+            self.data.write_u8(0)?;
+        }
+
+        Ok(())
+    }
+}
+
+const UTF8_FLAG: u32 = 0x00000100;
+pub(crate) fn load_string_pool(data: &mut (impl Read + Seek)) -> Result<(Vec<Rc<str>>, bool)> {
+    let begin_chunk = data.stream_position()? - 8; // -8 because of the chunk type/chunk length
+    let num_strings = data.read_u32::<LE>()?;
+    let _styles_offset = data.read_u32::<LE>()?; // Styles currently implemented
+
+    let flags = data.read_u32::<LE>()?;
+    // Default is UTF16 if the flag is not set
+    let utf8 = (flags & UTF8_FLAG) != 0;
+
+    let string_data_offset = data.read_u32::<LE>()?;
+    let _style_offset_count = data.read_u32::<LE>()?;
+
+    // Load the offsets of each string, which must be added to string_data_offset, then to the offset of the chunk beginning.
+    // This calculates the actual location of the string data.
+    let mut string_offsets = Vec::with_capacity((num_strings as usize).min(MAX_PREALLOCATED));
+    for _ in 0..num_strings {
+        string_offsets.push(data.read_u32::<LE>()?);
+    }
+
+    let mut result: Vec<Rc<str>> = Vec::with_capacity((num_strings as usize).min(MAX_PREALLOCATED));
+    for offset in string_offsets.into_iter() {
+        data.seek(SeekFrom::Start(begin_chunk + string_data_offset as u64 + offset as u64))?;
+
+        if utf8 {
+            let _ = read_utf8_len(data)?; // The length of the string in UTF-16 code units, which isn't needed to read it.
+
+            // TODO: Apparently extra bytes can exist beyond the end of this length according to our previous implementation
+            // Check if this is actually the case.
+            let length = read_utf8_len(data)? as usize;
+            let mut buffer = vec![0u8; length];
+            data.read_exact(&mut buffer)?;
+
+            result.push(std::str::from_utf8(&buffer)?.into());
+        }   else {
+            // Length is in UTF-16 codepoints
+            let length = read_utf16_len(data)? as usize;
+            let mut buffer: Vec<u16> = Vec::with_capacity(length.min(MAX_PREALLOCATED));
+            for _ in 0..length {
+                buffer.push(data.read_u16::<LE>()?);
+            }
+
+            result.push(String::from_utf16(&buffer)?.into());
+        }
+    }
+
+    Ok((result, utf8))
+}
+
+// Reads the length of a UTF-8 string as encoded in AXML. 
+// This is a 1-2 byte varint, meaning its maximum value is 32767, as 1 bit is wasted.
+fn read_utf8_len(data: &mut impl Read) -> Result<u16> {
+    let mut length = data.read_u8()? as u16;
+    if length & 0x80 != 0 { // Last bit set, so length is 2 bytes
+        length = ((length & 0x7F) << 8) | data.read_u8()? as u16;
+    }
+
+    Ok(length)
+}
+
+// Reads the length of a UTF-16 string as encoded in AXML.
+// This is a 2 or 4 byte varint.
+fn read_utf16_len(data: &mut impl Read) -> Result<u32> {
+    let mut length = data.read_u16::<LE>()? as u32;
+    if length & 0x8000 != 0 {
+        length = ((length & 0x7FFF) << 16) | data.read_u16::<LE>()? as u32;
+    }
+
+    Ok(length)
+}
+
+// Writes the given length as the varint used to represent the length of a UTF8 string in AXML
+fn write_utf8_len(data: &mut impl Write, len: usize) -> Result<()> {
+    if len > 0x7FFF {
+        return Err(anyhow!("String length is too long to save as UTF-8 {}", len))
+    }   else if len > 0x7F {
+        data.write_u16::<BE>((len | 0x8000) as u16)?;
+    }   else {
+        data.write_u8(len as u8)?;
+    }
+
+    Ok(())
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum ChunkType {
+    StringPool,
+    Xml,
+    XmlResourceMap,
+    XmlStartNamespace,
+    XmlEndNamespace,
+    XmlStartElement,
+    XmlEndElement
+}
+
+impl ChunkType {
+    pub fn parse(from: u32) -> Option<Self> {
+        match from & 0xFFFF {
+            0x0001 => Some(Self::StringPool),
+            0x0003 => Some(Self::Xml),
+            0x0180 => Some(Self::XmlResourceMap),
+            0x0103 => Some(Self::XmlEndElement),
+            0x0100 => Some(Self::XmlStartNamespace),
+            0x0101 => Some(Self::XmlEndNamespace),
+            0x0102 => Some(Self::XmlStartElement),
+            _ => None
+        }
+    }
+
+    pub fn save(&self) -> u32 {
+        let prefix = match self {
+            ChunkType::StringPool => 0x001C,
+            ChunkType::XmlResourceMap | ChunkType::Xml => 0x0008,
+            _ => 0x0010
+        };
+
+        let id = match self {
+            ChunkType::StringPool => 0x0001,
+            ChunkType::Xml => 0x0003,
+            ChunkType::XmlResourceMap => 0x0180,
+            ChunkType::XmlStartNamespace => 0x0100,
+            ChunkType::XmlEndNamespace => 0x0101,
+            ChunkType::XmlStartElement => 0x0102,
+            ChunkType::XmlEndElement => 0x0103,
+        };
+
+        id | prefix << 16
+    }
+}
+
+#[derive(Copy, Clone)]
+enum AttributeTypeId {
+    Int,
+    Boolean,
+    Hex,
+    Float,
+    Reference,
+    String
+}
+
+impl AttributeTypeId {
+    pub fn parse(from: u32) -> Option<AttributeTypeId> {
+        match from >> 24 {
+            0x10 => Some(Self::Int),
+            0x12 => Some(Self::Boolean),
+            0x11 => Some(Self::Hex),
+            0x04 => Some(Self::Float),
+            0x01 => Some(Self::Reference),
+            0x03 => Some(Self::String),
+            _ => None
+        }
+    }
+
+    pub fn save(&self) -> u32 {
+        let basic_type = match self {
+            Self::Int => 0x10,
+            Self::Boolean => 0x12,
+            Self::Hex => 0x11,
+            Self::Float => 0x04,
+            Self::Reference => 0x01,
+            Self::String => 0x03
+        };
+
+        Self::save_raw(basic_type)
+    }
+
+    // Gives the type field of an attribute value with the given type ID, i.e. the type ID and the size of the value (8 bytes).
+    pub fn save_raw(type_id: u8) -> u32 {
+        ((type_id as u32) << 24) | 0x000008
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    const ANDROID_NS: &str = "http://schemas.android.com/apk/res/android";
+    const ELEMENT_NAMES: &[&str] = &["manifest", "application", "activity", "uses-permission", "meta-data", "intent-filter"];
+    // Attribute names with the resource IDs that aapt gives them, so each name always has the same ID.
+    const MAPPED_ATTRIBUTES: &[(&str, u32)] = &[("label", 0x01010001), ("name", 0x01010003), ("value", 0x01010024), ("versionCode", 0x0101021b)];
+    const UNMAPPED_ATTRIBUTES: &[&str] = &["package", "name", "platformBuildVersionCode", "custom"];
+    const STRINGS: &[&str] = &["", "com.beatgames.beatsaber", "Beat Saber", "日本語", "🎵", "a\nb"];
+
+    fn random_value(rng: &mut StdRng) -> AttributeValue {
+        match rng.gen_range(0..7) {
+            0 => {
+                let mut string = STRINGS[rng.gen_range(0..STRINGS.len())].to_string();
+                // Strings longer than 127 bytes have a two byte length.
+                if rng.gen_bool(0.1) {
+                    string = string.repeat(100) + "x";
+                }
+                AttributeValue::String(string.into())
+            },
+            1 => AttributeValue::Boolean(rng.gen()),
+            2 => AttributeValue::Integer(rng.gen()),
+            3 => AttributeValue::Hex(rng.gen()),
+            4 => AttributeValue::Float(rng.gen()),
+            5 => AttributeValue::Reference(rng.gen()),
+            // Dimension and colour types, which aren't parsed.
+            _ => AttributeValue::Other { type_id: [0x05, 0x1c][rng.gen_range(0..2)], data: rng.gen() }
+        }
+    }
+
+    fn random_attribute(rng: &mut StdRng) -> Attribute {
+        let (name, resource_id) = if rng.gen_bool(0.6) {
+            let (name, id) = MAPPED_ATTRIBUTES[rng.gen_range(0..MAPPED_ATTRIBUTES.len())];
+            (name, Some(id))
+        }   else {
+            (UNMAPPED_ATTRIBUTES[rng.gen_range(0..UNMAPPED_ATTRIBUTES.len())], None)
+        };
+
+        Attribute {
+            name: name.into(),
+            namespace: resource_id.map(|_| ANDROID_NS.into()),
+            resource_id,
+            value: random_value(rng)
+        }
+    }
+
+    // Generates the events of a random manifest-like document, with a namespace around nested elements.
+    fn random_events(rng: &mut StdRng) -> Vec<Event> {
+        fn push_element(rng: &mut StdRng, events: &mut Vec<Event>, depth: u32) {
+            let name: Rc<str> = ELEMENT_NAMES[rng.gen_range(0..ELEMENT_NAMES.len())].into();
+            let line_num = rng.gen_range(0..1000);
+            events.push(Event::StartElement {
+                attributes: (0..rng.gen_range(0..5)).map(|_| random_attribute(rng)).collect(),
+                name: name.clone(),
+                namespace: None,
+                line_num
+            });
+            if depth < 6 {
+                for _ in 0..rng.gen_range(0..3) {
+                    push_element(rng, events, depth + 1);
+                }
+            }
+            events.push(Event::EndElement { line_num, namespace: None, name });
+        }
+
+        let namespace = Namespace { prefix: Some("android".into()), uri: ANDROID_NS.into() };
+        let mut events = vec![Event::StartNamespace(namespace.clone())];
+        push_element(rng, &mut events, 0);
+        if rng.gen_bool(0.2) {
+            events.push(Event::Unknown { contents: vec![1, 2, 3, 4, 0, 0, 0, 0], res_type: 0x0104 });
+        }
+        events.push(Event::EndNamespace(namespace));
+        events
+    }
+
+    fn write(events: impl IntoIterator<Item = Event>) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut writer = AxmlWriter::new(&mut output);
+        for event in events {
+            writer.write_event(event);
+        }
+        writer.finish().unwrap();
+        output
+    }
+
+    fn read(contents: &[u8]) -> Vec<Event> {
+        let mut cursor = Cursor::new(contents);
+        let mut reader = AxmlReader::new(&mut cursor).unwrap();
+        let mut events = Vec::new();
+        while let Some(event) = reader.read_next_event().unwrap() {
+            events.push(event);
+        }
+        events
+    }
+
+    // Attributes are sorted by resource ID when written, so are sorted the same way before comparing.
+    fn sorted_attributes(events: &[Event]) -> String {
+        let events: Vec<_> = events.iter().cloned().map(|mut event| {
+            if let Event::StartElement { attributes, .. } = &mut event {
+                attributes.sort_by_key(|attr| attr.resource_id);
+            }
+            event
+        }).collect();
+        format!("{events:?}")
+    }
+
+    // Reads every event from `contents`, which must finish (with an error or the end of the file) within a number of events
+    // bounded by the length of the file, since every chunk is at least 8 bytes long.
+    fn read_to_end_or_error(contents: &[u8]) {
+        let mut cursor = Cursor::new(contents);
+        let mut reader = match AxmlReader::new(&mut cursor) {
+            Ok(reader) => reader,
+            Err(_) => return
+        };
+
+        for _ in 0..=contents.len() / 8 {
+            match reader.read_next_event() {
+                Ok(Some(_)) => {},
+                Ok(None) | Err(_) => return
+            }
+        }
+        panic!("Reader did not finish within {} events", contents.len() / 8 + 1);
+    }
+
+    fn mutate(rng: &mut StdRng, contents: &mut Vec<u8>) {
+        for _ in 0..rng.gen_range(1..8) {
+            if contents.is_empty() {
+                return;
+            }
+
+            let pos = rng.gen_range(0..contents.len());
+            match rng.gen_range(0..5) {
+                0 => contents[pos] ^= 1 << rng.gen_range(0..8),
+                1 => contents[pos] = rng.gen(),
+                // Lengths and indices are little-endian u32s, so large values are most likely to find overflows.
+                2 => {
+                    let value: u32 = [0, 7, 0x7FFF_FFFF, 0xFFFF_FFFF, rng.gen()][rng.gen_range(0..5)];
+                    let at = pos.min(contents.len().saturating_sub(4));
+                    let end = (at + 4).min(contents.len());
+                    contents[at..end].copy_from_slice(&value.to_le_bytes()[..end - at]);
+                },
+                3 => contents.truncate(pos),
+                _ => contents.insert(pos, rng.gen())
+            }
+        }
+    }
+
+    #[test]
+    fn generated_documents_round_trip() {
+        let mut rng = StdRng::seed_from_u64(238);
+        for _ in 0..300 {
+            let events = random_events(&mut rng);
+            let written = write(events.clone());
+            let read_back = read(&written);
+            assert_eq!(sorted_attributes(&read_back), sorted_attributes(&events));
+
+            // Writing the events that were read gives exactly the same file.
+            assert_eq!(write(read_back), written);
+        }
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic_or_loop() {
+        let mut rng = StdRng::seed_from_u64(2380);
+        for _ in 0..3000 {
+            let len = rng.gen_range(0..256);
+            let mut contents: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            // Most random files fail on the first chunk type, so some are given a valid start.
+            if rng.gen_bool(0.5) && contents.len() >= 12 {
+                contents[0..4].copy_from_slice(&ChunkType::Xml.save().to_le_bytes());
+                contents[8..12].copy_from_slice(&ChunkType::StringPool.save().to_le_bytes());
+            }
+
+            read_to_end_or_error(&contents);
+        }
+    }
+
+    #[test]
+    fn mutated_documents_never_panic_or_loop() {
+        let mut rng = StdRng::seed_from_u64(2381);
+        for _ in 0..300 {
+            let written = write(random_events(&mut rng));
+            for _ in 0..20 {
+                let mut contents = written.clone();
+                mutate(&mut rng, &mut contents);
+
+                read_to_end_or_error(&contents);
+                if let Ok(mut reader) = AxmlReader::new(&mut Cursor::new(&contents)) {
+                    let _ = to_readable_xml(&mut reader);
+                }
+            }
+        }
+    }
+
+    // Builders for AXML laid out the way aapt lays it out, for tests of files that AxmlWriter would never produce itself,
+    // e.g. with UTF-16 or duplicate strings. These are written by hand from the format, not copied from a real APK.
+    fn raw_chunk(out: &mut Vec<u8>, chunk_type: u32, contents: &[u8]) {
+        out.extend_from_slice(&chunk_type.to_le_bytes());
+        out.extend_from_slice(&(contents.len() as u32 + CHUNK_HEADER_LEN).to_le_bytes());
+        out.extend_from_slice(contents);
+    }
+
+    fn raw_u32s(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    fn raw_document(strings: &[&str], utf8: bool, res_map: Option<&[u32]>, body: &[u8]) -> Vec<u8> {
+        let mut string_data = Vec::new();
+        let mut offsets = Vec::new();
+        for string in strings {
+            offsets.push(string_data.len() as u32);
+            if utf8 {
+                write_utf8_len(&mut string_data, string.encode_utf16().count()).unwrap();
+                write_utf8_len(&mut string_data, string.len()).unwrap();
+                string_data.extend_from_slice(string.as_bytes());
+                string_data.push(0);
+            }   else {
+                let units: Vec<u16> = string.encode_utf16().collect();
+                string_data.extend_from_slice(&(units.len() as u16).to_le_bytes());
+                string_data.extend(units.iter().chain(&[0]).flat_map(|unit| unit.to_le_bytes()));
+            }
+        }
+        while string_data.len() % 4 != 0 {
+            string_data.push(0);
+        }
+
+        let flags = if utf8 { UTF8_FLAG } else { 0 };
+        let mut pool = raw_u32s(&[strings.len() as u32, 0, flags, 28 + 4 * strings.len() as u32, 0]);
+        pool.extend(raw_u32s(&offsets));
+        pool.extend(string_data);
+
+        let mut contents = Vec::new();
+        raw_chunk(&mut contents, ChunkType::StringPool.save(), &pool);
+        if let Some(res_map) = res_map {
+            raw_chunk(&mut contents, ChunkType::XmlResourceMap.save(), &raw_u32s(res_map));
+        }
+        contents.extend_from_slice(body);
+
+        let mut document = Vec::new();
+        raw_chunk(&mut document, ChunkType::Xml.save(), &contents);
+        document
+    }
+
+    // Each attribute is its namespace, name, raw string, type and data, as in the file.
+    fn raw_start_element(body: &mut Vec<u8>, line_num: u32, name: u32, attributes: &[[u32; 5]]) {
+        let mut contents = raw_u32s(&[line_num, u32::MAX, u32::MAX, name, 0x00140014, attributes.len() as u32, 0]);
+        for attribute in attributes {
+            contents.extend(raw_u32s(attribute));
+        }
+        raw_chunk(body, ChunkType::XmlStartElement.save(), &contents);
+    }
+
+    fn raw_end_element(body: &mut Vec<u8>, line_num: u32, name: u32) {
+        raw_chunk(body, ChunkType::XmlEndElement.save(), &raw_u32s(&[line_num, u32::MAX, u32::MAX, name]));
+    }
+
+    fn raw_namespace(body: &mut Vec<u8>, chunk_type: ChunkType, prefix: u32, uri: u32) {
+        raw_chunk(body, chunk_type.save(), &raw_u32s(&[1, u32::MAX, prefix, uri]));
+    }
+
+    // Gives the string pool of `contents`, whether it is UTF-8, and the resource map.
+    fn pool_and_res_map(contents: &[u8]) -> (Vec<Rc<str>>, bool, Vec<u32>) {
+        let mut cursor = Cursor::new(contents);
+        cursor.set_position(16);
+        let (pool, utf8) = load_string_pool(&mut cursor).unwrap();
+
+        let pool_len = u32::from_le_bytes(contents[12..16].try_into().unwrap()) as usize;
+        let res_map_start = 8 + pool_len;
+        let res_map_len = u32::from_le_bytes(contents[res_map_start + 4..res_map_start + 8].try_into().unwrap()) as usize;
+        let res_map = contents[res_map_start + 8..res_map_start + res_map_len]
+            .chunks(4)
+            .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+            .collect();
+        (pool, utf8, res_map)
+    }
+
+    fn element(name: &str, attributes: Vec<Attribute>) -> [Event; 2] {
+        [
+            Event::StartElement { attributes, name: name.into(), namespace: None, line_num: 1 },
+            Event::EndElement { line_num: 1, namespace: None, name: name.into() }
+        ]
+    }
+
+    fn string_attribute(name: &str, resource_id: Option<u32>, value: &str) -> Attribute {
+        Attribute {
+            name: name.into(),
+            namespace: resource_id.map(|_| ANDROID_NS.into()),
+            resource_id,
+            value: AttributeValue::String(value.into())
+        }
+    }
+
+    const TYPE_INT: u32 = 0x10000008;
+    const TYPE_STRING: u32 = 0x03000008;
+
+    // A manifest with a UTF-16 string pool where the element and attribute value strings are repeated for each element that
+    // uses them, as some tools do.
+    fn utf16_manifest_with_duplicates() -> Vec<u8> {
+        let strings = ["versionCode", "name", "android", ANDROID_NS, "manifest", "package", "com.beatgames.beatsaber",
+            "uses-permission", "android.permission.INTERNET", "uses-permission", "android.permission.INTERNET",
+            "meta-data", "日本語 🎵", "package"];
+        let mut body = Vec::new();
+        raw_namespace(&mut body, ChunkType::XmlStartNamespace, 2, 3);
+        raw_start_element(&mut body, 2, 4, &[[u32::MAX, 5, 6, TYPE_STRING, 6], [3, 0, u32::MAX, TYPE_INT, 1130]]);
+        raw_start_element(&mut body, 3, 7, &[[3, 1, 8, TYPE_STRING, 8]]);
+        raw_end_element(&mut body, 3, 7);
+        raw_start_element(&mut body, 4, 9, &[[3, 1, 10, TYPE_STRING, 10]]);
+        raw_end_element(&mut body, 4, 9);
+        raw_start_element(&mut body, 5, 11, &[[3, 1, 12, TYPE_STRING, 12], [u32::MAX, 13, 6, TYPE_STRING, 6]]);
+        raw_end_element(&mut body, 5, 11);
+        raw_end_element(&mut body, 2, 4);
+        raw_namespace(&mut body, ChunkType::XmlEndNamespace, 2, 3);
+
+        raw_document(&strings, false, Some(&[0x0101021b, 0x01010003]), &body)
+    }
+
+    #[test]
+    fn utf16_manifest_with_duplicates_is_rewritten_without_them() {
+        let original = utf16_manifest_with_duplicates();
+        let events = read(&original);
+        let rewritten = write(events.clone());
+
+        assert_eq!(sorted_attributes(&read(&rewritten)), sorted_attributes(&events));
+        assert!(rewritten.len() <= original.len(), "Rewritten manifest was {} bytes, the original was {}", rewritten.len(), original.len());
+
+        let (pool, utf8, res_map) = pool_and_res_map(&rewritten);
+        assert!(utf8);
+        assert_eq!(res_map, [0x01010003, 0x0101021b]);
+        let pool: Vec<&str> = pool.iter().map(|s| &**s).collect();
+        assert_eq!(pool, ["name", "versionCode", "android", ANDROID_NS, "manifest", "package", "com.beatgames.beatsaber",
+            "uses-permission", "android.permission.INTERNET", "meta-data", "日本語 🎵"]);
+    }
+
+    #[test]
+    fn pool_has_one_entry_per_string() {
+        let mut rng = StdRng::seed_from_u64(283);
+        for _ in 0..100 {
+            let written = write(random_events(&mut rng));
+            let (pool, _, res_map) = pool_and_res_map(&written);
+
+            let mut seen = HashMap::new();
+            for (idx, string) in pool.iter().enumerate() {
+                if let Some(first_idx) = seen.insert(string.clone(), idx) {
+                    // The only repeated entries are the names of attributes with a resource ID, which are repeated once for attributes
+                    // with the same name but no resource ID.
+                    assert!(first_idx < res_map.len() && idx >= res_map.len(), "{string:?} repeated at {first_idx} and {idx}");
+                    assert!(UNMAPPED_ATTRIBUTES.contains(&&**string));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mapped_names_come_first_in_res_map_order() {
+        let mut rng = StdRng::seed_from_u64(2830);
+        for _ in 0..100 {
+            let events = random_events(&mut rng);
+            let written = write(events.clone());
+            let (pool, _, res_map) = pool_and_res_map(&written);
+
+            let mut used: Vec<(u32, &str)> = MAPPED_ATTRIBUTES.iter()
+                .filter(|(_, id)| events.iter().any(|event| matches!(event, Event::StartElement { attributes, .. }
+                    if attributes.iter().any(|attr| attr.resource_id == Some(*id)))))
+                .map(|(name, id)| (*id, *name))
+                .collect();
+            used.sort();
+
+            assert_eq!(res_map, used.iter().map(|(id, _)| *id).collect::<Vec<_>>());
+            let pool_names: Vec<&str> = pool[..res_map.len()].iter().map(|s| &**s).collect();
+            assert_eq!(pool_names, used.iter().map(|(_, name)| *name).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn unmapped_attribute_with_a_mapped_name_gets_a_second_entry() {
+        let events: Vec<Event> = element("activity", vec![string_attribute("name", Some(0x01010003), "name")]).into_iter()
+            .chain(element("activity", vec![string_attribute("name", None, "name")]))
+            .collect();
+        let written = write(events.clone());
+
+        let (pool, _, res_map) = pool_and_res_map(&written);
+        let pool: Vec<&str> = pool.iter().map(|s| &**s).collect();
+        assert_eq!(res_map, [0x01010003]);
+        // The values of both attributes share the entry of the mapped name.
+        assert_eq!(pool, ["name", "activity", ANDROID_NS, "name"]);
+        assert_eq!(sorted_attributes(&read(&written)), sorted_attributes(&events));
+    }
+
+    #[test]
+    fn pooled_strings_have_utf16_and_utf8_lengths() {
+        let long = "日本語".repeat(50);
+        let [start, end] = element("meta-data", vec![
+            string_attribute("emoji", None, "🎵"),
+            string_attribute("long", None, &long)
+        ]);
+        let written = write([start, end]);
+
+        // One byte lengths: 2 UTF-16 code units, 4 UTF-8 bytes.
+        assert!(written.windows(7).any(|w| w == [2, 4, 0xF0, 0x9F, 0x8E, 0xB5, 0]));
+        // Two byte big-endian lengths with the top bit set: 150 code units, 450 bytes.
+        let mut long_entry = vec![0x80, 150, 0x81, 0xC2];
+        long_entry.extend_from_slice(long.as_bytes());
+        long_entry.push(0);
+        assert!(written.windows(long_entry.len()).any(|w| w == long_entry));
+
+        let (pool, _, _) = pool_and_res_map(&written);
+        assert!(pool.iter().any(|s| **s == *long));
+    }
+
+    // A CDATA chunk, which the reader doesn't parse. The data is given as a string index, then as a typed string value.
+    const CDATA_CHUNK: u32 = 0x00100104;
+    fn raw_cdata(body: &mut Vec<u8>, line_num: u32, data: u32) {
+        raw_chunk(body, CDATA_CHUNK, &raw_u32s(&[line_num, u32::MAX, data, TYPE_STRING, data]));
+    }
+
+    // Small manifests with the oddities of files from other games, built by hand.
+    fn odd_manifests() -> Vec<(&'static str, Vec<u8>)> {
+        let mut corpus = Vec::new();
+
+        // No resource map at all, since no attributes have resource IDs, and text within the manifest.
+        let mut body = Vec::new();
+        raw_start_element(&mut body, 1, 0, &[[u32::MAX, 1, 2, TYPE_STRING, 2]]);
+        raw_cdata(&mut body, 2, 3);
+        raw_end_element(&mut body, 1, 0);
+        corpus.push(("no resource map", raw_document(&["manifest", "package", "com.example.game", "some text"], true, None, &body)));
+
+        // A second namespace started partway through the document, around one element.
+        let strings = ["name", "android", ANDROID_NS, "tools", "http://schemas.android.com/tools", "manifest", "application",
+            "activity", ".Main", "node"];
+        let mut body = Vec::new();
+        raw_namespace(&mut body, ChunkType::XmlStartNamespace, 1, 2);
+        raw_start_element(&mut body, 1, 5, &[]);
+        raw_start_element(&mut body, 2, 6, &[]);
+        raw_namespace(&mut body, ChunkType::XmlStartNamespace, 3, 4);
+        raw_start_element(&mut body, 3, 7, &[[2, 0, 8, TYPE_STRING, 8], [4, 9, 8, TYPE_STRING, 8]]);
+        raw_end_element(&mut body, 3, 7);
+        raw_namespace(&mut body, ChunkType::XmlEndNamespace, 3, 4);
+        raw_end_element(&mut body, 2, 6);
+        raw_end_element(&mut body, 1, 5);
+        raw_namespace(&mut body, ChunkType::XmlEndNamespace, 1, 2);
+        corpus.push(("nested namespace", raw_document(&strings, false, Some(&[0x01010003]), &body)));
+
+        // Chunks of types that don't exist in AXML (yet) between elements, one of which has no contents.
+        let mut body = Vec::new();
+        raw_start_element(&mut body, 1, 0, &[]);
+        raw_chunk(&mut body, 0x00100181, &raw_u32s(&[1, 2, 3]));
+        raw_start_element(&mut body, 2, 1, &[]);
+        raw_end_element(&mut body, 2, 1);
+        raw_chunk(&mut body, 0x00080105, &[]);
+        raw_cdata(&mut body, 3, 2);
+        raw_end_element(&mut body, 1, 0);
+        corpus.push(("unknown chunks", raw_document(&["manifest", "application", "text"], true, Some(&[]), &body)));
+
+        corpus
+    }
+
+    // Gives the raw bytes of every chunk in the body of `contents`, i.e. every chunk after the string pool and resource map.
+    fn body_chunks(contents: &[u8]) -> Vec<&[u8]> {
+        let pool_len = u32::from_le_bytes(contents[12..16].try_into().unwrap()) as usize;
+        let mut pos = 8 + pool_len;
+        let mut chunks = Vec::new();
+        while pos < contents.len() {
+            let len = u32::from_le_bytes(contents[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            if ChunkType::parse(u32::from_le_bytes(contents[pos..pos + 4].try_into().unwrap())) != Some(ChunkType::XmlResourceMap) {
+                chunks.push(&contents[pos..pos + len]);
+            }
+            pos += len;
+        }
+        chunks
+    }
+
+    #[test]
+    fn odd_manifests_round_trip() {
+        for (oddity, original) in odd_manifests() {
+            let events = read(&original);
+            let rewritten = write(events.clone());
+            assert_eq!(sorted_attributes(&read(&rewritten)), sorted_attributes(&events), "{oddity}");
+            // Rewriting again gives the same file.
+            assert_eq!(write(read(&rewritten)), rewritten, "{oddity}");
+
+            // Every chunk stays in the same place, and the unknown ones are copied exactly.
+            let (original_chunks, rewritten_chunks) = (body_chunks(&original), body_chunks(&rewritten));
+            assert_eq!(original_chunks.len(), rewritten_chunks.len(), "{oddity}");
+            for (original_chunk, rewritten_chunk) in original_chunks.iter().zip(&rewritten_chunks) {
+                let chunk_type = u32::from_le_bytes(original_chunk[0..4].try_into().unwrap());
+                if ChunkType::parse(chunk_type).is_none() {
+                    assert_eq!(original_chunk, rewritten_chunk, "{oddity}");
+                }   else {
+                    assert_eq!(original_chunk[0..4], rewritten_chunk[0..4], "{oddity}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_chunks_are_given_with_their_contents() {
+        let (_, original) = odd_manifests().remove(2);
+        let unknown: Vec<(u32, Vec<u8>)> = read(&original).into_iter()
+            .filter_map(|event| match event {
+                Event::Unknown { contents, res_type } => Some((res_type, contents)),
+                _ => None
+            })
+            .collect();
+
+        assert_eq!(unknown, [
+            (0x00100181, raw_u32s(&[1, 2, 3])),
+            (0x00080105, Vec::new()),
+            (CDATA_CHUNK, raw_u32s(&[3, u32::MAX, 2, TYPE_STRING, 2]))
+        ]);
+    }
+
+    #[test]
+    fn missing_resource_map_gives_attributes_without_ids() {
+        let (_, original) = odd_manifests().remove(0);
+        match &read(&original)[0] {
+            Event::StartElement { attributes, .. } => {
+                assert_eq!(attributes.len(), 1);
+                assert_eq!(&*attributes[0].name, "package");
+                assert_eq!(attributes[0].resource_id, None);
+                assert_eq!(attributes[0].value, AttributeValue::String("com.example.game".into()));
+            },
+            other => panic!("Expected the manifest element, got {other:?}")
+        }
+    }
+
+    #[test]
+    fn strict_reader_fails_on_unknown_chunks() {
+        for (oddity, original) in odd_manifests() {
+            let mut cursor = Cursor::new(&original);
+            let mut reader = AxmlReader::new(&mut cursor).unwrap().strict(true);
+            let mut result = Ok(None);
+            for _ in 0..original.len() / 8 {
+                result = reader.read_next_event();
+                if !matches!(result, Ok(Some(_))) {
+                    break;
+                }
+            }
+
+            if oddity == "nested namespace" {
+                // Nothing in this one is unknown.
+                assert!(matches!(result, Ok(None)), "{oddity}: {result:?}");
+            }   else {
+                let err = result.expect_err(oddity);
+                assert!(err.to_string().starts_with("Unknown chunk type"), "{oddity}: {err}");
+            }
+        }
+    }
+
+    // Values of each type as aapt writes them, with the type and data fields of the attribute record.
+    // The type field is the type ID in the top byte and the size of the value (8) in the bottom two bytes.
+    fn aapt_values() -> Vec<(&'static str, AttributeValue, u32, u32)> {
+        vec![
+            ("versionCode=\"1130\"", AttributeValue::Integer(1130), 0x10000008, 0x0000046a),
+            ("minSdkVersion=\"-1\"", AttributeValue::Integer(-1), 0x10000008, 0xffffffff),
+            ("configChanges=\"0x40003fb4\"", AttributeValue::Hex(0x40003fb4), 0x11000008, 0x40003fb4),
+            // aapt writes true as all bits set.
+            ("allowBackup=\"true\"", AttributeValue::Boolean(true), 0x12000008, 0xffffffff),
+            ("allowBackup=\"false\"", AttributeValue::Boolean(false), 0x12000008, 0x00000000),
+            ("maxAspectRatio=\"2.1\"", AttributeValue::Float(2.1), 0x04000008, 0x40066666),
+            ("label=\"@string/app_name\"", AttributeValue::Reference(0x7f0f0000), 0x01000008, 0x7f0f0000),
+            ("width=\"16dp\"", AttributeValue::Other { type_id: 0x05, data: 0x00001001 }, 0x05000008, 0x00001001),
+        ]
+    }
+
+    // Writes a manifest element with the given attribute and gives its attribute record as namespace, name, raw string, type and data.
+    fn written_record(value: AttributeValue) -> [u32; 5] {
+        let attribute = Attribute { name: "label".into(), namespace: Some(ANDROID_NS.into()), resource_id: Some(0x01010001), value };
+        let written = write(element("manifest", vec![attribute]));
+        let start_element = body_chunks(&written)[0];
+        assert_eq!(start_element.len(), 8 + 28 + 20);
+
+        let record: Vec<u32> = start_element[36..].chunks(4).map(|field| u32::from_le_bytes(field.try_into().unwrap())).collect();
+        record.try_into().unwrap()
+    }
+
+    #[test]
+    fn each_type_is_written_as_aapt_writes_it() {
+        for (example, value, type_field, data) in aapt_values() {
+            // The name is mapped, so comes first in the pool, then the element name and the namespace.
+            assert_eq!(written_record(value), [2, 0, u32::MAX, type_field, data], "{example}");
+        }
+    }
+
+    #[test]
+    fn string_is_written_with_its_index_in_both_fields() {
+        assert_eq!(written_record(AttributeValue::String("Beat Saber".into())), [2, 0, 3, TYPE_STRING, 3]);
+    }
+
+    #[test]
+    fn each_type_is_read_from_an_aapt_record() {
+        for (example, value, type_field, data) in aapt_values() {
+            let mut body = Vec::new();
+            raw_start_element(&mut body, 1, 1, &[[2, 0, u32::MAX, type_field, data]]);
+            raw_end_element(&mut body, 1, 1);
+            let contents = raw_document(&["label", "manifest", ANDROID_NS], false, Some(&[0x01010001]), &body);
+
+            match &read(&contents)[0] {
+                Event::StartElement { attributes, .. } => {
+                    assert_eq!(attributes[0].value, value, "{example}");
+                    assert_eq!(attributes[0].resource_id, Some(0x01010001), "{example}");
+                },
+                other => panic!("Expected an element, got {other:?}")
+            }
+
+            // Writing the value read gives the same record back.
+            assert_eq!(written_record(value), [2, 0, u32::MAX, type_field, data], "{example}");
+        }
+    }
+
+    #[test]
+    fn any_nonzero_boolean_is_true() {
+        let mut body = Vec::new();
+        raw_start_element(&mut body, 1, 1, &[[2, 0, u32::MAX, 0x12000008, 1]]);
+        raw_end_element(&mut body, 1, 1);
+        let contents = raw_document(&["enabled", "manifest", ANDROID_NS], true, Some(&[0x0101000e]), &body);
+
+        match &read(&contents)[0] {
+            Event::StartElement { attributes, .. } => assert_eq!(attributes[0].value, AttributeValue::Boolean(true)),
+            other => panic!("Expected an element, got {other:?}")
+        }
+    }
+}
+
@@ -0,0 +1,390 @@
+//! Breaks down what the space within an APK is used for, and why the size of an APK changed when it was patched.
+//! Everything is worked out from the central directory, so no entries are decompressed.
+
+use std::{collections::HashMap, fmt::Display};
+
+use serde::Serialize;
+
+use crate::{tag::MOD_TAG_PATH, zip::ArchiveLayout};
+
+// Entries that are only ever added to an APK by a patcher.
+const PATCHER_ENTRIES: &[&str] = &[MOD_TAG_PATH, "BMBF.modded"];
+
+/// What an entry within an APK is used for.
+#[derive(Serialize, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum EntryCategory {
+    /// Native libraries within `lib/<abi>/`
+    NativeLibs {
+        abi: String
+    },
+    Assets,
+    /// `resources.arsc` and the files within `res/`
+    Resources,
+    Dex,
+    /// The files within `META-INF/`, which include the V1 signature (if any).
+    Signing,
+    /// Entries added by MBF or another patcher, such as the mod tag.
+    PatcherAdded,
+    Other
+}
+
+impl EntryCategory {
+    /// Gets the category of the entry with the given name.
+    pub fn of(name: &str) -> Self {
+        if PATCHER_ENTRIES.contains(&name) {
+            Self::PatcherAdded
+        }   else if let Some(abi) = name.strip_prefix("lib/").and_then(|path| path.split_once('/')).map(|(abi, _)| abi) {
+            Self::NativeLibs { abi: abi.to_string() }
+        }   else if name.starts_with("assets/") {
+            Self::Assets
+        }   else if name.starts_with("res/") || name == "resources.arsc" {
+            Self::Resources
+        }   else if name.starts_with("META-INF/") {
+            Self::Signing
+        }   else if !name.contains('/') && name.ends_with(".dex") {
+            Self::Dex
+        }   else {
+            Self::Other
+        }
+    }
+}
+
+impl Display for EntryCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NativeLibs { abi } => write!(f, "Native libraries ({abi})"),
+            Self::Assets => write!(f, "Assets"),
+            Self::Resources => write!(f, "Resources"),
+            Self::Dex => write!(f, "DEX"),
+            Self::Signing => write!(f, "META-INF"),
+            Self::PatcherAdded => write!(f, "Added by patcher"),
+            Self::Other => write!(f, "Other")
+        }
+    }
+}
+
+/// The total size of the entries within a category.
+#[derive(Serialize, Clone, Debug)]
+pub struct CategoryTotals {
+    pub category: EntryCategory,
+    pub entries: usize,
+    pub compressed_len: u64,
+    pub uncompressed_len: u64
+}
+
+/// What the space within an APK is used for.
+#[derive(Serialize, Clone, Debug)]
+pub struct ApkComposition {
+    pub total_len: u64,
+    /// Ordered by category.
+    pub categories: Vec<CategoryTotals>,
+    /// The local headers of every entry, excluding their extra fields.
+    pub headers_len: u64,
+    /// See `ArchiveLayout::padding_len`
+    pub padding_len: u64,
+    pub signing_block_len: u64,
+    pub cent_dir_len: u64
+}
+
+impl ApkComposition {
+    /// Groups the entries of the given archive by category.
+    pub fn from_layout(layout: &ArchiveLayout) -> Self {
+        let mut categories: HashMap<EntryCategory, CategoryTotals> = HashMap::new();
+        for entry in &layout.entries {
+            let category = EntryCategory::of(&entry.name);
+            let totals = categories.entry(category.clone()).or_insert(CategoryTotals {
+                category,
+                entries: 0,
+                compressed_len: 0,
+                uncompressed_len: 0
+            });
+            totals.entries += 1;
+            totals.compressed_len += entry.compressed_len;
+            totals.uncompressed_len += entry.uncompressed_len;
+        }
+
+        let mut categories: Vec<CategoryTotals> = categories.into_values().collect();
+        categories.sort_by(|a, b| a.category.cmp(&b.category));
+
+        Self {
+            total_len: layout.total_len,
+            categories,
+            headers_len: layout.entries.iter().map(|entry| entry.header_len).sum(),
+            padding_len: layout.padding_len(),
+            signing_block_len: layout.signing_block_len,
+            cent_dir_len: layout.cent_dir_len
+        }
+    }
+}
+
+impl Display for ApkComposition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Total: {}", format_len(self.total_len))?;
+        for totals in &self.categories {
+            writeln!(f, "  {}: {} entries, {} ({} uncompressed)", totals.category, totals.entries,
+                format_len(totals.compressed_len), format_len(totals.uncompressed_len))?;
+        }
+        writeln!(f, "  Local headers: {}", format_len(self.headers_len))?;
+        writeln!(f, "  Padding/alignment: {}", format_len(self.padding_len))?;
+        writeln!(f, "  Signing block: {}", format_len(self.signing_block_len))?;
+        writeln!(f, "  Central directory: {}", format_len(self.cent_dir_len))
+    }
+}
+
+/// An entry that was added to or removed from an APK.
+#[derive(Serialize, Clone, Debug)]
+pub struct ChangedEntry {
+    pub name: String,
+    pub category: EntryCategory,
+    /// The compressed length of the entry, including its local header.
+    pub len: u64
+}
+
+/// The causes of a change in the size of an APK.
+/// The changes sum to `size_change`, so every byte of the change is attributed to one of them.
+#[derive(Serialize, Clone, Debug)]
+pub struct CompositionDelta {
+    pub size_change: i64,
+    pub added_entries: Vec<ChangedEntry>,
+    pub removed_entries: Vec<ChangedEntry>,
+    /// The entries whose contents changed, e.g. the manifest and replaced libraries.
+    pub replaced_entries: Vec<String>,
+    /// The change in the compressed size of `replaced_entries`.
+    pub replaced_change: i64,
+    /// The number of entries with the same contents whose compressed size changed, since they were compressed differently.
+    pub recompressed_entries: usize,
+    /// The change in the compressed size of `recompressed_entries`.
+    pub recompression_change: i64,
+    pub padding_change: i64,
+    pub signing_block_change: i64,
+    pub cent_dir_change: i64
+}
+
+impl CompositionDelta {
+    /// Attributes the difference in size between `before` and `after` to the entries that were added, removed or
+    /// changed, and to the metadata of each archive.
+    pub fn between(before: &ArchiveLayout, after: &ArchiveLayout) -> Self {
+        let before_entries: HashMap<&str, _> = before.entries.iter()
+            .map(|entry| (entry.name.as_str(), entry))
+            .collect();
+        let after_entries: HashMap<&str, _> = after.entries.iter()
+            .map(|entry| (entry.name.as_str(), entry))
+            .collect();
+
+        let mut delta = Self {
+            size_change: after.total_len as i64 - before.total_len as i64,
+            added_entries: Vec::new(),
+            removed_entries: Vec::new(),
+            replaced_entries: Vec::new(),
+            replaced_change: 0,
+            recompressed_entries: 0,
+            recompression_change: 0,
+            padding_change: after.padding_len() as i64 - before.padding_len() as i64,
+            signing_block_change: after.signing_block_len as i64 - before.signing_block_len as i64,
+            cent_dir_change: after.cent_dir_len as i64 - before.cent_dir_len as i64
+        };
+
+        for entry in &after.entries {
+            match before_entries.get(entry.name.as_str()) {
+                None => delta.added_entries.push(ChangedEntry {
+                    name: entry.name.clone(),
+                    category: EntryCategory::of(&entry.name),
+                    len: entry.header_len + entry.compressed_len
+                }),
+                // Entries with the same name have the same header length, so only the length of their data can change.
+                Some(original) => {
+                    let len_change = entry.compressed_len as i64 - original.compressed_len as i64;
+                    if entry.crc32 != original.crc32 || entry.uncompressed_len != original.uncompressed_len {
+                        delta.replaced_entries.push(entry.name.clone());
+                        delta.replaced_change += len_change;
+                    }   else if len_change != 0 {
+                        delta.recompressed_entries += 1;
+                        delta.recompression_change += len_change;
+                    }
+                }
+            }
+        }
+
+        delta.removed_entries = before.entries.iter()
+            .filter(|entry| !after_entries.contains_key(entry.name.as_str()))
+            .map(|entry| ChangedEntry {
+                name: entry.name.clone(),
+                category: EntryCategory::of(&entry.name),
+                len: entry.header_len + entry.compressed_len
+            })
+            .collect();
+
+        delta
+    }
+
+    /// The change in size caused by adding entries.
+    pub fn added_len(&self) -> u64 {
+        self.added_entries.iter().map(|entry| entry.len).sum()
+    }
+
+    /// The change in size caused by removing entries.
+    pub fn removed_len(&self) -> u64 {
+        self.removed_entries.iter().map(|entry| entry.len).sum()
+    }
+}
+
+impl Display for CompositionDelta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Total change: {}", format_change(self.size_change))?;
+        writeln!(f, "  Added {} entries: {}", self.added_entries.len(), format_change(self.added_len() as i64))?;
+        for entry in &self.added_entries {
+            writeln!(f, "    {} ({}): {}", entry.name, entry.category, format_len(entry.len))?;
+        }
+        if !self.removed_entries.is_empty() {
+            writeln!(f, "  Removed {} entries: {}", self.removed_entries.len(), format_change(-(self.removed_len() as i64)))?;
+            for entry in &self.removed_entries {
+                writeln!(f, "    {} ({}): {}", entry.name, entry.category, format_len(entry.len))?;
+            }
+        }
+        writeln!(f, "  Replaced {} entries: {}", self.replaced_entries.len(), format_change(self.replaced_change))?;
+        for name in &self.replaced_entries {
+            writeln!(f, "    {name}")?;
+        }
+        writeln!(f, "  Recompressed {} entries: {}", self.recompressed_entries, format_change(self.recompression_change))?;
+        writeln!(f, "  Padding/alignment: {}", format_change(self.padding_change))?;
+        writeln!(f, "  Signing block: {}", format_change(self.signing_block_change))?;
+        writeln!(f, "  Central directory: {}", format_change(self.cent_dir_change))
+    }
+}
+
+// Formats a length in bytes, with the length in MiB if it is large.
+fn format_len(len: u64) -> String {
+    const MIB: u64 = 1024 * 1024;
+    if len >= MIB {
+        format!("{:.1} MiB ({len} bytes)", len as f64 / MIB as f64)
+    }   else {
+        format!("{len} bytes")
+    }
+}
+
+fn format_change(change: i64) -> String {
+    if change < 0 {
+        format!("-{}", format_len(change.unsigned_abs()))
+    }   else {
+        format!("+{}", format_len(change as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zip::{EntryLayout, FileCompression};
+
+    fn entry(name: &str, crc32: u32, compressed_len: u64, uncompressed_len: u64) -> EntryLayout {
+        EntryLayout {
+            name: name.to_string(),
+            crc32,
+            compressed_len,
+            uncompressed_len,
+            compression_method: if compressed_len == uncompressed_len { FileCompression::Store } else { FileCompression::Deflate },
+            local_header_offset: 0,
+            header_len: 30 + name.len() as u64
+        }
+    }
+
+    // Lays out the entries one after the other with `padding_len` bytes of alignment between them.
+    fn layout(entries: Vec<EntryLayout>, padding_len: u64, signing_block_len: u64, cent_dir_len: u64) -> ArchiveLayout {
+        let entries_len: u64 = entries.iter().map(|entry| entry.header_len + entry.compressed_len).sum();
+        ArchiveLayout {
+            entries,
+            total_len: entries_len + padding_len + signing_block_len + cent_dir_len,
+            signing_block_len,
+            cent_dir_len
+        }
+    }
+
+    #[test]
+    fn entries_are_categorised_by_name() {
+        let cases = [
+            ("lib/arm64-v8a/libunity.so", EntryCategory::NativeLibs { abi: "arm64-v8a".to_string() }),
+            ("assets/bin/Data/data.unity3d", EntryCategory::Assets),
+            ("resources.arsc", EntryCategory::Resources),
+            ("res/drawable/icon.png", EntryCategory::Resources),
+            ("classes.dex", EntryCategory::Dex),
+            ("classes2.dex", EntryCategory::Dex),
+            ("META-INF/CERT.SF", EntryCategory::Signing),
+            ("modded.json", EntryCategory::PatcherAdded),
+            ("BMBF.modded", EntryCategory::PatcherAdded),
+            ("AndroidManifest.xml", EntryCategory::Other),
+            // Only DEX files at the root of the APK are loaded.
+            ("assets/classes.dex", EntryCategory::Assets),
+            ("lib/libmain.so", EntryCategory::Other)
+        ];
+        for (name, category) in cases {
+            assert_eq!(EntryCategory::of(name), category, "{name}");
+        }
+    }
+
+    #[test]
+    fn composition_totals_each_category() {
+        let layout = layout(vec![
+            entry("classes.dex", 1, 400, 1000),
+            entry("lib/arm64-v8a/libmain.so", 2, 5000, 5000),
+            entry("lib/arm64-v8a/libunity.so", 3, 7000, 7000),
+            entry("assets/a.bin", 4, 10, 20)
+        ], 300, 4096, 250);
+
+        let composition = ApkComposition::from_layout(&layout);
+        let totals: Vec<(EntryCategory, usize, u64, u64)> = composition.categories.iter()
+            .map(|totals| (totals.category.clone(), totals.entries, totals.compressed_len, totals.uncompressed_len))
+            .collect();
+        assert_eq!(totals, [
+            (EntryCategory::NativeLibs { abi: "arm64-v8a".to_string() }, 2, 12000, 12000),
+            (EntryCategory::Assets, 1, 10, 20),
+            (EntryCategory::Dex, 1, 400, 1000)
+        ]);
+        assert_eq!(composition.padding_len, 300);
+        assert_eq!(composition.headers_len, layout.entries.iter().map(|entry| entry.header_len).sum::<u64>());
+        assert!(composition.to_string().starts_with(&format!("Total: {} bytes\n", layout.total_len)));
+    }
+
+    #[test]
+    fn every_byte_of_a_size_change_is_attributed() {
+        let before = layout(vec![
+            entry("AndroidManifest.xml", 1, 800, 3000),
+            entry("classes.dex", 2, 400, 1000),
+            entry("lib/arm64-v8a/libmain.so", 3, 5000, 5000),
+            entry("META-INF/CERT.SF", 4, 200, 600),
+            entry("assets/a.bin", 5, 90, 200)
+        ], 100, 0, 400);
+        let after = layout(vec![
+            entry("AndroidManifest.xml", 6, 850, 3200),
+            entry("classes.dex", 2, 400, 1000),
+            entry("lib/arm64-v8a/libmain.so", 7, 2 * 1024 * 1024, 2 * 1024 * 1024),
+            entry("assets/a.bin", 5, 80, 200),
+            entry("modded.json", 8, 150, 300)
+        ], 16000, 4096, 420);
+
+        let delta = CompositionDelta::between(&before, &after);
+        assert_eq!(delta.replaced_entries, ["AndroidManifest.xml", "lib/arm64-v8a/libmain.so"]);
+        assert_eq!((delta.recompressed_entries, delta.recompression_change), (1, -10));
+        assert_eq!(delta.added_entries.iter().map(|entry| entry.name.as_str()).collect::<Vec<_>>(), ["modded.json"]);
+        assert_eq!(delta.removed_entries.iter().map(|entry| (entry.name.as_str(), &entry.category)).collect::<Vec<_>>(),
+            [("META-INF/CERT.SF", &EntryCategory::Signing)]);
+
+        let attributed = delta.added_len() as i64 - delta.removed_len() as i64 + delta.replaced_change + delta.recompression_change
+            + delta.padding_change + delta.signing_block_change + delta.cent_dir_change;
+        assert_eq!(attributed, delta.size_change);
+
+        let text = delta.to_string();
+        assert!(text.contains("  Added 1 entries: +191 bytes\n    modded.json (Added by patcher): 191 bytes\n"), "{text}");
+        assert!(text.contains("  Removed 1 entries: -246 bytes\n"), "{text}");
+        assert!(text.contains("  Signing block: +4096 bytes\n"), "{text}");
+        assert!(text.contains("  Replaced 2 entries: +2.0 MiB ("), "{text}");
+    }
+
+    #[test]
+    fn unchanged_archive_has_no_delta() {
+        let apk = layout(vec![entry("classes.dex", 2, 400, 1000)], 0, 4096, 100);
+        let delta = CompositionDelta::between(&apk, &apk);
+        assert_eq!(delta.size_change, 0);
+        assert!(delta.added_entries.is_empty() && delta.removed_entries.is_empty() && delta.replaced_entries.is_empty());
+        assert_eq!(delta.recompressed_entries, 0);
+        assert!(!delta.to_string().contains("Removed"));
+    }
+}
@@ -0,0 +1,157 @@
+//! Minimal parser for the header and string/type/class tables of DEX files.
+//! This is NOT a verifier: it only checks enough for a DEX file to be added to the APK with confidence that it contains a given class.
+
+use std::io::Cursor;
+
+use anyhow::{anyhow, Context, Result};
+use byteorder::{ReadBytesExt, LE};
+
+const HEADER_SIZE: usize = 0x70;
+const ENDIAN_CONSTANT: u32 = 0x12345678;
+const CLASS_DEF_SIZE: usize = 32;
+
+/// Checks that `dex` is a DEX file which declares the class with the (dot separated) name `class_name`, e.g. `com.example.App`
+pub fn check_declares_class(dex: &[u8], class_name: &str) -> Result<()> {
+    if dex.len() < HEADER_SIZE {
+        return Err(anyhow!("DEX file was too short to contain a header"));
+    }
+
+    // Magic is `dex\n` followed by a three digit version and a null byte.
+    if &dex[0..4] != b"dex\n" || !dex[4..7].iter().all(u8::is_ascii_digit) || dex[7] != 0 {
+        return Err(anyhow!("File was not a DEX file (invalid magic)"));
+    }
+    if read_u32(dex, 0x28)? != ENDIAN_CONSTANT {
+        return Err(anyhow!("DEX file had unsupported endianness"));
+    }
+
+    let string_ids_size = read_u32(dex, 0x38)? as usize;
+    let string_ids_off = read_u32(dex, 0x3C)? as usize;
+    let type_ids_size = read_u32(dex, 0x40)? as usize;
+    let type_ids_off = read_u32(dex, 0x44)? as usize;
+    let class_defs_size = read_u32(dex, 0x60)? as usize;
+    let class_defs_off = read_u32(dex, 0x64)? as usize;
+
+    let descriptor = format!("L{};", class_name.replace('.', "/"));
+    for class_def in 0..class_defs_size {
+        let type_idx = read_u32(dex, class_defs_off + class_def * CLASS_DEF_SIZE)? as usize;
+        if type_idx >= type_ids_size {
+            return Err(anyhow!("DEX class definition referred to invalid type {type_idx}"));
+        }
+
+        let string_idx = read_u32(dex, type_ids_off + type_idx * 4)? as usize;
+        if string_idx >= string_ids_size {
+            return Err(anyhow!("DEX type referred to invalid string {string_idx}"));
+        }
+
+        let string_data_off = read_u32(dex, string_ids_off + string_idx * 4)? as usize;
+        if read_string(dex, string_data_off)? == descriptor.as_bytes() {
+            return Ok(());
+        }
+    }
+
+    Err(anyhow!("DEX file does not declare the class `{class_name}`"))
+}
+
+fn read_u32(dex: &[u8], offset: usize) -> Result<u32> {
+    let bytes = dex.get(offset..offset + 4)
+        .ok_or(anyhow!("DEX file was truncated: offset {offset} is out of bounds"))?;
+    Ok(Cursor::new(bytes).read_u32::<LE>()?)
+}
+
+// Reads the (MUTF-8 encoded) bytes of the string_data_item at the given offset, not including the null terminator.
+fn read_string(dex: &[u8], offset: usize) -> Result<&[u8]> {
+    let data = dex.get(offset..).ok_or(anyhow!("DEX string offset {offset} is out of bounds"))?;
+
+    // Skip the ULEB128 encoded UTF-16 length of the string, which isn't needed since the string is null terminated.
+    let length_size = data.iter()
+        .take(5)
+        .position(|byte| byte & 0x80 == 0)
+        .context("DEX string had invalid length")? + 1;
+
+    let contents = &data[length_size..];
+    let end = contents.iter()
+        .position(|byte| *byte == 0)
+        .context("DEX string was not null terminated")?;
+    Ok(&contents[..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a DEX file with just a header and the string, type and class tables needed to declare the given classes.
+    // The string data comes last, so truncating the file cuts off the strings before the tables.
+    fn minimal_dex(class_names: &[&str]) -> Vec<u8> {
+        let count = class_names.len();
+        let string_ids_off = HEADER_SIZE;
+        let type_ids_off = string_ids_off + count * 4;
+        let class_defs_off = type_ids_off + count * 4;
+        let string_data_off = class_defs_off + count * CLASS_DEF_SIZE;
+
+        let mut dex = vec![0; string_data_off];
+        dex[0..8].copy_from_slice(b"dex\n035\0");
+        let write_u32 = |dex: &mut Vec<u8>, offset: usize, value: usize| dex[offset..offset + 4].copy_from_slice(&(value as u32).to_le_bytes());
+        write_u32(&mut dex, 0x28, ENDIAN_CONSTANT as usize);
+        for (offset, value) in [(0x38, count), (0x3C, string_ids_off), (0x40, count), (0x44, type_ids_off), (0x60, count), (0x64, class_defs_off)] {
+            write_u32(&mut dex, offset, value);
+        }
+
+        for (i, class_name) in class_names.iter().enumerate() {
+            let descriptor = format!("L{};", class_name.replace('.', "/"));
+            let string_data_off = dex.len();
+            write_u32(&mut dex, string_ids_off + i * 4, string_data_off);
+            dex.push(descriptor.len() as u8); // The UTF-16 length, as ULEB128
+            dex.extend_from_slice(descriptor.as_bytes());
+            dex.push(0);
+            write_u32(&mut dex, type_ids_off + i * 4, i);
+            write_u32(&mut dex, class_defs_off + i * CLASS_DEF_SIZE, i);
+        }
+        dex
+    }
+
+    #[test]
+    fn declared_classes_are_found() {
+        let dex = minimal_dex(&["com.example.Other", "com.example.App"]);
+        check_declares_class(&dex, "com.example.App").unwrap();
+        check_declares_class(&dex, "com.example.Other").unwrap();
+
+        let err = check_declares_class(&dex, "com.example.Missing").unwrap_err();
+        assert_eq!(err.to_string(), "DEX file does not declare the class `com.example.Missing`");
+    }
+
+    #[test]
+    fn truncated_dex_is_an_error_at_every_length() {
+        let dex = minimal_dex(&["com.example.App"]);
+        for len in 0..dex.len() {
+            let err = check_declares_class(&dex[..len], "com.example.App").expect_err("a truncated DEX file should be rejected");
+            let message = err.to_string();
+            assert!(message.contains("too short") || message.contains("truncated") || message.contains("out of bounds")
+                || message.contains("not null terminated") || message.contains("invalid length"), "{len} bytes: {message}");
+        }
+        assert_eq!(check_declares_class(&dex[..HEADER_SIZE - 1], "com.example.App").unwrap_err().to_string(),
+            "DEX file was too short to contain a header");
+    }
+
+    #[test]
+    fn files_that_are_not_dex_are_rejected() {
+        let mut dex = minimal_dex(&["com.example.App"]);
+        dex[4..7].copy_from_slice(b"0x5");
+        assert_eq!(check_declares_class(&dex, "com.example.App").unwrap_err().to_string(), "File was not a DEX file (invalid magic)");
+
+        let mut dex = minimal_dex(&["com.example.App"]);
+        dex[0x28..0x2C].copy_from_slice(&ENDIAN_CONSTANT.to_be_bytes());
+        assert_eq!(check_declares_class(&dex, "com.example.App").unwrap_err().to_string(), "DEX file had unsupported endianness");
+    }
+
+    #[test]
+    fn out_of_range_indices_are_rejected() {
+        let mut dex = minimal_dex(&["com.example.App"]);
+        let class_defs_off = u32::from_le_bytes(dex[0x64..0x68].try_into().unwrap()) as usize;
+        dex[class_defs_off..class_defs_off + 4].copy_from_slice(&7u32.to_le_bytes());
+        assert_eq!(check_declares_class(&dex, "com.example.App").unwrap_err().to_string(), "DEX class definition referred to invalid type 7");
+
+        let mut dex = minimal_dex(&["com.example.App"]);
+        dex[0x64..0x68].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(check_declares_class(&dex, "com.example.App").unwrap_err().to_string().contains("out of bounds"));
+    }
+}
@@ -0,0 +1,27 @@
+//! Device independent logic for modifying APKs: editing `AndroidManifest.xml`, replacing files, tagging the APK as modded and signing it.
+//! This is used by the MBF agent, which adds the steps needed to reinstall the APK on a quest, but has no dependency on Android itself.
+//!
+//! Most users will only need `ApkPatcher`:
+//! ```no_run
+//! # use mbf_patcher::{ApkPatcher, FileSource, manifest::ManifestMod};
+//! # fn patch(cert_pem: &[u8]) -> anyhow::Result<()> {
+//! let report = ApkPatcher::new("base.apk")
+//!     .with_manifest_mod(ManifestMod::new().debuggable(true))
+//!     .with_replaced_lib("libmain.so", FileSource::Path("libmain.so".into()))
+//!     .sign_with(cert_pem)?
+//!     .write_to("patched.apk".as_ref())?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod axml;
+pub mod composition;
+pub mod dex;
+pub mod manifest;
+pub mod zip;
+mod arsc;
+mod patcher;
+mod tag;
+
+pub use patcher::{ApkPatcher, FileSource, PatchPlan, PatchReport};
+pub use tag::{AppliedApplicationOverride, ModTag, MOD_TAG_PATH};
@@ -0,0 +1,1307 @@
+//! Module containing convenience functions for modifying AndroidManifest.xml
+
+use std::{collections::{HashMap, HashSet}, io::{Cursor, Read, Seek, Write}, rc::Rc};
+
+use anyhow::{Context, Result, anyhow};
+use byteorder::{ReadBytesExt, LE};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{arsc, axml::{Attribute, AttributeValue, AxmlReader, AxmlWriter, Event}, zip::ZipFile};
+
+const ANDROID_NS_URI: &str = "http://schemas.android.com/apk/res/android";
+const RESOURCE_ID_TABLE: &[u8] = include_bytes!("resourceIds.bin");
+const METADATA_TAG: &str = "com.modsbeforefriday.modded";
+// Added to each `<intent-filter>` added by MBF, so that they can be replaced when the APK is next patched.
+// This has no namespace or resource ID, so is ignored by Android.
+const INTENT_FILTER_MARKER: &str = "modsBeforeFriday";
+
+pub struct ManifestInfo {
+    // The `package` attribute of the manifest, i.e. the package ID of the app.
+    pub package_id: Option<String>,
+    pub package_version: String,
+    // The `android:versionCode` of the manifest, if it has one.
+    pub version_code: Option<u32>,
+    // True if the app requests legacy external storage rather than MANAGE_EXTERNAL_STORAGE, i.e. the storage fallback was applied.
+    pub legacy_storage: bool,
+    // The value of `android:name` on the `<application>` element, i.e. the app's custom Application subclass, if it has one.
+    pub application_name: Option<String>,
+    // The names of the permissions requested with `<uses-permission>`, in the order they appear.
+    pub permissions: Vec<String>
+}
+
+impl ManifestInfo {
+    pub fn read<T: Read + Seek>(reader: &mut AxmlReader<T>) -> Result<Self> {
+        let mut version: Option<String> = None;
+        let mut version_code = None;
+        let mut package_id = None;
+        let mut legacy_storage = false;
+        let mut application_name = None;
+        let mut permissions = Vec::new();
+        while let Some(event) = reader.read_next_event()? {
+            if let Event::StartElement {
+                attributes,
+                name,
+                .. 
+            } = event {
+                if &*name == "uses-permission" {
+                    if let Ok(permission) = ManifestMod::get_name_attribute(&attributes) {
+                        permissions.push(permission.to_string());
+                    }
+                }
+                if &*name == "application" {
+                    legacy_storage = attributes.iter()
+                        .any(|attr| &*attr.name == "requestLegacyExternalStorage" && attr.value == AttributeValue::Boolean(true));
+                    application_name = Self::get_application_name(&attributes);
+                }
+
+                if &*name != "manifest" {
+                    continue;
+                }
+
+                package_id = attributes.iter()
+                    .find(|attr| &*attr.name == "package")
+                    .and_then(|attr| match &attr.value {
+                        AttributeValue::String(s) => Some(s.to_string()),
+                        _ => None
+                    });
+
+                version_code = attributes.iter()
+                    .find(|attr| &*attr.name == "versionCode")
+                    .and_then(|attr| match attr.value {
+                        AttributeValue::Integer(i) => u32::try_from(i).ok(),
+                        _ => None
+                    });
+
+                let version_attr = attributes.iter()
+                    .find(|attr| &*attr.name == "versionName");
+
+                match version_attr {
+                    Some(attr) => match &attr.value {
+                        AttributeValue::String(s) => version = Some(s.to_string()),
+                        _ => return Err(anyhow!("Package version must be a string"))
+                    },
+                    None => return Err(anyhow!("No package version attribute"))
+                }
+            }
+        }
+
+        match version {
+            Some(package_version) => Ok(Self {
+                package_id,
+                package_version,
+                version_code,
+                legacy_storage,
+                application_name,
+                permissions
+            }),
+            None => Err(anyhow!("No useful information found in the manifest"))
+        }
+    }
+
+    fn get_application_name(attributes: &[Attribute]) -> Option<String> {
+        match &attributes.iter().find(|attr| &*attr.name == "name")?.value {
+            AttributeValue::String(s) => Some(s.to_string()),
+            _ => None
+        }
+    }
+}
+
+
+/// Convenient builder that can be used to modify the APK manifest
+#[derive(Deserialize)]
+pub struct ManifestMod {
+    add_permissions: Vec<Rc<str>>,
+    add_features: Vec<Rc<str>>,
+    // The permissions and features added on top of those the frontend always requests, e.g. for mods that need them.
+    // These are also in `add_permissions` and `add_features`, and are recorded in the mod tag.
+    #[serde(skip)]
+    extra_permissions: Vec<Rc<str>>,
+    #[serde(skip)]
+    extra_features: Vec<Rc<str>>,
+    // Permissions to remove from the manifest. These are never added, even if also in `add_permissions`.
+    #[serde(default)]
+    remove_permissions: Vec<Rc<str>>,
+    #[serde(default)]
+    remove_attributes: Vec<AttributeRemoval>,
+    #[serde(default)]
+    add_intent_filters: Vec<IntentFilter>,
+    // `<meta-data>` elements to add to the `<application>` element, replacing any existing ones with the same name.
+    // If a name is given more than once, the last value is used.
+    #[serde(default)]
+    meta_data: Vec<MetaData>,
+    // The package names and intent actions to declare in `<queries>`, so that they are visible to the app on Android 11+.
+    // These are added to the existing `<queries>` element if the manifest has one.
+    #[serde(default)]
+    query_packages: Vec<Rc<str>>,
+    #[serde(default)]
+    query_intent_actions: Vec<Rc<str>>,
+    // The values to give `android:versionName` and `android:versionCode` on the `<manifest>` element, or None to leave them unchanged.
+    #[serde(default)]
+    version_name: Option<Rc<str>>,
+    #[serde(default)]
+    version_code: Option<u32>,
+    #[serde(default = "bool::default")]
+    debuggable: bool,
+    // If true, the app will request legacy external storage and the classic storage permissions,
+    // instead of MANAGE_EXTERNAL_STORAGE, for devices where MANAGE_EXTERNAL_STORAGE can't be granted.
+    #[serde(default = "bool::default")]
+    legacy_storage: bool,
+    // Replaces the app's Application subclass with a class from an additional DEX file.
+    #[serde(default)]
+    application_override: Option<ApplicationOverride>,
+    // The value to give `android:name` on the `<application>` element.
+    // None leaves the attribute unchanged, Some(None) removes it.
+    #[serde(skip)]
+    application_name: Option<Option<Rc<str>>>,
+    // Other attributes to set on the `<application>` element, by name, replacing any existing attribute with the same name.
+    // Each has the resource ID to use, or None to look it up in `ResourceIds`.
+    #[serde(skip)]
+    application_attributes: Vec<(Rc<str>, Option<u32>, AttributeValue)>
+}
+
+/// An attribute to remove from every element with the given name, e.g. `requestLegacyExternalStorage` on `application`.
+#[derive(Deserialize, Clone)]
+pub struct AttributeRemoval {
+    pub element: String,
+    /// The name of the attribute, without its namespace.
+    pub attribute: String
+}
+
+/// A `<meta-data>` element within the `<application>` element, which mods and the modloader can read configuration from.
+#[derive(Deserialize, Clone)]
+pub struct MetaData {
+    /// The `android:name` of the element.
+    pub name: String,
+    /// The `android:value` of the element.
+    pub value: MetaDataValue
+}
+
+/// The value of a `<meta-data>` element, which is written with the matching AXML type.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum MetaDataValue {
+    Boolean(bool),
+    Integer(i32),
+    String(String)
+}
+
+impl MetaDataValue {
+    fn to_attribute_value(&self) -> AttributeValue {
+        match self {
+            Self::Boolean(b) => AttributeValue::Boolean(*b),
+            Self::Integer(i) => AttributeValue::Integer(*i),
+            Self::String(s) => AttributeValue::String(s.as_str().into())
+        }
+    }
+}
+
+/// An `<intent-filter>` to add to an activity, e.g. so that the app is opened for links with a custom URI scheme.
+#[derive(Deserialize, Clone)]
+pub struct IntentFilter {
+    /// The `android:name` of the activity to add the filter to, exactly as it appears in the manifest.
+    pub activity: String,
+    #[serde(default)]
+    pub actions: Vec<String>,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    /// The `android:scheme` of the `<data>` element, e.g. `beatsaver`. No `<data>` element is added if this and the host are None.
+    #[serde(default)]
+    pub data_scheme: Option<String>,
+    #[serde(default)]
+    pub data_host: Option<String>
+}
+
+impl IntentFilter {
+    /// Creates an empty intent filter for the activity with the given `android:name`.
+    pub fn new(activity: &str) -> Self {
+        Self {
+            activity: activity.to_string(),
+            actions: Vec::new(),
+            categories: Vec::new(),
+            data_scheme: None,
+            data_host: None
+        }
+    }
+
+    /// Adds an `<action>`, e.g. `android.intent.action.VIEW`.
+    pub fn with_action(mut self, action: &str) -> Self {
+        self.actions.push(action.to_string());
+        self
+    }
+
+    /// Adds a `<category>`, e.g. `android.intent.category.BROWSABLE`.
+    pub fn with_category(mut self, category: &str) -> Self {
+        self.categories.push(category.to_string());
+        self
+    }
+
+    /// Sets the scheme and (optionally) host of the `<data>` element.
+    pub fn with_data(mut self, scheme: &str, host: Option<&str>) -> Self {
+        self.data_scheme = Some(scheme.to_string());
+        self.data_host = host.map(str::to_string);
+        self
+    }
+
+    // Writes the filter as an `<intent-filter>` element and its children.
+    fn write<W: Write>(&self, writer: &mut AxmlWriter<W>, res_ids: &ResourceIds) {
+        let intent_filter: Rc<str> = "intent-filter".into();
+        writer.write_event(Event::StartElement {
+            attributes: vec![Attribute {
+                name: INTENT_FILTER_MARKER.into(),
+                namespace: None,
+                resource_id: None,
+                value: AttributeValue::Boolean(true)
+            }],
+            name: intent_filter.clone(),
+            namespace: None,
+            line_num: 0
+        });
+
+        for action in &self.actions {
+            write_named_element(writer, "action".into(), action.as_str().into(), res_ids);
+        }
+        for category in &self.categories {
+            write_named_element(writer, "category".into(), category.as_str().into(), res_ids);
+        }
+        if self.data_scheme.is_some() || self.data_host.is_some() {
+            let data: Rc<str> = "data".into();
+            let attributes = [("scheme", &self.data_scheme), ("host", &self.data_host)].into_iter()
+                .filter_map(|(name, value)| Some(android_attribute(name, AttributeValue::String(value.as_deref()?.into()), res_ids)))
+                .collect();
+            writer.write_event(Event::StartElement {
+                attributes,
+                name: data.clone(),
+                namespace: None,
+                line_num: 0
+            });
+            writer.write_event(Event::EndElement {
+                name: data,
+                namespace: None,
+                line_num: 0
+            });
+        }
+
+        writer.write_event(Event::EndElement {
+            name: intent_filter,
+            namespace: None,
+            line_num: 0
+        });
+    }
+}
+
+/// A custom Application subclass to use for the app, which allows code to run earlier than the modloader.
+#[derive(Deserialize, Clone)]
+pub struct ApplicationOverride {
+    /// The full name of the class, e.g. `com.example.EarlyApplication`
+    pub class_name: String,
+    /// Path to a DEX file on the quest which contains the class. This will be added to the APK as the next `classes{N}.dex`
+    pub dex_path: String
+}
+
+impl Default for ManifestMod {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ManifestMod {
+    pub fn new() -> Self {
+        Self {
+            add_permissions: Vec::new(),
+            add_features: Vec::new(),
+            extra_permissions: Vec::new(),
+            extra_features: Vec::new(),
+            remove_permissions: Vec::new(),
+            remove_attributes: Vec::new(),
+            add_intent_filters: Vec::new(),
+            meta_data: Vec::new(),
+            query_packages: Vec::new(),
+            query_intent_actions: Vec::new(),
+            version_name: None,
+            version_code: None,
+            debuggable: false,
+            legacy_storage: false,
+            application_override: None,
+            application_name: None,
+            application_attributes: Vec::new()
+        }
+    }
+
+    pub fn with_feature(mut self, feature: &str) -> Self {
+        self.add_features.push(feature.into());
+        self
+    }
+
+    pub fn with_permission(mut self, feature: &str) -> Self {
+        self.add_permissions.push(feature.into());
+        self
+    }
+
+    /// Adds a permission on top of those needed to mod the game, e.g. `android.permission.RECORD_AUDIO` for voice mods.
+    /// Unlike `with_permission`, the permission is listed by `get_extra_permissions` so that it can be recorded.
+    pub fn with_extra_permission(mut self, permission: &str) -> Self {
+        self.extra_permissions.push(permission.into());
+        self.with_permission(permission)
+    }
+
+    /// Adds a `uses-feature` on top of those needed to mod the game, which is listed by `get_extra_features`.
+    pub fn with_extra_feature(mut self, feature: &str) -> Self {
+        self.extra_features.push(feature.into());
+        self.with_feature(feature)
+    }
+
+    /// Removes the `uses-permission` with the given name from the manifest, if it exists, and stops it from being added.
+    pub fn without_permission(mut self, permission: &str) -> Self {
+        self.remove_permissions.push(permission.into());
+        self
+    }
+
+    /// Removes the attribute with the given name (without its namespace) from every element named `element`, if it exists.
+    /// This is done after any attributes are set, so takes priority over e.g. `debuggable`.
+    pub fn remove_attribute(mut self, element: &str, attribute: &str) -> Self {
+        self.remove_attributes.push(AttributeRemoval {
+            element: element.to_string(),
+            attribute: attribute.to_string()
+        });
+        self
+    }
+
+    /// Adds the given intent filter to its activity, replacing the intent filters added when the APK was last patched.
+    /// Patching fails if the manifest has no activity with the filter's `activity` name.
+    pub fn with_intent_filter(mut self, filter: IntentFilter) -> Self {
+        self.add_intent_filters.push(filter);
+        self
+    }
+
+    /// Adds a `<meta-data>` element with the given name and value to the `<application>` element.
+    /// Any existing element with the same name has its value replaced, as does a previous call with the same name.
+    pub fn with_meta_data(mut self, name: &str, value: MetaDataValue) -> Self {
+        self.meta_data.retain(|meta_data| meta_data.name != name);
+        self.meta_data.push(MetaData { name: name.to_string(), value });
+        self
+    }
+
+    /// Declares the package with the given name in `<queries>`, so that the app can see it if installed.
+    pub fn with_queried_package(mut self, package: &str) -> Self {
+        self.query_packages.push(package.into());
+        self
+    }
+
+    /// Declares an intent with the given action in `<queries>`, so that the app can see apps that handle it.
+    pub fn with_queried_intent(mut self, action: &str) -> Self {
+        self.query_intent_actions.push(action.into());
+        self
+    }
+
+    /// Sets `android:versionName` on the `<manifest>` element, which is the game version reported for the installed APK.
+    /// Mods and core mods are matched against this version, so changing it may stop them being found.
+    pub fn version_name(mut self, version_name: String) -> Self {
+        self.version_name = Some(version_name.into());
+        self
+    }
+
+    /// Sets `android:versionCode` on the `<manifest>` element, e.g. to a higher value so that the APK can be installed over
+    /// a newer version. Patching fails if this is more than `i32::MAX`, as Android stores it as a signed integer.
+    pub fn version_code(mut self, version_code: u32) -> Self {
+        self.version_code = Some(version_code);
+        self
+    }
+
+    pub fn get_extra_permissions(&self) -> &[Rc<str>] {
+        &self.extra_permissions
+    }
+
+    pub fn get_extra_features(&self) -> &[Rc<str>] {
+        &self.extra_features
+    }
+
+    pub fn debuggable(mut self, debuggable: bool) -> Self {
+        self.debuggable = debuggable;
+        self
+    }
+
+    pub fn legacy_storage(mut self, legacy_storage: bool) -> Self {
+        self.legacy_storage = legacy_storage;
+        self
+    }
+
+    pub fn uses_legacy_storage(&self) -> bool {
+        self.legacy_storage
+    }
+
+    pub fn get_application_override(&self) -> Option<&ApplicationOverride> {
+        self.application_override.as_ref()
+    }
+
+    /// Sets the value of `android:name` on the `<application>` element, or removes it if `name` is None.
+    pub fn application_name(mut self, name: Option<&str>) -> Self {
+        self.application_name = Some(name.map(Into::into));
+        self
+    }
+
+    /// Sets `android:extractNativeLibs` on the `<application>` element.
+    /// If false, Android requires native libraries to be stored uncompressed, which the patcher doesn't do, so the APK will fail to install.
+    pub fn extract_native_libs(self, extract: bool) -> Self {
+        self.with_application_attribute("extractNativeLibs", None, AttributeValue::Boolean(extract))
+    }
+
+    /// Sets `android:requestLegacyExternalStorage` on the `<application>` element.
+    /// Unlike `legacy_storage`, this doesn't change which storage permissions are granted after patching.
+    pub fn legacy_external_storage(self, legacy: bool) -> Self {
+        self.with_application_attribute("requestLegacyExternalStorage", None, AttributeValue::Boolean(legacy))
+    }
+
+    /// Sets the attribute with the given name (without its namespace) on the `<application>` element, in the android namespace.
+    /// `res_id` is the resource ID of the attribute, or None to use the ID from `ResourceIds`, in which case patching
+    /// panics if `ResourceIds` has no ID for the attribute.
+    /// If set more than once, the last value given is used.
+    pub fn with_application_attribute(mut self, name: &str, res_id: Option<u32>, value: AttributeValue) -> Self {
+        self.application_attributes.push((name.into(), res_id, value));
+        self
+    }
+
+    // Sets the `name` attribute on the given attribute list to `value`, or removes it if `value` is None.
+    // Returns true if any value was actually changed, false otherwise.
+    fn apply_name_attribute(attributes: &mut Vec<Attribute>, value: Option<&Rc<str>>, res_ids: &ResourceIds) -> bool {
+        let existing = attributes.iter().position(|attr| &*attr.name == "name");
+        match (existing, value) {
+            (Some(idx), Some(value)) => {
+                if attributes[idx].value != AttributeValue::String(value.clone()) {
+                    attributes[idx].value = AttributeValue::String(value.clone());
+                    true
+                }   else {
+                    false
+                }
+            },
+            (Some(idx), None) => {
+                attributes.remove(idx);
+                true
+            },
+            (None, Some(value)) => {
+                attributes.push(name_attribute(value.clone(), res_ids));
+                true
+            },
+            (None, None) => false
+        }
+    }
+
+    // Set the attribute with the given name on the given attribute list to "true".
+    // Returns true if any value was actually changed, false otherwise.
+    fn apply_true_attribute(attributes: &mut Vec<Attribute>, attr_name: &str, res_ids: &ResourceIds) -> bool {
+        Self::apply_attribute(attributes, attr_name, AttributeValue::Boolean(true), res_ids.get_res_id(attr_name))
+    }
+
+    // Set the attribute with the given name on the given attribute list to `value`, removing any duplicates of it,
+    // since `pm install` rejects an APK with duplicate attributes.
+    // Returns true if any value was actually changed, false otherwise.
+    fn apply_attribute(attributes: &mut Vec<Attribute>, attr_name: &str, value: AttributeValue, res_id: u32) -> bool {
+        let mut modified = false;
+        if let Some(idx) = attributes
+            .iter()
+            .position(|attr| &*attr.name == attr_name) {
+            // Set the value of the attribute if it exists
+            if attributes[idx].value != value {
+                attributes[idx].value = value;
+                modified = true;
+            }
+
+            // Remove any later duplicates of the attribute.
+            while let Some(duplicate) = attributes.iter().skip(idx + 1).position(|attr| &*attr.name == attr_name) {
+                attributes.remove(idx + 1 + duplicate);
+                modified = true;
+            }
+        }   else    {
+            // Add the attribute if one doesn't already exist.
+            attributes.push(Attribute {
+                name: attr_name.into(),
+                namespace: Some(ANDROID_NS_URI.into()),
+                value,
+                resource_id: Some(res_id)
+            });
+            modified = true;
+        }
+
+        modified
+    }
+
+    // Removes the attributes requested to be removed from an element with the given name.
+    // Returns true if any attribute was actually removed, false otherwise.
+    fn apply_attribute_removals(&self, element: &str, attributes: &mut Vec<Attribute>) -> bool {
+        let original_len = attributes.len();
+        attributes.retain(|attr| {
+            let remove = self.remove_attributes.iter()
+                .any(|removal| removal.element == element && removal.attribute == *attr.name);
+            if remove {
+                info!("Removing attribute `{}` from `{element}`", attr.name);
+            }
+            !remove
+        });
+
+        attributes.len() != original_len
+    }
+
+    // Gives the value to set for the `<meta-data>` element with the given name, if any.
+    fn get_meta_data(&self, name: &str) -> Option<&MetaDataValue> {
+        self.meta_data.iter()
+            .rev()
+            .find(|meta_data| meta_data.name == name)
+            .map(|meta_data| &meta_data.value)
+    }
+
+    // Sets the value of an existing `<meta-data>` element with the given attributes, if a value was requested for it.
+    // Returns true if any value was actually changed, false otherwise.
+    fn apply_meta_data(&self, attributes: &mut Vec<Attribute>, res_ids: &ResourceIds) -> bool {
+        let Ok(name) = Self::get_name_attribute(attributes) else {
+            return false;
+        };
+        let Some(value) = self.get_meta_data(&name) else {
+            return false;
+        };
+
+        info!("Setting meta-data `{name}` to `{value:?}`");
+        // The value replaces a resource, if the element referred to one.
+        let original_len = attributes.len();
+        attributes.retain(|attr| &*attr.name != "resource");
+        let value_res_id = res_ids.get_res_id("value");
+        Self::apply_attribute(attributes, "value", value.to_attribute_value(), value_res_id) || attributes.len() != original_len
+    }
+
+    // Writes the requested `<meta-data>` elements that weren't already in the `<application>` element.
+    // Returns true if any were written.
+    fn write_meta_data<W: Write>(&self, writer: &mut AxmlWriter<W>, existing: &mut HashSet<String>, res_ids: &ResourceIds) -> bool {
+        let mut modified = false;
+        for meta_data in &self.meta_data {
+            if existing.insert(meta_data.name.clone()) {
+                let value = self.get_meta_data(&meta_data.name).unwrap_or(&meta_data.value);
+                info!("Adding meta-data `{}` with value `{value:?}`", meta_data.name);
+                write_valued_element(writer, "meta-data".into(), meta_data.name.as_str().into(), value.to_attribute_value(), res_ids);
+                modified = true;
+            }
+        }
+
+        modified
+    }
+
+    // Writes the requested `<package>` and `<intent>` elements of `<queries>` that aren't in the given existing sets.
+    // Each is added to its set once written. Returns true if any were written.
+    fn write_queries<W: Write>(&self,
+        writer: &mut AxmlWriter<W>,
+        existing_packages: &mut HashSet<Rc<str>>,
+        existing_actions: &mut HashSet<Rc<str>>,
+        res_ids: &ResourceIds) -> bool {
+        let mut modified = false;
+        let package: Rc<str> = "package".into();
+        for package_name in &self.query_packages {
+            if existing_packages.insert(package_name.clone()) {
+                info!("Adding query for package `{package_name}`");
+                write_named_element(writer, package.clone(), package_name.clone(), res_ids);
+                modified = true;
+            }
+        }
+
+        let intent: Rc<str> = "intent".into();
+        for action in &self.query_intent_actions {
+            if existing_actions.insert(action.clone()) {
+                info!("Adding query for intent `{action}`");
+                writer.write_event(Event::StartElement {
+                    attributes: Vec::new(),
+                    name: intent.clone(),
+                    namespace: None,
+                    line_num: 0
+                });
+                write_named_element(writer, "action".into(), action.clone(), res_ids);
+                writer.write_event(Event::EndElement {
+                    name: intent.clone(),
+                    namespace: None,
+                    line_num: 0
+                });
+                modified = true;
+            }
+        }
+
+        modified
+    }
+
+    fn is_permission_removed(&self, permission: &str) -> bool {
+        self.remove_permissions.iter().any(|removed| &**removed == permission)
+    }
+
+    fn get_name_attribute(attributes: &[Attribute]) -> Result<Rc<str>> {
+        match &attributes.iter()
+            .find(|attr| &*attr.name == "name")
+            .ok_or(anyhow!("No valid `name` attribute existed"))?.value
+        {
+            AttributeValue::String(s) => Ok(s.clone()),
+            _ => Err(anyhow!("`name` attribute had non-string value!"))
+        }
+    }
+
+    // Adds the requested permissions and features to the APK, and removes the requested permissions and attributes.
+    // Removing something that isn't in the manifest does nothing. Every other element is copied unchanged.
+    // Returns true if any changes were actually made, false otherwise.
+    // This can be used to avoid overwriting the manifest which would add to the ZIP size due to the naive ZIP library.
+    pub fn apply_mod<R: Read + Seek, W: Write>(&self,
+        reader: &mut AxmlReader<R>,
+        writer: &mut AxmlWriter<W>,
+        res_ids: &ResourceIds) -> Result<bool> {
+        let mut modified = false;
+
+        let mut existing_features = HashSet::new();
+        let mut existing_permissions = HashSet::new();
+        let mut skipping_subsequent = false;
+        // The depth within an element being removed (so 0 if no element is being removed), so that everything within it is removed too.
+        let mut removing_depth = 0;
+        // The intent filters to write once the start of the activity they are for has been written.
+        let mut pending_filters = Vec::new();
+        let mut found_activities = HashSet::new();
+        let mut in_application = false;
+        let mut existing_meta_data = HashSet::new();
+        let mut has_queries = false;
+        let mut in_queries = false;
+        let mut queried_packages = HashSet::new();
+        let mut queried_actions = HashSet::new();
+
+        while let Some(mut ev) = reader.read_next_event().context("Failed to read original manifest")? {
+            if removing_depth > 0 {
+                match ev {
+                    Event::StartElement { .. } => removing_depth += 1,
+                    Event::EndElement { .. } => removing_depth -= 1,
+                    _ => {}
+                }
+                continue;
+            }
+
+            let is_end_of_manifest = match &mut ev { // Determine if the current event is the final tag: </manifest>
+                Event::StartElement { attributes, name, .. } => {
+                    if &**name == "manifest" {
+                        if let Some(version_name) = &self.version_name {
+                            info!("Setting version name to `{version_name}`");
+                            modified |= Self::apply_attribute(attributes, "versionName",
+                                AttributeValue::String(version_name.clone()), res_ids.get_res_id("versionName"));
+                        }
+                        if let Some(version_code) = self.version_code {
+                            info!("Setting version code to `{version_code}`");
+                            let version_code = i32::try_from(version_code)
+                                .map_err(|_| anyhow!("Version code {version_code} is too large"))?;
+                            modified |= Self::apply_attribute(attributes, "versionCode",
+                                AttributeValue::Integer(version_code), res_ids.get_res_id("versionCode"));
+                        }
+                    }   else if &**name == "application" {
+                        in_application = true;
+                        if self.debuggable {
+                            info!("Setting debuggable to `{}`", self.debuggable);
+                            modified |= Self::apply_true_attribute(attributes, "debuggable", res_ids);
+                        }
+                        if self.legacy_storage {
+                            info!("Requesting legacy external storage");
+                            modified |= Self::apply_true_attribute(attributes, "requestLegacyExternalStorage", res_ids);
+                        }
+                        if let Some(name) = &self.application_name {
+                            info!("Setting application class to `{}`", name.as_deref().unwrap_or("(default)"));
+                            modified |= Self::apply_name_attribute(attributes, name.as_ref(), res_ids);
+                        }
+                        for (attr_name, res_id, value) in &self.application_attributes {
+                            info!("Setting {attr_name} to `{value:?}`");
+                            let res_id = res_id.unwrap_or_else(|| res_ids.get_res_id(attr_name));
+                            modified |= Self::apply_attribute(attributes, attr_name, value.clone(), res_id);
+                        }
+                    }   else if &**name == "meta-data" && Self::get_name_attribute(attributes) // Locate existing modded metadata tag
+                        .is_ok_and(|name| &*name == METADATA_TAG) {
+                        skipping_subsequent = true; // Skip adding permissions/feats to the manifest that were added last time we patched.
+                    }   else if &**name == "meta-data" && in_application {
+                        modified |= self.apply_meta_data(attributes, res_ids);
+                        if let Ok(name) = Self::get_name_attribute(attributes) {
+                            existing_meta_data.insert(name.to_string());
+                        }
+                    }   else if &**name == "uses-permission" && !skipping_subsequent {
+                        // Silently fail for permissions without a name attribute
+                        // TODO: figure out why some permissions/features in the Beat Saber manifest don't have one.
+                        if let Ok(permission) = Self::get_name_attribute(attributes) {
+                            if self.is_permission_removed(&permission) {
+                                info!("Removing permission `{permission}`");
+                                removing_depth = 1;
+                                modified = true;
+                            }   else {
+                                existing_permissions.insert(permission);
+                            }
+                        }
+                    }   else if &**name == "uses-feature" && !skipping_subsequent {
+                        let _ = Self::get_name_attribute(attributes)
+                            .map(|feature| existing_features.insert(feature));
+                    }   else if &**name == "intent-filter" && attributes.iter().any(|attr| &*attr.name == INTENT_FILTER_MARKER) {
+                        // Intent filters added last time we patched are replaced by those requested this time, if any.
+                        removing_depth = 1;
+                        modified = true;
+                    }   else if &**name == "queries" && !skipping_subsequent {
+                        has_queries = true;
+                        in_queries = true;
+                    }   else if in_queries && (&**name == "package" || &**name == "action") {
+                        // Actions within `<queries>` are always within an `<intent>`.
+                        let existing = if &**name == "package" { &mut queried_packages } else { &mut queried_actions };
+                        let _ = Self::get_name_attribute(attributes)
+                            .map(|name| existing.insert(name));
+                    }   else if &**name == "activity" || &**name == "activity-alias" {
+                        if let Ok(activity) = Self::get_name_attribute(attributes) {
+                            pending_filters.extend(self.add_intent_filters.iter().filter(|filter| filter.activity == *activity));
+                            found_activities.insert(activity);
+                        }
+                    }
+                    modified |= self.apply_attribute_removals(name, attributes);
+                    false
+                },
+                Event::EndElement { name, .. } => {
+                    // Add any requested meta-data that the application didn't already have before it is closed.
+                    if &**name == "application" {
+                        in_application = false;
+                        modified |= self.write_meta_data(writer, &mut existing_meta_data, res_ids);
+                    }
+                    // Merge the requested queries into the existing `<queries>` element, rather than adding another.
+                    if &**name == "queries" && in_queries {
+                        in_queries = false;
+                        modified |= self.write_queries(writer, &mut queried_packages, &mut queried_actions, res_ids);
+                    }
+
+                    // Locate the closing </manifest> tag
+                    &**name == "manifest"
+                },
+                _ => false
+            };
+
+            if is_end_of_manifest {
+                let uses_feature: Rc<str> = "uses-feature".into();
+                let uses_permission: Rc<str> = "uses-permission".into();
+                // Before writing out the permissions and features, write a metadata tag indicating that the app was modded by MBF.
+                write_valued_element(writer,
+                    "meta-data".into(),
+                    METADATA_TAG.to_string().into(),
+                    AttributeValue::Boolean(true),
+                    res_ids
+                );
+
+                // If the manifest has no `<queries>` element, add one for any queries requested.
+                let queries_requested = !self.query_packages.is_empty() || !self.query_intent_actions.is_empty();
+                if queries_requested && !has_queries {
+                    let queries: Rc<str> = "queries".into();
+                    writer.write_event(Event::StartElement {
+                        attributes: Vec::new(),
+                        name: queries.clone(),
+                        namespace: None,
+                        line_num: 0
+                    });
+                    modified |= self.write_queries(writer, &mut queried_packages, &mut queried_actions, res_ids);
+                    writer.write_event(Event::EndElement {
+                        name: queries,
+                        namespace: None,
+                        line_num: 0
+                    });
+                }
+
+                // Write out permissions and features just before the final (closing) tag
+                // Each is added to the existing set once written, so that one requested more than once is only added once.
+                for feature in &self.add_features {
+                    if existing_features.insert(feature.clone()) {
+                        info!("Adding feature `{feature}`");
+                        write_named_element(writer, uses_feature.clone(), feature.clone(), res_ids);
+                        modified = true;
+                    }
+                }
+                for permission in &self.add_permissions {
+                    if !self.is_permission_removed(permission) && existing_permissions.insert(permission.clone()) {
+                        info!("Adding permission `{permission}`");
+                        write_named_element(writer, uses_permission.clone(), permission.clone(), res_ids);
+                        modified = true;
+                    }
+                }
+                // Make sure that the final closing tag gets written
+                skipping_subsequent = false;
+            }
+
+            if !skipping_subsequent && removing_depth == 0 {
+                writer.write_event(ev);
+            }
+            for filter in pending_filters.drain(..) {
+                info!("Adding intent filter to `{}`", filter.activity);
+                filter.write(writer, res_ids);
+                modified = true;
+            }
+        }
+
+        let missing: Vec<&str> = self.add_intent_filters.iter()
+            .map(|filter| filter.activity.as_str())
+            .filter(|activity| !found_activities.contains(*activity))
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow!("Could not add intent filters, since the manifest has no activity named: {}", missing.join(", ")));
+        }
+
+        Ok(modified)
+    }
+}
+
+
+/// Stores a map of AXML attribute names to resource IDs
+#[derive(Serialize, Deserialize)]
+pub struct ResourceIds {
+    ids: HashMap<Rc<str>, u32>
+}
+
+impl ResourceIds {
+    /// Loads the resource IDs from a file within the binary
+    /// Ideally, reuse the same instance once you have called this method. 
+    pub fn load() -> Result<Self> {
+        let mut file = Cursor::new(RESOURCE_ID_TABLE);
+        let mut ids = HashMap::new();
+        while file.stream_position()? < RESOURCE_ID_TABLE.len() as u64 {
+            let name_length = file.read_u32::<LE>()? >> 1;
+
+            let mut buffer: Vec<u16> = Vec::with_capacity(name_length as usize);
+            for _ in 0..name_length {
+                buffer.push(file.read_u16::<LE>()?);
+            }
+
+            let resource_id = file.read_u32::<LE>()?;
+            ids.insert(String::from_utf16(&buffer)?.into(), resource_id);
+        }
+
+        Ok(Self {
+            ids
+        })
+    }
+
+    /// Loads the resource IDs of the attributes in the resource table of the given framework APK, i.e. `framework-res.apk`.
+    /// Any attributes missing from the framework are taken from the table within the binary.
+    pub fn load_from_framework<T: Read + Seek>(framework_apk: T) -> Result<Self> {
+        let mut zip = ZipFile::open(framework_apk).context("Failed to read framework APK")?;
+        let table = zip.read_file("resources.arsc").context("Framework APK had no resource table")?;
+        let framework_ids = arsc::read_attribute_ids(&mut Cursor::new(table)).context("Failed to read resource table")?;
+
+        let mut res_ids = Self::load()?;
+        res_ids.ids.extend(framework_ids);
+        Ok(res_ids)
+    }
+
+    pub fn get_res_id(&self, name: &str) -> u32 {
+        *self.ids.get(name).expect("No resource ID existed for given attribute name")
+    }
+}
+
+// Writes an element with the "name" and "value attributes"
+fn write_valued_element<W: Write>(writer: &mut AxmlWriter<W>,
+    element_name: Rc<str>,
+    name: Rc<str>,
+    value: AttributeValue,
+    res_ids: &ResourceIds) {
+    writer.write_event(Event::StartElement {
+        attributes: vec![
+            name_attribute(name, res_ids),
+            value_attribute(value, res_ids)
+        ],
+        name: element_name.clone(),
+        namespace: None,
+        line_num: 0
+    });
+    writer.write_event(Event::EndElement {
+        name: element_name,
+        namespace: None,
+        line_num: 0
+    })
+}
+
+// Writes an element with the "name" attribute.
+fn write_named_element<W: Write>(writer: &mut AxmlWriter<W>, element_name: Rc<str>, name_value: Rc<str>, res_ids: &ResourceIds) {
+    writer.write_event(Event::StartElement {
+        attributes: vec![name_attribute(name_value, res_ids)],
+        name: element_name.clone(),
+        namespace: None,
+        line_num: 0
+    });
+    writer.write_event(Event::EndElement {
+        name: element_name,
+        namespace: None,
+        line_num: 0
+    })
+}
+
+fn name_attribute(name: Rc<str>, res_ids: &ResourceIds) -> Attribute {
+    android_attribute("name", AttributeValue::String(name), res_ids)
+}
+
+fn value_attribute(value: AttributeValue, res_ids: &ResourceIds) -> Attribute {
+    android_attribute("value", value, res_ids)
+}
+
+fn android_attribute(name: &str, value: AttributeValue, res_ids: &ResourceIds) -> Attribute {
+    Attribute {
+        name: name.into(),
+        namespace: Some(ANDROID_NS_URI.into()),
+        value,
+        resource_id: Some(res_ids.get_res_id(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Adds an element with the given attributes to `events`, with the children added by `children`.
+    fn element(events: &mut Vec<Event>, name: &str, attributes: Vec<Attribute>, children: impl FnOnce(&mut Vec<Event>)) {
+        events.push(Event::StartElement { attributes, name: name.into(), namespace: None, line_num: 1 });
+        children(events);
+        events.push(Event::EndElement { line_num: 1, namespace: None, name: name.into() });
+    }
+
+    // No manifest captured from a Beat Saber APK is kept in the repository, so this rebuilds the parts of one that MBF
+    // reads or changes: its permissions and features, the application with its meta-data, and the Unity activity with
+    // its launcher intent filter. Values of each attribute type which the game's manifest has are included.
+    fn beat_saber_manifest(res_ids: &ResourceIds) -> Vec<u8> {
+        let android = |name: &str, value: AttributeValue| android_attribute(name, value, res_ids);
+        let string = |value: &str| AttributeValue::String(value.into());
+        let named = |name: &str| vec![android("name", string(name))];
+        let no_children = |_: &mut Vec<Event>| {};
+
+        let mut events = Vec::new();
+        element(&mut events, "manifest", vec![
+            android("versionCode", AttributeValue::Integer(1130)),
+            android("versionName", string("1.37.0_9064817954")),
+            Attribute { name: "package".into(), namespace: None, resource_id: None, value: string("com.beatgames.beatsaber") }
+        ], |events| {
+            element(events, "uses-sdk", vec![
+                android("minSdkVersion", AttributeValue::Integer(29)),
+                android("targetSdkVersion", AttributeValue::Integer(32))
+            ], no_children);
+            for feature in ["android.hardware.vr.headtracking", "oculus.software.handtracking"] {
+                element(events, "uses-feature", vec![android("name", string(feature)), android("required", AttributeValue::Boolean(false))], no_children);
+            }
+            for permission in ["android.permission.INTERNET", "com.oculus.permission.HAND_TRACKING", "android.permission.RECORD_AUDIO"] {
+                element(events, "uses-permission", named(permission), no_children);
+            }
+
+            element(events, "application", vec![
+                android("label", AttributeValue::Reference(0x7f0e001b)),
+                android("icon", AttributeValue::Reference(0x7f0c0000)),
+                android("allowBackup", AttributeValue::Boolean(false)),
+                android("requestLegacyExternalStorage", AttributeValue::Boolean(true))
+            ], |events| {
+                element(events, "meta-data", vec![
+                    android("name", string("unityplayer.SkipPermissionsDialog")),
+                    android("value", AttributeValue::Boolean(true))
+                ], no_children);
+                element(events, "meta-data", vec![
+                    android("name", string("com.oculus.supportedDevices")),
+                    android("value", string("quest|quest2|quest3"))
+                ], no_children);
+                element(events, "activity", vec![
+                    android("name", string("com.unity3d.player.UnityPlayerActivity")),
+                    android("theme", AttributeValue::Reference(0x01030007)),
+                    android("launchMode", AttributeValue::Integer(2)),
+                    android("screenOrientation", AttributeValue::Integer(0)),
+                    android("configChanges", AttributeValue::Hex(0x40003fff))
+                ], |events| element(events, "intent-filter", Vec::new(), |events| {
+                    element(events, "action", named("android.intent.action.MAIN"), no_children);
+                    for category in ["android.intent.category.LAUNCHER", "com.oculus.intent.category.VR"] {
+                        element(events, "category", named(category), no_children);
+                    }
+                }));
+            });
+        });
+
+        let mut output = Vec::new();
+        let mut writer = AxmlWriter::new(&mut output);
+        for event in events {
+            writer.write_event(event);
+        }
+        writer.finish().unwrap();
+        output
+    }
+
+    // Applies `manifest_mod` to `manifest`, giving whether it reported a change and the modified manifest.
+    fn apply(manifest_mod: &ManifestMod, manifest: &[u8], res_ids: &ResourceIds) -> Result<(bool, Vec<u8>)> {
+        let mut cursor = Cursor::new(manifest);
+        let mut reader = AxmlReader::new(&mut cursor)?;
+        let mut output = Vec::new();
+        let mut writer = AxmlWriter::new(&mut output);
+        let modified = manifest_mod.apply_mod(&mut reader, &mut writer, res_ids)?;
+        writer.finish()?;
+        Ok((modified, output))
+    }
+
+    // Describes each element of the manifest on its own line, indented by its depth, with its attributes sorted by name.
+    // The meta-data that marks the manifest as modded by MBF is left out, since it is added whenever a manifest is patched.
+    fn describe(manifest: &[u8]) -> Vec<String> {
+        let mut cursor = Cursor::new(manifest);
+        let mut reader = AxmlReader::new(&mut cursor).unwrap();
+        let mut lines = Vec::new();
+        let mut depth = 0;
+        while let Some(event) = reader.read_next_event().unwrap() {
+            match event {
+                Event::StartElement { mut attributes, name, .. } => {
+                    attributes.sort_by(|a, b| a.name.cmp(&b.name));
+                    let attributes: String = attributes.iter()
+                        .map(|attr| format!(" {}{}{}={:?}",
+                            if attr.namespace.as_deref() == Some(ANDROID_NS_URI) { "android:" } else { "" },
+                            attr.name,
+                            attr.resource_id.map(|id| format!("#{id:08x}")).unwrap_or_default(),
+                            attr.value))
+                        .collect();
+                    lines.push(format!("{}{name}{attributes}", "  ".repeat(depth)));
+                    depth += 1;
+                },
+                Event::EndElement { .. } => depth -= 1,
+                other => lines.push(format!("{}{other:?}", "  ".repeat(depth)))
+            }
+        }
+
+        lines.retain(|line| !line.contains(METADATA_TAG));
+        lines
+    }
+
+    #[test]
+    fn removing_a_permission_leaves_the_rest_of_the_manifest_unchanged() {
+        let res_ids = ResourceIds::load().unwrap();
+        let manifest = beat_saber_manifest(&res_ids);
+        let manifest_mod = ManifestMod::new().without_permission("com.oculus.permission.HAND_TRACKING");
+
+        let (modified, patched) = apply(&manifest_mod, &manifest, &res_ids).unwrap();
+        assert!(modified);
+        let mut expected = describe(&manifest);
+        let removed = expected.iter().position(|line| line.contains("HAND_TRACKING")).unwrap();
+        expected.remove(removed);
+        assert_eq!(describe(&patched), expected);
+    }
+
+    #[test]
+    fn removing_an_attribute_leaves_the_rest_of_the_manifest_unchanged() {
+        let res_ids = ResourceIds::load().unwrap();
+        let manifest = beat_saber_manifest(&res_ids);
+        let manifest_mod = ManifestMod::new().remove_attribute("application", "requestLegacyExternalStorage");
+
+        let (modified, patched) = apply(&manifest_mod, &manifest, &res_ids).unwrap();
+        assert!(modified);
+        let expected: Vec<String> = describe(&manifest).into_iter()
+            .map(|line| if line.trim_start().starts_with("application ") {
+                line.split(" android:requestLegacyExternalStorage").next().unwrap().to_string()
+            }   else {
+                line
+            })
+            .collect();
+        assert_eq!(describe(&patched), expected);
+        assert!(!describe(&patched).iter().any(|line| line.contains("android:requestLegacyExternalStorage")));
+    }
+
+    #[test]
+    fn removing_something_that_is_not_present_does_nothing() {
+        let res_ids = ResourceIds::load().unwrap();
+        let manifest = beat_saber_manifest(&res_ids);
+        let manifest_mod = ManifestMod::new()
+            .without_permission("android.permission.CAMERA")
+            .remove_attribute("activity", "resizeableActivity")
+            .remove_attribute("receiver", "name");
+
+        let (modified, patched) = apply(&manifest_mod, &manifest, &res_ids).unwrap();
+        assert!(!modified);
+        assert_eq!(describe(&patched), describe(&manifest));
+    }
+
+    #[test]
+    fn removed_permission_is_not_added() {
+        let res_ids = ResourceIds::load().unwrap();
+        let manifest = beat_saber_manifest(&res_ids);
+        let manifest_mod = ManifestMod::new()
+            .with_permission("android.permission.MANAGE_EXTERNAL_STORAGE")
+            .with_permission("android.permission.RECORD_AUDIO")
+            .without_permission("android.permission.MANAGE_EXTERNAL_STORAGE")
+            .without_permission("android.permission.RECORD_AUDIO");
+
+        let (_, patched) = apply(&manifest_mod, &manifest, &res_ids).unwrap();
+        let lines = describe(&patched);
+        assert!(!lines.iter().any(|line| line.contains("MANAGE_EXTERNAL_STORAGE") || line.contains("RECORD_AUDIO")), "{lines:#?}");
+        assert_eq!(lines.len(), describe(&manifest).len() - 1);
+    }
+
+    // The one-click install filter that a mod would add to the Unity activity.
+    fn beatsaver_filter() -> IntentFilter {
+        IntentFilter::new("com.unity3d.player.UnityPlayerActivity")
+            .with_action("android.intent.action.VIEW")
+            .with_category("android.intent.category.DEFAULT")
+            .with_category("android.intent.category.BROWSABLE")
+            .with_data("beatsaver", Some("map"))
+    }
+
+    #[test]
+    fn intent_filter_is_added_to_the_activity() {
+        let res_ids = ResourceIds::load().unwrap();
+        let manifest = beat_saber_manifest(&res_ids);
+        let manifest_mod = ManifestMod::new().with_intent_filter(beatsaver_filter());
+
+        let (modified, patched) = apply(&manifest_mod, &manifest, &res_ids).unwrap();
+        assert!(modified);
+
+        let lines = describe(&patched);
+        let original = describe(&manifest);
+        let activity = lines.iter().position(|line| line.contains("UnityPlayerActivity")).unwrap();
+        let scheme_id = res_ids.get_res_id("scheme");
+        let host_id = res_ids.get_res_id("host");
+        let name_id = res_ids.get_res_id("name");
+        // The filter is the first child of the activity, and every attribute but the marker is in the android namespace with its resource ID.
+        assert_eq!(lines[activity + 1..activity + 6], [
+            format!("      intent-filter {INTENT_FILTER_MARKER}=Boolean(true)"),
+            format!("        action android:name#{name_id:08x}=String(\"android.intent.action.VIEW\")"),
+            format!("        category android:name#{name_id:08x}=String(\"android.intent.category.DEFAULT\")"),
+            format!("        category android:name#{name_id:08x}=String(\"android.intent.category.BROWSABLE\")"),
+            format!("        data android:host#{host_id:08x}=String(\"map\") android:scheme#{scheme_id:08x}=String(\"beatsaver\")")
+        ]);
+
+        // Everything else, including the launcher filter, is unchanged.
+        let mut without_filter = lines.clone();
+        without_filter.drain(activity + 1..activity + 6);
+        assert_eq!(without_filter, original);
+    }
+
+    #[test]
+    fn added_intent_filter_is_read_back_as_xml() {
+        let res_ids = ResourceIds::load().unwrap();
+        let manifest = beat_saber_manifest(&res_ids);
+        let (_, patched) = apply(&ManifestMod::new().with_intent_filter(beatsaver_filter()), &manifest, &res_ids).unwrap();
+
+        let mut cursor = Cursor::new(patched);
+        let xml = crate::axml::to_readable_xml(&mut AxmlReader::new(&mut cursor).unwrap()).unwrap();
+        let filter_start = xml.find(&format!("{INTENT_FILTER_MARKER}=\"true\"")).expect("the filter should be in the XML");
+        let filter = &xml[filter_start..xml[filter_start..].find("</intent-filter>").unwrap() + filter_start];
+        assert!(filter.contains("name=\"android.intent.action.VIEW\""), "{filter}");
+        assert!(filter.contains("scheme=\"beatsaver\""), "{filter}");
+        assert!(filter.contains("host=\"map\""), "{filter}");
+    }
+
+    #[test]
+    fn intent_filters_added_last_time_are_replaced() {
+        let res_ids = ResourceIds::load().unwrap();
+        let manifest = beat_saber_manifest(&res_ids);
+        let (_, once) = apply(&ManifestMod::new().with_intent_filter(beatsaver_filter()), &manifest, &res_ids).unwrap();
+        let (_, twice) = apply(&ManifestMod::new().with_intent_filter(beatsaver_filter()), &once, &res_ids).unwrap();
+        assert_eq!(describe(&twice), describe(&once));
+
+        // Patching without the filter removes it, leaving the original manifest.
+        let (modified, removed) = apply(&ManifestMod::new(), &once, &res_ids).unwrap();
+        assert!(modified);
+        assert_eq!(describe(&removed), describe(&manifest));
+    }
+
+    #[test]
+    fn intent_filter_for_a_missing_activity_fails() {
+        let res_ids = ResourceIds::load().unwrap();
+        let manifest = beat_saber_manifest(&res_ids);
+        let manifest_mod = ManifestMod::new().with_intent_filter(IntentFilter::new("com.example.MissingActivity").with_action("android.intent.action.VIEW"));
+
+        let err = apply(&manifest_mod, &manifest, &res_ids).expect_err("the activity doesn't exist");
+        assert!(err.to_string().contains("com.example.MissingActivity"), "{err}");
+    }
+
+    // Finds the lines describing the meta-data elements of the application, in order.
+    fn meta_data_lines(lines: &[String]) -> Vec<&str> {
+        lines.iter()
+            .map(|line| line.trim_start())
+            .filter(|line| line.starts_with("meta-data "))
+            .collect()
+    }
+
+    #[test]
+    fn meta_data_of_each_type_is_added_to_the_application() {
+        let res_ids = ResourceIds::load().unwrap();
+        let manifest = beat_saber_manifest(&res_ids);
+        let manifest_mod = ManifestMod::new()
+            .with_meta_data("mbf.string", MetaDataValue::String("hello".to_string()))
+            .with_meta_data("mbf.integer", MetaDataValue::Integer(-42))
+            .with_meta_data("mbf.boolean", MetaDataValue::Boolean(true));
+
+        let (modified, patched) = apply(&manifest_mod, &manifest, &res_ids).unwrap();
+        assert!(modified);
+
+        let lines = describe(&patched);
+        let (name_id, value_id) = (res_ids.get_res_id("name"), res_ids.get_res_id("value"));
+        let added: Vec<String> = [("mbf.string", "String(\"hello\")"), ("mbf.integer", "Integer(-42)"), ("mbf.boolean", "Boolean(true)")]
+            .into_iter()
+            .map(|(name, value)| format!("meta-data android:name#{name_id:08x}=String(\"{name}\") android:value#{value_id:08x}={value}"))
+            .collect();
+        assert_eq!(meta_data_lines(&lines)[2..], added);
+
+        // They are added as the last children of the application, which is otherwise unchanged.
+        let application_children: Vec<&String> = lines.iter()
+            .filter(|line| line.starts_with("    ") && !line.starts_with("     "))
+            .collect();
+        let last_three: Vec<&str> = application_children[application_children.len() - 3..].iter()
+            .map(|line| line.trim_start())
+            .collect();
+        assert_eq!(last_three, added);
+        let mut without_added = lines.clone();
+        without_added.retain(|line| !line.contains("\"mbf."));
+        assert_eq!(without_added, describe(&manifest));
+    }
+
+    #[test]
+    fn meta_data_survives_being_patched_again() {
+        let res_ids = ResourceIds::load().unwrap();
+        let manifest = beat_saber_manifest(&res_ids);
+        let manifest_mod = ManifestMod::new().with_meta_data("mbf.integer", MetaDataValue::Integer(i32::MAX));
+        let (_, once) = apply(&manifest_mod, &manifest, &res_ids).unwrap();
+
+        // Setting the same value again changes nothing.
+        let (modified, twice) = apply(&manifest_mod, &once, &res_ids).unwrap();
+        assert!(!modified);
+        assert_eq!(describe(&twice), describe(&once));
+        assert_eq!(meta_data_lines(&describe(&twice)).iter().filter(|line| line.contains("mbf.integer")).count(), 1);
+    }
+
+    #[test]
+    fn same_name_twice_uses_the_last_value() {
+        let res_ids = ResourceIds::load().unwrap();
+        let manifest = beat_saber_manifest(&res_ids);
+        let manifest_mod = ManifestMod::new()
+            .with_meta_data("mbf.mode", MetaDataValue::String("first".to_string()))
+            .with_meta_data("mbf.mode", MetaDataValue::Boolean(false));
+
+        let (_, patched) = apply(&manifest_mod, &manifest, &res_ids).unwrap();
+        let lines = describe(&patched);
+        let matching: Vec<&str> = meta_data_lines(&lines).into_iter().filter(|line| line.contains("mbf.mode")).collect();
+        assert_eq!(matching.len(), 1, "{matching:?}");
+        assert!(matching[0].ends_with("=Boolean(false)"), "{}", matching[0]);
+    }
+
+    #[test]
+    fn existing_meta_data_is_overwritten_in_place() {
+        let res_ids = ResourceIds::load().unwrap();
+        let manifest = beat_saber_manifest(&res_ids);
+        let manifest_mod = ManifestMod::new().with_meta_data("com.oculus.supportedDevices", MetaDataValue::String("quest2|quest3".to_string()));
+
+        let (modified, patched) = apply(&manifest_mod, &manifest, &res_ids).unwrap();
+        assert!(modified);
+        let lines = describe(&patched);
+        let expected: Vec<String> = describe(&manifest).into_iter()
+            .map(|line| line.replace("quest|quest2|quest3", "quest2|quest3"))
+            .collect();
+        assert_eq!(lines, expected);
+    }
+
+    #[test]
+    fn framework_attribute_ids_override_the_bundled_ids() {
+        let bundled = ResourceIds::load().unwrap();
+        assert_eq!(bundled.get_res_id("name"), 0x01010003);
+        assert!(!bundled.ids.contains_key("mbfNewAttribute"));
+
+        let path = std::env::temp_dir().join(format!("mbf-framework-res-{}.apk", std::process::id()));
+        let mut zip = ZipFile::create(std::fs::File::create(&path).unwrap());
+        let table = arsc::tests::android_attribute_table(&[("name", 0x7ff), ("mbfNewAttribute", 0x800)]);
+        zip.write_file("resources.arsc", &mut Cursor::new(table), crate::zip::FileCompression::Store).unwrap();
+        zip.save().unwrap();
+
+        let res_ids = ResourceIds::load_from_framework(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(res_ids.get_res_id("name"), 0x010107ff);
+        assert_eq!(res_ids.get_res_id("mbfNewAttribute"), 0x01010800);
+        // Attributes missing from the framework are still taken from the bundled table.
+        assert_eq!(res_ids.get_res_id("label"), bundled.get_res_id("label"));
+        assert_eq!(res_ids.ids.len(), bundled.ids.len() + 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
@@ -0,0 +1,680 @@
+//! Builder that applies a set of modifications to an APK.
+
+use std::{fs::{File, OpenOptions}, io::{Cursor, Read, Seek}, path::{Path, PathBuf}};
+
+use anyhow::{Context, Result};
+use log::info;
+use rasn_pkix::Certificate;
+use rsa::RsaPrivateKey;
+
+use crate::{axml::{AxmlReader, AxmlWriter}, manifest::{ManifestMod, ResourceIds}, tag::{ModTag, MOD_TAG_PATH}, zip::{signing::{self, SigningConfig}, CompressionLevel, FileCompression, ZipFile}};
+
+// The ABI used by current builds of quest games.
+const LIB_ABI: &str = "arm64-v8a";
+
+/// The contents of a file to add to the APK.
+pub enum FileSource {
+    Bytes(Vec<u8>),
+    /// A file on disk, which is streamed into the APK.
+    Path(PathBuf)
+}
+
+/// Summary of the changes made by an `ApkPatcher`.
+pub struct PatchReport {
+    /// False if the manifest mod made no changes, in which case the manifest was left as is.
+    pub manifest_modified: bool,
+    /// The files added or replaced, including the mod tag, in the order they were written.
+    pub written_files: Vec<String>,
+    /// The files removed from the APK. Files that were requested to be removed but didn't exist are not included.
+    pub removed_files: Vec<String>,
+    /// The number of entries with names that weren't decoded as UTF-8, i.e. non-ASCII names in code page 437 or invalid UTF-8. These are copied as is.
+    pub non_utf8_names: usize,
+    /// Hex encoded SHA-256 hash of the patched APK, excluding its signature (the signing block and any V1 signature files).
+    /// Patching the same APK with the same modifications, in the same way (`write_to` or `patch_in_place`), gives the same digest.
+    pub reproducibility_digest: String
+}
+
+/// The changes an `ApkPatcher` would make to an APK, worked out without writing anything.
+pub struct PatchPlan {
+    /// False if the manifest mod would make no changes, in which case the manifest would be left as is.
+    pub manifest_modified: bool,
+    /// The existing files that would be replaced, including the manifest (if it would be modified) and the mod tag.
+    pub replaced_files: Vec<String>,
+    /// The files that would be added, which don't already exist.
+    pub added_files: Vec<String>,
+    /// The files that would be removed. Files that were requested to be removed but don't exist are not included.
+    pub removed_files: Vec<String>
+}
+
+// Decides whether a file (by name) should be removed.
+type NamePredicate = dyn Fn(&str) -> bool + Send + Sync;
+
+/// Applies modifications to an APK, writing the modified APK to a new file or modifying it in place.
+///
+/// Modifications are applied in this order: the manifest mod, removing files, adding/replacing files, adding the mod tag and then signing.
+///
+/// The output is deterministic: added files are written in the order they were given with no timestamps, entries are listed in
+/// the central directory in the order their data appears, and the signature uses RSA PKCS#1 v1.5, which does not use a random nonce.
+pub struct ApkPatcher {
+    source: PathBuf,
+    manifest_mod: Option<ManifestMod>,
+    removed_files: Vec<String>,
+    // Files with names matching any of these are removed, along with those in `removed_files`.
+    removed_patterns: Vec<Box<NamePredicate>>,
+    files: Vec<(String, FileSource)>,
+    tag: Option<ModTag>,
+    signer: Option<(Certificate, RsaPrivateKey)>,
+    signing_config: SigningConfig,
+    res_ids: Option<ResourceIds>,
+    compression_level: CompressionLevel,
+    level_overrides: Vec<(String, CompressionLevel)>
+}
+
+impl ApkPatcher {
+    /// Creates a patcher for the APK at `source`. The APK isn't opened until the patcher is written.
+    pub fn new(source: impl Into<PathBuf>) -> Self {
+        Self {
+            source: source.into(),
+            manifest_mod: None,
+            removed_files: Vec::new(),
+            removed_patterns: Vec::new(),
+            files: Vec::new(),
+            tag: None,
+            signer: None,
+            signing_config: SigningConfig::default(),
+            res_ids: None,
+            compression_level: CompressionLevel::default(),
+            level_overrides: Vec::new()
+        }
+    }
+
+    /// Applies `manifest_mod` to `AndroidManifest.xml`
+    pub fn with_manifest_mod(mut self, manifest_mod: ManifestMod) -> Self {
+        self.manifest_mod = Some(manifest_mod);
+        self
+    }
+
+    /// Uses `res_ids` for the resource IDs of the attributes added to the manifest, e.g. those read from the device's framework.
+    /// If this isn't used, the table within the binary is used.
+    pub fn with_resource_ids(mut self, res_ids: ResourceIds) -> Self {
+        self.res_ids = Some(res_ids);
+        self
+    }
+
+    /// Adds the file with the given name to the APK, replacing any existing file with that name.
+    /// Native libraries (`.so` files) are stored uncompressed and aligned to 16KB, so that Android can load them directly from the APK.
+    pub fn with_replaced_file(mut self, name: &str, contents: FileSource) -> Self {
+        self.files.push((name.to_string(), contents));
+        self
+    }
+
+    /// Adds the native library with the given file name (e.g. `libmain.so`) to the APK for the `arm64-v8a` ABI, replacing any existing library.
+    pub fn with_replaced_lib(self, lib_name: &str, contents: FileSource) -> Self {
+        self.with_replaced_lib_for_abi(LIB_ABI, lib_name, contents)
+    }
+
+    /// Adds the native library with the given file name to the APK for the given ABI (e.g. `armeabi-v7a`), replacing any existing library.
+    pub fn with_replaced_lib_for_abi(self, abi: &str, lib_name: &str, contents: FileSource) -> Self {
+        self.with_replaced_file(&format!("lib/{abi}/{lib_name}"), contents)
+    }
+
+    /// Compresses the files added to the APK (other than native libraries, which are stored) at `level`, rather than the default level (6).
+    /// Unmodified entries are copied as they are, so are never recompressed.
+    pub fn with_compression_level(mut self, level: CompressionLevel) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    /// Compresses the added file with the given name at `level`, rather than the level given for other files.
+    pub fn with_file_compression_level(mut self, name: &str, level: CompressionLevel) -> Self {
+        self.level_overrides.push((name.to_string(), level));
+        self
+    }
+
+    /// Removes the file with the given name from the APK, if it exists.
+    pub fn with_removed_file(mut self, name: &str) -> Self {
+        self.removed_files.push(name.to_string());
+        self
+    }
+
+    /// Removes every file in the APK with a name for which `predicate` returns true, for removing files whose exact names aren't known.
+    /// Files are removed before any are added, so files added with `with_replaced_file` are never removed.
+    pub fn with_removed_files_matching(mut self, predicate: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        self.removed_patterns.push(Box::new(predicate));
+        self
+    }
+
+    /// Removes every file in the APK with a name starting with `prefix`, e.g. `lib/armeabi-v7a/`.
+    pub fn with_removed_prefix(self, prefix: &str) -> Self {
+        let prefix = prefix.to_string();
+        self.with_removed_files_matching(move |name| name.starts_with(&prefix))
+    }
+
+    /// Saves `tag` as the mod tag of the APK, replacing any existing tag.
+    pub fn with_tag(mut self, tag: ModTag) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    /// Signs the APK using the certificate and RSA private key in `pem_data`, with the V2 signature scheme unless `with_signing_config` is used.
+    /// If this isn't used, the APK is saved without a signature. Fails if `pem_data` doesn't contain a valid certificate and its key.
+    pub fn sign_with(mut self, pem_data: &[u8]) -> Result<Self> {
+        self.signer = Some(signing::load_cert_and_priv_key(pem_data)?);
+        Ok(self)
+    }
+
+    /// Signs the APK with the schemes enabled in `config`, if it is signed with `sign_with`. Only V2 is used by default.
+    pub fn with_signing_config(mut self, config: SigningConfig) -> Self {
+        self.signing_config = config;
+        self
+    }
+
+    /// Writes the patched APK to `dest`. The source APK is opened read-only, so is never modified.
+    /// Unmodified entries are copied byte for byte without recompressing them, so only the modified entries and central directory are written.
+    pub fn write_to(self, dest: &Path) -> Result<PatchReport> {
+        let src_file = File::open(&self.source).context("Failed to open APK to patch")?;
+        let mut src_zip = ZipFile::open(src_file).context("Failed to read APK to patch")?;
+        let modified_manifest = self.modify_manifest(&mut src_zip)?;
+
+        let dest_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(dest)
+            .context("Failed to create patched APK")?;
+        let mut zip = ZipFile::create(dest_file);
+
+        // Avoid copying files that will be replaced or removed anyway, e.g. libunity.so which is large.
+        // Removed files weren't copied, but should still be reported if they existed.
+        let removed_files = self.get_removed_files(&src_zip);
+        let mut skipped: Vec<&str> = self.files.iter()
+            .map(|(name, _)| name.as_str())
+            .chain(removed_files.iter().map(String::as_str))
+            .collect();
+        if self.tag.is_some() {
+            skipped.push(MOD_TAG_PATH);
+        }
+        if modified_manifest.is_some() {
+            skipped.push("AndroidManifest.xml");
+        }
+
+        info!("Copying unmodified APK contents");
+        zip.copy_entries_from(&mut src_zip, &skipped).context("Failed to copy APK contents")?;
+
+        let mut report = self.apply(zip, modified_manifest)?;
+        report.removed_files = removed_files;
+        Ok(report)
+    }
+
+    /// Works out the changes that writing the patcher would make, without writing anything.
+    /// The source APK is opened read-only, the manifest mod is applied in memory, and the files to add are never opened.
+    pub fn plan(&self) -> Result<PatchPlan> {
+        let src_file = File::open(&self.source).context("Failed to open APK to patch")?;
+        let mut zip = ZipFile::open(src_file).context("Failed to read APK to patch")?;
+
+        let manifest_modified = match &self.manifest_mod {
+            Some(manifest_mod) => modify_manifest(&mut zip, manifest_mod, self.res_ids.as_ref()).context("Failed to patch manifest")?.is_some(),
+            None => false
+        };
+        let mut plan = PatchPlan {
+            manifest_modified,
+            replaced_files: Vec::new(),
+            added_files: Vec::new(),
+            removed_files: self.get_removed_files(&zip)
+        };
+        if manifest_modified {
+            plan.replaced_files.push("AndroidManifest.xml".to_string());
+        }
+
+        let written_files = self.files.iter()
+            .map(|(name, _)| name.as_str())
+            .chain(self.tag.as_ref().map(|_| MOD_TAG_PATH));
+        for name in written_files {
+            if zip.contains_file(name) {
+                plan.replaced_files.push(name.to_string());
+            }   else {
+                plan.added_files.push(name.to_string());
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Patches the APK in place.
+    pub fn patch_in_place(self) -> Result<PatchReport> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.source)
+            .context("Failed to open APK to patch")?;
+        let mut zip = ZipFile::open(file).context("Failed to read APK to patch")?;
+        let modified_manifest = self.modify_manifest(&mut zip)?;
+
+        self.apply(zip, modified_manifest)
+    }
+
+    // Applies the manifest mod, if any, to the manifest of `zip` in memory, giving the modified manifest if the mod changed it.
+    fn modify_manifest(&self, zip: &mut ZipFile<File>) -> Result<Option<Vec<u8>>> {
+        let manifest_mod = match &self.manifest_mod {
+            Some(manifest_mod) => manifest_mod,
+            None => return Ok(None)
+        };
+
+        info!("Applying manifest mods");
+        let modified_manifest = modify_manifest(zip, manifest_mod, self.res_ids.as_ref()).context("Failed to patch manifest")?;
+        if modified_manifest.is_none() {
+            info!("Manifest unmodified, not saving");
+        }
+        Ok(modified_manifest)
+    }
+
+    // Gets the files within `zip` that will be removed: those given by name in the order they were given,
+    // followed by those matching a pattern in sorted order.
+    fn get_removed_files<T: Read + Seek>(&self, zip: &ZipFile<T>) -> Vec<String> {
+        let mut removed: Vec<String> = self.removed_files.iter()
+            .filter(|name| zip.contains_file(name))
+            .cloned()
+            .collect();
+        let mut matched: Vec<String> = zip.iter_entry_names()
+            .filter(|name| !removed.iter().any(|removed| removed == name))
+            .filter(|name| self.removed_patterns.iter().any(|predicate| predicate(name)))
+            .map(str::to_string)
+            .collect();
+        matched.sort();
+        removed.append(&mut matched);
+        removed
+    }
+
+    // Applies the modifications to `zip`, where `modified_manifest` is the manifest given by `modify_manifest`, and saves it.
+    fn apply(self, mut zip: ZipFile<File>, modified_manifest: Option<Vec<u8>>) -> Result<PatchReport> {
+        let mut report = PatchReport {
+            manifest_modified: modified_manifest.is_some(),
+            written_files: Vec::new(),
+            removed_files: Vec::new(),
+            non_utf8_names: zip.iter_file_names().filter(|name| !name.is_utf8()).count(),
+            reproducibility_digest: String::new()
+        };
+        zip.set_compression_level(self.compression_level);
+        for (name, level) in &self.level_overrides {
+            zip.set_entry_compression_level(name, *level);
+        }
+
+        if let Some(modified_manifest) = modified_manifest {
+            zip.delete_file("AndroidManifest.xml");
+            zip.write_file("AndroidManifest.xml", &mut Cursor::new(modified_manifest), FileCompression::Deflate)
+                .context("Failed to write modified manifest")?;
+        }
+
+        for name in &self.removed_files {
+            if zip.delete_file(name) {
+                report.removed_files.push(name.clone());
+            }
+        }
+        if !self.removed_patterns.is_empty() {
+            report.removed_files.extend(zip.delete_matching(|name| self.removed_patterns.iter().any(|predicate| predicate(name))));
+        }
+
+        for (name, contents) in self.files {
+            let compression = get_compression(&name);
+            match contents {
+                FileSource::Bytes(bytes) => zip.write_file(&name, &mut Cursor::new(bytes), compression)?,
+                FileSource::Path(path) => {
+                    let mut handle = File::open(&path).with_context(|| format!("Failed to open {path:?} to add to APK"))?;
+                    zip.write_file(&name, &mut handle, compression)?
+                }
+            }
+            report.written_files.push(name);
+        }
+
+        if let Some(tag) = &self.tag {
+            let mut saved_tag = serde_json::to_vec_pretty(tag)?;
+            saved_tag.push(b'\n');
+            zip.write_file(MOD_TAG_PATH, &mut Cursor::new(saved_tag), FileCompression::Deflate)?;
+            report.written_files.push(MOD_TAG_PATH.to_string());
+        }
+
+        // When signing, the contents are hashed while they are read to sign them, rather than being read twice.
+        let content_digest = match &self.signer {
+            Some((cert, priv_key)) => {
+                info!("Signing");
+                zip.save_and_sign(priv_key, cert, self.signing_config).context("Failed to save APK")?
+            },
+            None => {
+                let content_digest = zip.get_content_digest().context("Failed to hash APK contents")?;
+                zip.save().context("Failed to save APK")?;
+                content_digest
+            }
+        };
+        report.reproducibility_digest = content_digest.iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        Ok(report)
+    }
+}
+
+// Gives the compression method used for an added file with the given name.
+// Native libraries are stored (and so aligned by `write_file`) rather than compressed, since Android can then load them from the APK
+// without extracting them when it is installed, which is needed if `extractNativeLibs` is false.
+fn get_compression(name: &str) -> FileCompression {
+    if name.ends_with(".so") {
+        FileCompression::Store
+    }   else {
+        FileCompression::Deflate
+    }
+}
+
+// Applies `manifest_mod` to a copy of the manifest of the APK in memory.
+// Returns the modified manifest, or None if the mod made no changes.
+// The bundled resource IDs are used if `res_ids` is None.
+fn modify_manifest(zip: &mut ZipFile<File>, manifest_mod: &ManifestMod, res_ids: Option<&ResourceIds>) -> Result<Option<Vec<u8>>> {
+    let contents = zip.read_file("AndroidManifest.xml").context("Failed to read manifest")?;
+    let mut cursor = Cursor::new(contents);
+    let mut reader = AxmlReader::new(&mut cursor).context("Failed to read AXML manifest")?;
+    let mut data_output = Cursor::new(Vec::new());
+    let mut writer = AxmlWriter::new(&mut data_output);
+
+    let bundled_ids;
+    let res_ids = match res_ids {
+        Some(res_ids) => res_ids,
+        None => {
+            bundled_ids = ResourceIds::load()?;
+            &bundled_ids
+        }
+    };
+    let modified = manifest_mod.apply_mod(&mut reader, &mut writer, res_ids).context("Failed to apply mod")?;
+
+    writer.finish().context("Failed to save AXML manifest")?;
+
+    Ok(modified.then(|| data_output.into_inner()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{axml::Event, zip::signing::V2Verification};
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mbf-patcher-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    // Writes an APK like those from other modding tools, in which the native libraries are deflated.
+    fn write_apk_with_deflated_libs(path: &Path) {
+        let mut zip = ZipFile::create(File::create(path).unwrap());
+        zip.write_file("classes.dex", &mut Cursor::new(b"dex\n035".repeat(50)), FileCompression::Deflate).unwrap();
+        zip.write_file("lib/arm64-v8a/libunity.so", &mut Cursor::new(b"old unity".repeat(50)), FileCompression::Deflate).unwrap();
+        zip.write_file("lib/arm64-v8a/libmain.so", &mut Cursor::new(b"old main".repeat(50)), FileCompression::Deflate).unwrap();
+        zip.save().unwrap();
+    }
+
+    // Gives the compression method and the offset of the data of the entry with the given name, from its local header.
+    fn data_offset(path: &Path, name: &str) -> (FileCompression, u64) {
+        let mut zip = ZipFile::open(File::open(path).unwrap()).unwrap();
+        let entry = zip.get_layout().unwrap().entries.into_iter().find(|entry| entry.name == name).unwrap();
+
+        let contents = std::fs::read(path).unwrap();
+        let header = &contents[entry.local_header_offset as usize..];
+        let name_len = u16::from_le_bytes([header[26], header[27]]) as u64;
+        let extra_len = u16::from_le_bytes([header[28], header[29]]) as u64;
+        (entry.compression_method, entry.local_header_offset + 30 + name_len + extra_len)
+    }
+
+    fn assert_libs_stored_and_aligned(path: &Path) {
+        for name in ["lib/arm64-v8a/libmain.so", "lib/arm64-v8a/libunity.so"] {
+            let (compression, offset) = data_offset(path, name);
+            assert!(matches!(compression, FileCompression::Store), "{name} was {compression:?}");
+            assert_eq!(offset % 16384, 0, "{name} starts at {offset}");
+        }
+        let mut zip = ZipFile::open(File::open(path).unwrap()).unwrap();
+        assert_eq!(zip.read_file("lib/arm64-v8a/libmain.so").unwrap(), b"new main");
+        assert_eq!(zip.read_file("lib/arm64-v8a/libunity.so").unwrap(), b"new unity".repeat(3));
+        assert!(matches!(zip.get_entry("classes.dex").unwrap().compression_method, FileCompression::Deflate));
+    }
+
+    fn patch_libs(source: &Path, unity_path: &Path) -> ApkPatcher {
+        std::fs::write(unity_path, b"new unity".repeat(3)).unwrap();
+        ApkPatcher::new(source)
+            .with_replaced_lib("libmain.so", FileSource::Bytes(b"new main".to_vec()))
+            .with_replaced_lib("libunity.so", FileSource::Path(unity_path.to_path_buf()))
+    }
+
+    #[test]
+    fn replaced_libs_are_stored_and_page_aligned_when_written_to_a_new_apk() {
+        let (source, dest, unity) = (temp_path("libs-source.apk"), temp_path("libs-dest.apk"), temp_path("libs-unity.so"));
+        write_apk_with_deflated_libs(&source);
+
+        patch_libs(&source, &unity).write_to(&dest).unwrap();
+        assert_libs_stored_and_aligned(&dest);
+        for path in [source, dest, unity] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn replaced_libs_are_stored_and_page_aligned_when_patched_in_place() {
+        let (apk, unity) = (temp_path("libs-in-place.apk"), temp_path("libs-in-place-unity.so"));
+        write_apk_with_deflated_libs(&apk);
+
+        patch_libs(&apk, &unity).patch_in_place().unwrap();
+        assert_libs_stored_and_aligned(&apk);
+        for path in [apk, unity] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    // The agent's debug certificate, which is quick to sign test APKs with since no key needs generating.
+    const TEST_PEM: &[u8] = include_bytes!("../../src/debug_cert.pem");
+
+    // Writes an APK with deflated and stored entries, including a native library and `old.txt`, which sits between them to be removed.
+    fn write_apk_with_stored_entries(path: &Path) {
+        let mut zip = ZipFile::create(File::create(path).unwrap());
+        zip.write_file("classes.dex", &mut Cursor::new(b"dex\n035".repeat(500)), FileCompression::Deflate).unwrap();
+        zip.write_file("resources.arsc", &mut Cursor::new(b"resources".repeat(50)), FileCompression::Store).unwrap();
+        zip.write_file("old.txt", &mut Cursor::new(b"removed".repeat(7)), FileCompression::Deflate).unwrap();
+        zip.write_file("lib/arm64-v8a/libmain.so", &mut Cursor::new(b"old main".repeat(50)), FileCompression::Store).unwrap();
+        zip.write_file("lib/arm64-v8a/libother.so", &mut Cursor::new(b"other lib".repeat(50)), FileCompression::Store).unwrap();
+        zip.write_file("assets/config.json", &mut Cursor::new(b"{}".repeat(100)), FileCompression::Deflate).unwrap();
+        zip.save().unwrap();
+    }
+
+    // Gives the bytes of the entry with the given name, from the start of its local header to the end of its data, and the offset of its data.
+    fn entry_bytes(path: &Path, name: &str) -> (Vec<u8>, u64) {
+        let mut zip = ZipFile::open(File::open(path).unwrap()).unwrap();
+        let entry = zip.get_layout().unwrap().entries.into_iter().find(|entry| entry.name == name).unwrap();
+        let (_, data_offset) = data_offset(path, name);
+
+        let contents = std::fs::read(path).unwrap();
+        (contents[entry.local_header_offset as usize..(data_offset + entry.compressed_len) as usize].to_vec(), data_offset)
+    }
+
+    #[test]
+    fn untouched_entries_are_copied_as_they_are() {
+        let (source, dest) = (temp_path("untouched-source.apk"), temp_path("untouched-dest.apk"));
+        write_apk_with_stored_entries(&source);
+
+        ApkPatcher::new(&source)
+            .with_removed_file("old.txt")
+            .with_replaced_lib("libmain.so", FileSource::Bytes(b"new main".to_vec()))
+            .sign_with(TEST_PEM).unwrap()
+            .write_to(&dest).unwrap();
+
+        let mut source_zip = ZipFile::open(File::open(&source).unwrap()).unwrap();
+        let mut dest_zip = ZipFile::open(File::open(&dest).unwrap()).unwrap();
+        assert!(matches!(dest_zip.verify_v2_signature().unwrap(), V2Verification::Valid { .. }));
+        assert!(!dest_zip.contains_file("old.txt"));
+        for name in ["classes.dex", "resources.arsc", "lib/arm64-v8a/libother.so", "assets/config.json"] {
+            assert_eq!(dest_zip.get_crc32(name), source_zip.get_crc32(name), "{name} has a different CRC");
+            assert_eq!(dest_zip.read_file(name).unwrap(), source_zip.read_file(name).unwrap());
+        }
+
+        // The entries before `old.txt` haven't moved, and the deflated entry after it has moved without any changes.
+        for name in ["classes.dex", "resources.arsc", "assets/config.json"] {
+            assert_eq!(entry_bytes(&dest, name).0, entry_bytes(&source, name).0, "{name} was changed");
+        }
+        // The library after `old.txt` would be misaligned if it had been moved as is, so only its local header was rewritten.
+        let (lib_bytes, lib_data_offset) = entry_bytes(&dest, "lib/arm64-v8a/libother.so");
+        assert_eq!(lib_data_offset % 16384, 0);
+        assert!(lib_bytes.ends_with(&b"other lib".repeat(50)));
+
+        for path in [source, dest] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    // Writes a manifest with just an application, for a manifest mod to add to.
+    fn minimal_manifest() -> Vec<u8> {
+        let mut output = Cursor::new(Vec::new());
+        let mut writer = AxmlWriter::new(&mut output);
+        for name in ["manifest", "application"] {
+            writer.write_event(Event::StartElement { attributes: Vec::new(), name: name.into(), namespace: None, line_num: 1 });
+        }
+        for name in ["application", "manifest"] {
+            writer.write_event(Event::EndElement { line_num: 1, namespace: None, name: name.into() });
+        }
+        writer.finish().unwrap();
+        output.into_inner()
+    }
+
+    #[test]
+    fn modified_manifest_is_written_instead_of_being_copied() {
+        let (source, dest) = (temp_path("manifest-source.apk"), temp_path("manifest-dest.apk"));
+        let mut zip = ZipFile::create(File::create(&source).unwrap());
+        zip.write_file("AndroidManifest.xml", &mut Cursor::new(minimal_manifest()), FileCompression::Store).unwrap();
+        zip.write_file("classes.dex", &mut Cursor::new(b"dex\n035".repeat(500)), FileCompression::Deflate).unwrap();
+        zip.save().unwrap();
+
+        let report = ApkPatcher::new(&source)
+            .with_manifest_mod(ManifestMod::new().with_permission("android.permission.INTERNET"))
+            .write_to(&dest).unwrap();
+        assert!(report.manifest_modified);
+
+        let mut dest_zip = ZipFile::open(File::open(&dest).unwrap()).unwrap();
+        let manifest = dest_zip.read_file("AndroidManifest.xml").unwrap();
+        let xml = crate::axml::to_readable_xml(&mut AxmlReader::new(&mut Cursor::new(manifest)).unwrap()).unwrap();
+        assert!(xml.contains("android.permission.INTERNET"), "{xml}");
+        // The original manifest was stored, so would appear as it was if it had been copied before being replaced.
+        let original = minimal_manifest();
+        assert!(!std::fs::read(&dest).unwrap().windows(original.len()).any(|window| window == original));
+        assert_eq!(entry_bytes(&dest, "classes.dex").0, entry_bytes(&source, "classes.dex").0);
+
+        for path in [source, dest] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    // Times writing a patched copy of a large APK, so that the time taken can be compared between changes with `--ignored --nocapture`.
+    // Nothing is asserted about the time, since it depends on the machine running the test.
+    #[test]
+    #[ignore]
+    fn benchmark_write_to() {
+        let (source, dest) = (temp_path("benchmark-source.apk"), temp_path("benchmark-dest.apk"));
+        let mut zip = ZipFile::create(File::create(&source).unwrap());
+        for index in 0..2000 {
+            let contents: Vec<u8> = (0..64 * 1024u32).map(|byte| (byte * 7 + index) as u8).collect();
+            let compression = if index % 2 == 0 { FileCompression::Store } else { FileCompression::Deflate };
+            zip.write_file(&format!("assets/file{index}.bin"), &mut Cursor::new(contents), compression).unwrap();
+        }
+        zip.save().unwrap();
+
+        let start = std::time::Instant::now();
+        ApkPatcher::new(&source)
+            .with_removed_file("assets/file1000.bin")
+            .with_replaced_file("assets/added.bin", FileSource::Bytes(vec![1; 1024]))
+            .sign_with(TEST_PEM).unwrap()
+            .write_to(&dest).unwrap();
+        println!("Wrote {} bytes in {:?}", std::fs::metadata(&dest).unwrap().len(), start.elapsed());
+
+        for path in [source, dest] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    // Writes an APK like one modded by another tool, with a V1 signature and the libraries of an old loader.
+    fn write_apk_modded_by_another_tool(path: &Path) {
+        let mut zip = ZipFile::create(File::create(path).unwrap());
+        zip.write_file("classes.dex", &mut Cursor::new(b"dex\n035"), FileCompression::Deflate).unwrap();
+        zip.write_file("META-INF/MANIFEST.MF", &mut Cursor::new(b"Manifest-Version: 1.0"), FileCompression::Deflate).unwrap();
+        zip.write_file("META-INF/CERT.SF", &mut Cursor::new(b"Signature-Version: 1.0"), FileCompression::Deflate).unwrap();
+        zip.write_file("META-INF/CERT.RSA", &mut Cursor::new(b"signature"), FileCompression::Store).unwrap();
+        zip.write_file("META-INF/services/provider", &mut Cursor::new(b"not a signature"), FileCompression::Deflate).unwrap();
+        zip.write_file("lib/armeabi-v7a/libmodloader.so", &mut Cursor::new(b"old loader"), FileCompression::Store).unwrap();
+        zip.write_file("lib/armeabi-v7a/libunity.so", &mut Cursor::new(b"old unity"), FileCompression::Store).unwrap();
+        zip.save().unwrap();
+    }
+
+    #[test]
+    fn files_matching_are_removed_before_files_are_added() {
+        let (source, dest) = (temp_path("matching-source.apk"), temp_path("matching-dest.apk"));
+        write_apk_modded_by_another_tool(&source);
+
+        let report = ApkPatcher::new(&source)
+            .with_removed_files_matching(crate::zip::signing::is_v1_signature_file)
+            .with_removed_prefix("lib/armeabi-v7a/")
+            .with_replaced_file("META-INF/CERT.SF", FileSource::Bytes(b"added".to_vec()))
+            .write_to(&dest).unwrap();
+
+        assert_eq!(report.removed_files, [
+            "META-INF/CERT.RSA",
+            "META-INF/CERT.SF",
+            "META-INF/MANIFEST.MF",
+            "lib/armeabi-v7a/libmodloader.so",
+            "lib/armeabi-v7a/libunity.so"
+        ]);
+        let mut zip = ZipFile::open(File::open(&dest).unwrap()).unwrap();
+        assert_eq!(zip.iter_entry_names().count(), 3);
+        assert_eq!(zip.read_file("META-INF/CERT.SF").unwrap(), b"added");
+        assert!(zip.contains_file("META-INF/services/provider"));
+        assert!(zip.contains_file("classes.dex"));
+
+        for path in [source, dest] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn nothing_is_removed_if_no_files_match() {
+        let apk = temp_path("matching-none.apk");
+        write_apk_modded_by_another_tool(&apk);
+
+        let report = ApkPatcher::new(&apk)
+            .with_removed_files_matching(|name| name.ends_with(".bak"))
+            .with_removed_prefix("lib/arm64-v8a/")
+            .patch_in_place().unwrap();
+
+        assert!(report.removed_files.is_empty());
+        assert_eq!(ZipFile::open(File::open(&apk).unwrap()).unwrap().iter_entry_names().count(), 7);
+        std::fs::remove_file(apk).unwrap();
+    }
+
+    #[test]
+    fn patching_the_same_apk_twice_gives_identical_output() {
+        let (source, first, second) = (temp_path("deterministic-source.apk"), temp_path("deterministic-first.apk"), temp_path("deterministic-second.apk"));
+        write_apk_with_stored_entries(&source);
+        let mut zip = ZipFile::open(std::fs::OpenOptions::new().read(true).write(true).open(&source).unwrap()).unwrap();
+        zip.write_file("AndroidManifest.xml", &mut Cursor::new(minimal_manifest()), FileCompression::Deflate).unwrap();
+        zip.save().unwrap();
+
+        for dest in [&first, &second] {
+            ApkPatcher::new(&source)
+                .with_manifest_mod(ManifestMod::new().with_permission("android.permission.INTERNET"))
+                .with_removed_file("old.txt")
+                .with_replaced_lib("libmain.so", FileSource::Bytes(b"new main".to_vec()))
+                .with_replaced_file("assets/added.bin", FileSource::Bytes(vec![7; 5000]))
+                .sign_with(TEST_PEM).unwrap()
+                .write_to(dest).unwrap();
+        }
+
+        // Comparing the whole files covers the entry order, local headers, alignment padding and signature block.
+        let (first_bytes, second_bytes) = (std::fs::read(&first).unwrap(), std::fs::read(&second).unwrap());
+        assert_eq!(first_bytes.len(), second_bytes.len());
+        assert!(first_bytes == second_bytes, "the output first differs at byte {:?}",
+            first_bytes.iter().zip(&second_bytes).position(|(a, b)| a != b));
+
+        for path in [source, first, second] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! The `modded.json` tag that indicates that an APK has been modded, and what by.
+
+use serde::{Deserialize, Serialize};
+
+/// The path of the mod tag within the APK.
+pub const MOD_TAG_PATH: &str = "modded.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ModTag {
+    pub patcher_name: String,
+    pub patcher_version: Option<String>,
+    pub modloader_name: String,
+    pub modloader_version: Option<String>,
+    /// Details of the custom Application subclass added to the APK, so that it can be removed when the APK is next patched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub application_override: Option<AppliedApplicationOverride>,
+    /// The name of the modloader the APK was modded with before being migrated to this one, if it was migrated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migrated_from: Option<String>,
+    /// The permissions added to the manifest on top of those needed to mod the game.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_permissions: Vec<String>,
+    /// The `uses-feature` names added to the manifest on top of those needed to mod the game.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_features: Vec<String>,
+    /// The hex encoded SHA-256 hash of the certificate the APK was signed with, so that a change of certificate can be detected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_cert_sha256: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AppliedApplicationOverride {
+    /// The `android:name` of the application before it was overridden, if any.
+    pub original_name: Option<String>,
+    pub class_name: String,
+    /// The name of the DEX file added to the APK, e.g. `classes2.dex`
+    pub dex_entry: String
+}
@@ -0,0 +1,619 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use anyhow::{Result, anyhow, Context};
+
+use super::{FileCompression, LOCAL_HEADER_MIN_LEN};
+
+// Written in place of a size, offset or record count that doesn't fit in its field, which is then given in a ZIP64 record instead.
+const ZIP64_SENTINEL: u32 = 0xFFFFFFFF;
+const ZIP64_SENTINEL_16: u16 = 0xFFFF;
+// The header ID of the ZIP64 extended information extra field, which gives the sizes and offsets of an entry that don't fit in 32 bits.
+const ZIP64_EXTRA_FIELD_ID: u16 = 0x0001;
+// The minimum version needed to extract an archive that uses ZIP64 (4.5)
+const ZIP64_VERSION_NEEDED: u16 = 45;
+// Length of the ZIP64 EOCD record, excluding its signature and the length field itself.
+const ZIP64_EOCD_LEN: u64 = 44;
+// Length of the ZIP64 EOCD locator, which sits directly before the EOCD.
+const ZIP64_LOCATOR_LEN: u64 = 20;
+// Length of the ZIP64 extra field of a local header, which gives both lengths.
+const ZIP64_LOCAL_FIELD_LEN: u64 = 20;
+// General purpose flag set when the file name (and comment) of an entry are encoded in UTF-8, rather than code page 437.
+const UTF8_NAME_FLAG: u16 = 1 << 11;
+
+// The characters given by bytes 0x80 to 0xFF in code page 437, which ZIP names are encoded in unless they are flagged as UTF-8.
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}'
+];
+
+impl From<u16> for FileCompression {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => Self::Store,
+            8 => Self::Deflate,
+            other => Self::Unsupported(other)
+        }
+    }
+}
+
+impl From<FileCompression> for u16 {
+    fn from(value: FileCompression) -> Self {
+        match value {
+            FileCompression::Store => 0,
+            FileCompression::Deflate => 8,
+            FileCompression::Unsupported(other) => other
+        }
+    }
+}
+
+/// The name of an entry within a ZIP file.
+/// The raw bytes are stored exactly as found in the archive so that they can be written back unchanged,
+/// alongside a display form which is used for matching and showing to the user.
+#[derive(Clone)]
+pub struct FileName {
+    raw: Vec<u8>,
+    display: String,
+    utf8: bool
+}
+
+impl FileName {
+    /// Creates a file name from the bytes stored in a ZIP header, with `utf8_flag` true if the header flags the name as UTF-8.
+    /// Names are decoded strictly by the flag: flagged names as UTF-8, and others as code page 437. The two only differ for bytes
+    /// of 0x80 and above, so an unflagged name written as UTF-8 by a tool that doesn't set the flag will have a display form
+    /// that doesn't match its intended name, but its raw bytes are still kept as they are.
+    pub fn from_raw(raw: Vec<u8>, utf8_flag: bool) -> Self {
+        // We must never fail to read a name, otherwise the entry would be lost when the archive is saved.
+        let (display, utf8) = if raw.is_ascii() {
+            (String::from_utf8(raw.clone()).expect("ASCII is valid UTF-8"), true)
+        }   else if utf8_flag {
+            match std::str::from_utf8(&raw) {
+                Ok(s) => (s.to_string(), true),
+                Err(_) => (String::from_utf8_lossy(&raw).to_string(), false)
+            }
+        }   else {
+            (decode_cp437(&raw), false)
+        };
+
+        Self {
+            // Some tools (typically on windows) separate directories with backslashes.
+            // These are normalised in the display form only, and the raw name is left intact.
+            display: display.replace('\\', "/"),
+            raw,
+            utf8
+        }
+    }
+
+    /// The name as it is stored within the archive.
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// The name used for lookups, with any backslashes replaced with forward slashes.
+    /// Invalid UTF-8 sequences in names flagged as UTF-8 are replaced with U+FFFD.
+    pub fn display(&self) -> &str {
+        &self.display
+    }
+
+    /// Returns true if the raw name was decoded as UTF-8: it is either ASCII, or flagged as UTF-8 and valid UTF-8.
+    pub fn is_utf8(&self) -> bool {
+        self.utf8
+    }
+
+    /// The general purpose flags needed to write this name: the UTF-8 flag (bit 11) if it is UTF-8 and isn't plain ASCII, or 0 otherwise.
+    pub fn encoding_flags(&self) -> u16 {
+        if self.utf8 && !self.raw.is_ascii() {
+            UTF8_NAME_FLAG
+        }   else {
+            0
+        }
+    }
+}
+
+impl From<&str> for FileName {
+    fn from(value: &str) -> Self {
+        Self::from_raw(value.as_bytes().to_vec(), true)
+    }
+}
+
+fn decode_cp437(raw: &[u8]) -> String {
+    raw.iter()
+        .map(|&byte| if byte < 0x80 { byte as char } else { CP437_HIGH[byte as usize - 0x80] })
+        .collect()
+}
+
+/// Returns true if `value` doesn't fit in a 32 bit ZIP field, and so must be given in a ZIP64 record.
+pub fn needs_zip64(value: u64) -> bool {
+    value >= ZIP64_SENTINEL as u64
+}
+
+// ZIP end of central directory record
+#[derive(Clone)]
+pub struct EndOfCentDir {
+    pub cent_dir_records: u64,
+    pub cent_dir_size: u64,
+    pub cent_dir_offset: u64,
+    pub comment: Vec<u8>,
+    // Whether the ZIP64 EOCD record and locator are written before the EOCD.
+    // They are always written if the record count, size or offset doesn't fit in the EOCD, even if this is false.
+    pub zip64: bool
+}
+
+// ZIP central directory record
+#[derive(Clone)]
+pub struct CentDirHeader {
+    pub os_version_made_by: u16,
+    pub version_needed: u16,
+    pub flags: u16,
+    pub compression_method: FileCompression,
+    pub last_modified: u32, // TODO: parse this
+    pub crc32: u32,
+    pub compressed_len: u64,
+    pub uncompressed_len: u64,
+
+    pub internal_attrs: u16,
+    pub external_attrs: u32,
+    pub local_header_offset: u64,
+
+    pub file_name: FileName,
+    pub extra_field: Vec<u8>,
+    pub comment: Vec<u8>,
+}
+
+// ZIP local file header record
+#[derive(Clone)]
+pub struct LocalFileHeader {
+    pub version_needed: u16,
+    pub flags: u16,
+    pub compression_method: FileCompression,
+    pub last_modified: u32, // TODO: parse this
+    pub crc32: u32,
+    pub compressed_len: u64,
+    pub uncompressed_len: u64,
+
+    pub file_name: Vec<u8>,
+    // Excludes the ZIP64 extra field, which is read into the lengths above and written again when needed.
+    pub extra_field: Vec<u8>,
+    // Whether the lengths are given in a ZIP64 extra field.
+    // This is always done if either length doesn't fit in 32 bits, even if this is false.
+    pub zip64: bool
+}
+
+// Removes the ZIP64 extended information field from `extra_field`, giving its contents if there was one.
+// Any trailing bytes that are too short to be a field (e.g. padding added by older versions of zipalign) are left as they are.
+fn take_zip64_field(extra_field: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let mut pos = 0;
+    while pos + 4 <= extra_field.len() {
+        let id = u16::from_le_bytes([extra_field[pos], extra_field[pos + 1]]);
+        let len = u16::from_le_bytes([extra_field[pos + 2], extra_field[pos + 3]]) as usize;
+        let end = pos + 4 + len;
+        if end > extra_field.len() {
+            break;
+        }
+
+        if id == ZIP64_EXTRA_FIELD_ID {
+            let contents = extra_field[pos + 4..end].to_vec();
+            extra_field.drain(pos..end);
+            return Some(contents);
+        }
+        pos = end;
+    }
+
+    None
+}
+
+// Replaces each of `values` that is set to the ZIP64 sentinel with the next value in the ZIP64 extra field, if there is one.
+// The values must be given in the order they appear in the ZIP64 field: uncompressed length, compressed length, then local header offset.
+fn read_zip64_values(extra_field: &mut Vec<u8>, values: &mut [&mut u64]) -> Result<()> {
+    let zip64_field = match take_zip64_field(extra_field) {
+        Some(field) => field,
+        None => return Ok(())
+    };
+
+    let mut zip64_data = &zip64_field[..];
+    for value in values.iter_mut().filter(|value| ***value == ZIP64_SENTINEL as u64) {
+        **value = zip64_data.read_u64::<LE>().context("ZIP64 extra field was too short")?;
+    }
+
+    Ok(())
+}
+
+// Prepends a ZIP64 extended information field with the given values to `extra_field`, unless there are no values.
+fn with_zip64_field(zip64_values: &[u64], extra_field: &[u8]) -> Vec<u8> {
+    if zip64_values.is_empty() {
+        return extra_field.to_vec();
+    }
+
+    let mut result = Vec::with_capacity(4 + zip64_values.len() * 8 + extra_field.len());
+    result.extend(ZIP64_EXTRA_FIELD_ID.to_le_bytes());
+    result.extend((zip64_values.len() as u16 * 8).to_le_bytes());
+    for value in zip64_values {
+        result.extend(value.to_le_bytes());
+    }
+    result.extend(extra_field);
+    result
+}
+
+// Gives the value to write to a 32 bit field, adding `value` to `zip64_values` and giving the sentinel if it doesn't fit.
+fn to_u32_or_zip64(value: u64, zip64_values: &mut Vec<u64>) -> u32 {
+    if needs_zip64(value) {
+        zip64_values.push(value);
+        ZIP64_SENTINEL
+    }   else {
+        value as u32
+    }
+}
+
+impl EndOfCentDir {
+    pub const HEADER: u32 = 0x06054b50;
+    const ZIP64_HEADER: u32 = 0x06064b50;
+    const ZIP64_LOCATOR_HEADER: u32 = 0x07064b50;
+
+    /// Reads the EOCD, which `data` must be seeked to the start of, along with the ZIP64 EOCD record if the archive has one.
+    pub fn read(data: &mut (impl Read + Seek)) -> Result<Self> {
+        let eocd_offset = data.stream_position()?;
+        if data.read_u32::<LE>()? != Self::HEADER {
+            return Err(anyhow!("Invalid EOCD header"));
+        }
+
+        let disk_num = data.read_u16::<LE>()?;
+        let start_of_cd_disk = data.read_u16::<LE>()?;
+        let cd_records_on_disk = data.read_u16::<LE>()?;
+        let cent_dir_records = data.read_u16::<LE>()?;
+
+        let mut result = Self {
+            cent_dir_records: cent_dir_records as u64,
+            cent_dir_size: data.read_u32::<LE>()? as u64,
+            cent_dir_offset: data.read_u32::<LE>()? as u64,
+            comment: vec![0u8; data.read_u16::<LE>()? as usize],
+            zip64: false
+        };
+
+        data.read_exact(&mut result.comment)?;
+        
+        if cent_dir_records != cd_records_on_disk || start_of_cd_disk != 0 || disk_num != 0 {
+            return Err(anyhow!("Multi-disk archives are not supported"));
+        }
+
+        if cent_dir_records == ZIP64_SENTINEL_16
+            || result.cent_dir_size == ZIP64_SENTINEL as u64
+            || result.cent_dir_offset == ZIP64_SENTINEL as u64 {
+            result.read_zip64(data, eocd_offset).context("Invalid ZIP64 EOCD")?;
+        }
+
+        Ok(result)
+    }
+
+    // Reads the record count, size and offset of the central directory from the ZIP64 EOCD record, if there is one.
+    // The ZIP64 EOCD locator, which gives the offset of the record, is directly before the EOCD at `eocd_offset`.
+    fn read_zip64(&mut self, data: &mut (impl Read + Seek), eocd_offset: u64) -> Result<()> {
+        if eocd_offset < ZIP64_LOCATOR_LEN {
+            return Ok(());
+        }
+        data.seek(SeekFrom::Start(eocd_offset - ZIP64_LOCATOR_LEN))?;
+        if data.read_u32::<LE>()? != Self::ZIP64_LOCATOR_HEADER {
+            // Some archives set fields to the sentinel without needing ZIP64, in which case these are the real values.
+            return Ok(());
+        }
+
+        let zip64_eocd_disk = data.read_u32::<LE>()?;
+        let zip64_eocd_offset = data.read_u64::<LE>()?;
+        let total_disks = data.read_u32::<LE>()?;
+        if zip64_eocd_disk != 0 || total_disks > 1 {
+            return Err(anyhow!("Multi-disk archives are not supported"));
+        }
+
+        data.seek(SeekFrom::Start(zip64_eocd_offset))?;
+        if data.read_u32::<LE>()? != Self::ZIP64_HEADER {
+            return Err(anyhow!("Invalid ZIP64 EOCD header"));
+        }
+        let _record_len = data.read_u64::<LE>()?;
+        let _version_made_by = data.read_u16::<LE>()?;
+        let _version_needed = data.read_u16::<LE>()?;
+        let disk_num = data.read_u32::<LE>()?;
+        let start_of_cd_disk = data.read_u32::<LE>()?;
+        let cd_records_on_disk = data.read_u64::<LE>()?;
+
+        self.cent_dir_records = data.read_u64::<LE>()?;
+        self.cent_dir_size = data.read_u64::<LE>()?;
+        self.cent_dir_offset = data.read_u64::<LE>()?;
+        self.zip64 = true;
+        if self.cent_dir_records != cd_records_on_disk || start_of_cd_disk != 0 || disk_num != 0 {
+            return Err(anyhow!("Multi-disk archives are not supported"));
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if the ZIP64 EOCD record and locator will be written before the EOCD.
+    pub fn uses_zip64(&self) -> bool {
+        self.zip64
+            || self.cent_dir_records >= ZIP64_SENTINEL_16 as u64
+            || needs_zip64(self.cent_dir_size)
+            || needs_zip64(self.cent_dir_offset)
+    }
+
+    /// Writes the EOCD, preceded by the ZIP64 EOCD record and locator if they are needed.
+    /// The ZIP64 EOCD record is written directly after the central directory, so `data` must be seeked to the end of it.
+    pub fn write(&self, data: &mut impl Write) -> Result<()> {
+        let zip64 = self.uses_zip64();
+        if zip64 {
+            self.write_zip64(data)?;
+        }
+
+        data.write_u32::<LE>(Self::HEADER)?;
+
+        // Assuming a single-disk archive
+        data.write_u16::<LE>(0)?;
+        data.write_u16::<LE>(0)?;
+        if zip64 {
+            data.write_u16::<LE>(ZIP64_SENTINEL_16)?;
+            data.write_u16::<LE>(ZIP64_SENTINEL_16)?;
+            data.write_u32::<LE>(ZIP64_SENTINEL)?;
+            data.write_u32::<LE>(ZIP64_SENTINEL)?;
+        }   else {
+            data.write_u16::<LE>(self.cent_dir_records as u16)?;
+            data.write_u16::<LE>(self.cent_dir_records as u16)?;
+            data.write_u32::<LE>(self.cent_dir_size as u32)?;
+            data.write_u32::<LE>(self.cent_dir_offset as u32)?;
+        }
+        data.write_u16::<LE>(self.comment.len()
+            .try_into()
+            .context("File comment longer than max length")?)?;
+        data.write_all(&self.comment)?;
+
+        Ok(())
+    }
+
+    // Writes the ZIP64 EOCD record, followed by the locator giving its offset.
+    fn write_zip64(&self, data: &mut impl Write) -> Result<()> {
+        data.write_u32::<LE>(Self::ZIP64_HEADER)?;
+        data.write_u64::<LE>(ZIP64_EOCD_LEN)?;
+        data.write_u16::<LE>(ZIP64_VERSION_NEEDED)?; // Version made by
+        data.write_u16::<LE>(ZIP64_VERSION_NEEDED)?;
+
+        // Assuming a single-disk archive
+        data.write_u32::<LE>(0)?;
+        data.write_u32::<LE>(0)?;
+        data.write_u64::<LE>(self.cent_dir_records)?;
+
+        data.write_u64::<LE>(self.cent_dir_records)?;
+        data.write_u64::<LE>(self.cent_dir_size)?;
+        data.write_u64::<LE>(self.cent_dir_offset)?;
+
+        data.write_u32::<LE>(Self::ZIP64_LOCATOR_HEADER)?;
+        data.write_u32::<LE>(0)?; // Disk containing the ZIP64 EOCD
+        data.write_u64::<LE>(self.cent_dir_offset + self.cent_dir_size)?;
+        data.write_u32::<LE>(1)?; // Total number of disks
+
+        Ok(())
+    }
+}
+
+impl CentDirHeader {
+    pub const HEADER: u32 = 0x02014b50;
+
+    pub fn read(data: &mut impl Read) -> Result<Self> {
+        if data.read_u32::<LE>()? != Self::HEADER {
+            return Err(anyhow!("Invalid CD header signature"));
+        }
+
+        let version_made_by = data.read_u16::<LE>()?;
+        let version_needed = data.read_u16::<LE>()?;
+        let flags = data.read_u16::<LE>()?;
+        let compression_method = FileCompression::from(data.read_u16::<LE>()?);
+        let last_modified = data.read_u32::<LE>()?;
+        let crc32 = data.read_u32::<LE>()?;
+        let mut compressed_len = data.read_u32::<LE>()? as u64;
+        let mut uncompressed_len = data.read_u32::<LE>()? as u64;
+
+        let mut file_name_buf = vec![0u8; data.read_u16::<LE>()? as usize];
+        let mut extra_field_buf = vec![0u8; data.read_u16::<LE>()? as usize];
+        let mut comment_buf = vec![0u8; data.read_u16::<LE>()? as usize];
+
+        if data.read_u16::<LE>()? != 0 {
+            return Err(anyhow!("Multi-disk archives are not supported"))
+        }
+
+        let internal_attrs = data.read_u16::<LE>()?;
+        let external_attrs = data.read_u32::<LE>()?;
+        let mut local_header_offset = data.read_u32::<LE>()? as u64;
+
+        data.read_exact(&mut file_name_buf)?;
+        data.read_exact(&mut extra_field_buf)?;
+        data.read_exact(&mut comment_buf)?;
+        read_zip64_values(&mut extra_field_buf, &mut [&mut uncompressed_len, &mut compressed_len, &mut local_header_offset])?;
+
+        Ok(Self {
+            os_version_made_by: version_made_by,
+            version_needed,
+            flags,
+            compression_method,
+            last_modified,
+            crc32,
+            compressed_len,
+            uncompressed_len,
+            internal_attrs,
+            external_attrs,
+            local_header_offset,
+
+            // The raw bytes are kept, so the name (and its flag) is never altered when saving.
+            file_name: FileName::from_raw(file_name_buf, flags & UTF8_NAME_FLAG != 0),
+            extra_field: extra_field_buf,
+            comment: comment_buf
+        })
+    }
+
+    pub fn write(&self, data: &mut impl Write) -> Result<()> {
+        // Values that don't fit are given in a ZIP64 extra field, in this order.
+        let mut zip64_values = Vec::new();
+        let uncompressed_len = to_u32_or_zip64(self.uncompressed_len, &mut zip64_values);
+        let compressed_len = to_u32_or_zip64(self.compressed_len, &mut zip64_values);
+        let local_header_offset = to_u32_or_zip64(self.local_header_offset, &mut zip64_values);
+        let version_needed = if zip64_values.is_empty() {
+            self.version_needed
+        }   else {
+            self.version_needed.max(ZIP64_VERSION_NEEDED)
+        };
+        let extra_field = with_zip64_field(&zip64_values, &self.extra_field);
+
+        data.write_u32::<LE>(Self::HEADER)?;
+        data.write_u16::<LE>(self.os_version_made_by)?;
+        data.write_u16::<LE>(version_needed)?;
+        data.write_u16::<LE>(self.flags)?;
+        data.write_u16::<LE>(self.compression_method.into())?;
+        data.write_u32::<LE>(self.last_modified)?;
+        data.write_u32::<LE>(self.crc32)?;
+        data.write_u32::<LE>(compressed_len)?;
+        data.write_u32::<LE>(uncompressed_len)?;
+
+        data.write_u16::<LE>(self.file_name.raw().len()
+            .try_into().context("File name longer than max length")?)?;
+        data.write_u16::<LE>(extra_field.len()
+            .try_into().context("Extra field longer than max length")?)?;
+        data.write_u16::<LE>(self.comment.len()
+            .try_into().context("Comment longer than max length")?)?;
+
+        data.write_u16::<LE>(0)?; // Disk number
+        data.write_u16::<LE>(self.internal_attrs)?;
+        data.write_u32::<LE>(self.external_attrs)?;
+        data.write_u32::<LE>(local_header_offset)?;
+
+        data.write_all(self.file_name.raw())?;
+        data.write_all(&extra_field)?;
+        data.write_all(&self.comment)?;
+
+        Ok(())
+    }
+}
+
+impl LocalFileHeader {
+    const HEADER: u32 = 0x04034b50;
+
+    pub fn read(data: &mut impl Read) -> Result<Self> {
+        if data.read_u32::<LE>()? != Self::HEADER {
+            return Err(anyhow!("Invalid LFH header signature"));
+        }
+
+        let version_needed = data.read_u16::<LE>()?;
+        let flags = data.read_u16::<LE>()?;
+        let compression_method = FileCompression::from(data.read_u16::<LE>()?);
+        let last_modified = data.read_u32::<LE>()?;
+        let crc32 = data.read_u32::<LE>()?;
+        let mut compressed_len = data.read_u32::<LE>()? as u64;
+        let mut uncompressed_len = data.read_u32::<LE>()? as u64;
+
+        let mut file_name_buf = vec![0u8; data.read_u16::<LE>()? as usize];
+        let mut extra_field_buf = vec![0u8; data.read_u16::<LE>()? as usize];
+
+        data.read_exact(&mut file_name_buf)?;
+        data.read_exact(&mut extra_field_buf)?;
+        let zip64 = compressed_len == ZIP64_SENTINEL as u64 || uncompressed_len == ZIP64_SENTINEL as u64;
+        read_zip64_values(&mut extra_field_buf, &mut [&mut uncompressed_len, &mut compressed_len])?;
+
+        Ok(Self {
+            version_needed,
+            flags,
+            compression_method,
+            last_modified,
+            crc32,
+            compressed_len,
+            uncompressed_len,
+            file_name: file_name_buf,
+            extra_field: extra_field_buf,
+            zip64
+        })
+    }
+
+    /// Returns true if the lengths will be written to a ZIP64 extra field.
+    pub fn uses_zip64(&self) -> bool {
+        self.zip64 || needs_zip64(self.compressed_len) || needs_zip64(self.uncompressed_len)
+    }
+
+    /// The number of bytes that `write` will give, including any ZIP64 extra field.
+    pub fn header_len(&self) -> u64 {
+        let zip64_field_len = if self.uses_zip64() { ZIP64_LOCAL_FIELD_LEN } else { 0 };
+        LOCAL_HEADER_MIN_LEN + self.file_name.len() as u64 + self.extra_field.len() as u64 + zip64_field_len
+    }
+
+    pub fn write(&self, data: &mut impl Write) -> Result<()> {
+        // Unlike the central directory, the local header must give both lengths in the ZIP64 field if either is given there.
+        let zip64 = self.uses_zip64();
+        let (version_needed, compressed_len, uncompressed_len, extra_field) = if zip64 {
+            (self.version_needed.max(ZIP64_VERSION_NEEDED),
+                ZIP64_SENTINEL,
+                ZIP64_SENTINEL,
+                with_zip64_field(&[self.uncompressed_len, self.compressed_len], &self.extra_field))
+        }   else {
+            (self.version_needed, self.compressed_len as u32, self.uncompressed_len as u32, self.extra_field.clone())
+        };
+
+        data.write_u32::<LE>(Self::HEADER)?;
+        data.write_u16::<LE>(version_needed)?;
+        data.write_u16::<LE>(self.flags)?;
+        data.write_u16::<LE>(self.compression_method.into())?;
+        data.write_u32::<LE>(self.last_modified)?;
+        data.write_u32::<LE>(self.crc32)?;
+        data.write_u32::<LE>(compressed_len)?;
+        data.write_u32::<LE>(uncompressed_len)?;
+
+        data.write_u16::<LE>(self.file_name.len()
+            .try_into().context("File name longer than max length")?)?;
+        data.write_u16::<LE>(extra_field.len()
+            .try_into().context("Extra field longer than max length")?)?;
+
+        data.write_all(&self.file_name)?;
+        data.write_all(&extra_field)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_flagged_as_utf8_are_decoded_as_utf8() {
+        for name in ["assets/日本語/曲.dat", "Música/canción.ogg"] {
+            let file_name = FileName::from_raw(name.as_bytes().to_vec(), true);
+            assert_eq!(file_name.display(), name);
+            assert!(file_name.is_utf8());
+            assert_eq!(file_name.encoding_flags(), UTF8_NAME_FLAG);
+        }
+    }
+
+    #[test]
+    fn names_without_the_flag_are_decoded_as_cp437() {
+        assert_eq!(FileName::from_raw(b"caf\x82.txt".to_vec(), false).display(), "café.txt");
+        // Valid UTF-8 isn't treated as such without the flag: `é` in UTF-8 is 0xC3 0xA9, which are `├` and `⌐` in code page 437.
+        let utf8_name = FileName::from_raw("café.txt".as_bytes().to_vec(), false);
+        assert_eq!(utf8_name.display(), "caf├⌐.txt");
+        assert_eq!(utf8_name.raw(), "café.txt".as_bytes());
+        assert!(!utf8_name.is_utf8());
+        assert_eq!(utf8_name.encoding_flags(), 0);
+    }
+
+    #[test]
+    fn ascii_names_are_the_same_with_or_without_the_flag() {
+        for utf8_flag in [true, false] {
+            let file_name = FileName::from_raw(b"lib/arm64-v8a/libmain.so".to_vec(), utf8_flag);
+            assert_eq!(file_name.display(), "lib/arm64-v8a/libmain.so");
+            assert!(file_name.is_utf8());
+            assert_eq!(file_name.encoding_flags(), 0);
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_flagged_as_utf8_is_decoded_lossily() {
+        let file_name = FileName::from_raw(b"bad\xff.txt".to_vec(), true);
+        assert_eq!(file_name.display(), "bad\u{FFFD}.txt");
+        assert!(!file_name.is_utf8());
+    }
+}
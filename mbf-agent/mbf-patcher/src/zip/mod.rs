@@ -0,0 +1,2199 @@
+use std::{collections::HashMap, fmt::Display, fs::File, io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Take, Write}, path::{Component, Path, PathBuf}};
+use anyhow::{Result, anyhow, Context};
+use crc::{Crc, Algorithm};
+use flate2::{write::DeflateEncoder, Compression};
+use libflate::deflate;
+use log::warn;
+use rasn_pkix::Certificate;
+use rsa::{sha2::{Digest, Sha256}, RsaPrivateKey};
+use serde::Serialize;
+
+use self::data::{EndOfCentDir, CentDirHeader, LocalFileHeader, needs_zip64};
+use self::signing::{SigningConfig, V2Verification};
+pub use self::data::FileName;
+
+mod data;
+pub mod signing;
+
+// Minimum version needed to extract ZIP files made by this module
+const VERSION_NEEDED_TO_EXTRACT: u16 = 0x0002;
+// General purpose flag set when the sizes and CRC of an entry are given in a data descriptor after its data.
+const DATA_DESCRIPTOR_FLAG: u16 = 1 << 3;
+// Length of the EOCD record with no comment
+const EOCD_MIN_LEN: u64 = 22;
+// Length of a local file header with no file name or extra field
+const LOCAL_HEADER_MIN_LEN: u64 = 30;
+// Alignment of the data of STORE entries added by `write_file`, so that they can be read in place.
+const STORE_ALIGNMENT: u64 = 4;
+// Stored native libraries are aligned to 16KB pages, so that Android can map them directly from the APK on devices with 4KB or 16KB pages.
+const NATIVE_LIB_ALIGNMENT: u64 = 16384;
+// An upper bound on the length of the APK signing block, which holds at most two signatures of one certificate each.
+const SIGNING_BLOCK_MAX_LEN: u64 = 1024 * 1024;
+
+pub const ZIP_CRC: Crc<u32> =  Crc::<u32>::new(&Algorithm {
+    width: 32,
+    poly: 0x04c11db7,
+    init: 0xffffffff,
+    refin: true,
+    refout: true,
+    xorout: 0xffffffff,
+    check: 0xcbf43926,
+    residue: 0xdebb20e3,
+});
+
+// The compression method of a file within the archive, which may be an unsupported method.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub enum FileCompression {
+    Deflate,
+    Store,
+    Unsupported(u16)
+}
+
+/// The level that files written with `FileCompression::Deflate` are compressed at,
+/// from 0 (no compression) to 9 (smallest output, but slowest).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CompressionLevel(u8);
+
+impl CompressionLevel {
+    pub const FASTEST: Self = Self(1);
+    pub const DEFAULT: Self = Self(6);
+    pub const BEST: Self = Self(9);
+
+    /// Fails if `level` is greater than 9.
+    pub fn new(level: u8) -> Result<Self> {
+        if level > 9 {
+            return Err(anyhow!("Compression level must be from 0 to 9, got {level}"));
+        }
+        Ok(Self(level))
+    }
+
+    pub fn level(&self) -> u8 {
+        self.0
+    }
+}
+
+impl Default for CompressionLevel {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// The size and position of an entry within an archive, as given by the central directory.
+#[derive(Clone, Debug)]
+pub struct EntryLayout {
+    /// The display form of the file name.
+    pub name: String,
+    pub crc32: u32,
+    pub compressed_len: u64,
+    pub uncompressed_len: u64,
+    pub compression_method: FileCompression,
+    pub local_header_offset: u64,
+    /// The length of the local header, excluding its extra field, which is counted as padding since it is used to align entries.
+    pub header_len: u64
+}
+
+/// The layout of the entries and metadata within an archive, read without decompressing any entries.
+#[derive(Clone, Debug)]
+pub struct ArchiveLayout {
+    /// Ordered by the position of the data of each entry.
+    pub entries: Vec<EntryLayout>,
+    /// The length of the whole archive.
+    pub total_len: u64,
+    /// The length of the APK signing block, or 0 if the archive isn't signed with the V2 (or a later) scheme.
+    pub signing_block_len: u64,
+    /// The length of the central directory and EOCD.
+    pub cent_dir_len: u64
+}
+
+impl ArchiveLayout {
+    /// The number of bytes within the archive that aren't part of the data or header of any entry, the signing block or the central directory.
+    /// This includes local extra fields, data descriptors and any gaps between entries.
+    pub fn padding_len(&self) -> u64 {
+        let entries_len: u64 = self.entries.iter()
+            .map(|entry| entry.header_len + entry.compressed_len)
+            .sum();
+        self.total_len.saturating_sub(entries_len + self.signing_block_len + self.cent_dir_len)
+    }
+}
+
+/// A date and time in the MS-DOS format used for the modification times of ZIP entries, which is in local time and accurate to 2 seconds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DosDateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8
+}
+
+impl DosDateTime {
+    /// Parses a DOS date and time, with the time in the lower 16 bits and the date in the upper 16 bits, as given in ZIP headers.
+    pub fn from_raw(raw: u32) -> Self {
+        let time = raw & 0xFFFF;
+        let date = raw >> 16;
+        Self {
+            year: 1980 + (date >> 9) as u16,
+            month: ((date >> 5) & 0x0F) as u8,
+            day: (date & 0x1F) as u8,
+            hour: (time >> 11) as u8,
+            minute: ((time >> 5) & 0x3F) as u8,
+            second: ((time & 0x1F) * 2) as u8
+        }
+    }
+}
+
+impl Display for DosDateTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// The metadata of an entry given by the central directory, which can be read without reading the entry itself.
+#[derive(Clone, Debug, Serialize)]
+pub struct EntryInfo {
+    /// The display form of the file name.
+    pub name: String,
+    pub compressed_len: u64,
+    pub uncompressed_len: u64,
+    pub compression_method: FileCompression,
+    pub crc32: u32,
+    pub last_modified: DosDateTime
+}
+
+impl EntryInfo {
+    fn from_header(header: &CentDirHeader) -> Self {
+        Self {
+            name: header.file_name.display().to_string(),
+            compressed_len: header.compressed_len,
+            uncompressed_len: header.uncompressed_len,
+            compression_method: header.compression_method,
+            crc32: header.crc32,
+            last_modified: DosDateTime::from_raw(header.last_modified)
+        }
+    }
+
+    /// Returns true if and only if the uncompressed contents of the entry have the same length and CRC-32 as `contents`.
+    /// This doesn't read the entry, so is only as reliable as a CRC-32 comparison.
+    pub fn matches(&self, contents: &[u8]) -> bool {
+        self.uncompressed_len == contents.len() as u64 && self.crc32 == ZIP_CRC.checksum(contents)
+    }
+}
+
+/// The files written by `ZipFile::extract_to`.
+#[derive(Clone, Debug)]
+pub struct ExtractSummary {
+    /// The paths of the extracted files, in the order they were written. Directories aren't included.
+    pub files: Vec<PathBuf>,
+    /// The total length of the extracted files.
+    pub bytes: u64
+}
+
+pub struct ZipFile<T: Read + Seek> {
+    file: T,
+    // Keyed by the raw file name, so that entries with names that are not valid UTF-8 (or that use backslashes as separators)
+    // are always saved back with exactly the same name.
+    entries: HashMap<Vec<u8>, CentDirHeader>,
+    // Maps the display form of each file name to the raw names used as keys in `entries`, in the order they were added.
+    // Different raw names can have the same display form (e.g. `a\b` and `a/b`), in which case every entry is kept,
+    // and lookups by display name use the last. No list is ever empty.
+    names: HashMap<String, Vec<Vec<u8>>>,
+    end_of_entries_offset: u64,
+    // Offset of the central directory when the archive was opened, which is where any APK signing block ends.
+    cent_dir_offset: u64,
+    // Offset of the EOCD when the archive was opened, or 0 for a new archive.
+    eocd_offset: u64,
+    compression_level: CompressionLevel,
+    // Compression levels for particular (display) file names, used instead of `compression_level`.
+    level_overrides: HashMap<String, CompressionLevel>
+}
+
+impl<T: Read + Seek> ZipFile<T> {
+    /// Opens a ZIP archive from a readable stream.
+    pub fn open(mut file: T) -> Result<Self> {
+        // The EOCD is at least 22 bytes long, and is followed by a comment of at most 65535 bytes,
+        // so only the end of the file needs to be searched.
+        let file_len = file.seek(SeekFrom::End(0))?;
+        if file_len < EOCD_MIN_LEN {
+            return Err(anyhow!("File is too short to be a ZIP archive"));
+        }
+        let tail_len = file_len.min(EOCD_MIN_LEN + u16::MAX as u64);
+        let tail_offset = file_len - tail_len;
+        let mut tail = vec![0u8; tail_len as usize];
+        file.seek(SeekFrom::Start(tail_offset))?;
+        file.read_exact(&mut tail)?;
+
+        // Search backwards, since the EOCD is almost always right at the end.
+        let eocd_pos = (0..=tail.len() - EOCD_MIN_LEN as usize).rev()
+            .find(|&pos| tail[pos..pos + 4] == EndOfCentDir::HEADER.to_le_bytes())
+            .ok_or(anyhow!("No EOCD found in APK"))?;
+        let eocd_offset = tail_offset + eocd_pos as u64;
+        file.seek(SeekFrom::Start(eocd_offset))?;
+
+        let eocd: EndOfCentDir = EndOfCentDir::read(&mut file).context("Invalid EOCD")?;
+        file.seek(SeekFrom::Start(eocd.cent_dir_offset))?;
+
+        // Read the central directory file headers
+        let mut entries = HashMap::new();
+        let mut names = HashMap::new();
+        // The local header offset and compressed length of the last entry
+        let mut last_entry = (0, 0);
+
+        for _ in 0..eocd.cent_dir_records {
+            let cd_record = CentDirHeader::read(&mut file).context("Invalid CD file header")?;
+            last_entry = last_entry.max((cd_record.local_header_offset, cd_record.compressed_len));
+
+            // Like Android, the last entry with a given raw name is used, so any earlier entries with exactly that name are dropped
+            // and won't be saved. Entries with different raw names are always kept, even if their display names are the same.
+            let display = cd_record.file_name.display().to_string();
+            let raw = cd_record.file_name.raw().to_vec();
+            let raw_names: &mut Vec<Vec<u8>> = names.entry(display.clone()).or_default();
+            if entries.contains_key(&raw) {
+                warn!("Archive contains more than one entry named {display}, so only the last will be kept");
+                raw_names.retain(|existing| *existing != raw);
+            }   else if !raw_names.is_empty() {
+                warn!("Archive contains entries with different names that are both shown as {display}. Both will be kept, but the last is used");
+            }
+            raw_names.push(raw.clone());
+            entries.insert(raw, cd_record);
+        }
+        
+        // Read the last LFH to figure out the location of the first byte after the last entry.
+        // We could just use the central directory offset here, however this will leave the original signature intact,
+        // ... which may not cause any problems but is a waste of space.
+        // The length is taken from the CD, since the LFH may give zero if a data descriptor was used, or omit it if it uses ZIP64.
+        file.seek(SeekFrom::Start(last_entry.0))?;
+        let _ = LocalFileHeader::read(&mut file)?;
+        // Entries written later would start after this, so lengths that go past the central directory can't be trusted.
+        let end_of_entries_offset = file.stream_position()?.checked_add(last_entry.1)
+            .filter(|end| *end <= eocd.cent_dir_offset)
+            .ok_or(anyhow!("Last entry has length {}, which goes past the central directory", last_entry.1))?;
+
+        Ok(Self {
+            end_of_entries_offset,
+            cent_dir_offset: eocd.cent_dir_offset,
+            eocd_offset,
+            compression_level: CompressionLevel::default(),
+            level_overrides: HashMap::new(),
+            file,
+            entries,
+            names
+        })
+    }
+
+    /// Gets the DER encoded certificates of the first signer in the V2 signature of the archive, as it was when opened.
+    /// Gives an empty list if the archive has no V2 signature.
+    pub fn get_v2_signer_certs(&mut self) -> Result<Vec<Vec<u8>>> {
+        signing::read_v2_signer_certs(&mut self.file, self.cent_dir_offset)
+    }
+
+    /// Verifies the V2 signature of the archive as it was when opened, which reads the whole archive to check its digest.
+    /// This should be called before any entries are written or deleted.
+    pub fn verify_v2_signature(&mut self) -> Result<V2Verification> {
+        signing::verify_v2_signature(&mut self.file, self.cent_dir_offset, self.eocd_offset)
+    }
+
+    /// Reads the contents of the file with the given name from the ZIP.
+    /// This loads the whole file into memory, so `open_entry` or `read_file_to` should be used for files that may be large.
+    pub fn read_file(&mut self, name: &str) -> Result<Vec<u8>> {
+        let mut cursor = Cursor::new(vec![]);
+
+        self.read_file_to(name, &mut cursor)?;
+        Ok(cursor.into_inner())
+    }
+
+    /// Extracts a file from the ZIP to a particular path.
+    pub fn extract_file_to(&mut self, name: &str, to: impl AsRef<Path>) -> Result<()> {
+        let mut handle = std::fs::OpenOptions::new()
+            .truncate(true)
+            .create(true)
+            .write(true)
+            .open(to)
+            .context("Failed to create extracted file at")?;
+
+        self.read_file_to(name, &mut handle)?;
+        Ok(())
+    }
+
+    /// Writes the contents of the file with the given name to `write_to`, as they are decompressed. Gives the number of bytes written.
+    /// Fails with a `ZipError` if the contents don't match the size or CRC-32 given in the central directory.
+    pub fn read_file_to(&mut self, name: &str, write_to: &mut impl Write) -> Result<u64> {
+        let mut reader = self.open_entry(name)?;
+        std::io::copy(&mut reader, write_to).map_err(|err| read_error(err, name))
+    }
+
+    /// Extracts every entry with a (display) name for which `filter` returns true to the same path within `dir`, creating any
+    /// subdirectories needed and replacing existing files. Contents are streamed to disk and checked against their CRC-32.
+    /// `progress` is called with the total number of bytes extracted so far, as with `copy_stream_progress`.
+    ///
+    /// Fails before extracting anything if a matching entry has an absolute name or one containing `..`,
+    /// so that an archive can't write outside of `dir`.
+    pub fn extract_to(&mut self,
+        dir: &Path,
+        filter: impl Fn(&str) -> bool,
+        progress: &mut impl FnMut(usize)) -> Result<ExtractSummary> {
+        let mut to_extract: Vec<(&CentDirHeader, PathBuf)> = Vec::new();
+        for header in self.entries.values().filter(|header| filter(header.file_name.display())) {
+            let name = header.file_name.display();
+            let name_path = Path::new(name);
+            if !name_path.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+                return Err(anyhow!("Entry {name:?} would be extracted outside of the destination directory"));
+            }
+            to_extract.push((header, dir.join(name_path)));
+        }
+        // Extract in the order of the archive to avoid seeking back and forth
+        to_extract.sort_by_key(|(header, _)| header.local_header_offset);
+        let to_extract: Vec<(String, PathBuf)> = to_extract.into_iter()
+            .map(|(header, path)| (header.file_name.display().to_string(), path))
+            .collect();
+
+        let mut summary = ExtractSummary {
+            files: Vec::new(),
+            bytes: 0
+        };
+        let mut buffer = vec![0u8; 64 * 1024];
+        for (name, path) in to_extract {
+            // Directories are given as entries with names ending in `/`
+            if name.ends_with('/') {
+                std::fs::create_dir_all(&path).with_context(|| format!("Failed to create directory {path:?}"))?;
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).with_context(|| format!("Failed to create directory {parent:?}"))?;
+            }
+
+            let mut handle = File::create(&path).with_context(|| format!("Failed to create extracted file at {path:?}"))?;
+            let mut reader = self.open_entry(&name)?;
+            loop {
+                let bytes_read = reader.read(&mut buffer).map_err(|err| read_error(err, &name))?;
+                if bytes_read == 0 {
+                    break;
+                }
+
+                handle.write_all(&buffer[..bytes_read]).with_context(|| format!("Failed to write {path:?}"))?;
+                summary.bytes += bytes_read as u64;
+                progress(summary.bytes as usize);
+            }
+            summary.files.push(path);
+        }
+
+        Ok(summary)
+    }
+
+    /// Opens the file with the given name for reading, giving a reader that decompresses its contents as they are read.
+    /// The reader borrows the archive, so only one entry can be read at a time: to read entries concurrently, open the archive
+    /// once for each reader.
+    pub fn open_entry(&mut self, name: &str) -> Result<EntryReader<'_, T>> {
+        let cd_header = match self.get_header(name) {
+            Some(header) => header.clone(),
+            None => return Err(anyhow!("File with name {name} did not exist"))
+        };
+
+        self.file.seek(SeekFrom::Start(cd_header.local_header_offset))?;
+        let _ = LocalFileHeader::read(&mut self.file).context("Invalid local file header")?;
+
+        let compressed_contents = (&mut self.file).take(cd_header.compressed_len);
+        let data = match cd_header.compression_method {
+            FileCompression::Deflate => EntryData::Deflate(deflate::Decoder::new(compressed_contents)),
+            FileCompression::Store => EntryData::Store(compressed_contents),
+            FileCompression::Unsupported(method) => return Err(anyhow!("Compression method `{method}` not supported for reading"))
+        };
+
+        Ok(EntryReader {
+            name: name.to_string(),
+            data,
+            expected_len: cd_header.uncompressed_len,
+            remaining: cd_header.uncompressed_len,
+            crc: ZIP_CRC.digest(),
+            expected_crc: cd_header.crc32,
+            verified: false
+        })
+    }
+
+    // Gets the central directory header of the last entry with (display) name `name`.
+    fn get_header(&self, name: &str) -> Option<&CentDirHeader> {
+        self.names.get(name)
+            .and_then(|raw_names| raw_names.last())
+            .and_then(|raw| self.entries.get(raw))
+    }
+
+    /// Returns an iterator over the (display) names of the entries within the ZIP file.
+    /// A name is given once for each entry with it, if entries with different raw names have the same display name.
+    pub fn iter_entry_names(&self) -> impl Iterator<Item = &str> {
+        self.entries.values().map(|header| header.file_name.display())
+    }
+
+    /// Returns an iterator over the metadata of the entries within the ZIP file, in no particular order.
+    pub fn iter_entries(&self) -> impl Iterator<Item = EntryInfo> + '_ {
+        self.entries.values().map(EntryInfo::from_header)
+    }
+
+    /// Gets the metadata of the entry with (display) name `name`, or None if there is no such entry.
+    pub fn get_entry(&self, name: &str) -> Option<EntryInfo> {
+        self.get_header(name).map(EntryInfo::from_header)
+    }
+
+    /// Returns an iterator over the names of the entries within the ZIP file, including both the raw and display forms.
+    pub fn iter_file_names(&self) -> impl Iterator<Item = &FileName> {
+        self.entries.values().map(|header| &header.file_name)
+    }
+
+    /// Returns the CRC-32 of the uncompressed contents of the file with (display) name `name`, or None if there is no such file.
+    pub fn get_crc32(&self, name: &str) -> Option<u32> {
+        self.get_header(name).map(|header| header.crc32)
+    }
+
+    /// Returns true if and only if a file exists with (display) name `name`
+    pub fn contains_file(&self, name: &str) -> bool {
+        self.names.contains_key(name)
+    }
+
+    /// Gets the layout of the archive from the central directory alone. This should be called before any entries are written or deleted.
+    pub fn get_layout(&mut self) -> Result<ArchiveLayout> {
+        let total_len = self.file.seek(SeekFrom::End(0))?;
+        let mut entries: Vec<EntryLayout> = self.entries.values()
+            .map(|header| EntryLayout {
+                name: header.file_name.display().to_string(),
+                crc32: header.crc32,
+                compressed_len: header.compressed_len,
+                uncompressed_len: header.uncompressed_len,
+                compression_method: header.compression_method,
+                local_header_offset: header.local_header_offset,
+                header_len: LOCAL_HEADER_MIN_LEN + header.file_name.raw().len() as u64
+            })
+            .collect();
+        entries.sort_by_key(|entry| entry.local_header_offset);
+
+        Ok(ArchiveLayout {
+            entries,
+            total_len,
+            signing_block_len: self.cent_dir_offset.saturating_sub(self.end_of_entries_offset),
+            cent_dir_len: total_len.saturating_sub(self.cent_dir_offset)
+        })
+    }
+}
+
+// Converts an error given while reading the entry with the given name. Any `ZipError` is taken out of the IO error,
+// since it wouldn't otherwise be part of the error's chain.
+fn read_error(err: std::io::Error, name: &str) -> anyhow::Error {
+    let err = match err.downcast::<ZipError>() {
+        Ok(zip_err) => anyhow::Error::from(zip_err),
+        Err(err) => err.into()
+    };
+    err.context(format!("Failed to read {name}"))
+}
+
+/// Given when the contents of an entry don't match its central directory header, which means that the archive is corrupt.
+#[derive(Debug)]
+pub enum ZipError {
+    CrcMismatch {
+        entry: String,
+        expected: u32,
+        actual: u32
+    },
+    /// The decompressed contents were longer or shorter than the length given in the header.
+    LengthMismatch {
+        entry: String,
+        expected: u64
+    }
+}
+
+impl Display for ZipError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CrcMismatch { entry, expected, actual } =>
+                write!(f, "CRC-32 of {entry} was {actual:08x}, but its header gave {expected:08x}"),
+            Self::LengthMismatch { entry, expected } =>
+                write!(f, "Decompressed size of {entry} did not match the size given in its header ({expected} bytes)")
+        }
+    }
+}
+
+impl std::error::Error for ZipError {}
+
+/// Reads the contents of an entry within a `ZipFile`, decompressing them as they are read. Given by `ZipFile::open_entry`.
+/// Once every byte has been read, the contents are checked against the size and CRC-32 in the central directory,
+/// and the final read fails if they don't match, with an error of kind `InvalidData` containing a `ZipError`.
+pub struct EntryReader<'a, T: Read> {
+    // The display name of the entry, for errors.
+    name: String,
+    data: EntryData<'a, T>,
+    expected_len: u64,
+    // The number of uncompressed bytes not yet read.
+    remaining: u64,
+    crc: crc::Digest<'static, u32>,
+    expected_crc: u32,
+    verified: bool
+}
+
+enum EntryData<'a, T: Read> {
+    Store(Take<&'a mut T>),
+    Deflate(deflate::Decoder<Take<&'a mut T>>)
+}
+
+impl<T: Read> EntryReader<'_, T> {
+    // Checks that the entry has no more data than given in its header, and that the CRC-32 of its contents matches.
+    fn verify(&mut self) -> std::io::Result<()> {
+        self.verified = true;
+        // Decompress no more than the size given in the header, so that a malformed entry can't expand without limit.
+        if let EntryData::Deflate(decoder) = &mut self.data {
+            if decoder.read(&mut [0u8])? != 0 {
+                return Err(self.length_mismatch());
+            }
+        }
+
+        let actual = self.crc.clone().finalize();
+        if actual != self.expected_crc {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, ZipError::CrcMismatch {
+                entry: self.name.clone(),
+                expected: self.expected_crc,
+                actual
+            }));
+        }
+        Ok(())
+    }
+
+    fn length_mismatch(&self) -> std::io::Error {
+        std::io::Error::new(ErrorKind::InvalidData, ZipError::LengthMismatch {
+            entry: self.name.clone(),
+            expected: self.expected_len
+        })
+    }
+}
+
+impl<T: Read> Read for EntryReader<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.remaining == 0 {
+            if !self.verified {
+                self.verify()?;
+            }
+            return Ok(0);
+        }
+
+        let max_len = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let buf = &mut buf[0..max_len];
+        let bytes_read = match &mut self.data {
+            EntryData::Store(data) => data.read(buf)?,
+            EntryData::Deflate(decoder) => decoder.read(buf)?
+        };
+        if bytes_read == 0 && !buf.is_empty() {
+            return Err(self.length_mismatch());
+        }
+
+        self.crc.update(&buf[0..bytes_read]);
+        self.remaining -= bytes_read as u64;
+        if self.remaining == 0 {
+            self.verify()?;
+        }
+        Ok(bytes_read)
+    }
+}
+
+// Gives the largest alignment (of those used by zipalign and `write_file`) that the data of a STORE entry at `data_offset` has.
+fn get_alignment(data_offset: u64) -> u64 {
+    [NATIVE_LIB_ALIGNMENT, 4096, STORE_ALIGNMENT].into_iter()
+        .find(|alignment| data_offset.is_multiple_of(*alignment))
+        .unwrap_or(1)
+}
+
+// Copies the range `run` of `source`, if any, to the current position of `to`.
+fn copy_run(source: &mut (impl Read + Seek), to: &mut File, run: Option<(u64, u64)>) -> Result<()> {
+    if let Some((start, end)) = run {
+        source.seek(SeekFrom::Start(start))?;
+        let copied = std::io::copy(&mut source.take(end - start), to).context("Failed to copy entries")?;
+        if copied != end - start {
+            return Err(anyhow!("Archive ended {} bytes before the end of its entries", end - start - copied));
+        }
+    }
+
+    Ok(())
+}
+
+// Copies the contents of `from` to `to`, calculating the ZIP CRC-32 of the copied data.
+fn copy_to_with_crc(from: &mut impl Read, to: &mut impl Write) -> Result<u32> {
+    const BUFFER_SIZE: usize = 4096;
+    let mut buffer = vec![0; BUFFER_SIZE];
+
+    let mut crc = ZIP_CRC.digest();
+    loop {
+        let bytes_read = from.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break Ok(crc.finalize());
+        }
+
+        crc.update(&buffer[0..bytes_read]);
+        to.write_all(&buffer[0..bytes_read])?;
+    }
+}
+
+impl ZipFile<File> {
+    /// Creates a new, empty ZIP archive that will be written to `file`.
+    /// Any existing content of `file` is ignored, and will be overwritten once entries are written and the archive is saved.
+    pub fn create(file: File) -> Self {
+        Self {
+            file,
+            entries: HashMap::new(),
+            names: HashMap::new(),
+            end_of_entries_offset: 0,
+            cent_dir_offset: 0,
+            eocd_offset: 0,
+            compression_level: CompressionLevel::default(),
+            level_overrides: HashMap::new()
+        }
+    }
+
+    /// Sets the level that files written with `FileCompression::Deflate` are compressed at, other than those with their own level.
+    /// Entries copied from another archive are never recompressed, so keep the level they were compressed at.
+    pub fn set_compression_level(&mut self, level: CompressionLevel) {
+        self.compression_level = level;
+    }
+
+    /// Sets the level that the file with (display) name `name` is compressed at if it is written with `FileCompression::Deflate`.
+    pub fn set_entry_compression_level(&mut self, name: &str, level: CompressionLevel) {
+        self.level_overrides.insert(name.to_string(), level);
+    }
+
+    /// Copies all entries from `source` into this archive without decompressing them, other than those with (display) names in `skip`.
+    /// Entries are copied byte for byte, local header included, and runs of entries that are next to each other in `source` are copied at once,
+    /// so only the central directory records their new offsets. STORE entries keep the alignment they had within the source archive,
+    /// since uncompressed `.so` files and `resources.arsc` must remain aligned in order for Android to load them: if moving an entry
+    /// would misalign it, or its local header doesn't match its central directory header (e.g. due to a data descriptor),
+    /// its local header is rewritten instead.
+    pub fn copy_entries_from<S: Read + Seek>(&mut self, source: &mut ZipFile<S>, skip: &[&str]) -> Result<()> {
+        let mut to_copy: Vec<&CentDirHeader> = source.entries.values()
+            .filter(|header| !skip.contains(&header.file_name.display()))
+            .collect();
+        // Copy in the order of the source archive to avoid seeking back and forth
+        to_copy.sort_by_key(|header| header.local_header_offset);
+
+        self.file.seek(SeekFrom::Start(self.end_of_entries_offset))?;
+        // The range of `source` that will next be copied as is, which grows while the entries being copied are next to each other.
+        let mut run: Option<(u64, u64)> = None;
+        for source_header in to_copy {
+            let name = source_header.file_name.display();
+            source.file.seek(SeekFrom::Start(source_header.local_header_offset))?;
+            let source_lfh = LocalFileHeader::read(&mut source.file)
+                .with_context(|| format!("Invalid local file header for {name}"))?;
+            let source_data_offset = source.file.stream_position()?;
+            let source_end = source_data_offset + source_header.compressed_len;
+
+            let alignment = match source_header.compression_method {
+                FileCompression::Store => get_alignment(source_data_offset),
+                _ => 1
+            };
+            let lfh_offset = self.file.stream_position()? + run.map(|(start, end)| end - start).unwrap_or(0);
+            let keeps_alignment = (lfh_offset + source_data_offset - source_header.local_header_offset).is_multiple_of(alignment);
+            let matches_cent_dir = source_lfh.flags & DATA_DESCRIPTOR_FLAG == 0
+                && source_lfh.crc32 == source_header.crc32
+                && source_lfh.compressed_len == source_header.compressed_len
+                && source_lfh.uncompressed_len == source_header.uncompressed_len;
+
+            let mut central_dir_header = source_header.clone();
+            central_dir_header.local_header_offset = lfh_offset;
+            if keeps_alignment && matches_cent_dir {
+                run = match run {
+                    Some((start, end)) if end == source_header.local_header_offset => Some((start, source_end)),
+                    _ => {
+                        copy_run(&mut source.file, &mut self.file, run)?;
+                        Some((source_header.local_header_offset, source_end))
+                    }
+                };
+            }   else {
+                copy_run(&mut source.file, &mut self.file, run.take())?;
+
+                // The sizes/CRC in the LFH may be zero if a data descriptor was used, so take them from the CD instead.
+                // The data descriptor is not copied, so the flag indicating its presence is cleared.
+                // A ZIP64 field is only written if the lengths need it.
+                let mut local_header = LocalFileHeader {
+                    flags: source_lfh.flags & !DATA_DESCRIPTOR_FLAG,
+                    crc32: source_header.crc32,
+                    compressed_len: source_header.compressed_len,
+                    uncompressed_len: source_header.uncompressed_len,
+                    zip64: false,
+                    ..source_lfh
+                };
+
+                // Pad the extra field with zeroes (as zipalign does) until the data is aligned
+                let data_offset = lfh_offset + local_header.header_len();
+                let padding = (alignment - data_offset % alignment) % alignment;
+                local_header.extra_field.resize(local_header.extra_field.len() + padding as usize, 0);
+
+                local_header.write(&mut self.file).context("Failed to write local file header")?;
+                source.file.seek(SeekFrom::Start(source_data_offset))?;
+                std::io::copy(&mut (&mut source.file).take(source_header.compressed_len), &mut self.file)
+                    .with_context(|| format!("Failed to copy data for {name}"))?;
+                central_dir_header.flags &= !DATA_DESCRIPTOR_FLAG;
+            }
+
+            // Replace any entry with exactly the same raw name that was written before copying, rather than saving both.
+            // Entries which only share a display name are all copied.
+            self.insert_entry(central_dir_header);
+        }
+        copy_run(&mut source.file, &mut self.file, run)?;
+
+        self.end_of_entries_offset = self.file.stream_position()?;
+        Ok(())
+    }
+
+    /// Writes a file with the given name and contents, replacing any existing file with that name.
+    /// The data of STORE entries is aligned to 4 bytes, or to 16KB for native libraries (`.so` files).
+    pub fn write_file(&mut self,
+        name: &str,
+        contents: &mut (impl Read + Seek),
+        compression_method: FileCompression) -> Result<()> {
+        self.file.seek(SeekFrom::Start(self.end_of_entries_offset))?;
+        let lfh_offset = self.file.stream_position()?;
+
+        // Names are always written as UTF-8, so must be flagged as such unless they are ASCII (which is the same in code page 437).
+        // The name is normalised once, and that form is both written and used to replace existing entries.
+        let file_name = FileName::from(FileName::from(name).display());
+        let name = file_name.display().to_string();
+        let uncompressed_len = contents.seek(SeekFrom::End(0))?;
+        let mut local_header = LocalFileHeader {
+            version_needed: VERSION_NEEDED_TO_EXTRACT,
+            flags: file_name.encoding_flags(),
+            compression_method,
+            last_modified: 0, // TODO: write correct value
+            crc32: 0,
+            compressed_len: 0,
+            uncompressed_len,
+            file_name: file_name.raw().to_vec(),
+            extra_field: Vec::new(),
+            // Space for the LFH is reserved before the data is compressed, so it must include a ZIP64 field if the compressed data
+            // could need one. Deflate can make incompressible data slightly larger, so a margin is left.
+            zip64: needs_zip64(uncompressed_len + uncompressed_len / 1024 + 1024)
+        };
+        if let FileCompression::Store = compression_method {
+            // Pad the extra field with zeroes (as zipalign does) until the data is aligned
+            let alignment = if name.ends_with(".so") { NATIVE_LIB_ALIGNMENT } else { STORE_ALIGNMENT };
+            let padding = (alignment - (lfh_offset + local_header.header_len()) % alignment) % alignment;
+            local_header.extra_field.resize(padding as usize, 0);
+        }
+        let header_len = local_header.header_len();
+        self.file.seek(SeekFrom::Current(header_len as i64))?; // Skip the location of the new LFH for now, since we don't know the data size yet.
+        
+        let data_start = self.file.stream_position()?;
+
+        contents.seek(SeekFrom::Start(0))?;
+        local_header.crc32 = match compression_method {
+            FileCompression::Deflate => {
+                let level = self.level_overrides.get(&name).copied().unwrap_or(self.compression_level);
+                let mut encoder = DeflateEncoder::new(&mut self.file, Compression::new(level.level() as u32));
+                let crc = copy_to_with_crc(contents, &mut encoder).context("Failed to write/compress file data")?;
+                encoder.finish()?;
+
+                crc
+            },
+            FileCompression::Store => copy_to_with_crc(contents, &mut self.file)
+                .context("Failed to write file data")?,
+            FileCompression::Unsupported(method) => return Err(anyhow!("Compression method `{method}` is not supported"))
+        };
+
+        // Update the offset for the next file to be written
+        self.end_of_entries_offset = self.file.stream_position()?;
+        local_header.compressed_len = self.end_of_entries_offset - data_start;
+        if local_header.header_len() != header_len {
+            return Err(anyhow!("Compressed data of {name} was too large for the space reserved for its local header"));
+        }
+
+        // Write the local header with the known length/CRC
+        self.file.seek(SeekFrom::Start(lfh_offset))?;
+        local_header.write(&mut self.file).context("Failed to write local file header")?;
+
+
+        let central_dir_header = CentDirHeader {
+            os_version_made_by: 0, // 0 seems to be accepted as a valid OS, TODO: give actual value?
+            version_needed: VERSION_NEEDED_TO_EXTRACT,
+            flags: local_header.flags,
+            compression_method,
+            last_modified: 0, // TODO: write correct value
+            crc32: local_header.crc32,
+            compressed_len: local_header.compressed_len,
+            uncompressed_len,
+            file_name,
+            extra_field: Vec::new(),
+            internal_attrs: 0,
+            external_attrs: 0,
+            local_header_offset: lfh_offset,
+            comment: Vec::new(),
+        };
+
+        // Replace every existing entry with the same display name, so that the name only refers to this entry.
+        self.delete_file(&name);
+        self.insert_entry(central_dir_header);
+        Ok(())
+    }
+
+    // Adds the given central directory header, replacing any entry with exactly the same raw name.
+    fn insert_entry(&mut self, header: CentDirHeader) {
+        let raw = header.file_name.raw().to_vec();
+        let raw_names = self.names.entry(header.file_name.display().to_string()).or_default();
+        raw_names.retain(|existing| *existing != raw);
+        raw_names.push(raw.clone());
+        self.entries.insert(raw, header);
+    }
+
+    // Deletes the file with the given (display) name from the ZIP, if it existed.
+    // This removes every entry with the display name, including those whose raw names differ.
+    pub fn delete_file(&mut self, name: &str) -> bool {
+        match self.names.remove(name) {
+            Some(raw_names) => {
+                for raw in raw_names {
+                    self.entries.remove(&raw);
+                }
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Deletes every file with a (display) name for which `predicate` returns true, giving the names of the deleted files in sorted order.
+    /// Gives an empty list if no files matched.
+    pub fn delete_matching(&mut self, predicate: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut deleted: Vec<String> = self.names.keys()
+            .filter(|name| predicate(name))
+            .cloned()
+            .collect();
+        deleted.sort();
+        for name in &deleted {
+            self.delete_file(name);
+        }
+
+        deleted
+    }
+
+    /// Deletes every file with a (display) name starting with `prefix`, e.g. `lib/armeabi-v7a/`, giving the names of the deleted files in sorted order.
+    pub fn delete_prefix(&mut self, prefix: &str) -> Vec<String> {
+        self.delete_matching(|name| name.starts_with(prefix))
+    }
+
+    // Serializes the central directory.
+    // Entries are listed in the order their data appears in the archive, so that the same entries always give the same bytes.
+    fn get_cent_dir_bytes(&self) -> Result<Vec<u8>> {
+        let mut headers: Vec<&CentDirHeader> = self.entries.values().collect();
+        headers.sort_by_key(|header| header.local_header_offset);
+
+        let mut cd_bytes = Vec::new();
+        let mut cd_cursor = Cursor::new(&mut cd_bytes);
+        for cd_header in headers {
+            cd_header.write(&mut cd_cursor)?;
+        }
+
+        Ok(cd_bytes)
+    }
+
+    /// Gets the SHA-256 hash of the entries and central directory that will be saved, i.e. the archive excluding any signing block.
+    /// Two archives with the same digest have identical contents, and so will be identical once signed with the same key.
+    pub fn get_content_digest(&mut self) -> Result<[u8; 32]> {
+        let mut hasher = Sha256::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        std::io::copy(&mut (&mut self.file).take(self.end_of_entries_offset), &mut hasher)
+            .context("Failed to read archive entries")?;
+        hasher.update(self.get_cent_dir_bytes()?);
+
+        Ok(hasher.finalize().into())
+    }
+
+    /// Saves the ZIP central directory, while signing the APK with the signature schemes enabled in `config`.
+    /// If V1 signing is enabled, any existing V1 signature is replaced, and every entry is decompressed to sign it.
+    /// Gives the content digest (see `get_content_digest`) of the archive before it was signed, which is calculated while the
+    /// V2/V3 signatures are, so that the archive is only read once.
+    pub fn save_and_sign(&mut self, priv_key: &RsaPrivateKey, cert: &Certificate, config: SigningConfig) -> Result<[u8; 32]> {
+        // The content digest excludes the V1 signature files, so is calculated separately if they will be added.
+        let v2_or_v3 = config.v2 || config.v3;
+        let early_content_digest = if config.v1 || !v2_or_v3 {
+            Some(self.get_content_digest()?)
+        }   else {
+            None
+        };
+
+        // The V1 signature files are entries, so must be written before the V2/V3 signatures, which sign every entry.
+        if config.v1 {
+            self.write_v1_signature(priv_key, cert, config).context("Failed to add V1 signature")?;
+        }
+        if let (false, Some(content_digest)) = (v2_or_v3, early_content_digest) {
+            self.write_cent_dir()?;
+            return Ok(content_digest);
+        }
+
+        let cd_bytes = self.get_cent_dir_bytes()?;
+
+        let mut eocd = EndOfCentDir {
+            cent_dir_records: self.entries.len() as u64,
+            cent_dir_size: cd_bytes.len() as u64,
+            cent_dir_offset: 0, // Can be set after we know the length of the signing block
+            comment: Vec::new(),
+            zip64: false
+        };
+        // The ZIP64 records are signed along with the EOCD, so whether they are needed must be decided before the exact length
+        // of the signing block is known.
+        eocd.zip64 = eocd.uses_zip64() || needs_zip64(self.end_of_entries_offset + SIGNING_BLOCK_MAX_LEN);
+        if eocd.zip64 {
+            warn!("APK is too large for a 32 bit ZIP file, so ZIP64 is used. Android may refuse to install it");
+        }
+
+        // Remove existing CD and EOCD
+        self.file.set_len(self.end_of_entries_offset)?;
+
+        // Add signature
+        let mut content_hasher = Sha256::new();
+        self.file.seek(SeekFrom::Start(self.end_of_entries_offset))?;
+        signing::write_signature(&mut self.file, priv_key, cert, &cd_bytes, eocd.clone(), config,
+            early_content_digest.is_none().then_some(&mut content_hasher))
+            .context("Failed to sign APK")?;
+
+        eocd.cent_dir_offset = self.file.stream_position()?;
+        if !eocd.zip64 && needs_zip64(eocd.cent_dir_offset) {
+            return Err(anyhow!("APK signing block was longer than expected"));
+        }
+        self.file.write_all(&cd_bytes)?;
+        eocd.write(&mut self.file)?;
+
+        Ok(early_content_digest.unwrap_or_else(|| content_hasher.finalize().into()))
+    }
+
+    // Adds a V1 signature of every other entry, replacing any existing V1 signature.
+    fn write_v1_signature(&mut self, priv_key: &RsaPrivateKey, cert: &Certificate, config: SigningConfig) -> Result<()> {
+        let old_signature_files: Vec<String> = self.names.keys()
+            .filter(|name| signing::is_v1_signature_file(name))
+            .cloned()
+            .collect();
+        for name in old_signature_files {
+            self.delete_file(&name);
+        }
+
+        // Sorted so that the same entries always give the same signature.
+        let mut names: Vec<String> = self.names.keys()
+            .filter(|name| !name.ends_with('/'))
+            .cloned()
+            .collect();
+        names.sort();
+
+        let mut entry_digests = Vec::with_capacity(names.len());
+        for name in names {
+            let mut hasher = Sha256::new();
+            self.read_file_to(&name, &mut hasher).with_context(|| format!("Failed to read {name} to sign it"))?;
+            entry_digests.push((name, hasher.finalize().into()));
+        }
+
+        for (name, contents) in signing::generate_v1_files(&entry_digests, cert, priv_key, config)? {
+            self.write_file(name, &mut Cursor::new(contents), FileCompression::Deflate)?;
+        }
+        Ok(())
+    }
+
+    /// Saves the ZIP central directory.
+    /// If this is not called, any newly written files or deleted files will not be respected in the final archive.
+    /// The CD is NOT automatically saved on drop.
+    /// The MBF agent always signs the APKs it saves, but this is used by `ApkPatcher` if no signing key is given.
+    pub fn save(mut self) -> Result<()> {
+        self.write_cent_dir()
+    }
+
+    // Writes the central directory and EOCD after the last entry, with no signing block.
+    fn write_cent_dir(&mut self) -> Result<()> {
+        // Remove existing CD and EOCD
+        self.file.set_len(self.end_of_entries_offset)?;
+
+        self.file.seek(SeekFrom::Start(self.end_of_entries_offset))?;
+
+        let cd_bytes = self.get_cent_dir_bytes().context("Failed to save central directory")?;
+        self.file.write_all(&cd_bytes)?;
+
+        let eocd = EndOfCentDir {
+            cent_dir_records: self.entries.len() as u64,
+            cent_dir_size: self.file.stream_position()? - self.end_of_entries_offset,
+            cent_dir_offset: self.end_of_entries_offset,
+            comment: Vec::new(),
+            zip64: false
+        };
+
+        eocd.write(&mut self.file).context("Failed to save end of central directory")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    // Gives a path in the temporary directory for a file used by a test, removing anything left there by a previous run.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mbf-zip-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    fn open_rw(path: &Path) -> File {
+        std::fs::OpenOptions::new().read(true).write(true).open(path).unwrap()
+    }
+
+    // Counts the number of times `needle` appears within `haystack`.
+    fn count_occurrences(haystack: &[u8], needle: &[u8]) -> usize {
+        haystack.windows(needle.len()).filter(|window| *window == needle).count()
+    }
+
+    // A CP437 name (0x82 is `é`), a name separated with backslashes, and a name flagged as UTF-8 that isn't valid UTF-8.
+    const CP437_NAME: &[u8] = b"caf\x82.txt";
+    const BACKSLASH_NAME: &[u8] = b"assets\\bin\\Data\\level0";
+    const INVALID_UTF8_NAME: &[u8] = b"bad\xff\xfe.txt";
+
+    // Writes an archive of STORE entries with the given raw names, flagging each name as UTF-8 if its flag is true.
+    // `write_file` only takes names as `&str`, so the headers are written directly to give names that aren't valid UTF-8.
+    fn write_archive_with_raw_names(path: &Path, entries: &[(&[u8], bool, &[u8])]) {
+        let mut file = File::create(path).unwrap();
+        let mut cent_dir = Vec::new();
+        for (raw_name, utf8, contents) in entries {
+            // Bit 11 flags the name as UTF-8
+            let flags = if *utf8 { 1 << 11 } else { 0 };
+            let local_header_offset = file.stream_position().unwrap();
+            let crc32 = ZIP_CRC.checksum(contents);
+            let len = contents.len() as u64;
+            LocalFileHeader {
+                version_needed: VERSION_NEEDED_TO_EXTRACT,
+                flags,
+                compression_method: FileCompression::Store,
+                last_modified: 0,
+                crc32,
+                compressed_len: len,
+                uncompressed_len: len,
+                file_name: raw_name.to_vec(),
+                extra_field: Vec::new(),
+                zip64: false
+            }.write(&mut file).unwrap();
+            file.write_all(contents).unwrap();
+
+            CentDirHeader {
+                os_version_made_by: 0,
+                version_needed: VERSION_NEEDED_TO_EXTRACT,
+                flags,
+                compression_method: FileCompression::Store,
+                last_modified: 0,
+                crc32,
+                compressed_len: len,
+                uncompressed_len: len,
+                internal_attrs: 0,
+                external_attrs: 0,
+                local_header_offset,
+                file_name: FileName::from_raw(raw_name.to_vec(), *utf8),
+                extra_field: Vec::new(),
+                comment: Vec::new()
+            }.write(&mut cent_dir).unwrap();
+        }
+
+        let cent_dir_offset = file.stream_position().unwrap();
+        file.write_all(&cent_dir).unwrap();
+        EndOfCentDir {
+            cent_dir_records: entries.len() as u64,
+            cent_dir_size: cent_dir.len() as u64,
+            cent_dir_offset,
+            comment: Vec::new(),
+            zip64: false
+        }.write(&mut file).unwrap();
+    }
+
+    fn write_odd_names_archive(path: &Path) {
+        write_archive_with_raw_names(path, &[
+            (CP437_NAME, false, b"cp437"),
+            (BACKSLASH_NAME, false, b"backslash"),
+            (INVALID_UTF8_NAME, true, b"invalid")
+        ]);
+    }
+
+    // Checks that the archive at `path` has the names written by `write_odd_names_archive` (plus `added.txt`) with exactly
+    // the same bytes, in both the local header and the central directory header of each entry.
+    fn assert_raw_names_preserved(path: &Path) {
+        let bytes = std::fs::read(path).unwrap();
+        let zip = ZipFile::open(Cursor::new(&bytes)).unwrap();
+        let mut raw_names: Vec<&[u8]> = zip.iter_file_names().map(FileName::raw).collect();
+        raw_names.sort();
+        let mut expected = vec![CP437_NAME, BACKSLASH_NAME, INVALID_UTF8_NAME, b"added.txt"];
+        expected.sort();
+        assert_eq!(raw_names, expected);
+
+        for name in [CP437_NAME, BACKSLASH_NAME, INVALID_UTF8_NAME] {
+            assert_eq!(count_occurrences(&bytes, name), 2, "{name:?} should be in one local and one central header");
+        }
+    }
+
+    #[test]
+    fn odd_names_have_normalised_display_forms() {
+        let path = temp_path("odd-names-display");
+        write_odd_names_archive(&path);
+        let zip = ZipFile::open(File::open(&path).unwrap()).unwrap();
+
+        let mut names: Vec<&str> = zip.iter_entry_names().collect();
+        names.sort();
+        assert_eq!(names, ["assets/bin/Data/level0", "bad\u{FFFD}\u{FFFD}.txt", "café.txt"]);
+
+        let non_utf8: Vec<&[u8]> = zip.iter_file_names()
+            .filter(|name| !name.is_utf8())
+            .map(FileName::raw)
+            .collect();
+        assert_eq!(non_utf8.len(), 2);
+        assert!(non_utf8.contains(&CP437_NAME) && non_utf8.contains(&INVALID_UTF8_NAME));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn odd_names_are_matched_by_display_form() {
+        let path = temp_path("odd-names-matching");
+        write_odd_names_archive(&path);
+        let mut zip = ZipFile::open(open_rw(&path)).unwrap();
+
+        assert!(zip.contains_file("assets/bin/Data/level0"));
+        assert!(!zip.contains_file("assets\\bin\\Data\\level0"));
+        assert_eq!(zip.read_file("café.txt").unwrap(), b"cp437");
+        assert_eq!(zip.get_entry("assets/bin/Data/level0").unwrap().uncompressed_len, 9);
+
+        assert_eq!(zip.delete_prefix("assets/"), ["assets/bin/Data/level0"]);
+        assert!(zip.delete_file("café.txt"));
+        assert_eq!(zip.iter_entry_names().collect::<Vec<_>>(), ["bad\u{FFFD}\u{FFFD}.txt"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn odd_names_are_unchanged_when_copied() {
+        let source_path = temp_path("odd-names-copy-source");
+        let dest_path = temp_path("odd-names-copy-dest");
+        write_odd_names_archive(&source_path);
+
+        let mut source = ZipFile::open(File::open(&source_path).unwrap()).unwrap();
+        let dest_file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&dest_path).unwrap();
+        let mut dest = ZipFile::create(dest_file);
+        dest.copy_entries_from(&mut source, &[]).unwrap();
+        dest.write_file("added.txt", &mut Cursor::new(b"added"), FileCompression::Deflate).unwrap();
+        dest.save().unwrap();
+
+        assert_raw_names_preserved(&dest_path);
+        std::fs::remove_file(&source_path).unwrap();
+        std::fs::remove_file(&dest_path).unwrap();
+    }
+
+    #[test]
+    fn odd_names_are_unchanged_when_patched_in_place() {
+        let path = temp_path("odd-names-in-place");
+        write_odd_names_archive(&path);
+
+        let mut zip = ZipFile::open(open_rw(&path)).unwrap();
+        zip.write_file("added.txt", &mut Cursor::new(b"added"), FileCompression::Deflate).unwrap();
+        zip.save().unwrap();
+
+        assert_raw_names_preserved(&path);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Writes a small archive with entries of each compression method, and gives its contents.
+    fn fuzz_seed_archive() -> Vec<u8> {
+        let path = temp_path("fuzz-seed.zip");
+        let mut zip = ZipFile::create(File::create(&path).unwrap());
+        zip.write_file("AndroidManifest.xml", &mut Cursor::new(vec![3u8; 300]), FileCompression::Deflate).unwrap();
+        zip.write_file("lib/arm64-v8a/libmain.so", &mut Cursor::new(b"\x7fELF".repeat(10)), FileCompression::Store).unwrap();
+        zip.write_file("assets/empty.txt", &mut Cursor::new(Vec::new()), FileCompression::Store).unwrap();
+        zip.save().unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        contents
+    }
+
+    // Opens `contents` as an archive and reads every entry, which must give an error rather than panicking if the archive is invalid.
+    fn open_and_read_all(contents: &[u8]) {
+        let mut zip = match ZipFile::open(Cursor::new(contents)) {
+            Ok(zip) => zip,
+            Err(_) => return
+        };
+
+        let names: Vec<String> = zip.iter_entry_names().map(str::to_string).collect();
+        for name in &names {
+            let _ = zip.read_file_to(name, &mut std::io::sink());
+        }
+        let _ = zip.get_layout();
+        let _ = zip.verify_v2_signature();
+    }
+
+    fn mutate(rng: &mut StdRng, contents: &mut Vec<u8>) {
+        for _ in 0..rng.gen_range(1..6) {
+            let pos = rng.gen_range(0..contents.len());
+            match rng.gen_range(0..4) {
+                0 => contents[pos] ^= 1 << rng.gen_range(0..8),
+                // Offsets and lengths in the headers are u16s, u32s or (for ZIP64) u64s, so extreme values are most likely to overflow.
+                1 => {
+                    let value: u64 = [0, u16::MAX as u64, u32::MAX as u64, u64::MAX, rng.gen()][rng.gen_range(0..5)];
+                    let len = [2, 4, 8][rng.gen_range(0..3)];
+                    let end = (pos + len).min(contents.len());
+                    contents[pos..end].copy_from_slice(&value.to_le_bytes()[..end - pos]);
+                },
+                2 => contents[pos] = rng.gen(),
+                _ => { contents.remove(pos); }
+            }
+        }
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic() {
+        let mut rng = StdRng::seed_from_u64(238);
+        for _ in 0..2000 {
+            let len = rng.gen_range(0..200);
+            let mut contents: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            // Most random files have no EOCD, so some are given one to get further.
+            if contents.len() >= 22 && rng.gen_bool(0.7) {
+                let eocd_pos = rng.gen_range(0..=contents.len() - 22);
+                contents[eocd_pos..eocd_pos + 4].copy_from_slice(&EndOfCentDir::HEADER.to_le_bytes());
+            }
+
+            open_and_read_all(&contents);
+        }
+    }
+
+    #[test]
+    fn mutated_archives_never_panic() {
+        let seed = fuzz_seed_archive();
+        open_and_read_all(&seed);
+
+        let mut rng = StdRng::seed_from_u64(2382);
+        for _ in 0..5000 {
+            let mut contents = seed.clone();
+            mutate(&mut rng, &mut contents);
+            open_and_read_all(&contents);
+        }
+    }
+
+    #[test]
+    fn seed_archive_reads_back() {
+        let mut zip = ZipFile::open(Cursor::new(fuzz_seed_archive())).unwrap();
+        assert_eq!(zip.read_file("AndroidManifest.xml").unwrap(), vec![3u8; 300]);
+        assert_eq!(zip.read_file("lib/arm64-v8a/libmain.so").unwrap(), b"\x7fELF".repeat(10));
+        assert!(zip.read_file("assets/empty.txt").unwrap().is_empty());
+    }
+
+    // Writes an archive with one STORE entry containing `contents`, but with the given lengths and offset in its CD header.
+    // Values that don't fit in 32 bits are given in a ZIP64 extra field.
+    fn archive_with_declared_lengths(contents: &[u8], compressed_len: u64, uncompressed_len: u64, local_header_offset: u64) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let header = LocalFileHeader {
+            version_needed: VERSION_NEEDED_TO_EXTRACT,
+            flags: 0,
+            compression_method: FileCompression::Store,
+            last_modified: 0,
+            crc32: ZIP_CRC.checksum(contents),
+            compressed_len: contents.len() as u64,
+            uncompressed_len: contents.len() as u64,
+            file_name: b"entry".to_vec(),
+            extra_field: Vec::new(),
+            zip64: false
+        };
+        header.write(&mut archive).unwrap();
+        archive.extend_from_slice(contents);
+
+        let mut cent_dir = Vec::new();
+        CentDirHeader {
+            os_version_made_by: 0,
+            version_needed: VERSION_NEEDED_TO_EXTRACT,
+            flags: 0,
+            compression_method: FileCompression::Store,
+            last_modified: 0,
+            crc32: header.crc32,
+            compressed_len,
+            uncompressed_len,
+            internal_attrs: 0,
+            external_attrs: 0,
+            local_header_offset,
+            file_name: FileName::from("entry"),
+            extra_field: Vec::new(),
+            comment: Vec::new()
+        }.write(&mut cent_dir).unwrap();
+
+        let cent_dir_offset = archive.len() as u64;
+        archive.extend_from_slice(&cent_dir);
+        EndOfCentDir {
+            cent_dir_records: 1,
+            cent_dir_size: cent_dir.len() as u64,
+            cent_dir_offset,
+            comment: Vec::new(),
+            zip64: false
+        }.write(&mut archive).unwrap();
+        archive
+    }
+
+    #[test]
+    fn huge_declared_lengths_and_offsets_never_panic() {
+        let contents = b"contents";
+        for (compressed_len, uncompressed_len, offset) in [
+            (u64::MAX, 8, 0),
+            (8, u64::MAX, 0),
+            (u64::MAX - 20, u64::MAX, 0),
+            (8, 8, u64::MAX),
+            (8, 8, u64::MAX - 10),
+            (u32::MAX as u64 + 1, 8, 0),
+            (8, 8, 1 << 40)
+        ] {
+            let archive = archive_with_declared_lengths(contents, compressed_len, uncompressed_len, offset);
+            open_and_read_all(&archive);
+        }
+
+        // The same archive with its real lengths can be read, so the failures above are due to the lengths alone.
+        let archive = archive_with_declared_lengths(contents, 8, 8, 0);
+        assert_eq!(ZipFile::open(Cursor::new(archive)).unwrap().read_file("entry").unwrap(), contents);
+    }
+
+    #[test]
+    fn zip64_cent_dir_header_round_trips() {
+        // Another extra field after the ZIP64 field is kept as it is.
+        let other_field = vec![0x55, 0x54, 0x01, 0x00, 0x07];
+        let header = CentDirHeader {
+            os_version_made_by: 0,
+            version_needed: VERSION_NEEDED_TO_EXTRACT,
+            flags: 0,
+            compression_method: FileCompression::Deflate,
+            last_modified: 0,
+            crc32: 0x12345678,
+            compressed_len: 0x1_2000_0000,
+            uncompressed_len: 0x1_8000_0000,
+            internal_attrs: 0,
+            external_attrs: 0,
+            local_header_offset: 0x2_0000_0000,
+            file_name: FileName::from("assets/big.bin"),
+            extra_field: other_field.clone(),
+            comment: Vec::new()
+        };
+
+        let mut bytes = Vec::new();
+        header.write(&mut bytes).unwrap();
+        // Each of the three values is replaced with the sentinel.
+        assert_eq!(bytes[20..28], [0xFF; 8]);
+        assert_eq!(bytes[42..46], [0xFF; 4]);
+        assert_eq!(u16::from_le_bytes([bytes[6], bytes[7]]), 45);
+
+        let read_back = CentDirHeader::read(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!((read_back.compressed_len, read_back.uncompressed_len, read_back.local_header_offset),
+            (0x1_2000_0000, 0x1_8000_0000, 0x2_0000_0000));
+        assert_eq!(read_back.extra_field, other_field);
+    }
+
+    #[test]
+    fn zip64_field_only_has_values_that_need_it() {
+        let header = CentDirHeader {
+            os_version_made_by: 0,
+            version_needed: VERSION_NEEDED_TO_EXTRACT,
+            flags: 0,
+            compression_method: FileCompression::Store,
+            last_modified: 0,
+            crc32: 0,
+            compressed_len: 100,
+            uncompressed_len: 100,
+            internal_attrs: 0,
+            external_attrs: 0,
+            local_header_offset: 0x1_0000_0000,
+            file_name: FileName::from("a"),
+            extra_field: Vec::new(),
+            comment: Vec::new()
+        };
+
+        let mut bytes = Vec::new();
+        header.write(&mut bytes).unwrap();
+        assert_eq!(u32::from_le_bytes(bytes[20..24].try_into().unwrap()), 100);
+        // The field has just the offset: ID 1, length 8.
+        assert_eq!(bytes[46 + 1..], [1, 0, 8, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(CentDirHeader::read(&mut Cursor::new(&bytes)).unwrap().local_header_offset, 0x1_0000_0000);
+
+        // With no large values, there is no ZIP64 field.
+        let mut small_bytes = Vec::new();
+        CentDirHeader { local_header_offset: 10, ..header }.write(&mut small_bytes).unwrap();
+        assert_eq!(small_bytes.len(), 46 + 1);
+    }
+
+    #[test]
+    fn zip64_local_header_gives_both_lengths() {
+        let header = LocalFileHeader {
+            version_needed: VERSION_NEEDED_TO_EXTRACT,
+            flags: 0,
+            compression_method: FileCompression::Store,
+            last_modified: 0,
+            crc32: 0,
+            compressed_len: 0x1_0000_0001,
+            uncompressed_len: 5,
+            file_name: b"big".to_vec(),
+            extra_field: vec![0; 3],
+            zip64: false
+        };
+
+        let mut bytes = Vec::new();
+        header.write(&mut bytes).unwrap();
+        assert_eq!(bytes.len() as u64, header.header_len());
+        assert_eq!(bytes[18..26], [0xFF; 8]);
+
+        let read_back = LocalFileHeader::read(&mut Cursor::new(&bytes)).unwrap();
+        assert!(read_back.zip64);
+        assert_eq!((read_back.compressed_len, read_back.uncompressed_len), (0x1_0000_0001, 5));
+        // The zipalign padding after the ZIP64 field is kept.
+        assert_eq!(read_back.extra_field, [0; 3]);
+        assert_eq!(read_back.header_len(), header.header_len());
+    }
+
+    // The length of the entry in `write_sparse_zip64_archive`, which is over 4GB.
+    const HUGE_LEN: u64 = 0x1_1000_0000;
+
+    // Writes an archive with one STORE entry, `huge.bin`, of `HUGE_LEN` zeroes, which is left as a hole in the file so that it
+    // takes no space on disk. Calculating its CRC-32 would mean hashing the whole entry, so it is given as 0 and the entry
+    // must never be read.
+    fn write_sparse_zip64_archive(path: &Path) {
+        let mut file = File::create(path).unwrap();
+        let local_header = LocalFileHeader {
+            version_needed: VERSION_NEEDED_TO_EXTRACT,
+            flags: 0,
+            compression_method: FileCompression::Store,
+            last_modified: 0,
+            crc32: 0,
+            compressed_len: HUGE_LEN,
+            uncompressed_len: HUGE_LEN,
+            file_name: b"huge.bin".to_vec(),
+            extra_field: Vec::new(),
+            zip64: false
+        };
+        local_header.write(&mut file).unwrap();
+        let cent_dir_offset = local_header.header_len() + HUGE_LEN;
+        file.seek(SeekFrom::Start(cent_dir_offset)).unwrap();
+
+        let mut cent_dir = Vec::new();
+        CentDirHeader {
+            os_version_made_by: 0,
+            version_needed: VERSION_NEEDED_TO_EXTRACT,
+            flags: 0,
+            compression_method: FileCompression::Store,
+            last_modified: 0,
+            crc32: 0,
+            compressed_len: HUGE_LEN,
+            uncompressed_len: HUGE_LEN,
+            internal_attrs: 0,
+            external_attrs: 0,
+            local_header_offset: 0,
+            file_name: FileName::from("huge.bin"),
+            extra_field: Vec::new(),
+            comment: Vec::new()
+        }.write(&mut cent_dir).unwrap();
+        file.write_all(&cent_dir).unwrap();
+        EndOfCentDir {
+            cent_dir_records: 1,
+            cent_dir_size: cent_dir.len() as u64,
+            cent_dir_offset,
+            comment: Vec::new(),
+            zip64: false
+        }.write(&mut file).unwrap();
+    }
+
+    #[test]
+    fn entries_after_4gb_round_trip() {
+        let path = temp_path("zip64-sparse.apk");
+        write_sparse_zip64_archive(&path);
+
+        let mut zip = ZipFile::open(open_rw(&path)).unwrap();
+        let huge = zip.get_entry("huge.bin").unwrap();
+        assert_eq!((huge.compressed_len, huge.uncompressed_len), (HUGE_LEN, HUGE_LEN));
+
+        // Both are written after the huge entry, so start after 4GB.
+        zip.write_file("after.txt", &mut Cursor::new(b"after the huge entry"), FileCompression::Deflate).unwrap();
+        zip.write_file("lib/arm64-v8a/libmain.so", &mut Cursor::new(b"\x7fELF".repeat(100)), FileCompression::Store).unwrap();
+        zip.save().unwrap();
+
+        let mut zip = ZipFile::open(File::open(&path).unwrap()).unwrap();
+        assert_eq!(zip.read_file("after.txt").unwrap(), b"after the huge entry");
+        assert_eq!(zip.read_file("lib/arm64-v8a/libmain.so").unwrap(), b"\x7fELF".repeat(100));
+        assert_eq!(zip.get_entry("huge.bin").unwrap().uncompressed_len, HUGE_LEN);
+
+        let layout = zip.get_layout().unwrap();
+        let offsets: Vec<(&str, u64)> = layout.entries.iter().map(|entry| (entry.name.as_str(), entry.local_header_offset)).collect();
+        assert_eq!(offsets[0], ("huge.bin", 0));
+        assert!(offsets[1..].iter().all(|(_, offset)| needs_zip64(*offset)), "{offsets:?}");
+        // The library is still page aligned, which needs the real offset rather than the truncated 32 bit one.
+        let lib = &layout.entries[2];
+        let mut file = File::open(&path).unwrap();
+        file.seek(SeekFrom::Start(lib.local_header_offset)).unwrap();
+        let lib_header_len = LocalFileHeader::read(&mut file).unwrap().header_len();
+        assert_eq!((lib.local_header_offset + lib_header_len) % NATIVE_LIB_ALIGNMENT, 0);
+
+        // The EOCD only gives sentinels, and the real values are in the ZIP64 EOCD record.
+        let file_len = file.seek(SeekFrom::End(0)).unwrap();
+        let mut tail = vec![0u8; (EOCD_MIN_LEN + 20 + 56) as usize];
+        file.seek(SeekFrom::Start(file_len - tail.len() as u64)).unwrap();
+        file.read_exact(&mut tail).unwrap();
+        assert_eq!(tail[0..4], 0x06064b50u32.to_le_bytes());
+        assert_eq!(tail[56..60], 0x07064b50u32.to_le_bytes());
+        assert_eq!(tail[76 + 16..76 + 20], [0xFF; 4]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn entries_copied_from_a_zip64_archive_do_not_need_zip64() {
+        let source_path = temp_path("zip64-copy-source.apk");
+        let dest_path = temp_path("zip64-copy-dest.apk");
+        write_sparse_zip64_archive(&source_path);
+
+        let mut source = ZipFile::open(File::open(&source_path).unwrap()).unwrap();
+        let dest_file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&dest_path).unwrap();
+        let mut dest = ZipFile::create(dest_file);
+        dest.copy_entries_from(&mut source, &["huge.bin"]).unwrap();
+        dest.write_file("small.txt", &mut Cursor::new(b"small"), FileCompression::Store).unwrap();
+        dest.save().unwrap();
+
+        // Without the huge entry, the copy doesn't need ZIP64.
+        let copied = std::fs::read(&dest_path).unwrap();
+        assert_eq!(count_occurrences(&copied, &0x06064b50u32.to_le_bytes()), 0);
+        assert_eq!(ZipFile::open(Cursor::new(copied)).unwrap().read_file("small.txt").unwrap(), b"small");
+        std::fs::remove_file(&source_path).unwrap();
+        std::fs::remove_file(&dest_path).unwrap();
+    }
+
+    // Reads zeroes, without storing them anywhere, to give a source for entries over 4GB.
+    struct Zeroes {
+        len: u64,
+        pos: u64
+    }
+
+    impl Read for Zeroes {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = (buf.len() as u64).min(self.len - self.pos) as usize;
+            buf[..len].fill(0);
+            self.pos += len as u64;
+            Ok(len)
+        }
+    }
+
+    impl Seek for Zeroes {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.pos = match pos {
+                SeekFrom::Start(pos) => pos,
+                SeekFrom::End(offset) => self.len.saturating_add_signed(offset),
+                SeekFrom::Current(offset) => self.pos.saturating_add_signed(offset)
+            }.min(self.len);
+            Ok(self.pos)
+        }
+    }
+
+    #[test]
+    #[ignore = "writes, signs and reads over 4GB, which takes minutes"]
+    fn entry_over_4gb_is_written_signed_and_read() {
+        let path = temp_path("zip64-signed.apk");
+        let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let mut zip = ZipFile::create(file);
+        zip.write_file("AndroidManifest.xml", &mut Cursor::new(b"manifest"), FileCompression::Deflate).unwrap();
+        zip.write_file("assets/huge.bin", &mut Zeroes { len: HUGE_LEN, pos: 0 }, FileCompression::Store).unwrap();
+        zip.write_file("after.txt", &mut Cursor::new(b"after"), FileCompression::Deflate).unwrap();
+
+        let (cert, priv_key) = signing::generate_cert_and_priv_key("MBF ZIP64 test").unwrap();
+        zip.save_and_sign(&priv_key, &cert, SigningConfig::default()).unwrap();
+
+        let mut zip = ZipFile::open(File::open(&path).unwrap()).unwrap();
+        // The signing block is found from the ZIP64 central directory offset.
+        assert_eq!(zip.get_v2_signer_certs().unwrap(), [rasn::der::encode(&cert).unwrap()]);
+        assert_eq!(zip.read_file("after.txt").unwrap(), b"after");
+        assert_eq!(zip.read_file_to("assets/huge.bin", &mut std::io::sink()).unwrap(), HUGE_LEN);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Gives the compression method and offset of the data of each entry in the archive at `path`, which is read from its local header.
+    fn data_offsets(path: &Path) -> HashMap<String, (FileCompression, u64)> {
+        let mut zip = ZipFile::open(File::open(path).unwrap()).unwrap();
+        let layout = zip.get_layout().unwrap();
+        let mut file = File::open(path).unwrap();
+        layout.entries.into_iter().map(|entry| {
+            file.seek(SeekFrom::Start(entry.local_header_offset)).unwrap();
+            let header_len = LocalFileHeader::read(&mut file).unwrap().header_len();
+            (entry.name, (entry.compression_method, entry.local_header_offset + header_len))
+        }).collect()
+    }
+
+    fn assert_aligned(offsets: &HashMap<String, (FileCompression, u64)>, name: &str, alignment: u64) {
+        let (compression, offset) = offsets[name];
+        assert!(matches!(compression, FileCompression::Store), "{name} was {compression:?}");
+        assert_eq!(offset % alignment, 0, "Data of {name} at {offset} is not aligned to {alignment}");
+    }
+
+    #[test]
+    fn stored_entries_are_aligned() {
+        let path = temp_path("aligned.apk");
+        let mut zip = ZipFile::create(File::create(&path).unwrap());
+        // Entries with odd lengths (and names) in between, so that no entry is aligned by chance.
+        for (name, compression) in [
+            ("a.txt", FileCompression::Deflate),
+            ("assets/data.bin", FileCompression::Store),
+            ("odd", FileCompression::Store),
+            ("lib/arm64-v8a/libmain.so", FileCompression::Store),
+            ("b.xml", FileCompression::Deflate),
+            ("lib/arm64-v8a/libunity.so", FileCompression::Store),
+        ] {
+            zip.write_file(name, &mut Cursor::new(name.as_bytes().repeat(37)), compression).unwrap();
+        }
+        zip.save().unwrap();
+
+        let offsets = data_offsets(&path);
+        assert_aligned(&offsets, "assets/data.bin", STORE_ALIGNMENT);
+        assert_aligned(&offsets, "odd", STORE_ALIGNMENT);
+        assert_aligned(&offsets, "lib/arm64-v8a/libmain.so", NATIVE_LIB_ALIGNMENT);
+        assert_aligned(&offsets, "lib/arm64-v8a/libunity.so", NATIVE_LIB_ALIGNMENT);
+        // Deflated entries aren't padded.
+        assert_eq!(offsets["a.txt"].1, LOCAL_HEADER_MIN_LEN + 5);
+
+        let mut zip = ZipFile::open(File::open(&path).unwrap()).unwrap();
+        assert_eq!(zip.read_file("lib/arm64-v8a/libunity.so").unwrap(), "lib/arm64-v8a/libunity.so".repeat(37).as_bytes());
+        assert_eq!(zip.get_entry("odd").unwrap().compressed_len, 3 * 37);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn copied_entries_keep_their_alignment() {
+        let source_path = temp_path("aligned-source.apk");
+        let dest_path = temp_path("aligned-dest.apk");
+        let mut source = ZipFile::create(File::create(&source_path).unwrap());
+        source.write_file("lib/arm64-v8a/libmain.so", &mut Cursor::new(vec![1u8; 5000]), FileCompression::Store).unwrap();
+        source.write_file("resources.arsc", &mut Cursor::new(vec![2u8; 333]), FileCompression::Store).unwrap();
+        source.save().unwrap();
+
+        let mut source = ZipFile::open(File::open(&source_path).unwrap()).unwrap();
+        let dest_file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&dest_path).unwrap();
+        let mut dest = ZipFile::create(dest_file);
+        // An entry before the copied entries, so they move to unaligned positions unless they are padded.
+        dest.write_file("first.txt", &mut Cursor::new(b"x"), FileCompression::Deflate).unwrap();
+        dest.copy_entries_from(&mut source, &[]).unwrap();
+        dest.save().unwrap();
+
+        let offsets = data_offsets(&dest_path);
+        assert_aligned(&offsets, "lib/arm64-v8a/libmain.so", NATIVE_LIB_ALIGNMENT);
+        assert_aligned(&offsets, "resources.arsc", STORE_ALIGNMENT);
+        assert_eq!(ZipFile::open(File::open(&dest_path).unwrap()).unwrap().read_file("resources.arsc").unwrap(), vec![2u8; 333]);
+        std::fs::remove_file(&source_path).unwrap();
+        std::fs::remove_file(&dest_path).unwrap();
+    }
+
+    const STORED_CONTENTS: &[u8] = b"stored contents of a file that will be corrupted";
+    const DEFLATED_CONTENTS: &[u8] = b"deflated contents of a file that will be corrupted";
+
+    // Gives the bytes of an archive with a stored and a deflated entry.
+    // The deflated entry is compressed at level 0, so its contents are kept in a stored deflate block and still decode once corrupted.
+    fn archive_to_corrupt() -> Vec<u8> {
+        let path = temp_path("to-corrupt.zip");
+        let mut zip = ZipFile::create(File::create(&path).unwrap());
+        zip.set_compression_level(CompressionLevel::new(0).unwrap());
+        zip.write_file("stored.bin", &mut Cursor::new(STORED_CONTENTS), FileCompression::Store).unwrap();
+        zip.write_file("deflated.txt", &mut Cursor::new(DEFLATED_CONTENTS), FileCompression::Deflate).unwrap();
+        zip.save().unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        contents
+    }
+
+    // Flips a bit in the middle of the first occurrence of `contents` within `archive`, as failing storage might.
+    fn flip_bit_within(archive: &mut [u8], contents: &[u8]) {
+        let pos = archive.windows(contents.len()).position(|window| window == contents).unwrap();
+        archive[pos + contents.len() / 2] ^= 0x10;
+    }
+
+    // Gives the `ZipError` that reading the entry with the given name from `archive` fails with.
+    fn read_error_of(archive: &[u8], name: &str) -> ZipError {
+        let mut zip = ZipFile::open(Cursor::new(archive)).unwrap();
+        let err = zip.read_file(name).expect_err("Corrupted entry should fail to read");
+        assert!(format!("{err:#}").contains(&format!("Failed to read {name}")));
+        err.downcast::<ZipError>().unwrap()
+    }
+
+    #[test]
+    fn corrupted_stored_entry_gives_crc_mismatch() {
+        let mut archive = archive_to_corrupt();
+        flip_bit_within(&mut archive, STORED_CONTENTS);
+        let mut corrupted = STORED_CONTENTS.to_vec();
+        flip_bit_within(&mut corrupted, STORED_CONTENTS);
+
+        match read_error_of(&archive, "stored.bin") {
+            ZipError::CrcMismatch { entry, expected, actual } => {
+                assert_eq!(entry, "stored.bin");
+                assert_eq!(expected, ZIP_CRC.checksum(STORED_CONTENTS));
+                assert_eq!(actual, ZIP_CRC.checksum(&corrupted));
+            },
+            err => panic!("Expected a CRC mismatch, got {err}")
+        }
+        // The other entry is unaffected.
+        let mut zip = ZipFile::open(Cursor::new(&archive)).unwrap();
+        assert_eq!(zip.read_file("deflated.txt").unwrap(), DEFLATED_CONTENTS);
+    }
+
+    #[test]
+    fn corrupted_deflated_entry_gives_crc_mismatch() {
+        let mut archive = archive_to_corrupt();
+        flip_bit_within(&mut archive, DEFLATED_CONTENTS);
+
+        assert!(matches!(read_error_of(&archive, "deflated.txt"),
+            ZipError::CrcMismatch { entry, expected, .. } if entry == "deflated.txt" && expected == ZIP_CRC.checksum(DEFLATED_CONTENTS)));
+    }
+
+    #[test]
+    fn corrupted_entry_fails_the_last_read_of_the_streaming_reader() {
+        let mut archive = archive_to_corrupt();
+        flip_bit_within(&mut archive, STORED_CONTENTS);
+        let mut zip = ZipFile::open(Cursor::new(&archive)).unwrap();
+        let mut reader = zip.open_entry("stored.bin").unwrap();
+
+        // The CRC-32 is only known once the whole entry has been read, so only the read reaching the end fails.
+        let mut buf = vec![0u8; STORED_CONTENTS.len() - 1];
+        reader.read_exact(&mut buf).unwrap();
+        let err = reader.read(&mut [0u8; 16]).expect_err("Last read should fail");
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(*err.into_inner().unwrap().downcast::<ZipError>().unwrap(), ZipError::CrcMismatch { .. }));
+    }
+
+    #[test]
+    fn wrong_length_gives_length_mismatch() {
+        let mut archive = archive_to_corrupt();
+        // The CD header of the deflated entry is the last, and its uncompressed length is 24 bytes in.
+        let header_offset = archive.windows(4).rposition(|window| window == CentDirHeader::HEADER.to_le_bytes()).unwrap();
+        archive[header_offset + 24] ^= 0x01;
+
+        assert!(matches!(read_error_of(&archive, "deflated.txt"), ZipError::LengthMismatch { entry, .. } if entry == "deflated.txt"));
+    }
+
+    // Writes an archive with a few directories of files, for deleting by prefix or pattern.
+    fn write_archive_to_delete_from(path: &Path) {
+        let mut zip = ZipFile::create(File::create(path).unwrap());
+        for name in ["lib/armeabi-v7a/libmodloader.so", "lib/armeabi-v7a/libunity.so", "lib/arm64-v8a/libunity.so", "libs.txt", "assets/old.bak"] {
+            zip.write_file(name, &mut Cursor::new(name.as_bytes()), FileCompression::Store).unwrap();
+        }
+        zip.save().unwrap();
+    }
+
+    #[test]
+    fn delete_prefix_deletes_every_file_with_the_prefix() {
+        let path = temp_path("delete-prefix");
+        write_archive_to_delete_from(&path);
+        let mut zip = ZipFile::open(open_rw(&path)).unwrap();
+
+        assert_eq!(zip.delete_prefix("lib/armeabi-v7a/"), ["lib/armeabi-v7a/libmodloader.so", "lib/armeabi-v7a/libunity.so"]);
+        assert!(zip.delete_prefix("lib/armeabi-v7a/").is_empty());
+        zip.save().unwrap();
+
+        let mut zip = ZipFile::open(File::open(&path).unwrap()).unwrap();
+        let mut names: Vec<_> = zip.iter_entry_names().collect();
+        names.sort();
+        assert_eq!(names, ["assets/old.bak", "lib/arm64-v8a/libunity.so", "libs.txt"]);
+        assert_eq!(zip.read_file("libs.txt").unwrap(), b"libs.txt");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn delete_matching_gives_sorted_names_of_deleted_files() {
+        let path = temp_path("delete-matching");
+        write_archive_to_delete_from(&path);
+        let mut zip = ZipFile::open(open_rw(&path)).unwrap();
+
+        assert_eq!(zip.delete_matching(|name| name.ends_with("libunity.so") || name.ends_with(".bak")),
+            ["assets/old.bak", "lib/arm64-v8a/libunity.so", "lib/armeabi-v7a/libunity.so"]);
+        assert_eq!(zip.iter_entry_names().count(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn delete_matching_nothing_gives_an_empty_list() {
+        let path = temp_path("delete-nothing");
+        write_archive_to_delete_from(&path);
+        let mut zip = ZipFile::open(open_rw(&path)).unwrap();
+
+        assert!(zip.delete_matching(|_| false).is_empty());
+        assert!(zip.delete_prefix("META-INF/").is_empty());
+        assert_eq!(zip.iter_entry_names().count(), 5);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Names from a regional build of a game, with CJK and accented characters, and an ASCII name.
+    const REGIONAL_NAMES: &[&str] = &["assets/日本語/曲.dat", "Música/canción.ogg", "assets/plain.txt"];
+
+    // Writes an archive with an entry for each of `REGIONAL_NAMES`, in which the contents of each entry is its name.
+    fn write_regional_archive(path: &Path) {
+        let mut zip = ZipFile::create(File::create(path).unwrap());
+        for (idx, name) in REGIONAL_NAMES.iter().enumerate() {
+            let compression = if idx % 2 == 0 { FileCompression::Store } else { FileCompression::Deflate };
+            zip.write_file(name, &mut Cursor::new(name.as_bytes()), compression).unwrap();
+        }
+        zip.save().unwrap();
+    }
+
+    // Gives the general purpose flags and raw name field of each local header, then of each central directory header, in the archive.
+    fn name_fields(bytes: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        let field = |header: &[u8], flags_offset: usize, name_len_offset: usize, name_offset: usize| {
+            let flags = u16::from_le_bytes([header[flags_offset], header[flags_offset + 1]]);
+            let name_len = u16::from_le_bytes([header[name_len_offset], header[name_len_offset + 1]]) as usize;
+            (flags, header[name_offset..name_offset + name_len].to_vec())
+        };
+
+        let layout = ZipFile::open(Cursor::new(bytes)).unwrap().get_layout().unwrap();
+        let mut fields: Vec<_> = layout.entries.iter()
+            .map(|entry| field(&bytes[entry.local_header_offset as usize..], 6, 26, 30))
+            .collect();
+
+        let mut offset = (layout.total_len - layout.cent_dir_len) as usize;
+        for _ in &layout.entries {
+            let header = &bytes[offset..];
+            assert_eq!(header[0..4], CentDirHeader::HEADER.to_le_bytes());
+            let lens: Vec<usize> = [28, 30, 32].iter().map(|&at| u16::from_le_bytes([header[at], header[at + 1]]) as usize).collect();
+            fields.push(field(header, 8, 28, 46));
+            offset += 46 + lens.iter().sum::<usize>();
+        }
+        fields
+    }
+
+    #[test]
+    fn non_ascii_names_are_flagged_as_utf8_when_written() {
+        let path = temp_path("regional-written");
+        write_regional_archive(&path);
+        let bytes = std::fs::read(&path).unwrap();
+
+        let fields = name_fields(&bytes);
+        assert_eq!(fields.len(), REGIONAL_NAMES.len() * 2);
+        for (flags, name) in fields {
+            // Bit 11 flags the name as UTF-8, which an ASCII name doesn't need since it is the same in code page 437.
+            assert_eq!(flags & (1 << 11) != 0, !name.is_ascii(), "Wrong flags for {:?}", String::from_utf8_lossy(&name));
+            assert!(REGIONAL_NAMES.iter().any(|expected| expected.as_bytes() == name));
+        }
+
+        let mut zip = ZipFile::open(Cursor::new(&bytes)).unwrap();
+        assert!(zip.iter_file_names().all(FileName::is_utf8));
+        for name in REGIONAL_NAMES {
+            assert_eq!(zip.read_file(name).unwrap(), name.as_bytes());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn non_ascii_name_fields_are_byte_identical_after_copying() {
+        let (source_path, dest_path) = (temp_path("regional-copy-source"), temp_path("regional-copy-dest"));
+        write_regional_archive(&source_path);
+
+        let mut source = ZipFile::open(File::open(&source_path).unwrap()).unwrap();
+        let dest_file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&dest_path).unwrap();
+        let mut dest = ZipFile::create(dest_file);
+        dest.copy_entries_from(&mut source, &[]).unwrap();
+        dest.save().unwrap();
+
+        assert_eq!(name_fields(&std::fs::read(&dest_path).unwrap()), name_fields(&std::fs::read(&source_path).unwrap()));
+        std::fs::remove_file(&source_path).unwrap();
+        std::fs::remove_file(&dest_path).unwrap();
+    }
+
+    #[test]
+    fn non_ascii_name_fields_are_byte_identical_after_patching_in_place() {
+        let path = temp_path("regional-in-place");
+        write_regional_archive(&path);
+        let before = name_fields(&std::fs::read(&path).unwrap());
+
+        let mut zip = ZipFile::open(open_rw(&path)).unwrap();
+        // The central directory is rewritten without the removed entry, so the names of the other entries are written again.
+        assert!(zip.delete_file(REGIONAL_NAMES[0]));
+        zip.save().unwrap();
+
+        let after = name_fields(&std::fs::read(&path).unwrap());
+        let kept: Vec<_> = before.into_iter().filter(|(_, name)| name != REGIONAL_NAMES[0].as_bytes()).collect();
+        assert_eq!(after, kept);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Entries of an archive written by a buggy tool, in central directory order. `modded.json` is given twice, and `assets/config.json`
+    // is given with backslashes and then with forward slashes, which have the same display name but are kept as separate entries.
+    const DUPLICATE_ENTRIES: &[(&str, &[u8])] = &[
+        ("modded.json", b"{\"first\":1}"),
+        ("classes.dex", b"dex"),
+        ("assets\\config.json", b"backslash"),
+        ("modded.json", b"{\"second\":2}"),
+        ("assets/config.json", b"slash")
+    ];
+
+    // Writes `DUPLICATE_ENTRIES` as STORE entries, with a ZIP64 end of central directory record if `zip64` is true.
+    fn write_archive_with_duplicates(path: &Path, zip64: bool) {
+        write_stored_entries(path, DUPLICATE_ENTRIES, zip64);
+    }
+
+    // Writes the given entries as STORE entries with unflagged names.
+    // `write_file` replaces entries with the same name, so the headers are written directly.
+    fn write_stored_entries(path: &Path, entries: &[(&str, &[u8])], zip64: bool) {
+        let mut file = File::create(path).unwrap();
+        let mut cent_dir = Vec::new();
+        for (name, contents) in entries {
+            let local_header_offset = file.stream_position().unwrap();
+            let crc32 = ZIP_CRC.checksum(contents);
+            let len = contents.len() as u64;
+            LocalFileHeader {
+                version_needed: VERSION_NEEDED_TO_EXTRACT,
+                flags: 0,
+                compression_method: FileCompression::Store,
+                last_modified: 0,
+                crc32,
+                compressed_len: len,
+                uncompressed_len: len,
+                file_name: name.as_bytes().to_vec(),
+                extra_field: Vec::new(),
+                zip64: false
+            }.write(&mut file).unwrap();
+            file.write_all(contents).unwrap();
+
+            CentDirHeader {
+                os_version_made_by: 0,
+                version_needed: VERSION_NEEDED_TO_EXTRACT,
+                flags: 0,
+                compression_method: FileCompression::Store,
+                last_modified: 0,
+                crc32,
+                compressed_len: len,
+                uncompressed_len: len,
+                internal_attrs: 0,
+                external_attrs: 0,
+                local_header_offset,
+                file_name: FileName::from(*name),
+                extra_field: Vec::new(),
+                comment: Vec::new()
+            }.write(&mut cent_dir).unwrap();
+        }
+
+        let cent_dir_offset = file.stream_position().unwrap();
+        file.write_all(&cent_dir).unwrap();
+        EndOfCentDir {
+            cent_dir_records: entries.len() as u64,
+            cent_dir_size: cent_dir.len() as u64,
+            cent_dir_offset,
+            comment: Vec::new(),
+            zip64
+        }.write(&mut file).unwrap();
+    }
+
+    // Counts the central directory headers in the archive at `path` with the given raw name.
+    fn cent_dir_name_count(path: &Path, raw_name: &[u8]) -> usize {
+        let layout = ZipFile::open(File::open(path).unwrap()).unwrap().get_layout().unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        count_occurrences(&bytes[(layout.total_len - layout.cent_dir_len) as usize..], raw_name)
+    }
+
+    #[test]
+    fn reads_resolve_to_the_last_duplicate() {
+        for zip64 in [false, true] {
+            let path = temp_path(&format!("duplicates-read-{zip64}"));
+            write_archive_with_duplicates(&path, zip64);
+            let bytes = std::fs::read(&path).unwrap();
+            assert_eq!(count_occurrences(&bytes, &0x06064b50u32.to_le_bytes()), usize::from(zip64));
+
+            let mut zip = ZipFile::open(Cursor::new(bytes)).unwrap();
+            assert_eq!(zip.read_file("modded.json").unwrap(), b"{\"second\":2}");
+            assert_eq!(zip.read_file("assets/config.json").unwrap(), b"slash");
+            assert_eq!(zip.get_entry("modded.json").unwrap().crc32, ZIP_CRC.checksum(b"{\"second\":2}"));
+            let mut names: Vec<&str> = zip.iter_entry_names().collect();
+            names.sort();
+            // Both config files are kept, since their raw names differ.
+            assert_eq!(names, ["assets/config.json", "assets/config.json", "classes.dex", "modded.json"]);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn delete_file_removes_every_duplicate() {
+        let path = temp_path("duplicates-delete");
+        write_archive_with_duplicates(&path, false);
+
+        let mut zip = ZipFile::open(open_rw(&path)).unwrap();
+        assert!(zip.delete_file("modded.json"));
+        assert!(!zip.delete_file("modded.json"));
+        assert!(zip.delete_file("assets/config.json"));
+        zip.save().unwrap();
+
+        assert_eq!(cent_dir_name_count(&path, b"modded.json"), 0);
+        assert_eq!(cent_dir_name_count(&path, b"config.json"), 0);
+        let zip = ZipFile::open(File::open(&path).unwrap()).unwrap();
+        assert_eq!(zip.iter_entry_names().collect::<Vec<_>>(), ["classes.dex"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn write_file_replaces_every_duplicate() {
+        let path = temp_path("duplicates-write");
+        write_archive_with_duplicates(&path, false);
+
+        let mut zip = ZipFile::open(open_rw(&path)).unwrap();
+        zip.write_file("modded.json", &mut Cursor::new(b"{\"third\":3}"), FileCompression::Deflate).unwrap();
+        zip.save().unwrap();
+
+        assert_eq!(cent_dir_name_count(&path, b"modded.json"), 1);
+        let mut zip = ZipFile::open(File::open(&path).unwrap()).unwrap();
+        assert_eq!(zip.read_file("modded.json").unwrap(), b"{\"third\":3}");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // The agent's debug certificate, which is quick to sign test archives with since no key needs generating.
+    const DUPLICATES_TEST_PEM: &[u8] = include_bytes!("../../../src/debug_cert.pem");
+
+    #[test]
+    fn signed_copy_of_an_archive_with_duplicates_has_one_entry_per_raw_name() {
+        let (source_path, dest_path) = (temp_path("duplicates-sign-source"), temp_path("duplicates-sign-dest"));
+        write_archive_with_duplicates(&source_path, true);
+
+        let mut source = ZipFile::open(File::open(&source_path).unwrap()).unwrap();
+        let dest_file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&dest_path).unwrap();
+        let mut dest = ZipFile::create(dest_file);
+        dest.copy_entries_from(&mut source, &[]).unwrap();
+        let (cert, priv_key) = signing::load_cert_and_priv_key(DUPLICATES_TEST_PEM).unwrap();
+        dest.save_and_sign(&priv_key, &cert, SigningConfig { v1: false, v2: true, v3: false }).unwrap();
+
+        assert_eq!(cent_dir_name_count(&dest_path, b"modded.json"), 1);
+        assert_eq!(cent_dir_name_count(&dest_path, b"assets/config.json"), 1);
+        assert_eq!(cent_dir_name_count(&dest_path, b"assets\\config.json"), 1);
+        let mut dest = ZipFile::open(File::open(&dest_path).unwrap()).unwrap();
+        assert!(matches!(dest.verify_v2_signature().unwrap(), signing::V2Verification::Valid { .. }));
+        assert_eq!(dest.read_file("modded.json").unwrap(), b"{\"second\":2}");
+        assert_eq!(dest.read_file("assets/config.json").unwrap(), b"slash");
+        std::fs::remove_file(&source_path).unwrap();
+        std::fs::remove_file(&dest_path).unwrap();
+    }
+
+    // Two entries whose raw names differ only in the path separator, so that both have the display name `a/b`.
+    const SEPARATOR_VARIANTS: &[(&str, &[u8])] = &[
+        ("a\\b", b"first contents"),
+        ("a/b", b"second contents")
+    ];
+
+    #[test]
+    fn entries_sharing_a_display_name_are_copied_byte_for_byte() {
+        let (source_path, dest_path) = (temp_path("separators-source"), temp_path("separators-dest"));
+        write_stored_entries(&source_path, SEPARATOR_VARIANTS, false);
+
+        let mut source = ZipFile::open(File::open(&source_path).unwrap()).unwrap();
+        let mut dest = ZipFile::create(File::create(&dest_path).unwrap());
+        dest.copy_entries_from(&mut source, &[]).unwrap();
+        dest.save().unwrap();
+
+        // Saving in place keeps both entries too.
+        let mut zip = ZipFile::open(open_rw(&source_path)).unwrap();
+        zip.write_file("other", &mut Cursor::new(b"other"), FileCompression::Store).unwrap();
+        zip.save().unwrap();
+
+        for path in [&source_path, &dest_path] {
+            // Each name is given once in its local header and once in the central directory.
+            let bytes = std::fs::read(path).unwrap();
+            assert_eq!(count_occurrences(&bytes, b"a\\b"), 2);
+            assert_eq!(count_occurrences(&bytes, b"a/b"), 2);
+            assert_eq!(cent_dir_name_count(path, b"a\\b"), 1);
+            assert_eq!(cent_dir_name_count(path, b"a/b"), 1);
+
+            let mut zip = ZipFile::open(File::open(path).unwrap()).unwrap();
+            assert_eq!(zip.iter_entry_names().filter(|name| *name == "a/b").count(), 2);
+            assert_eq!(zip.read_file("a/b").unwrap(), b"second contents");
+            for (_, contents) in SEPARATOR_VARIANTS {
+                assert_eq!(count_occurrences(&bytes, contents), 1);
+            }
+        }
+        std::fs::remove_file(&source_path).unwrap();
+        std::fs::remove_file(&dest_path).unwrap();
+    }
+
+    #[test]
+    fn write_file_replaces_every_entry_with_the_normalised_name() {
+        let path = temp_path("separators-write");
+        write_stored_entries(&path, SEPARATOR_VARIANTS, false);
+
+        let mut zip = ZipFile::open(open_rw(&path)).unwrap();
+        zip.write_file("a\\b", &mut Cursor::new(b"replaced"), FileCompression::Store).unwrap();
+        zip.save().unwrap();
+
+        assert_eq!(cent_dir_name_count(&path, b"a\\b"), 0);
+        assert_eq!(cent_dir_name_count(&path, b"a/b"), 1);
+        let mut zip = ZipFile::open(File::open(&path).unwrap()).unwrap();
+        assert_eq!(zip.iter_entry_names().collect::<Vec<_>>(), ["a/b"]);
+        assert_eq!(zip.read_file("a/b").unwrap(), b"replaced");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Gives an empty directory to extract to, removing anything left there by a previous run.
+    fn extract_dir(name: &str) -> PathBuf {
+        let dir = temp_path(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Gives the bytes of an archive with a safe entry and an entry with the given (malicious) name.
+    fn archive_with_entry_named(name: &str) -> Vec<u8> {
+        let path = temp_path("extract-malicious.zip");
+        let mut zip = ZipFile::create(File::create(&path).unwrap());
+        zip.write_file("assets/safe.txt", &mut Cursor::new(b"safe"), FileCompression::Deflate).unwrap();
+        zip.write_file(name, &mut Cursor::new(b"malicious"), FileCompression::Deflate).unwrap();
+        zip.save().unwrap();
+
+        let contents = std::fs::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        contents
+    }
+
+    #[test]
+    fn entries_that_would_be_extracted_outside_the_directory_are_rejected() {
+        let root = extract_dir("extract-malicious");
+        let dir = root.join("out");
+        for name in ["../evil.txt", "assets/../../evil.txt", "..\\evil.txt", "/tmp/evil.txt", ".."] {
+            let mut zip = ZipFile::open(Cursor::new(archive_with_entry_named(name))).unwrap();
+            let err = zip.extract_to(&dir, |_| true, &mut |_| {}).expect_err("Malicious name should be rejected");
+            assert!(err.to_string().contains("outside of the destination directory"), "{name}: {err}");
+            // Nothing is extracted, not even the safe entry.
+            assert!(!dir.exists(), "{name} caused files to be extracted");
+        }
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+
+        // The safe entry can still be extracted by leaving the malicious one out.
+        let mut zip = ZipFile::open(Cursor::new(archive_with_entry_named("../evil.txt"))).unwrap();
+        let summary = zip.extract_to(&dir, |name| name.starts_with("assets/"), &mut |_| {}).unwrap();
+        assert_eq!(summary.files, [dir.join("assets/safe.txt")]);
+        assert_eq!(std::fs::read(dir.join("assets/safe.txt")).unwrap(), b"safe");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    // The length of the stored entry extracted in `large_stored_entries_are_streamed_to_disk`, which takes many reads to extract.
+    const LARGE_ENTRY_LEN: usize = 5 * 1024 * 1024 + 123;
+
+    #[test]
+    fn large_stored_entries_are_streamed_to_disk() {
+        let mut contents = vec![0u8; LARGE_ENTRY_LEN];
+        StdRng::seed_from_u64(300).fill(&mut contents[..]);
+        let path = temp_path("extract-large.zip");
+        let mut zip = ZipFile::create(File::create(&path).unwrap());
+        zip.write_file("assets/bin/Data/sharedassets0.resource", &mut Cursor::new(&contents), FileCompression::Store).unwrap();
+        zip.write_file("assets/small.txt", &mut Cursor::new(b"small"), FileCompression::Deflate).unwrap();
+        zip.save().unwrap();
+
+        let dir = extract_dir("extract-large");
+        let mut zip = ZipFile::open(File::open(&path).unwrap()).unwrap();
+        let mut reported = Vec::new();
+        let summary = zip.extract_to(&dir, |_| true, &mut |bytes| reported.push(bytes)).unwrap();
+
+        assert_eq!(summary.bytes, LARGE_ENTRY_LEN as u64 + 5);
+        assert_eq!(summary.files, [dir.join("assets/bin/Data/sharedassets0.resource"), dir.join("assets/small.txt")]);
+        assert_eq!(std::fs::read(&summary.files[0]).unwrap(), contents);
+        // Progress is given as a running total after each read, so there are many updates for the large entry.
+        assert!(reported.len() > 10);
+        assert!(reported.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(reported.last(), Some(&(summary.bytes as usize)));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_entries_fail_to_extract() {
+        let mut contents = vec![0u8; 100_000];
+        StdRng::seed_from_u64(301).fill(&mut contents[..]);
+        let path = temp_path("extract-corrupt.zip");
+        let mut zip = ZipFile::create(File::create(&path).unwrap());
+        zip.write_file("assets/data.bin", &mut Cursor::new(&contents), FileCompression::Store).unwrap();
+        zip.save().unwrap();
+        let mut archive = std::fs::read(&path).unwrap();
+        let pos = archive.windows(64).position(|window| window == &contents[..64]).unwrap();
+        archive[pos + 50_000] ^= 0x01;
+
+        let dir = extract_dir("extract-corrupt");
+        let err = ZipFile::open(Cursor::new(archive)).unwrap().extract_to(&dir, |_| true, &mut |_| {}).unwrap_err();
+        assert!(matches!(err.downcast_ref::<ZipError>(), Some(ZipError::CrcMismatch { .. })));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
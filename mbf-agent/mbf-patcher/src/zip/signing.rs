@@ -0,0 +1,1070 @@
+//! Basic APK signing implementation, which supports V1 (JAR) signatures and the V2 and V3 signature schemes as described here: 
+//! https://source.android.com/docs/security/features/apksigning/v2
+//! https://source.android.com/docs/security/features/apksigning/v3
+//! 
+//! Key rotation (the V3 proof-of-rotation attribute) is not yet supported, so the V3 signature is made with the same key as the V2 signature.
+//! V1 signatures use SHA-256, so are only accepted by Android 4.3 and later.
+
+use std::{io::{Seek, Read, Write, SeekFrom, Cursor, ErrorKind}, fs::{File, OpenOptions}, num::NonZeroUsize, path::Path, time::{SystemTime, UNIX_EPOCH}};
+use byteorder::{LE, ReadBytesExt, WriteBytesExt};
+use chrono::{DateTime, Utc};
+use rand::{rngs::OsRng, RngCore};
+use rasn_pkix::{Certificate, Time, Validity};
+use rsa::{sha2::{Sha256, Digest}, RsaPrivateKey, RsaPublicKey, pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey, EncodeRsaPublicKey}, pkcs8::{DecodePrivateKey, DecodePublicKey}, Pkcs1v15Sign, Pss};
+use anyhow::{Result, Context, anyhow};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+
+use super::data::EndOfCentDir;
+
+/// The signature schemes that an APK is signed with.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SigningConfig {
+    /// JAR signing, which is the only scheme checked before Android 7.0. Every entry must be decompressed to sign it.
+    pub v1: bool,
+    pub v2: bool,
+    /// Checked instead of V2 by Android 9 and later. The V2 and V3 signatures sign the same digest.
+    pub v3: bool
+}
+
+impl Default for SigningConfig {
+    /// Only the V2 scheme.
+    fn default() -> Self {
+        Self {
+            v1: false,
+            v2: true,
+            v3: false
+        }
+    }
+}
+
+/// Writes the APK signing block, containing the V2 and/or V3 signatures given in `config`, to the APK.
+/// The `apk` stream should be seeked to the first byte after the contents of the last ZIP entry.
+/// If `content_hasher` is given, it is updated with the entries and central directory as they are read to sign them.
+pub(super) fn write_signature(apk: &mut File,
+    priv_key: &RsaPrivateKey,
+    cert: &Certificate,
+    central_dir_bytes: &[u8],
+    mut eocd: EndOfCentDir,
+    config: SigningConfig,
+    content_hasher: Option<&mut Sha256>) -> Result<()> {
+    let after_entries_offset = apk.stream_position()?;
+
+    // For the purpose of signing, the EOCD must set the central directory offset to point to the position of the signature.
+    // If ZIP64 is used, the ZIP64 EOCD record and locator are included with the EOCD, giving offsets as if the signing block was absent.
+    eocd.cent_dir_offset = after_entries_offset;
+
+    let mut eocd_bytes = Vec::new();
+    eocd.write(&mut Cursor::new(&mut eocd_bytes))?;
+
+    let apk_digest = calculate_apk_digest(apk, after_entries_offset, central_dir_bytes, &eocd_bytes, content_hasher)?;
+    write_signature_block(apk, &apk_digest, cert, priv_key, config)?;
+    Ok(())
+}
+
+/// Loads an X509 certificate and RSA private key from the given PEM data.
+/// The private key may be given in PKCS#1 (`RSA PRIVATE KEY`) or PKCS#8 (`PRIVATE KEY`) form, and must be the key of the certificate.
+pub fn load_cert_and_priv_key(pem_data: &[u8]) -> Result<(Certificate, RsaPrivateKey)> {
+    let pem = pem::parse_many(pem_data).context("Invalid PEM")?;
+
+    let mut cert = None;
+    let mut priv_key = None;
+
+    for pem_sect in pem.iter() {
+        match pem_sect.tag() {
+            "RSA PRIVATE KEY" => priv_key = Some(RsaPrivateKey::from_pkcs1_der(pem_sect.contents())
+                .context("Invalid private key")?),
+            "PRIVATE KEY" => priv_key = Some(RsaPrivateKey::from_pkcs8_der(pem_sect.contents())
+                .context("Invalid private key: only RSA keys are supported")?),
+            "CERTIFICATE" => cert = Some(rasn::der::decode::<Certificate>(pem_sect.contents())
+                .map_err(|err| anyhow!("Invalid certificate: {err}"))?),
+            _ => {}
+        }
+    }
+
+    let cert = cert.ok_or(anyhow!("No certificate found in PEM"))?;
+    let priv_key = priv_key.ok_or(anyhow!("No private key found in PEM"))?;
+
+    // The subject public key of an RSA certificate is the PKCS#1 encoded public key.
+    let public_key = priv_key.to_public_key().to_pkcs1_der().context("Failed to encode public key")?;
+    if cert.tbs_certificate.subject_public_key_info.subject_public_key.as_raw_slice() != public_key.as_bytes() {
+        return Err(anyhow!("The private key does not belong to the certificate"));
+    }
+
+    Ok((cert, priv_key))
+}
+
+/// Loads the certificate and private key saved at `path` by an earlier call, or generates a new self-signed certificate and key
+/// and saves them there if there is no file at `path`. Gives the PEM data of the certificate and key.
+/// An existing file that can't be loaded gives an error rather than being replaced, since the APKs signed with it could no longer be updated.
+pub fn get_or_create_device_keypair(path: &Path) -> Result<Vec<u8>> {
+    match std::fs::read(path) {
+        Ok(pem_data) => {
+            load_cert_and_priv_key(&pem_data).with_context(|| format!("Saved signing key at {path:?} was invalid"))?;
+            return Ok(pem_data);
+        },
+        Err(err) if err.kind() == ErrorKind::NotFound => {},
+        Err(err) => return Err(err).with_context(|| format!("Failed to read saved signing key at {path:?}"))
+    }
+
+    let (cert, priv_key) = generate_cert_and_priv_key(DEVICE_CERT_NAME)?;
+    let pem_data = pem::encode_many(&[
+        pem::Pem::new("CERTIFICATE", rasn::der::encode(&cert).map_err(|err| anyhow!("Failed to encode certificate: {err}"))?),
+        pem::Pem::new("RSA PRIVATE KEY", priv_key.to_pkcs1_der().context("Failed to encode private key")?.as_bytes())
+    ]).into_bytes();
+
+    // The key is written to a temporary file first, so that a partly written key is never left at `path`.
+    // Only the owner can read it, since anyone with the key can sign updates to the APKs signed with it.
+    let temp_path = path.with_extension("tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&temp_path)
+        .and_then(|mut file| file.write_all(&pem_data))
+        .and_then(|_| std::fs::rename(&temp_path, path))
+        .with_context(|| format!("Failed to save signing key to {path:?}"))?;
+
+    Ok(pem_data)
+}
+
+/// Generates a new RSA private key and a self-signed certificate for it with the given common name, valid for `GENERATED_CERT_VALIDITY_YEARS`.
+pub fn generate_cert_and_priv_key(common_name: &str) -> Result<(Certificate, RsaPrivateKey)> {
+    let priv_key = RsaPrivateKey::new(&mut OsRng, GENERATED_KEY_BITS).context("Failed to generate private key")?;
+    let public_key = priv_key.to_public_key().to_pkcs1_der().context("Failed to encode public key")?;
+
+    // The serial number is random, and is kept positive (and its first byte nonzero, so it is minimally encoded) by setting the top bits.
+    let mut serial_number = [0u8; 8];
+    OsRng.fill_bytes(&mut serial_number);
+    serial_number[0] = (serial_number[0] & 0x7f) | 0x40;
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).context("System clock is before 1970")?.as_secs() as i64;
+    let not_before = DateTime::<Utc>::from_timestamp(now, 0).ok_or(anyhow!("Invalid current time"))?;
+    let not_after = DateTime::<Utc>::from_timestamp(now + GENERATED_CERT_VALIDITY_YEARS * SECONDS_PER_YEAR, 0)
+        .ok_or(anyhow!("Invalid expiry time"))?;
+    // UTCTime can only give years up to 2049, so the expiry time is a GeneralizedTime.
+    let validity = rasn::der::encode(&Validity {
+        not_before: Time::Utc(not_before),
+        not_after: Time::General(not_after.into())
+    }).map_err(|err| anyhow!("Failed to encode validity: {err}"))?;
+
+    let sha256_rsa_alg = der(DER_SEQUENCE, &[OID_SHA256_WITH_RSA, DER_NULL].concat());
+    let name = der(DER_SEQUENCE, &der(DER_SET, &der(DER_SEQUENCE, &[
+        OID_COMMON_NAME,
+        &der(DER_UTF8_STRING, common_name.as_bytes())
+    ].concat())));
+
+    // Version 1, which is given by leaving out the version, since the certificate has no extensions.
+    let tbs_certificate = der(DER_SEQUENCE, &[
+        der(DER_INTEGER, &serial_number),
+        sha256_rsa_alg.clone(),
+        name.clone(), // Issuer
+        validity,
+        name, // Subject
+        der(DER_SEQUENCE, &[
+            der(DER_SEQUENCE, &[OID_RSA_ENCRYPTION, DER_NULL].concat()),
+            der(DER_BIT_STRING, &[&[0], public_key.as_bytes()].concat()) // No unused bits
+        ].concat())
+    ].concat());
+
+    let signature = priv_key.sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(&tbs_certificate))
+        .context("Failed to sign certificate")?;
+    let cert_data = der(DER_SEQUENCE, &[
+        tbs_certificate,
+        sha256_rsa_alg,
+        der(DER_BIT_STRING, &[&[0], signature.as_slice()].concat())
+    ].concat());
+
+    let cert = rasn::der::decode::<Certificate>(&cert_data).map_err(|err| anyhow!("Generated invalid certificate: {err}"))?;
+    Ok((cert, priv_key))
+}
+
+/// Reads the DER encoded certificates of the first signer in the V2 signature block of `apk`, which ends at `cent_dir_offset`.
+/// Gives an empty list if there is no signing block, or it contains no V2 signature.
+/// The signature itself is not verified.
+pub(super) fn read_v2_signer_certs(apk: &mut (impl Read + Seek), cent_dir_offset: u64) -> Result<Vec<Vec<u8>>> {
+    match read_v2_signature_value(apk, cent_dir_offset)? {
+        Some((_, Some(value))) => read_first_signer_certs(&value).context("Invalid V2 signature"),
+        _ => Ok(Vec::new())
+    }
+}
+
+// Reads the APK signing block that ends at `cent_dir_offset`, giving the offset of its start and the value of its V2 signature (if any),
+// or None if there is no signing block.
+fn read_v2_signature_value(apk: &mut (impl Read + Seek), cent_dir_offset: u64) -> Result<Option<(u64, Option<Vec<u8>>)>> {
+    // The block ends with its length (excluding the length at its start) followed by the footer
+    let footer_len = 8 + APK_SIG_BLOCK_FOOTER.len() as u64;
+    if cent_dir_offset < footer_len {
+        return Ok(None);
+    }
+
+    apk.seek(SeekFrom::Start(cent_dir_offset - footer_len))?;
+    let block_len = apk.read_u64::<LE>()?;
+    let mut footer = [0u8; 16];
+    apk.read_exact(&mut footer)?;
+    if footer != APK_SIG_BLOCK_FOOTER {
+        return Ok(None);
+    }
+
+    // The pairs are between the length at the start of the block and the length at its end.
+    let pairs_len = block_len.checked_sub(footer_len)
+        .filter(|&len| len.checked_add(8).is_some_and(|len| len <= cent_dir_offset - footer_len))
+        .ok_or(anyhow!("APK signing block had invalid length {block_len}"))?;
+    let pairs_start = cent_dir_offset - footer_len - pairs_len;
+    apk.seek(SeekFrom::Start(pairs_start))?;
+    let mut pairs = Vec::new();
+    apk.take(pairs_len).read_to_end(&mut pairs)?;
+    let mut pairs = Cursor::new(pairs.as_slice());
+
+    while (pairs.position() as usize) < pairs.get_ref().len() {
+        let pair_len = pairs.read_u64::<LE>()?;
+        let pair_len = usize::try_from(pair_len).ok()
+            .filter(|&len| len >= 4 && len <= remaining(&pairs))
+            .ok_or(anyhow!("APK signing block pair had invalid length {pair_len}"))?;
+        let id = pairs.read_u32::<LE>()?;
+        let value_start = pairs.position() as usize;
+        let value = &pairs.get_ref()[value_start..value_start + pair_len - 4];
+        pairs.set_position((value_start + pair_len - 4) as u64);
+
+        if id == V2_SIGNATURE_ID {
+            return Ok(Some((pairs_start - 8, Some(value.to_vec()))));
+        }
+    }
+
+    Ok(Some((pairs_start - 8, None)))
+}
+
+/// The outcome of verifying the V2 signature of an APK with `ZipFile::verify_v2_signature`.
+#[derive(Clone, Debug)]
+pub enum V2Verification {
+    /// The APK has no V2 signature.
+    Unsigned,
+    /// The signature of every signer is valid and signs the current contents of the APK.
+    Valid {
+        /// The DER encoded certificate of the first signer.
+        signer_cert: Vec<u8>
+    },
+    /// The signature is malformed, doesn't verify against the signer's key, or doesn't match the contents of the APK,
+    /// which means that the APK was modified after it was signed.
+    Invalid {
+        reason: String
+    },
+    /// A signer only used algorithms that can't be verified (i.e. anything other than RSA with SHA-256), so the signature couldn't be checked.
+    Unsupported {
+        /// The IDs of the signature algorithms used by the signer.
+        algorithms: Vec<u32>
+    }
+}
+
+// The signers within a V2 signature, once their signatures have been verified.
+enum SignerCheck {
+    Verified {
+        first_cert: Vec<u8>,
+        // The APK digest signed by each signer.
+        digests: Vec<Vec<u8>>
+    },
+    Unsupported(Vec<u32>)
+}
+
+/// Verifies the V2 signature of `apk` in the same way as Android: the signature of each signer must verify against its public key,
+/// which must be the key of its certificate, and the digest that it signs must match the contents of the APK.
+/// `cent_dir_offset` and `eocd_offset` give the positions of the central directory and EOCD, which must be the end of the archive.
+/// Reading the APK fails with an error, whereas an invalid signature is given as `V2Verification::Invalid`.
+pub(super) fn verify_v2_signature(apk: &mut (impl Read + Seek), cent_dir_offset: u64, eocd_offset: u64) -> Result<V2Verification> {
+    let (block_start, value) = match read_v2_signature_value(apk, cent_dir_offset) {
+        Ok(Some((block_start, Some(value)))) => (block_start, value),
+        Ok(_) => return Ok(V2Verification::Unsigned),
+        Err(err) if err.is::<std::io::Error>() => return Err(err),
+        Err(err) => return Ok(V2Verification::Invalid { reason: format!("{err:#}") })
+    };
+
+    let (first_cert, digests) = match check_v2_signers(&value) {
+        Ok(SignerCheck::Verified { first_cert, digests }) => (first_cert, digests),
+        Ok(SignerCheck::Unsupported(algorithms)) => return Ok(V2Verification::Unsupported { algorithms }),
+        Err(err) => return Ok(V2Verification::Invalid { reason: format!("{err:#}") })
+    };
+
+    let mut eocd = Vec::new();
+    apk.seek(SeekFrom::Start(eocd_offset))?;
+    apk.read_to_end(&mut eocd)?;
+    if eocd.len() < 22 {
+        return Err(anyhow!("EOCD was truncated"));
+    }
+    // The digest is calculated as if the EOCD gave the offset of the signing block rather than the central directory.
+    let cd_offset_field = &mut eocd[16..20];
+    if cd_offset_field == u32::MAX.to_le_bytes() {
+        return Err(anyhow!("Signatures of ZIP64 archives cannot be verified"));
+    }
+    cd_offset_field.copy_from_slice(&(block_start as u32).to_le_bytes());
+
+    let mut central_dir = vec![0u8; eocd_offset.saturating_sub(cent_dir_offset) as usize];
+    apk.seek(SeekFrom::Start(cent_dir_offset))?;
+    apk.read_exact(&mut central_dir)?;
+
+    let apk_digest = calculate_apk_digest(apk, block_start, &central_dir, &eocd, None)?;
+    if digests.iter().all(|digest| *digest == apk_digest) {
+        Ok(V2Verification::Valid { signer_cert: first_cert })
+    }   else {
+        Ok(V2Verification::Invalid { reason: "APK contents did not match the signed digest".to_string() })
+    }
+}
+
+// Verifies the signature of each signer in the given V2 signature value over its signed data, giving the digests that they signed.
+fn check_v2_signers(signature_value: &[u8]) -> Result<SignerCheck> {
+    let mut signers = Cursor::new(read_prefixed(&mut Cursor::new(signature_value))?);
+    if remaining(&signers) == 0 {
+        return Err(anyhow!("V2 signature had no signers"));
+    }
+
+    let mut first_cert = None;
+    let mut digests = Vec::new();
+    while remaining(&signers) > 0 {
+        let mut signer = Cursor::new(read_prefixed(&mut signers)?);
+        let signed_data = read_prefixed(&mut signer)?;
+        let mut signatures = Cursor::new(read_prefixed(&mut signer)?);
+        let public_key_info = read_prefixed(&mut signer)?;
+
+        let mut algorithms = Vec::new();
+        let mut supported = None;
+        while remaining(&signatures) > 0 {
+            let mut signature = Cursor::new(read_prefixed(&mut signatures)?);
+            let algorithm = signature.read_u32::<LE>()?;
+            let value = read_prefixed(&mut signature)?;
+            if supported.is_none() && [RSA_PKCS1_15_SHA256, RSA_PSS_SHA256].contains(&algorithm) {
+                supported = Some((algorithm, value));
+            }
+            algorithms.push(algorithm);
+        }
+        let (algorithm, signature) = match supported {
+            Some(supported) => supported,
+            None => return Ok(SignerCheck::Unsupported(algorithms))
+        };
+
+        let public_key = RsaPublicKey::from_public_key_der(public_key_info).map_err(|err| anyhow!("Invalid signer public key: {err}"))?;
+        let hashed = Sha256::digest(signed_data);
+        let verified = if algorithm == RSA_PSS_SHA256 {
+            public_key.verify(Pss::new::<Sha256>(), &hashed, signature)
+        }   else {
+            public_key.verify(Pkcs1v15Sign::new::<Sha256>(), &hashed, signature)
+        };
+        verified.map_err(|_| anyhow!("Signature did not verify against the signer's public key"))?;
+
+        // The signed data can only be trusted once its signature has been verified.
+        let mut signed_data = Cursor::new(signed_data);
+        let mut signed_digests = Cursor::new(read_prefixed(&mut signed_data)?);
+        let mut certs = Cursor::new(read_prefixed(&mut signed_data)?);
+
+        let mut digest_algorithms = Vec::new();
+        let mut digest = None;
+        while remaining(&signed_digests) > 0 {
+            let mut signed_digest = Cursor::new(read_prefixed(&mut signed_digests)?);
+            let digest_algorithm = signed_digest.read_u32::<LE>()?;
+            let value = read_prefixed(&mut signed_digest)?;
+            if digest_algorithm == algorithm {
+                digest = Some(value.to_vec());
+            }
+            digest_algorithms.push(digest_algorithm);
+        }
+        // As in Android, a digest must be given for exactly the algorithms that signatures are, so that signatures can't be removed.
+        if digest_algorithms != algorithms {
+            return Err(anyhow!("Signature algorithms {algorithms:x?} did not match digest algorithms {digest_algorithms:x?}"));
+        }
+        digests.push(digest.ok_or(anyhow!("Signer had no digest for algorithm {algorithm:x}"))?);
+
+        if remaining(&certs) == 0 {
+            return Err(anyhow!("Signer had no certificate"));
+        }
+        let cert_data = read_prefixed(&mut certs)?;
+        let cert = rasn::der::decode::<Certificate>(cert_data).map_err(|err| anyhow!("Invalid signer certificate: {err}"))?;
+        let cert_key_info = rasn::der::encode(&cert.tbs_certificate.subject_public_key_info)
+            .map_err(|err| anyhow!("Failed to encode public key of certificate: {err}"))?;
+        if cert_key_info != public_key_info {
+            return Err(anyhow!("Signer's certificate did not match its public key"));
+        }
+        first_cert.get_or_insert_with(|| cert_data.to_vec());
+    }
+
+    Ok(SignerCheck::Verified {
+        first_cert: first_cert.expect("There is at least one signer"),
+        digests
+    })
+}
+
+// Reads the certificates within the signed data of the first signer in the given V2 signature value.
+fn read_first_signer_certs(signature_value: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut signers = Cursor::new(read_prefixed(&mut Cursor::new(signature_value))?);
+    if remaining(&signers) == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut signer = Cursor::new(read_prefixed(&mut signers)?);
+    let mut signed_data = Cursor::new(read_prefixed(&mut signer)?);
+    let _digests = read_prefixed(&mut signed_data)?;
+    let mut certs = Cursor::new(read_prefixed(&mut signed_data)?);
+
+    let mut cert_list = Vec::new();
+    while remaining(&certs) > 0 {
+        cert_list.push(read_prefixed(&mut certs)?.to_vec());
+    }
+    Ok(cert_list)
+}
+
+// Reads a sequence of bytes prefixed with its length as a u32, checking that the sequence is not truncated.
+fn read_prefixed<'a>(data: &mut Cursor<&'a [u8]>) -> Result<&'a [u8]> {
+    let len = data.read_u32::<LE>()? as usize;
+    if len > remaining(data) {
+        return Err(anyhow!("Length-prefixed sequence of {len} bytes was truncated"));
+    }
+
+    let start = data.position() as usize;
+    data.set_position((start + len) as u64);
+    Ok(&data.get_ref()[start..start + len])
+}
+
+fn remaining(data: &Cursor<&[u8]>) -> usize {
+    data.get_ref().len() - data.position() as usize
+}
+
+const CHUNK_SIZE: u64 = 0x100000;
+// The number of chunks read at a time when calculating the APK digest, which are digested in parallel.
+const CHUNKS_PER_BATCH: u64 = 16;
+const APK_SIG_BLOCK_FOOTER: [u8; 16] = *b"APK Sig Block 42";
+const RSA_PKCS1_15_SHA256: u32 = 0x0103;
+// Only checked when verifying signatures. APKs are always signed with `RSA_PKCS1_15_SHA256`.
+const RSA_PSS_SHA256: u32 = 0x0101;
+const V2_SIGNATURE_ID: u32 = 0x7109871a;
+const V3_SIGNATURE_ID: u32 = 0xf05368c0;
+// Added to the V2 signed data if the APK also has a V3 signature, so that the V3 signature can't be removed to fall back to V2.
+// The value is the version of the other scheme.
+const STRIPPING_PROTECTION_ATTR_ID: u32 = 0xbeeff00d;
+const V3_SCHEME_VERSION: u32 = 3;
+// The range of SDK versions that the V3 signer applies to. Only Android 9 (SDK 28) and later check V3 signatures,
+// but the range starts from Android 7.0, the earliest version this module supports.
+const V3_MIN_SDK: u32 = 24;
+const V3_MAX_SDK: u32 = i32::MAX as u32;
+
+// Calculates the digest of contiguous data in a stream, using the chunked method described in the V2 signing documentation.
+// The chunks are read `CHUNKS_PER_BATCH` at a time into `batch_buffer`, and the chunks of each batch are digested in parallel.
+// If `content_hasher` is given, it is also updated with the data, at the same time as the chunks are digested.
+fn calculate_chunked_digest(offset: u64,
+    length: u64,
+    source: &mut (impl Read + Seek),
+    output: &mut impl Write,
+    batch_buffer: &mut [u8],
+    mut content_hasher: Option<&mut Sha256>) -> Result<u32> {
+    let threads = std::thread::available_parallelism().map(NonZeroUsize::get).unwrap_or(1);
+
+    source.seek(SeekFrom::Start(offset))?;
+    let mut remaining = length;
+    let mut chunk_count = 0;
+    while remaining > 0 {
+        let batch_len = remaining.min(CHUNK_SIZE * CHUNKS_PER_BATCH);
+        let batch = &mut batch_buffer[0..batch_len as usize];
+        source.read_exact(batch)?;
+        remaining -= batch_len;
+
+        // The final chunk may be less than CHUNK_SIZE
+        let chunks: Vec<&[u8]> = batch.chunks(CHUNK_SIZE as usize).collect();
+        let mut digests = vec![[0u8; 32]; chunks.len()];
+        let chunks_per_thread = chunks.len().div_ceil(threads);
+        std::thread::scope(|scope| {
+            if let Some(hasher) = content_hasher.as_deref_mut() {
+                let batch = &*batch;
+                scope.spawn(move || hasher.update(batch));
+            }
+            for (chunks, digests) in chunks.chunks(chunks_per_thread).zip(digests.chunks_mut(chunks_per_thread)) {
+                scope.spawn(move || for (chunk, digest) in chunks.iter().zip(digests) {
+                    *digest = digest_chunk(chunk);
+                });
+            }
+        });
+
+        for digest in digests {
+            output.write_all(&digest)?;
+        }
+        chunk_count += chunks.len() as u32;
+    }
+
+    Ok(chunk_count)
+}
+
+// Calculates the digest of one chunk of an APK for the V2 signature scheme.
+fn digest_chunk(chunk: &[u8]) -> [u8; 32] {
+    let mut sha = Sha256::default();
+    sha.update([0xa5u8]); // Magic value for chunk
+    sha.update((chunk.len() as u32).to_le_bytes());
+    sha.update(chunk);
+    sha.finalize().into()
+}
+
+// Calculates the digest of an APK, based on the chunked contents of the CD, EOCD and file headers/entries.
+// If `content_hasher` is given, it is also updated with the entries and CD, so that they don't need to be read again to hash them.
+fn calculate_apk_digest(apk: &mut (impl Read + Seek),
+    entries_data_length: u64,
+    central_dir: &[u8],
+    eocd: &[u8],
+    mut content_hasher: Option<&mut Sha256>) -> Result<Vec<u8>> {
+    let mut digests: Vec<u8> = Vec::new();
+    let mut digests_stream = Cursor::new(&mut digests);
+    digests_stream.write_u8(0x5a)?; // Magic value for the APK digest
+    digests_stream.write_u32::<LE>(0)?; // Chunk count, not yet known
+
+    let mut batch_buffer = vec![0u8; (CHUNK_SIZE * CHUNKS_PER_BATCH) as usize];
+
+    let mut chunk_count = 0;
+    let mut cd_stream = Cursor::new(central_dir);
+    let mut eocd_stream = Cursor::new(eocd);
+
+    // Add the digests of each chunk, keeping track of the overall chunk count
+    chunk_count += calculate_chunked_digest(0, entries_data_length, apk, &mut digests_stream, &mut batch_buffer, content_hasher.as_deref_mut())?;
+    chunk_count += calculate_chunked_digest(0, central_dir.len() as u64, &mut cd_stream, &mut digests_stream, &mut batch_buffer, content_hasher)?;
+    chunk_count += calculate_chunked_digest(0, eocd.len() as u64, &mut eocd_stream, &mut digests_stream, &mut batch_buffer, None)?;
+
+    // Overwrite the chunk count now that we know the correct value
+    digests_stream.seek(SeekFrom::Start(1))?;
+    digests_stream.write_u32::<LE>(chunk_count)?;
+
+    let mut top_level_sha = Sha256::default();
+    top_level_sha.update(digests);
+
+    Ok(top_level_sha.finalize().to_vec())
+}
+
+// Writes the APK signing block, with a signature over `apk_digest` for the V2 and V3 schemes if they are enabled in `config`.
+fn write_signature_block(apk: &mut File, apk_digest: &[u8], cert: &Certificate, priv_key: &RsaPrivateKey, config: SigningConfig) -> Result<()> {
+    let mut pairs = Vec::new();
+    if config.v2 {
+        let attributes = if config.v3 {
+            vec![(STRIPPING_PROTECTION_ATTR_ID, V3_SCHEME_VERSION)]
+        }   else {
+            Vec::new()
+        };
+        let signed_data = generate_signed_data(apk_digest, cert, None, &attributes)?;
+        pairs.push((V2_SIGNATURE_ID, generate_signers(&signed_data, cert, priv_key, None)?));
+    }
+    if config.v3 {
+        let sdk_range = Some((V3_MIN_SDK, V3_MAX_SDK));
+        let signed_data = generate_signed_data(apk_digest, cert, sdk_range, &[])?;
+        pairs.push((V3_SIGNATURE_ID, generate_signers(&signed_data, cert, priv_key, sdk_range)?));
+    }
+
+    // Calculate the total length of the signing block. Each pair has an 8 byte length followed by its 4 byte ID.
+    let pairs_len: usize = pairs.iter().map(|(_, value)| 8 + 4 + value.len()).sum();
+    let signing_block_len = pairs_len + 8 + APK_SIG_BLOCK_FOOTER.len();
+
+    // Begin the APK signing block
+    apk.write_u64::<LE>(signing_block_len as u64)?;
+    for (id, value) in pairs {
+        apk.write_u64::<LE>((4 + value.len()) as u64)?;
+        apk.write_u32::<LE>(id)?;
+        apk.write_all(&value)?;
+    }
+
+    // Write the APK signing block footer
+    apk.write_u64::<LE>(signing_block_len as u64)?;
+    apk.write_all(&APK_SIG_BLOCK_FOOTER)?;
+    Ok(())
+}
+
+// Signs `signed_data`, giving the value of a V2 or V3 signature pair, which is a sequence with one signer.
+// V3 signers give the range of SDK versions they apply to after the signed data, which must match the range within it.
+fn generate_signers(signed_data: &[u8], cert: &Certificate, priv_key: &RsaPrivateKey, sdk_range: Option<(u32, u32)>) -> Result<Vec<u8>> {
+    let mut signed_data_digest = Sha256::new();
+    signed_data_digest.update(signed_data);
+
+    let signature = priv_key.sign(Pkcs1v15Sign::new::<Sha256>(), &signed_data_digest.finalize())
+        .context("Failed to sign data")?;
+
+    let public_key_info = rasn::der::encode(&cert.tbs_certificate.subject_public_key_info)
+        .expect("Failed to encode public key");
+
+    // Calculate the total length of the signer section
+    let sdk_range_len = if sdk_range.is_some() { 4 + 4 } else { 0 };
+    let signer_len = 4 + signed_data.len() + sdk_range_len + 4 + 4 + 4 + 4 + signature.len() + 4 + public_key_info.len();
+
+    let mut signers = Vec::with_capacity(4 + 4 + signer_len);
+    signers.write_u32::<LE>((4 + signer_len) as u32)?; // Length of signers array
+    signers.write_u32::<LE>(signer_len as u32)?; // Length of first and only signer
+
+    signers.write_u32::<LE>(signed_data.len() as u32)?;
+    signers.write_all(signed_data)?;
+
+    if let Some((min_sdk, max_sdk)) = sdk_range {
+        signers.write_u32::<LE>(min_sdk)?;
+        signers.write_u32::<LE>(max_sdk)?;
+    }
+
+    signers.write_u32::<LE>((4 + 4 + 4 + signature.len()) as u32)?; // Length of the signatures
+    signers.write_u32::<LE>((4 + 4 + signature.len()) as u32)?; // Length of our one signature
+    signers.write_u32::<LE>(RSA_PKCS1_15_SHA256)?;
+    signers.write_u32::<LE>(signature.len() as u32)?;
+    signers.write_all(&signature)?;
+
+    signers.write_u32::<LE>(public_key_info.len() as u32)?;
+    signers.write_all(&public_key_info)?;
+
+    Ok(signers)
+}
+
+// Generates the signed data of a signer. V3 signed data also gives the range of SDK versions the signer applies to.
+// Each additional attribute is given as its ID and a 32 bit value.
+fn generate_signed_data(apk_digest: &[u8], cert: &Certificate, sdk_range: Option<(u32, u32)>, attributes: &[(u32, u32)]) -> Result<Vec<u8>> {
+    let mut signed_data: Vec<u8> = Vec::new();
+    let mut signed_data_stream = Cursor::new(&mut signed_data);
+
+    let digest_length = 4 + 4 + 32; // 4 bytes for signature alg. ID, 4 bytes for digest length, 32 bytes for digest
+    let digest_seq_length = digest_length + 4; // 4 extra bytes for the digest length
+    signed_data_stream.write_u32::<LE>(digest_seq_length)?; // Write the length of the digests array, which includes the length written just below
+    signed_data_stream.write_u32::<LE>(digest_length)?; // Write the length of our one digest
+    signed_data_stream.write_u32::<LE>(RSA_PKCS1_15_SHA256)?;
+    signed_data_stream.write_u32::<LE>(apk_digest.len() as u32)?;
+    signed_data_stream.write_all(apk_digest)?;
+
+    let cert_data = rasn::der::encode(cert)
+        .expect("Failed to encode certificate");
+
+    signed_data_stream.write_u32::<LE>((cert_data.len() + 4) as u32)?; // Length of certificates array
+    signed_data_stream.write_u32::<LE>(cert_data.len() as u32)?; // Length of our one certificate
+    signed_data_stream.write_all(&cert_data)?;
+
+    if let Some((min_sdk, max_sdk)) = sdk_range {
+        signed_data_stream.write_u32::<LE>(min_sdk)?;
+        signed_data_stream.write_u32::<LE>(max_sdk)?;
+    }
+
+    signed_data_stream.write_u32::<LE>((attributes.len() * (4 + 4 + 4)) as u32)?; // Length of additional attributes
+    for (id, value) in attributes {
+        signed_data_stream.write_u32::<LE>(4 + 4)?; // Length of this attribute
+        signed_data_stream.write_u32::<LE>(*id)?;
+        signed_data_stream.write_u32::<LE>(*value)?;
+    }
+   
+    Ok(signed_data)
+}
+
+const V1_MANIFEST_PATH: &str = "META-INF/MANIFEST.MF";
+const V1_SIGNATURE_FILE_PATH: &str = "META-INF/CERT.SF";
+const V1_SIGNATURE_BLOCK_PATH: &str = "META-INF/CERT.RSA";
+const V1_CREATED_BY: &str = "1.0 (ModsBeforeFriday)";
+// Lines of the manifest and signature file are wrapped to this many bytes, with each continuation line starting with a space.
+const MANIFEST_LINE_LEN: usize = 70;
+
+// DER tags and encoded object identifiers used in the V1 signature block and generated certificates.
+const DER_SEQUENCE: u8 = 0x30;
+const DER_SET: u8 = 0x31;
+const DER_INTEGER: u8 = 0x02;
+const DER_BIT_STRING: u8 = 0x03;
+const DER_OCTET_STRING: u8 = 0x04;
+const DER_UTF8_STRING: u8 = 0x0c;
+const DER_CONTEXT_0: u8 = 0xa0;
+const DER_NULL: &[u8] = &[0x05, 0x00];
+const OID_SIGNED_DATA: &[u8] = &[0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x02]; // 1.2.840.113549.1.7.2
+const OID_DATA: &[u8] = &[0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01]; // 1.2.840.113549.1.7.1
+const OID_SHA256: &[u8] = &[0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01]; // 2.16.840.1.101.3.4.2.1
+const OID_RSA_ENCRYPTION: &[u8] = &[0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01]; // 1.2.840.113549.1.1.1
+const OID_SHA256_WITH_RSA: &[u8] = &[0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b]; // 1.2.840.113549.1.1.11
+const OID_COMMON_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03]; // 2.5.4.3
+
+// The size of generated RSA keys, and how long generated certificates are valid for. Android doesn't check the expiry of signing
+// certificates, but other tools may, so they are valid for longer than a modded APK could reasonably be kept.
+const GENERATED_KEY_BITS: usize = 2048;
+const GENERATED_CERT_VALIDITY_YEARS: i64 = 30;
+const SECONDS_PER_YEAR: i64 = 365 * 24 * 60 * 60;
+// The common name of the certificate generated by `get_or_create_device_keypair`.
+const DEVICE_CERT_NAME: &str = "ModsBeforeFriday device key";
+
+/// Returns true if the entry with the given name is part of a V1 signature, and so isn't signed by it.
+/// This includes the V1 signatures of other tools, which may use names other than the ones used by MBF.
+pub fn is_v1_signature_file(name: &str) -> bool {
+    if name == V1_MANIFEST_PATH {
+        return true;
+    }
+
+    match name.strip_prefix("META-INF/") {
+        Some(file_name) if !file_name.contains('/') => {
+            let file_name = file_name.to_ascii_uppercase();
+            [".SF", ".RSA", ".DSA", ".EC"].iter().any(|ext| file_name.ends_with(ext))
+        },
+        _ => false
+    }
+}
+
+/// Generates the files of a V1 signature (the manifest, signature file and signature block), giving the name and contents of each.
+/// `entry_digests` gives the name and SHA-256 digest of the contents of each entry to sign.
+/// The signature file notes the other schemes in `config`, so that Android versions that support them reject the APK if they are removed.
+pub(super) fn generate_v1_files(entry_digests: &[(String, [u8; 32])],
+    cert: &Certificate,
+    priv_key: &RsaPrivateKey,
+    config: SigningConfig) -> Result<Vec<(&'static str, Vec<u8>)>> {
+    let mut manifest = Vec::new();
+    write_manifest_attribute(&mut manifest, "Manifest-Version", "1.0");
+    write_manifest_attribute(&mut manifest, "Created-By", V1_CREATED_BY);
+    manifest.extend(b"\r\n");
+
+    // Each section of the signature file gives the digest of the section of the manifest for the same entry.
+    let mut signature_sections = Vec::new();
+    for (name, digest) in entry_digests {
+        let mut section = Vec::new();
+        write_manifest_attribute(&mut section, "Name", name);
+        write_manifest_attribute(&mut section, "SHA-256-Digest", &BASE64.encode(digest));
+        section.extend(b"\r\n");
+
+        write_manifest_attribute(&mut signature_sections, "Name", name);
+        write_manifest_attribute(&mut signature_sections, "SHA-256-Digest", &BASE64.encode(Sha256::digest(&section)));
+        signature_sections.extend(b"\r\n");
+        manifest.extend(section);
+    }
+
+    let mut signature_file = Vec::new();
+    write_manifest_attribute(&mut signature_file, "Signature-Version", "1.0");
+    write_manifest_attribute(&mut signature_file, "Created-By", V1_CREATED_BY);
+    write_manifest_attribute(&mut signature_file, "SHA-256-Digest-Manifest", &BASE64.encode(Sha256::digest(&manifest)));
+    let other_schemes: Vec<&str> = [(config.v2, "2"), (config.v3, "3")].iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, id)| *id)
+        .collect();
+    if !other_schemes.is_empty() {
+        write_manifest_attribute(&mut signature_file, "X-Android-APK-Signed", &other_schemes.join(", "));
+    }
+    signature_file.extend(b"\r\n");
+    signature_file.extend(signature_sections);
+
+    let signature = priv_key.sign(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(&signature_file))
+        .context("Failed to sign data")?;
+    let signature_block = generate_signature_block(cert, &signature);
+
+    Ok(vec![
+        (V1_MANIFEST_PATH, manifest),
+        (V1_SIGNATURE_FILE_PATH, signature_file),
+        (V1_SIGNATURE_BLOCK_PATH, signature_block)
+    ])
+}
+
+// Writes a `name: value` line of a JAR manifest or signature file.
+fn write_manifest_attribute(output: &mut Vec<u8>, name: &str, value: &str) {
+    let line = format!("{name}: {value}");
+    let (first, mut remaining) = line.as_bytes().split_at(line.len().min(MANIFEST_LINE_LEN));
+    output.extend(first);
+    output.extend(b"\r\n");
+
+    while !remaining.is_empty() {
+        let (next, after) = remaining.split_at(remaining.len().min(MANIFEST_LINE_LEN - 1));
+        output.push(b' ');
+        output.extend(next);
+        output.extend(b"\r\n");
+        remaining = after;
+    }
+}
+
+// Generates the V1 signature block: a DER encoded PKCS #7 SignedData structure containing the certificate and the signature
+// of the signature file. The signature file itself isn't included, and no signed attributes are used.
+fn generate_signature_block(cert: &Certificate, signature: &[u8]) -> Vec<u8> {
+    let cert_data = rasn::der::encode(cert)
+        .expect("Failed to encode certificate");
+    let issuer = rasn::der::encode(&cert.tbs_certificate.issuer)
+        .expect("Failed to encode certificate issuer");
+    let serial_number = rasn::der::encode(&cert.tbs_certificate.serial_number)
+        .expect("Failed to encode certificate serial number");
+
+    let version = der(DER_INTEGER, &[1]);
+    let sha256_alg = der(DER_SEQUENCE, &[OID_SHA256, DER_NULL].concat());
+    let rsa_alg = der(DER_SEQUENCE, &[OID_RSA_ENCRYPTION, DER_NULL].concat());
+
+    let signer_info = der(DER_SEQUENCE, &[
+        version.clone(),
+        der(DER_SEQUENCE, &[issuer, serial_number].concat()), // Issuer and serial number of the certificate
+        sha256_alg.clone(),
+        rsa_alg,
+        der(DER_OCTET_STRING, signature)
+    ].concat());
+
+    let signed_data = der(DER_SEQUENCE, &[
+        version,
+        der(DER_SET, &sha256_alg), // Digest algorithms
+        der(DER_SEQUENCE, OID_DATA), // Content info, which has no content
+        der(DER_CONTEXT_0, &cert_data), // Certificates
+        der(DER_SET, &signer_info)
+    ].concat());
+
+    der(DER_SEQUENCE, &[OID_SIGNED_DATA, &der(DER_CONTEXT_0, &signed_data)].concat())
+}
+
+// Encodes a DER value with the given tag and contents.
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut result = vec![tag];
+    if contents.len() < 0x80 {
+        result.push(contents.len() as u8);
+    }   else {
+        // Long form: the number of length bytes, followed by the length in big endian
+        let len_bytes: Vec<u8> = contents.len().to_be_bytes().into_iter()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        result.push(0x80 | len_bytes.len() as u8);
+        result.extend(len_bytes);
+    }
+
+    result.extend(contents);
+    result
+}
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use rsa::traits::PublicKeyParts;
+
+    use super::*;
+    use crate::zip::{FileCompression, ZipFile};
+
+    // The agent's debug certificate, which is quick to sign test archives with since no key needs generating.
+    const TEST_PEM: &[u8] = include_bytes!("../../../src/debug_cert.pem");
+    // Longer than a manifest line, so that its `Name` attribute is wrapped.
+    const LONG_NAME: &str = "assets/bin/Data/StreamingAssets/aa/Android/a_bundle_with_a_name_long_enough_to_wrap.bundle";
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mbf-signing-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    // Writes a small archive to a temporary file, signed with the given schemes by the certificate and key in `pem_data`.
+    fn write_signed_archive(name: &str, pem_data: &[u8], config: SigningConfig) -> PathBuf {
+        let path = temp_path(name);
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let mut zip = ZipFile::create(file);
+        zip.write_file("AndroidManifest.xml", &mut Cursor::new(vec![3u8; 300]), FileCompression::Deflate).unwrap();
+        zip.write_file("classes.dex", &mut Cursor::new(b"dex\n035\0"), FileCompression::Deflate).unwrap();
+        zip.write_file(LONG_NAME, &mut Cursor::new(b"bundle".repeat(50)), FileCompression::Store).unwrap();
+        let (cert, priv_key) = load_cert_and_priv_key(pem_data).unwrap();
+        zip.save_and_sign(&priv_key, &cert, config).unwrap();
+        path
+    }
+
+    // Gives the ID and value of each pair in the APK signing block of the archive at `path`, checking that the block is well formed.
+    fn signing_block_pairs(path: &Path) -> Vec<(u32, Vec<u8>)> {
+        let cent_dir_offset = ZipFile::open(File::open(path).unwrap()).unwrap().cent_dir_offset as usize;
+        let contents = std::fs::read(path).unwrap();
+        let footer_start = cent_dir_offset - 8 - APK_SIG_BLOCK_FOOTER.len();
+        assert_eq!(contents[footer_start + 8..cent_dir_offset], APK_SIG_BLOCK_FOOTER);
+
+        let block_len = u64::from_le_bytes(contents[footer_start..footer_start + 8].try_into().unwrap()) as usize;
+        let block_start = cent_dir_offset - block_len - 8;
+        assert_eq!(contents[block_start..block_start + 8], contents[footer_start..footer_start + 8]);
+
+        let mut pairs = Cursor::new(&contents[block_start + 8..footer_start]);
+        let mut result = Vec::new();
+        while remaining(&pairs) > 0 {
+            let pair_len = pairs.read_u64::<LE>().unwrap() as usize;
+            let id = pairs.read_u32::<LE>().unwrap();
+            let mut value = vec![0u8; pair_len - 4];
+            pairs.read_exact(&mut value).unwrap();
+            result.push((id, value));
+        }
+        result
+    }
+
+    fn pair_ids(pairs: &[(u32, Vec<u8>)]) -> Vec<u32> {
+        pairs.iter().map(|(id, _)| *id).collect()
+    }
+
+    fn pair_value(pairs: &[(u32, Vec<u8>)], id: u32) -> &[u8] {
+        &pairs.iter().find(|(pair_id, _)| *pair_id == id).expect("signing block should contain the pair").1
+    }
+
+    fn cert_public_key(cert: &Certificate) -> RsaPublicKey {
+        RsaPublicKey::from_public_key_der(&rasn::der::encode(&cert.tbs_certificate.subject_public_key_info).unwrap()).unwrap()
+    }
+
+    fn assert_v2_valid(path: &Path, cert: &Certificate) {
+        let mut zip = ZipFile::open(File::open(path).unwrap()).unwrap();
+        match zip.verify_v2_signature().unwrap() {
+            V2Verification::Valid { signer_cert } => assert_eq!(signer_cert, rasn::der::encode(cert).unwrap()),
+            verification => panic!("V2 signature should be valid, but was {verification:?}")
+        }
+    }
+
+    // Gives the digest signed by the one signer in the given V2 signature value, once its signature has been verified.
+    fn v2_signed_digest(value: &[u8]) -> Vec<u8> {
+        match check_v2_signers(value).unwrap() {
+            SignerCheck::Verified { mut digests, .. } => digests.remove(0),
+            SignerCheck::Unsupported(algorithms) => panic!("V2 signer used unsupported algorithms {algorithms:x?}")
+        }
+    }
+
+    // Gives the additional attributes within the signed data of the one signer in the given V2 signature value.
+    fn v2_signed_attributes(value: &[u8]) -> Vec<(u32, u32)> {
+        let mut signers = Cursor::new(read_prefixed(&mut Cursor::new(value)).unwrap());
+        let mut signer = Cursor::new(read_prefixed(&mut signers).unwrap());
+        let mut signed_data = Cursor::new(read_prefixed(&mut signer).unwrap());
+        let _digests = read_prefixed(&mut signed_data).unwrap();
+        let _certs = read_prefixed(&mut signed_data).unwrap();
+        let mut attributes = Cursor::new(read_prefixed(&mut signed_data).unwrap());
+
+        let mut result = Vec::new();
+        while remaining(&attributes) > 0 {
+            let mut attribute = Cursor::new(read_prefixed(&mut attributes).unwrap());
+            result.push((attribute.read_u32::<LE>().unwrap(), attribute.read_u32::<LE>().unwrap()));
+        }
+        result
+    }
+
+    // Checks the one signer in the given V3 signature value in the same way as Android: its signature must verify against its public key,
+    // which must be the key of its certificate, and the SDK range after its signed data must match the range within it.
+    // Gives the digest that it signs.
+    fn check_v3_signer(value: &[u8], cert: &Certificate) -> Vec<u8> {
+        let mut signers = Cursor::new(read_prefixed(&mut Cursor::new(value)).unwrap());
+        let mut signer = Cursor::new(read_prefixed(&mut signers).unwrap());
+        assert_eq!(remaining(&signers), 0, "V3 signature should have one signer");
+        let signed_data = read_prefixed(&mut signer).unwrap();
+        let sdk_range = (signer.read_u32::<LE>().unwrap(), signer.read_u32::<LE>().unwrap());
+        let mut signatures = Cursor::new(read_prefixed(&mut signer).unwrap());
+        let public_key_info = read_prefixed(&mut signer).unwrap();
+        assert_eq!(sdk_range, (V3_MIN_SDK, V3_MAX_SDK));
+
+        let mut signature = Cursor::new(read_prefixed(&mut signatures).unwrap());
+        assert_eq!(signature.read_u32::<LE>().unwrap(), RSA_PKCS1_15_SHA256);
+        let public_key = RsaPublicKey::from_public_key_der(public_key_info).unwrap();
+        assert_eq!(public_key, cert_public_key(cert));
+        public_key.verify(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(signed_data), read_prefixed(&mut signature).unwrap())
+            .expect("V3 signature should verify against the signer's public key");
+
+        let mut signed_data = Cursor::new(signed_data);
+        let mut digests = Cursor::new(read_prefixed(&mut signed_data).unwrap());
+        let mut digest = Cursor::new(read_prefixed(&mut digests).unwrap());
+        assert_eq!(digest.read_u32::<LE>().unwrap(), RSA_PKCS1_15_SHA256);
+        let digest = read_prefixed(&mut digest).unwrap().to_vec();
+        let mut certs = Cursor::new(read_prefixed(&mut signed_data).unwrap());
+        assert_eq!(read_prefixed(&mut certs).unwrap(), rasn::der::encode(cert).unwrap());
+        let signed_sdk_range = (signed_data.read_u32::<LE>().unwrap(), signed_data.read_u32::<LE>().unwrap());
+        assert_eq!(signed_sdk_range, sdk_range);
+        digest
+    }
+
+    // Checks the V1 signature of the archive at `path`: the manifest must give the digest of every other entry, the signature file
+    // the digest of the manifest and of each of its sections, and the signature block a signature of the signature file by `cert`.
+    // Gives the signature file.
+    fn check_v1_signature(path: &Path, cert: &Certificate) -> String {
+        let mut zip = ZipFile::open(File::open(path).unwrap()).unwrap();
+        let mut names: Vec<String> = zip.iter_entry_names().map(str::to_string).collect();
+        names.sort();
+        let manifest = zip.read_file(V1_MANIFEST_PATH).unwrap();
+        let signature_file = zip.read_file(V1_SIGNATURE_FILE_PATH).unwrap();
+        let signature_block = zip.read_file(V1_SIGNATURE_BLOCK_PATH).unwrap();
+
+        // Each section (including the main attributes) ends with a blank line.
+        let manifest = String::from_utf8(manifest).unwrap();
+        let sections: Vec<&str> = manifest.split_inclusive("\r\n\r\n").collect();
+        let unwrapped_manifest = manifest.replace("\r\n ", "");
+        for name in names.iter().filter(|name| !is_v1_signature_file(name)) {
+            let contents = zip.read_file(name).unwrap();
+            let expected = format!("Name: {name}\r\nSHA-256-Digest: {}\r\n\r\n", BASE64.encode(Sha256::digest(contents)));
+            assert!(unwrapped_manifest.contains(&expected), "manifest should give the digest of {name}");
+        }
+        assert_eq!(sections.len(), 1 + names.iter().filter(|name| !is_v1_signature_file(name)).count());
+        assert!(manifest.lines().all(|line| line.len() <= MANIFEST_LINE_LEN), "manifest lines should be wrapped");
+
+        let signature_file = String::from_utf8(signature_file).unwrap();
+        let unwrapped_signature_file = signature_file.replace("\r\n ", "");
+        assert!(unwrapped_signature_file.contains(&format!("SHA-256-Digest-Manifest: {}\r\n", BASE64.encode(Sha256::digest(&manifest)))));
+        for section in &sections[1..] {
+            let name_line = section.replace("\r\n ", "").lines().next().unwrap().to_string();
+            let expected = format!("{name_line}\r\nSHA-256-Digest: {}\r\n", BASE64.encode(Sha256::digest(section)));
+            assert!(unwrapped_signature_file.contains(&expected), "signature file should give the digest of the section for {name_line}");
+        }
+
+        // The signature is the last value within the signature block, and is as long as the key.
+        let public_key = cert_public_key(cert);
+        let signature = &signature_block[signature_block.len() - public_key.size()..];
+        public_key.verify(Pkcs1v15Sign::new::<Sha256>(), &Sha256::digest(&signature_file), signature)
+            .expect("V1 signature should verify against the certificate");
+        let cert_data = rasn::der::encode(cert).unwrap();
+        assert!(signature_block.windows(cert_data.len()).any(|window| window == cert_data));
+        unwrapped_signature_file
+    }
+
+    #[test]
+    fn v2_only_signature_verifies() {
+        let (cert, _) = load_cert_and_priv_key(TEST_PEM).unwrap();
+        let path = write_signed_archive("v2", TEST_PEM, SigningConfig::default());
+
+        assert_v2_valid(&path, &cert);
+        let pairs = signing_block_pairs(&path);
+        assert_eq!(pair_ids(&pairs), [V2_SIGNATURE_ID]);
+        // Nothing notes a V3 signature, since there isn't one to strip.
+        assert_eq!(v2_signed_attributes(pair_value(&pairs, V2_SIGNATURE_ID)), []);
+        let zip = ZipFile::open(File::open(&path).unwrap()).unwrap();
+        assert!(!zip.iter_entry_names().any(is_v1_signature_file));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn v3_signature_verifies_and_signs_the_same_digest_as_v2() {
+        let (cert, _) = load_cert_and_priv_key(TEST_PEM).unwrap();
+        let path = write_signed_archive("v3", TEST_PEM, SigningConfig { v1: false, v2: true, v3: true });
+
+        assert_v2_valid(&path, &cert);
+        let pairs = signing_block_pairs(&path);
+        assert_eq!(pair_ids(&pairs), [V2_SIGNATURE_ID, V3_SIGNATURE_ID]);
+        let v2_value = pair_value(&pairs, V2_SIGNATURE_ID);
+        assert_eq!(v2_signed_attributes(v2_value), [(STRIPPING_PROTECTION_ATTR_ID, V3_SCHEME_VERSION)]);
+        // The V2 digest was checked against the contents of the archive, so the V3 signature signs them too.
+        assert_eq!(check_v3_signer(pair_value(&pairs, V3_SIGNATURE_ID), &cert), v2_signed_digest(v2_value));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn v1_signature_verifies_and_notes_the_other_schemes() {
+        let (cert, _) = load_cert_and_priv_key(TEST_PEM).unwrap();
+        let path = write_signed_archive("v1", TEST_PEM, SigningConfig { v1: true, v2: true, v3: true });
+
+        let signature_file = check_v1_signature(&path, &cert);
+        assert!(signature_file.contains("X-Android-APK-Signed: 2, 3\r\n"));
+        // The V1 signature files are entries, so are signed by the V2 and V3 signatures.
+        assert_v2_valid(&path, &cert);
+        let pairs = signing_block_pairs(&path);
+        assert_eq!(pair_ids(&pairs), [V2_SIGNATURE_ID, V3_SIGNATURE_ID]);
+        assert_eq!(check_v3_signer(pair_value(&pairs, V3_SIGNATURE_ID), &cert), v2_signed_digest(pair_value(&pairs, V2_SIGNATURE_ID)));
+
+        // Without the other schemes, the archive has no signing block and Android versions that support them accept it anyway.
+        let v1_only_path = write_signed_archive("v1-only", TEST_PEM, SigningConfig { v1: true, v2: false, v3: false });
+        assert!(!check_v1_signature(&v1_only_path, &cert).contains("X-Android-APK-Signed"));
+        let mut zip = ZipFile::open(File::open(&v1_only_path).unwrap()).unwrap();
+        assert!(matches!(zip.verify_v2_signature().unwrap(), V2Verification::Unsigned));
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&v1_only_path).unwrap();
+    }
+
+    #[test]
+    fn device_key_is_generated_once_and_signs_with_every_scheme() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let key_path = temp_path("device-key.pem");
+        let pem_data = get_or_create_device_keypair(&key_path).unwrap();
+        assert_eq!(std::fs::metadata(&key_path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert!(!key_path.with_extension("tmp").exists());
+        // The saved key is loaded rather than replaced, so APKs signed with it can still be updated.
+        assert_eq!(get_or_create_device_keypair(&key_path).unwrap(), pem_data);
+
+        let (cert, priv_key) = load_cert_and_priv_key(&pem_data).unwrap();
+        assert_eq!(priv_key.size() * 8, GENERATED_KEY_BITS);
+        let cert_data = rasn::der::encode(&cert).unwrap();
+        assert!(cert_data.windows(DEVICE_CERT_NAME.len()).any(|window| window == DEVICE_CERT_NAME.as_bytes()));
+        let (debug_cert, _) = load_cert_and_priv_key(TEST_PEM).unwrap();
+        assert_ne!(cert_data, rasn::der::encode(&debug_cert).unwrap());
+
+        let path = write_signed_archive("device-key", &pem_data, SigningConfig { v1: true, v2: true, v3: true });
+        check_v1_signature(&path, &cert);
+        assert_v2_valid(&path, &cert);
+        let pairs = signing_block_pairs(&path);
+        assert_eq!(check_v3_signer(pair_value(&pairs, V3_SIGNATURE_ID), &cert), v2_signed_digest(pair_value(&pairs, V2_SIGNATURE_ID)));
+
+        // A saved key that can't be loaded is an error, and is kept.
+        std::fs::write(&key_path, b"not a key").unwrap();
+        assert!(get_or_create_device_keypair(&key_path).is_err());
+        assert_eq!(std::fs::read(&key_path).unwrap(), b"not a key");
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&key_path).unwrap();
+    }
+}
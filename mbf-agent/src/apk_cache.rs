@@ -0,0 +1,251 @@
+//! Caches the details read from the installed APK. Almost every request needs these, and reading them involves reading the
+//! central directory of the (large) APK and parsing its manifest.
+//! The cache is keyed by the path, size and modification time of the APK, so a reinstalled or replaced APK is always read again.
+//! The CRC-32s of OBB files are cached in the same way, since finding a renamed OBB by its content reads every OBB,
+//! as is the signature status of the installed APK, since verifying its signature hashes the whole APK.
+
+use std::{io::Cursor, path::{Path, PathBuf}, time::UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{axml::AxmlReader, fs_ops, integrity::SignatureStatus, manifest::ManifestInfo, patching, requests::ModLoader, zip::ZipFile, APK_CACHE_PATH, CRC_CACHE_PATH, SIGNATURE_CACHE_PATH};
+
+/// The details of the installed APK that are cached.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApkSummary {
+    pub package_version: String,
+    pub version_code: Option<u32>,
+    pub legacy_storage: bool,
+    pub application_name: Option<String>,
+    pub loader_installed: Option<ModLoader>
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+struct CacheKey {
+    path: String,
+    size: u64,
+    // Nanoseconds since the UNIX epoch
+    modified: u128
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    key: CacheKey,
+    summary: ApkSummary
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedSignature {
+    key: CacheKey,
+    status: SignatureStatus
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedCrc {
+    key: CacheKey,
+    crc32: u32
+}
+
+/// The CRC-32s of files that have been read before, keyed by their path, size and modification time.
+pub struct CrcCache {
+    path: PathBuf,
+    entries: Vec<CachedCrc>,
+    changed: bool
+}
+
+impl CrcCache {
+    /// Loads the cache from `CRC_CACHE_PATH`, giving an empty cache if it doesn't exist or can't be read.
+    pub fn load() -> Self {
+        Self::load_from(CRC_CACHE_PATH)
+    }
+
+    /// Loads the cache saved at `path`, giving an empty cache if it doesn't exist or can't be read.
+    pub fn load_from(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let entries = std::fs::read(&path).ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default();
+
+        Self {
+            path,
+            entries,
+            changed: false
+        }
+    }
+
+    /// Gets the CRC-32 of the file at `file_path`, using `calculate` to work it out only if the file has changed since it was cached.
+    pub fn get_or_calculate(&mut self, file_path: &Path, calculate: impl FnOnce(&Path) -> Result<u32>) -> Result<u32> {
+        let key = get_key(file_path)?;
+        if let Some(entry) = self.entries.iter().find(|entry| entry.key == key) {
+            return Ok(entry.crc32);
+        }
+
+        let crc32 = calculate(file_path)?;
+        self.entries.retain(|entry| entry.key.path != key.path);
+        self.entries.push(CachedCrc { key, crc32 });
+        self.changed = true;
+        Ok(crc32)
+    }
+
+    /// Saves the cache if any CRC-32s were calculated since it was loaded. Failing to save the cache is only logged.
+    pub fn save(&self) {
+        if !self.changed {
+            return;
+        }
+
+        let mut contents = match serde_json::to_vec(&self.entries) {
+            Ok(contents) => contents,
+            Err(err) => {
+                warn!("Failed to serialize cached CRCs: {err}");
+                return;
+            }
+        };
+        contents.push(b'\n');
+        if let Err(err) = std::fs::write(&self.path, contents) {
+            warn!("Failed to cache CRCs: {err}");
+        }
+    }
+}
+
+/// Gets the details of the APK at `apk_path`, reading them from the cache if the APK hasn't changed since they were cached.
+pub fn get_summary(apk_path: &str) -> Result<ApkSummary> {
+    let key = get_key(Path::new(apk_path))?;
+    if let Some(entry) = load_entry() {
+        if entry.key == key {
+            return Ok(entry.summary);
+        }
+    }
+
+    info!("Reading APK details");
+    let summary = read_summary(apk_path)?;
+    if let Err(err) = save_entry(&CacheEntry { key, summary: summary.clone() }) {
+        warn!("Failed to cache APK details: {err}");
+    }
+
+    Ok(summary)
+}
+
+/// Gets the signature status of the APK at `apk_path`, using `check` to work it out only if the APK has changed since it was cached.
+/// Only the status of one APK is cached, since only the installed APK is checked.
+pub fn get_signature_status(apk_path: &Path, check: impl FnOnce(&Path) -> Result<SignatureStatus>) -> Result<SignatureStatus> {
+    get_signature_status_cached_at(Path::new(SIGNATURE_CACHE_PATH), apk_path, check)
+}
+
+fn get_signature_status_cached_at(cache_path: &Path,
+    apk_path: &Path,
+    check: impl FnOnce(&Path) -> Result<SignatureStatus>) -> Result<SignatureStatus> {
+    let key = get_key(apk_path)?;
+    let cached: Option<CachedSignature> = std::fs::read(cache_path).ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok());
+    if let Some(cached) = cached {
+        if cached.key == key {
+            return Ok(cached.status);
+        }
+    }
+
+    let status = check(apk_path)?;
+    let mut contents = serde_json::to_vec(&CachedSignature { key, status: status.clone() })?;
+    contents.push(b'\n');
+    if let Err(err) = std::fs::write(cache_path, contents) {
+        warn!("Failed to cache APK signature status: {err}");
+    }
+
+    Ok(status)
+}
+
+/// Removes the cached details and signature status, so that they are read again next time.
+/// The cache key should already change when the APK is replaced, but this is used whenever the agent replaces it to be certain.
+pub fn invalidate() {
+    for (path, description) in [(APK_CACHE_PATH, "APK details"), (SIGNATURE_CACHE_PATH, "APK signature status")] {
+        if Path::new(path).exists() {
+            if let Err(err) = fs_ops::remove_file(path) {
+                warn!("Failed to remove cached {description}: {err}");
+            }
+        }
+    }
+}
+
+fn get_key(path: &Path) -> Result<CacheKey> {
+    let metadata = std::fs::metadata(path).with_context(|| format!("Failed to get metadata of {path:?}"))?;
+    Ok(CacheKey {
+        path: path.to_string_lossy().to_string(),
+        size: metadata.len(),
+        modified: metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+    })
+}
+
+fn load_entry() -> Option<CacheEntry> {
+    let contents = std::fs::read(APK_CACHE_PATH).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+fn save_entry(entry: &CacheEntry) -> Result<()> {
+    let mut contents = serde_json::to_vec(entry)?;
+    contents.push(b'\n');
+    std::fs::write(APK_CACHE_PATH, contents)?;
+    Ok(())
+}
+
+/// Reads the details of the APK at `apk_path` without using the cache, which only holds the details of the installed APK.
+pub fn read_summary(apk_path: &str) -> Result<ApkSummary> {
+    let apk_reader = fs_ops::open(apk_path)?;
+    let mut apk = ZipFile::open(apk_reader).context("Failed to read APK as ZIP")?;
+
+    let loader_installed = patching::get_modloader_installed(&mut apk)?;
+
+    let manifest = apk.read_file("AndroidManifest.xml").context("Failed to read manifest")?;
+    let mut manifest_reader = Cursor::new(manifest);
+
+    let mut axml_reader = AxmlReader::new(&mut manifest_reader)?;
+    let info = ManifestInfo::read(&mut axml_reader)?;
+
+    Ok(ApkSummary {
+        package_version: info.package_version,
+        version_code: info.version_code,
+        legacy_storage: info.legacy_storage,
+        application_name: info.application_name,
+        loader_installed
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts how many times the signature was checked, giving the status to cache.
+    fn counting_check(checked: &mut usize) -> impl FnOnce(&Path) -> Result<SignatureStatus> + '_ {
+        move |_| {
+            *checked += 1;
+            Ok(SignatureStatus::Signed { signer_sha256: "ab".repeat(32) })
+        }
+    }
+
+    #[test]
+    fn signature_status_is_only_checked_when_the_apk_changes() {
+        let dir = std::env::temp_dir().join(format!("mbf-signature-cache-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cache_path, apk_path) = (dir.join("signature-cache.json"), dir.join("base.apk"));
+        std::fs::write(&apk_path, b"apk").unwrap();
+
+        let mut checked = 0;
+        let status = get_signature_status_cached_at(&cache_path, &apk_path, counting_check(&mut checked)).unwrap();
+        assert_eq!(status, SignatureStatus::Signed { signer_sha256: "ab".repeat(32) });
+        assert_eq!(get_signature_status_cached_at(&cache_path, &apk_path, counting_check(&mut checked)).unwrap(), status);
+        assert_eq!(checked, 1, "unchanged APK should not be checked again");
+
+        // Replacing the APK changes its size and modification time, so it is checked again.
+        std::fs::write(&apk_path, b"replaced apk").unwrap();
+        get_signature_status_cached_at(&cache_path, &apk_path, counting_check(&mut checked)).unwrap();
+        assert_eq!(checked, 2);
+
+        // A failed check isn't cached.
+        std::fs::write(&apk_path, b"unreadable apk").unwrap();
+        assert!(get_signature_status_cached_at(&cache_path, &apk_path, |_| Err(anyhow::anyhow!("Failed to read APK"))).is_err());
+        get_signature_status_cached_at(&cache_path, &apk_path, counting_check(&mut checked)).unwrap();
+        assert_eq!(checked, 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
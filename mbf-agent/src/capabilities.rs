@@ -0,0 +1,218 @@
+//! Detects which forms of system commands are supported by the quest's firmware, so that the right form can be used up front
+//! rather than discovering an incompatibility partway through patching.
+//! Every probe is read-only, and probes are only run once per agent process.
+
+use std::{collections::HashMap, panic::Location, process::{Command, Output, Stdio}, sync::OnceLock, thread, time::{Duration, Instant}};
+
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::{commands, apk_id};
+
+// If a probe takes longer than this, it is killed and the capability is marked as unknown.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(800);
+
+/// A form of a command that may or may not be supported, depending on the firmware.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Capability {
+    /// `appops <command> --uid <package> ...`
+    AppopsUid,
+    /// `appops <command> <package> ...`
+    AppopsPackage
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum Support {
+    Supported,
+    Unsupported,
+    /// The probe failed to run or timed out.
+    Unknown
+}
+
+struct Probe {
+    capability: Capability,
+    program: &'static str,
+    // The package ID isn't known until the request is read, so the arguments are built when the probe runs.
+    args: fn() -> Vec<&'static str>,
+    succeeded: fn(&Output) -> bool
+}
+
+const PROBES: &[Probe] = &[
+    Probe {
+        capability: Capability::AppopsUid,
+        program: "appops",
+        args: || vec!["get", "--uid", apk_id(), "MANAGE_EXTERNAL_STORAGE"],
+        succeeded: appops_succeeded
+    },
+    Probe {
+        capability: Capability::AppopsPackage,
+        program: "appops",
+        args: || vec!["get", apk_id(), "MANAGE_EXTERNAL_STORAGE"],
+        succeeded: appops_succeeded
+    }
+];
+
+static CAPABILITIES: OnceLock<HashMap<Capability, Support>> = OnceLock::new();
+
+// appops exits successfully on some firmware even if the arguments are invalid, so the output is checked too.
+fn appops_succeeded(output: &Output) -> bool {
+    let stdout = String::from_utf8_lossy(&output.stdout).to_lowercase();
+    output.status.success() && !stdout.contains("error") && !stdout.contains("unknown")
+}
+
+/// Gets the support for every capability, probing them if this hasn't been done yet.
+pub fn get_all() -> &'static HashMap<Capability, Support> {
+    CAPABILITIES.get_or_init(|| {
+        let start = Instant::now();
+        // The probes are run concurrently so that a slow probe doesn't hold up the others.
+        let capabilities = thread::scope(|scope| {
+            let handles: Vec<_> = PROBES.iter()
+                .map(|probe| (probe.capability, scope.spawn(move || run_probe(probe))))
+                .collect();
+
+            handles.into_iter()
+                .map(|(capability, handle)| (capability, handle.join().unwrap_or(Support::Unknown)))
+                .collect()
+        });
+
+        info!("Probed firmware capabilities in {:.2}s: {capabilities:?}", start.elapsed().as_secs_f32());
+        capabilities
+    })
+}
+
+/// Gets the support for the given capability, probing it if this hasn't been done yet.
+pub fn get(capability: Capability) -> Support {
+    get_all().get(&capability).copied().unwrap_or(Support::Unknown)
+}
+
+/// Gets the arguments used to refer to the game in an appops command.
+/// The `--uid` form has always been used, so is used unless it is known to be unsupported and the package form is known to work.
+pub fn appops_target_args() -> Vec<&'static str> {
+    if get(Capability::AppopsUid) == Support::Unsupported && get(Capability::AppopsPackage) == Support::Supported {
+        vec![apk_id()]
+    }   else {
+        vec!["--uid", apk_id()]
+    }
+}
+
+fn run_probe(probe: &Probe) -> Support {
+    let start = Instant::now();
+    let args = (probe.args)();
+    let record = |output: Result<&Output, String>| commands::record(probe.program,
+        &args,
+        Location::caller(),
+        start.elapsed(),
+        Some(PROBE_TIMEOUT),
+        output);
+
+    let mut child = match Command::new(probe.program)
+        .args(&args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            warn!("Failed to run probe for {:?}: {err}", probe.capability);
+            record(Err(format!("failed to run: {err}")));
+            return Support::Unknown;
+        }
+    };
+
+    let deadline = start + PROBE_TIMEOUT;
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            Ok(None) => {
+                warn!("Probe for {:?} timed out", probe.capability);
+                let _ = child.kill();
+                let _ = child.wait();
+                record(Err("timed out".to_string()));
+                return Support::Unknown;
+            },
+            Err(err) => {
+                warn!("Failed to wait for probe for {:?}: {err}", probe.capability);
+                record(Err(format!("failed to wait: {err}")));
+                return Support::Unknown;
+            }
+        }
+    }
+
+    match child.wait_with_output() {
+        Ok(output) => {
+            record(Ok(&output));
+            if (probe.succeeded)(&output) {
+                Support::Supported
+            }   else {
+                Support::Unsupported
+            }
+        },
+        Err(err) => {
+            record(Err(format!("failed to read output: {err}")));
+            Support::Unknown
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+    use crate::test_harness::FakeDevice;
+
+    fn appops_output(exit_code: i32, stdout: &str) -> Output {
+        Output {
+            status: std::process::ExitStatus::from_raw(exit_code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new()
+        }
+    }
+
+    #[test]
+    fn appops_output_is_checked_as_well_as_the_exit_code() {
+        assert!(appops_succeeded(&appops_output(0, "MANAGE_EXTERNAL_STORAGE: allow\n")));
+        assert!(appops_succeeded(&appops_output(0, "No operations.\n")));
+        assert!(!appops_succeeded(&appops_output(1, "MANAGE_EXTERNAL_STORAGE: allow\n")));
+        // Some firmware prints the usage and exits successfully when given arguments it doesn't understand.
+        assert!(!appops_succeeded(&appops_output(0, "Error: Unknown command: --uid\n")));
+        assert!(!appops_succeeded(&appops_output(0, "Unknown package: com.beatgames.beatsaber\n")));
+        assert!(!appops_succeeded(&appops_output(0, "Error: Invalid uid\n")));
+    }
+
+    #[test]
+    fn probes_are_supported_only_if_appops_succeeds() {
+        let device = FakeDevice::builder("capabilities-supported").build();
+        assert_eq!(run_probe(&PROBES[0]), Support::Supported);
+        assert_eq!(run_probe(&PROBES[1]), Support::Supported);
+        assert_eq!(device.calls("appops"), [
+            format!("get --uid {} MANAGE_EXTERNAL_STORAGE", apk_id()),
+            format!("get {} MANAGE_EXTERNAL_STORAGE", apk_id())
+        ]);
+
+        drop(device);
+        let _device = FakeDevice::builder("capabilities-unsupported").failing_appops().build();
+        assert_eq!(run_probe(&PROBES[0]), Support::Unsupported);
+    }
+
+    #[test]
+    fn probes_that_cannot_run_or_time_out_are_unknown() {
+        let missing = Probe {
+            capability: Capability::AppopsUid,
+            program: "mbf-missing-program",
+            args: Vec::new,
+            succeeded: appops_succeeded
+        };
+        assert_eq!(run_probe(&missing), Support::Unknown);
+
+        let slow = Probe {
+            capability: Capability::AppopsUid,
+            program: "sleep",
+            args: || vec!["5"],
+            succeeded: appops_succeeded
+        };
+        let start = Instant::now();
+        assert_eq!(run_probe(&slow), Support::Unknown);
+        assert!(start.elapsed() < Duration::from_secs(3), "the probe should have been killed once it timed out");
+    }
+}
@@ -0,0 +1,283 @@
+//! Runs system commands such as `pm` and `appops`, recording a transcript of each one so that the exact commands run and
+//! what they printed can be included in the operation report when patching fails on unusual firmware.
+
+use std::{fmt::Display, fs::File, io, panic::Location, process::{Command, Output}, sync::Mutex, time::{Duration, Instant}};
+
+use crate::reports;
+
+// The maximum number of bytes of stdout/stderr kept within each transcript.
+const MAX_OUTPUT_LEN: usize = 4096;
+
+// Applied to the arguments and output of every command before its transcript is stored, so that sensitive values are never
+// written to a report. Any new kind of sensitive value passed to a command should have a redaction added here.
+const REDACTIONS: &[fn(&str) -> String] = &[
+    // URLs may contain an access token in their query string.
+    reports::redact_urls
+];
+
+// The transcript of every command run while handling the current request.
+static TRANSCRIPTS: Mutex<Vec<Transcript>> = Mutex::new(Vec::new());
+
+/// A record of a command that was run.
+#[derive(Clone, Debug)]
+pub struct Transcript {
+    pub argv: Vec<String>,
+    /// The source location that ran the command.
+    pub caller: String,
+    pub duration: Duration,
+    /// The timeout applied to the command, if any.
+    pub timeout: Option<Duration>,
+    /// The exit code, or a description of why there isn't one.
+    pub status: Result<i32, String>,
+    pub stdout: String,
+    pub stderr: String
+}
+
+impl Transcript {
+    /// Whether the command failed to run or gave a non-zero exit code.
+    pub fn failed(&self) -> bool {
+        self.status != Ok(0)
+    }
+}
+
+impl Display for Transcript {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "$ {} (from {}, took {:.2}s", self.argv.join(" "), self.caller, self.duration.as_secs_f32())?;
+        if let Some(timeout) = self.timeout {
+            write!(f, ", timeout {:.2}s", timeout.as_secs_f32())?;
+        }
+        match &self.status {
+            Ok(code) => writeln!(f, ", exit code {code})")?,
+            Err(reason) => writeln!(f, ", {reason})")?
+        }
+
+        for (name, output) in [("stdout", &self.stdout), ("stderr", &self.stderr)] {
+            if !output.trim().is_empty() {
+                writeln!(f, "{name}:")?;
+                for line in output.trim_end().lines() {
+                    writeln!(f, "  {line}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs `program` with the given arguments, waiting for it to finish and recording its transcript.
+#[track_caller]
+pub fn run<S: AsRef<str>>(program: &str, args: &[S]) -> io::Result<Output> {
+    let caller = Location::caller();
+    let start = Instant::now();
+    let output = Command::new(program)
+        .args(args.iter().map(AsRef::as_ref))
+        .output();
+
+    record(program, args, caller, start.elapsed(), None, match &output {
+        Ok(output) => Ok(output),
+        Err(err) => Err(format!("failed to run: {err}"))
+    });
+    output
+}
+
+/// Runs `program` in the same way as `run`, but with `input` as its stdin, e.g. to stream a file into it.
+#[track_caller]
+pub fn run_with_input<S: AsRef<str>>(program: &str, args: &[S], input: File) -> io::Result<Output> {
+    let caller = Location::caller();
+    let start = Instant::now();
+    let output = Command::new(program)
+        .args(args.iter().map(AsRef::as_ref))
+        .stdin(input)
+        .output();
+
+    record(program, args, caller, start.elapsed(), None, match &output {
+        Ok(output) => Ok(output),
+        Err(err) => Err(format!("failed to run: {err}"))
+    });
+    output
+}
+
+/// Records the transcript of a command run without `run`, e.g. one that had to be killed after a timeout.
+/// `output` is the output of the command, or a description of why there is none.
+pub fn record<S: AsRef<str>>(program: &str,
+    args: &[S],
+    caller: &Location,
+    duration: Duration,
+    timeout: Option<Duration>,
+    output: Result<&Output, String>) {
+    let transcript = make_transcript(program, args, caller, duration, timeout, output);
+    TRANSCRIPTS.lock().unwrap_or_else(|err| err.into_inner()).push(transcript);
+}
+
+// Makes the transcript of a command, capping and redacting its output.
+fn make_transcript<S: AsRef<str>>(program: &str,
+    args: &[S],
+    caller: &Location,
+    duration: Duration,
+    timeout: Option<Duration>,
+    output: Result<&Output, String>) -> Transcript {
+    let argv = std::iter::once(program)
+        .chain(args.iter().map(AsRef::as_ref))
+        .map(redact)
+        .collect();
+
+    match output {
+        Ok(output) => Transcript {
+            argv,
+            caller: caller.to_string(),
+            duration,
+            timeout,
+            status: output.status.code().ok_or_else(|| "killed by a signal".to_string()),
+            stdout: redact(&cap_output(&output.stdout)),
+            stderr: redact(&cap_output(&output.stderr))
+        },
+        Err(reason) => Transcript {
+            argv,
+            caller: caller.to_string(),
+            duration,
+            timeout,
+            status: Err(reason),
+            stdout: String::new(),
+            stderr: String::new()
+        }
+    }
+}
+
+/// Gets the transcripts of every command run so far.
+pub fn get_transcripts() -> Vec<Transcript> {
+    TRANSCRIPTS.lock().unwrap_or_else(|err| err.into_inner()).clone()
+}
+
+/// Gets the transcripts that should be included inline within an error: only those of failed commands, to keep the error small.
+pub fn failed_transcripts(transcripts: &[Transcript]) -> impl Iterator<Item = &Transcript> {
+    transcripts.iter().filter(|transcript| transcript.failed())
+}
+
+// Converts the output of a command to a string, keeping at most `MAX_OUTPUT_LEN` bytes.
+fn cap_output(output: &[u8]) -> String {
+    if output.len() <= MAX_OUTPUT_LEN {
+        return String::from_utf8_lossy(output).into_owned();
+    }
+
+    format!("{}\n[{} more bytes truncated]", String::from_utf8_lossy(&output[..MAX_OUTPUT_LEN]), output.len() - MAX_OUTPUT_LEN)
+}
+
+fn redact(text: &str) -> String {
+    REDACTIONS.iter().fold(text.to_string(), |text, redaction| redaction(&text))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::process::ExitStatusExt, process::ExitStatus};
+
+    use super::*;
+
+    fn output(code: i32, stdout: &[u8], stderr: &[u8]) -> Output {
+        Output {
+            // The raw wait status holds the exit code in its second byte.
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.to_vec(),
+            stderr: stderr.to_vec()
+        }
+    }
+
+    #[track_caller]
+    fn transcript(args: &[&str], output: Result<&Output, String>) -> Transcript {
+        make_transcript("pm", args, Location::caller(), Duration::from_millis(250), Some(Duration::from_secs(30)), output)
+    }
+
+    #[test]
+    fn output_up_to_the_limit_is_kept() {
+        let output = vec![b'a'; MAX_OUTPUT_LEN];
+        assert_eq!(cap_output(&output), "a".repeat(MAX_OUTPUT_LEN));
+        assert_eq!(cap_output(b""), "");
+    }
+
+    #[test]
+    fn longer_output_is_truncated_with_a_note() {
+        let output = vec![b'a'; MAX_OUTPUT_LEN + 10];
+        assert_eq!(cap_output(&output), format!("{}\n[10 more bytes truncated]", "a".repeat(MAX_OUTPUT_LEN)));
+    }
+
+    #[test]
+    fn truncating_within_a_character_does_not_panic() {
+        // Each character is 3 bytes, so the limit falls partway through one of them.
+        let output = "\u{3042}".repeat(MAX_OUTPUT_LEN).into_bytes();
+        let capped = cap_output(&output);
+        assert!(capped.ends_with(&format!("[{} more bytes truncated]", output.len() - MAX_OUTPUT_LEN)));
+    }
+
+    #[test]
+    fn stdout_and_stderr_are_capped_separately() {
+        let long = vec![b'x'; MAX_OUTPUT_LEN * 2];
+        let transcript = transcript(&["list", "packages"], Ok(&output(0, &long, b"short")));
+
+        assert!(transcript.stdout.ends_with(&format!("[{MAX_OUTPUT_LEN} more bytes truncated]")));
+        assert_eq!(transcript.stderr, "short");
+    }
+
+    #[test]
+    fn urls_in_arguments_and_output_are_redacted() {
+        let transcript = transcript(&["install", "https://example.com/app.apk?token=secret"],
+            Ok(&output(1, b"fetching https://example.com/app.apk?token=secret", b"Failure [https://example.com/?token=secret]")));
+
+        assert_eq!(transcript.argv, ["pm", "install", "https://example.com/app.apk"]);
+        assert_eq!(transcript.stdout, "fetching https://example.com/app.apk");
+        assert!(!transcript.stderr.contains("secret"));
+        assert!(!transcript.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn every_registered_redaction_is_applied() {
+        let message = "see https://example.com/a?token=secret and https://example.com/b?key=other";
+        let redacted = redact(message);
+        for redaction in REDACTIONS {
+            assert_eq!(redaction(&redacted), redacted, "redacting twice should change nothing");
+        }
+        assert_eq!(redacted, "see https://example.com/a and https://example.com/b");
+    }
+
+    #[test]
+    fn commands_that_did_not_run_have_their_reason() {
+        let transcript = transcript(&["path"], Err("failed to run: not found".to_string()));
+
+        assert!(transcript.failed());
+        assert_eq!(transcript.status, Err("failed to run: not found".to_string()));
+        assert!(transcript.to_string().starts_with("$ pm path ("));
+        assert!(transcript.to_string().contains(", failed to run: not found)"));
+    }
+
+    #[test]
+    fn killed_commands_have_no_exit_code() {
+        // A raw wait status of 9 means that the process was killed by SIGKILL.
+        let killed = Output { status: ExitStatus::from_raw(9), stdout: Vec::new(), stderr: Vec::new() };
+        let transcript = transcript(&["install-write"], Ok(&killed));
+
+        assert!(transcript.failed());
+        assert_eq!(transcript.status, Err("killed by a signal".to_string()));
+    }
+
+    #[test]
+    fn transcript_gives_the_command_caller_timeout_and_output() {
+        let transcript = transcript(&["install-commit", "5"], Ok(&output(1, b"", b"Failure [INSTALL_FAILED_INVALID_APK]\n")));
+        let text = transcript.to_string();
+
+        assert!(text.starts_with("$ pm install-commit 5 (from "));
+        assert!(text.contains("commands.rs"), "the caller should be given: {text}");
+        assert!(text.contains("took 0.25s, timeout 30.00s, exit code 1)\n"));
+        assert!(text.ends_with("stderr:\n  Failure [INSTALL_FAILED_INVALID_APK]\n"));
+        assert!(!text.contains("stdout:"), "empty output should be left out: {text}");
+    }
+
+    #[test]
+    fn only_failed_transcripts_are_included_in_errors() {
+        let transcripts = [
+            transcript(&["install-create"], Ok(&output(0, b"Success: created install session [5]", b""))),
+            transcript(&["install-write"], Ok(&output(1, b"", b"Failure [INSTALL_FAILED_INVALID_APK]"))),
+            transcript(&["install-abandon"], Ok(&output(0, b"Success", b"")))
+        ];
+
+        let failed: Vec<_> = failed_transcripts(&transcripts).collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].argv, ["pm", "install-write"]);
+    }
+}
@@ -0,0 +1,330 @@
+//! Finds the most recent native crash of the game and attempts to match it against known causes of crashes,
+//! so that the frontend can suggest a fix without the user having to collect a tombstone themselves.
+
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use log::info;
+use serde::Serialize;
+
+use crate::apk_id;
+
+// The dropbox tags that native crashes are saved under.
+const CRASH_TAGS: &[&str] = &["data_app_native_crash", "SYSTEM_TOMBSTONE"];
+// Limits on the amount of information extracted, since crash dumps can be very large.
+const MAX_FRAMES: usize = 10;
+const MAX_ABORT_MESSAGE_LEN: usize = 500;
+
+/// Information extracted from a native crash dump.
+#[derive(Serialize)]
+pub struct CrashSummary {
+    /// The time the crash was recorded, as given by dropbox.
+    pub time: Option<String>,
+    /// e.g. `signal 11 (SIGSEGV), code 1 (SEGV_MAPERR), fault addr 0x0`
+    pub signal: Option<String>,
+    pub abort_message: Option<String>,
+    /// The first library in the backtrace that isn't part of Android.
+    pub faulting_library: Option<String>,
+    /// The top frames of the backtrace of the crashing thread.
+    pub top_frames: Vec<String>
+}
+
+/// The likely cause of a crash.
+#[derive(Serialize)]
+pub enum CrashDiagnosis {
+    /// The crash occurred within libunity.so, which is usually caused by an incorrect or missing unstripped libunity.so.
+    LibUnityCrash,
+    /// The crash occurred within the modloader.
+    ModloaderCrash,
+    /// The game could not access the quest's storage.
+    StorageAccessDenied,
+    /// The crash occurred within a library that isn't part of the game or Android, so is most likely from a mod.
+    ModCrash {
+        library: String
+    }
+}
+
+/// The action the frontend should offer to fix a crash.
+#[derive(Serialize)]
+pub enum CrashRemediation {
+    /// Patch the game again, which replaces libunity.so
+    Repatch,
+    /// Send a `QuickFix` request, which replaces the modloader
+    QuickFix,
+    /// Send an `ApplyLegacyStorageFallback` request.
+    ApplyLegacyStorageFallback,
+    /// Disable the mod that caused the crash.
+    DisableMod
+}
+
+// Libraries that are part of the game itself, rather than mods. (libunity.so and the modloader are handled separately)
+const GAME_LIBRARIES: &[&str] = &["libil2cpp.so", "libmain.so"];
+
+/// Finds the most recent native crash of the game, if there is one.
+pub fn get_latest_crash() -> Result<Option<CrashSummary>> {
+    let mut latest: Option<(String, String)> = None;
+    for tag in CRASH_TAGS {
+        let output = Command::new("dumpsys")
+            .args(["dropbox", "--print", tag])
+            .output()
+            .context("Failed to read crashes from dropbox")?;
+
+        for (time, entry) in split_dropbox_entries(&String::from_utf8_lossy(&output.stdout)) {
+            if !entry.contains(&format!(">>> {} <<<", apk_id())) && !entry.contains(&format!("Process: {}", apk_id())) {
+                continue;
+            }
+
+            // Dropbox times are of the form `YYYY-MM-DD HH:MM:SS` so can be compared as strings.
+            if latest.as_ref().is_none_or(|(latest_time, _)| time >= *latest_time) {
+                latest = Some((time, entry));
+            }
+        }
+    }
+
+    Ok(latest.map(|(time, entry)| {
+        info!("Found crash from {time}");
+        let mut summary = parse_crash(&entry);
+        summary.time = Some(time);
+        summary
+    }))
+}
+
+// Splits the output of `dumpsys dropbox --print` into each entry, along with the time it was recorded.
+// Each entry begins with a line of `=` characters, followed by a line of the form `YYYY-MM-DD HH:MM:SS <tag> (text, <n> bytes)`
+fn split_dropbox_entries(output: &str) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    // Anything before the first entry is a summary of the dropbox, e.g. `Drop box contents: 2 entries`.
+    for section in output.split("========================================").skip(1) {
+        let section = section.trim_start_matches('=').trim_start();
+        let mut lines = section.splitn(2, '\n');
+        let header = lines.next().unwrap_or_default();
+        let body = lines.next().unwrap_or_default();
+
+        // The time is the first two space separated parts of the header
+        let time: Vec<&str> = header.splitn(3, ' ').take(2).collect();
+        if time.len() == 2 && !body.is_empty() {
+            entries.push((time.join(" "), body.to_string()));
+        }
+    }
+
+    entries
+}
+
+// Extracts the key information from a tombstone, in either the 32 or 64 bit format.
+fn parse_crash(tombstone: &str) -> CrashSummary {
+    let mut summary = CrashSummary {
+        time: None,
+        signal: None,
+        abort_message: None,
+        faulting_library: None,
+        top_frames: Vec::new()
+    };
+
+    let mut in_backtrace = false;
+    for line in tombstone.lines() {
+        let line = line.trim();
+        if summary.signal.is_none() && line.starts_with("signal ") {
+            summary.signal = Some(line.to_string());
+        }   else if summary.abort_message.is_none() && line.starts_with("Abort message:") {
+            let message = line.trim_start_matches("Abort message:").trim().trim_matches('\'');
+            summary.abort_message = Some(message.chars().take(MAX_ABORT_MESSAGE_LEN).collect());
+        }   else if line == "backtrace:" {
+            // Only the first backtrace (that of the crashing thread) is used.
+            in_backtrace = summary.top_frames.is_empty();
+        }   else if in_backtrace {
+            // Frames are of the form `#00 pc 000000000004e8ec  /path/to/lib.so (function+164) (BuildId: ...)`
+            // The program counter is 8 hex digits long for 32 bit tombstones, and 16 for 64 bit tombstones.
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() < 4 || !parts[0].starts_with('#') || parts[1] != "pc" {
+                in_backtrace = false;
+                continue;
+            }
+
+            let library = parts[3];
+            if summary.faulting_library.is_none() && !library.starts_with("/system/") && !library.starts_with("/apex/") {
+                summary.faulting_library = Some(library.to_string());
+            }
+
+            if summary.top_frames.len() < MAX_FRAMES {
+                summary.top_frames.push(line.to_string());
+            }
+        }
+    }
+
+    summary
+}
+
+/// Attempts to find the cause of the given crash, and the action that should be taken to fix it.
+pub fn diagnose(crash: &CrashSummary) -> Option<(CrashDiagnosis, CrashRemediation)> {
+    let abort_message = crash.abort_message.as_deref().unwrap_or_default();
+    if abort_message.contains("Permission denied") || abort_message.contains("EACCES") {
+        return Some((CrashDiagnosis::StorageAccessDenied, CrashRemediation::ApplyLegacyStorageFallback));
+    }
+
+    let library = crash.faulting_library.as_deref()?;
+    let library_name = library.rsplit('/').next().unwrap_or(library);
+    if library_name == "libunity.so" {
+        Some((CrashDiagnosis::LibUnityCrash, CrashRemediation::Repatch))
+    }   else if library_name == "libsl2.so" {
+        Some((CrashDiagnosis::ModloaderCrash, CrashRemediation::QuickFix))
+    }   else if library_name.ends_with(".so") && !GAME_LIBRARIES.contains(&library_name) {
+        Some((CrashDiagnosis::ModCrash { library: library_name.to_string() }, CrashRemediation::DisableMod))
+    }   else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 64 bit tombstone of a crash within libunity.so, as saved under `data_app_native_crash`.
+    const LIBUNITY_TOMBSTONE: &str = "\
+Process: com.beatgames.beatsaber
+Flags: 0x38c8be46
+Build: oculus/hollywood/hollywood:12/SQ3A.220605.009.A1/51740100189300150:user/release-keys
+
+*** *** *** *** *** *** *** *** *** *** *** *** *** *** *** ***
+Build fingerprint: 'oculus/hollywood/hollywood:12/SQ3A.220605.009.A1/51740100189300150:user/release-keys'
+Revision: '0'
+ABI: 'arm64'
+Timestamp: 2025-10-09 08:53:20.118162728+0000
+pid: 12345, tid: 12399, name: UnityMain  >>> com.beatgames.beatsaber <<<
+uid: 10123
+signal 11 (SIGSEGV), code 1 (SEGV_MAPERR), fault addr 0x0000000000000000
+Cause: null pointer dereference
+    x0  0000000000000000  x1  0000007a1c2f3e20  x2  0000000000000001  x3  0000000000000000
+
+backtrace:
+      #00 pc 0000000000b1e0c4  /data/app/~~abc==/com.beatgames.beatsaber-xyz==/lib/arm64/libunity.so (BuildId: 7e3d)
+      #01 pc 0000000000b1d9f0  /data/app/~~abc==/com.beatgames.beatsaber-xyz==/lib/arm64/libunity.so (BuildId: 7e3d)
+      #02 pc 00000000000e2c58  /apex/com.android.runtime/lib64/bionic/libc.so (__pthread_start(void*)+264) (BuildId: 24c0)
+      #03 pc 000000000007a3b0  /apex/com.android.runtime/lib64/bionic/libc.so (__start_thread+64) (BuildId: 24c0)
+
+backtrace:
+      #00 pc 000000000009a1c8  /apex/com.android.runtime/lib64/bionic/libc.so (syscall+24) (BuildId: 24c0)
+";
+
+    // A 32 bit tombstone of a crash within the modloader, whose program counters are 8 hex digits long.
+    const MODLOADER_TOMBSTONE: &str = "\
+*** *** *** *** *** *** *** *** *** *** *** *** *** *** *** ***
+ABI: 'arm'
+pid: 4321, tid: 4321, name: beatgames.beatsaber  >>> com.beatgames.beatsaber <<<
+signal 6 (SIGABRT), code -1 (SI_QUEUE), fault addr --------
+Abort message: 'Failed to load mod: libsongloader.so'
+    r0  00000000  r1  000010e1  r2  00000006  r3  ffb3a1e8
+
+backtrace:
+    #00 pc 0005fa6c  /apex/com.android.runtime/lib/bionic/libc.so (abort+172) (BuildId: 9c1a)
+    #01 pc 00012f40  /sdcard/ModData/com.beatgames.beatsaber/Modloader/libsl2.so (modloader_load+88)
+    #02 pc 00009e2c  /data/app/~~def==/com.beatgames.beatsaber-uvw==/lib/arm/libmain.so (BuildId: 11aa)
+";
+
+    // A tombstone of the game aborting because it could not access its files on the quest's storage.
+    const STORAGE_TOMBSTONE: &str = "\
+pid: 12345, tid: 12399, name: UnityMain  >>> com.beatgames.beatsaber <<<
+signal 6 (SIGABRT), code -1 (SI_QUEUE), fault addr --------
+Abort message: 'Failed to open /sdcard/ModData/com.beatgames.beatsaber/Mods: Permission denied (EACCES)'
+
+backtrace:
+      #00 pc 000000000004e8ec  /apex/com.android.runtime/lib64/bionic/libc.so (abort+164) (BuildId: 24c0)
+      #01 pc 0000000000123456  /data/app/~~abc==/com.beatgames.beatsaber-xyz==/lib/arm64/libil2cpp.so (BuildId: 55ff)
+";
+
+    // A tombstone of a crash within a mod, with more frames than are kept.
+    fn mod_tombstone() -> String {
+        let mut tombstone = "\
+pid: 12345, tid: 12399, name: UnityMain  >>> com.beatgames.beatsaber <<<
+signal 11 (SIGSEGV), code 2 (SEGV_ACCERR), fault addr 0x0000007a00001000
+
+backtrace:
+      #00 pc 00000000000a1b2c  /apex/com.android.runtime/lib64/bionic/libc.so (memcpy+92) (BuildId: 24c0)
+      #01 pc 0000000000031f00  /sdcard/ModData/com.beatgames.beatsaber/Modloader/mods/libchroma.so (BuildId: 0c0c)
+".to_string();
+        for frame in 2..=15 {
+            tombstone.push_str(&format!("      #{frame:02} pc 00000000001{frame:05x}  /data/app/lib/arm64/libil2cpp.so (BuildId: 55ff)\n"));
+        }
+        tombstone
+    }
+
+    #[test]
+    fn libunity_crash_is_fixed_by_repatching() {
+        let summary = parse_crash(LIBUNITY_TOMBSTONE);
+        assert_eq!(summary.signal.as_deref(), Some("signal 11 (SIGSEGV), code 1 (SEGV_MAPERR), fault addr 0x0000000000000000"));
+        assert_eq!(summary.abort_message, None);
+        assert_eq!(summary.faulting_library.as_deref(), Some("/data/app/~~abc==/com.beatgames.beatsaber-xyz==/lib/arm64/libunity.so"));
+        // Only the backtrace of the crashing thread is kept.
+        assert_eq!(summary.top_frames.len(), 4);
+        assert!(summary.top_frames[0].starts_with("#00 pc 0000000000b1e0c4"));
+
+        assert!(matches!(diagnose(&summary), Some((CrashDiagnosis::LibUnityCrash, CrashRemediation::Repatch))));
+    }
+
+    #[test]
+    fn modloader_crash_in_32_bit_tombstone_is_fixed_by_quick_fix() {
+        let summary = parse_crash(MODLOADER_TOMBSTONE);
+        assert_eq!(summary.signal.as_deref(), Some("signal 6 (SIGABRT), code -1 (SI_QUEUE), fault addr --------"));
+        assert_eq!(summary.abort_message.as_deref(), Some("Failed to load mod: libsongloader.so"));
+        // Frames within Android are skipped when finding the faulting library.
+        assert_eq!(summary.faulting_library.as_deref(), Some("/sdcard/ModData/com.beatgames.beatsaber/Modloader/libsl2.so"));
+        assert_eq!(summary.top_frames.len(), 3);
+
+        assert!(matches!(diagnose(&summary), Some((CrashDiagnosis::ModloaderCrash, CrashRemediation::QuickFix))));
+    }
+
+    #[test]
+    fn storage_permission_abort_is_fixed_by_legacy_storage() {
+        let summary = parse_crash(STORAGE_TOMBSTONE);
+        assert!(summary.abort_message.as_deref().unwrap().ends_with("Permission denied (EACCES)"));
+        assert!(matches!(diagnose(&summary), Some((CrashDiagnosis::StorageAccessDenied, CrashRemediation::ApplyLegacyStorageFallback))));
+    }
+
+    #[test]
+    fn mod_crash_names_the_mod_library_and_keeps_only_the_top_frames() {
+        let summary = parse_crash(&mod_tombstone());
+        assert_eq!(summary.top_frames.len(), MAX_FRAMES);
+        assert!(summary.top_frames[MAX_FRAMES - 1].starts_with("#09 pc"));
+
+        match diagnose(&summary) {
+            Some((CrashDiagnosis::ModCrash { library }, CrashRemediation::DisableMod)) => assert_eq!(library, "libchroma.so"),
+            _ => panic!("crash should be diagnosed as from libchroma.so")
+        }
+    }
+
+    #[test]
+    fn crash_within_the_game_is_not_diagnosed() {
+        let tombstone = STORAGE_TOMBSTONE.replace("Abort message: 'Failed to open /sdcard/ModData/com.beatgames.beatsaber/Mods: Permission denied (EACCES)'", "");
+        let summary = parse_crash(&tombstone);
+        assert!(summary.faulting_library.as_deref().unwrap().ends_with("libil2cpp.so"));
+        assert!(diagnose(&summary).is_none());
+
+        // A tombstone without a backtrace, e.g. one that was cut off, can't be diagnosed either.
+        assert!(diagnose(&parse_crash("signal 11 (SIGSEGV), code 1 (SEGV_MAPERR), fault addr 0x0\n")).is_none());
+    }
+
+    #[test]
+    fn long_abort_messages_are_truncated() {
+        let tombstone = format!("Abort message: '{}'\n", "\u{3042}".repeat(MAX_ABORT_MESSAGE_LEN * 2));
+        let summary = parse_crash(&tombstone);
+        assert_eq!(summary.abort_message.unwrap().chars().count(), MAX_ABORT_MESSAGE_LEN);
+    }
+
+    #[test]
+    fn dropbox_output_is_split_into_timed_entries() {
+        let output = format!("Drop box contents: 2 entries\n\
+            Max entries: 1000\n\
+            \n\
+            ========================================\n\
+            2025-10-09 08:50:01 data_app_native_crash (text, 2048 bytes)\n\
+            {MODLOADER_TOMBSTONE}\n\
+            ========================================\n\
+            2025-10-09 08:53:21 data_app_native_crash (text, 4096 bytes)\n\
+            {LIBUNITY_TOMBSTONE}");
+
+        let entries = split_dropbox_entries(&output);
+        assert_eq!(entries.iter().map(|(time, _)| time.as_str()).collect::<Vec<_>>(), ["2025-10-09 08:50:01", "2025-10-09 08:53:21"]);
+        assert!(entries[0].1.contains("libsl2.so"));
+        assert!(entries[1].1.starts_with("Process: com.beatgames.beatsaber"));
+        assert!(split_dropbox_entries("Drop box contents: 0 entries\n").is_empty());
+    }
+}
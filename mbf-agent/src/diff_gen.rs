@@ -3,10 +3,10 @@
 use std::{fs::OpenOptions, io::{BufReader, Read}, path::Path};
 use anyhow::{Result, anyhow};
 use external_res::{Diff, VersionDiffs};
-use zip::ZIP_CRC;
+use mbf_patcher::zip::ZIP_CRC;
+use rsa::sha2::{Digest, Sha256};
 
 mod external_res;
-mod zip;
 
 fn read_to_vec(path: impl AsRef<Path>) -> Result<Vec<u8>> {
     let mut reader = BufReader::new(std::fs::File::open(path)?);
@@ -52,6 +52,12 @@ fn generate_diff(
     let _diff_bytes = qbsdiff::Bsdiff::new(&from_bytes, &to_bytes)
         .compression_level(9)
         .compare(&mut output)?;
+    drop(output);
+
+    let diff_bytes = read_to_vec(&output_path)?;
+    let diff_sha256 = Sha256::digest(&diff_bytes).iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
 
     Ok(Diff {
         diff_name: get_file_name(output_path),
@@ -60,6 +66,9 @@ fn generate_diff(
         output_file_name: get_file_name(to_file),
         output_crc: to_crc,
         output_size: to_bytes.len(),
+        file_size: Some(from_bytes.len()),
+        diff_sha256: Some(diff_sha256),
+        diff_size: Some(diff_bytes.len())
     })
 }
 
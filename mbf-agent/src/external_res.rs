@@ -1,8 +1,9 @@
 //! Collection of types used to read the BMBF resources repository to fetch core mod information.
+use log::{info, warn};
 use semver::Version;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Display};
-use anyhow::{Context, Result};
+use std::{collections::{BTreeMap, HashMap, VecDeque}, fmt::Display, io::Write, path::Path, sync::Mutex};
+use anyhow::{anyhow, Context, Result};
 
 #[derive(Deserialize)]
 #[derive(Serialize)]
@@ -43,36 +44,127 @@ impl Display for JsonPullError {
 
 impl std::error::Error for JsonPullError { }
 
-const CORE_MODS_URL: &str = "https://git.bmbf.dev/unicorns/resources/-/raw/master/com.beatgames.beatsaber/core-mods.json";
+// The resources repo has a directory of core mods for each game, named by its package ID.
+const CORE_MODS_URL_FORMAT: &str = "https://git.bmbf.dev/unicorns/resources/-/raw/master/{0}/core-mods.json";
 
-pub fn fetch_json<T: DeserializeOwned>(from: &str) -> Result<T, JsonPullError> {
-    let response = match ureq::get(from)
+/// The name of the core mod index in the metadata health summary.
+pub const CORE_MODS_INDEX: &str = "core_mods";
+/// The name of the diff index in the metadata health summary.
+pub const DIFF_INDEX: &str = "diffs";
+
+/// How much of a remote index could be read.
+/// The indices are updated faster than deployed agents, so entries that can't be read (e.g. as they use a newer format)
+/// are skipped rather than failing the whole index.
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct MetadataHealth {
+    pub parsed: usize,
+    /// The game version (or versions, for diffs) of each entry that was skipped.
+    pub skipped: Vec<String>
+}
+
+static METADATA_HEALTH: Mutex<BTreeMap<&'static str, MetadataHealth>> = Mutex::new(BTreeMap::new());
+
+/// Gets how much of each remote index fetched so far could be read.
+pub fn get_metadata_health() -> BTreeMap<&'static str, MetadataHealth> {
+    METADATA_HEALTH.lock().unwrap_or_else(|err| err.into_inner()).clone()
+}
+
+/// Returns true if the entry for `version` in the given index was skipped as it couldn't be read.
+pub fn was_skipped(index_name: &str, version: &str) -> bool {
+    get_metadata_health().get(index_name)
+        .is_some_and(|health| health.skipped.iter().any(|skipped| skipped == version))
+}
+
+fn record_health(index_name: &'static str, health: MetadataHealth) {
+    if !health.skipped.is_empty() {
+        warn!("Skipped {} entries of the {index_name} index that could not be read ({}). These may need a newer version of MBF",
+            health.skipped.len(), health.skipped.join(", "));
+    }
+    METADATA_HEALTH.lock().unwrap_or_else(|err| err.into_inner()).insert(index_name, health);
+}
+
+fn fetch_string(agent: &ureq::Agent, from: &str) -> Result<String, JsonPullError> {
+    let response = match agent.get(from)
         .call()
         .context("Failed to GET resource") {
             Ok(resp) => resp,
             Err(err) => return Err(JsonPullError::FetchError(err))
         };
 
-    let resp_string = match response.into_string() {
-        Ok(str) => str,
-        Err(err) => return Err(JsonPullError::ParseError(err.into()))
-    };
+    response.into_string().map_err(|err| JsonPullError::ParseError(err.into()))
+}
+
+// Parses a JSON object, skipping any values that can't be parsed as `T`, and gives how many were parsed and skipped.
+fn parse_tolerant_map<T: DeserializeOwned>(json: &str) -> Result<(HashMap<String, T>, MetadataHealth), JsonPullError> {
+    let raw: HashMap<String, serde_json::Value> = serde_json::from_str(json)
+        .context("JSON was invalid")
+        .map_err(JsonPullError::ParseError)?;
 
-    match serde_json::from_str(&resp_string).context("JSON was invalid") {
-        Ok(parsed) => Ok(parsed),
-        Err(err) => Err(JsonPullError::ParseError(err.into()))
+    let mut health = MetadataHealth::default();
+    let mut parsed = HashMap::new();
+    for (key, value) in raw {
+        match serde_json::from_value(value) {
+            Ok(entry) => {
+                health.parsed += 1;
+                parsed.insert(key, entry);
+            },
+            Err(_) => health.skipped.push(key)
+        }
     }
+
+    health.skipped.sort();
+    Ok((parsed, health))
 }
 
-pub fn fetch_core_mods() -> Result<CoreModIndex, JsonPullError> {
-    fetch_json(CORE_MODS_URL)
+// Parses a JSON array, skipping any elements that can't be parsed as `T`, and gives how many were parsed and skipped.
+// `describe` gives the description of a skipped element used in the metadata health summary.
+fn parse_tolerant_list<T: DeserializeOwned>(json: &str,
+    describe: fn(&serde_json::Value) -> String) -> Result<(Vec<T>, MetadataHealth), JsonPullError> {
+    let raw: Vec<serde_json::Value> = serde_json::from_str(json)
+        .context("JSON was invalid")
+        .map_err(JsonPullError::ParseError)?;
+
+    let mut health = MetadataHealth::default();
+    let mut parsed = Vec::new();
+    for value in raw {
+        let description = describe(&value);
+        match serde_json::from_value(value) {
+            Ok(entry) => {
+                health.parsed += 1;
+                parsed.push(entry);
+            },
+            Err(_) => health.skipped.push(description)
+        }
+    }
+
+    Ok((parsed, health))
+}
+
+/// Fetches the core mod index for the game with the given package ID. A version is left out of the index if any of its core mods
+/// can't be read, since installing only some of the core mods would leave the game unmodded.
+pub fn fetch_core_mods(apk_id: &str) -> Result<CoreModIndex, JsonPullError> {
+    let (index, health) = parse_tolerant_map(&fetch_string(&ureq::agent(), &CORE_MODS_URL_FORMAT.replace("{0}", apk_id))?)?;
+    record_health(CORE_MODS_INDEX, health);
+    Ok(index)
+}
+
+// Only the arm64-v8a build of LibMainLoader is bundled with the agent, so the 32-bit build is downloaded if an APK needs it.
+const LIBMAIN_ARMV7_URL: &str = "https://github.com/sc2ad/LibMainLoader/releases/download/v0.1.0-alpha/libmain-armeabi-v7a.so";
+
+/// Gets the URL of the libmain.so built for the given ABI, or None if there isn't one to download.
+/// The arm64-v8a build is bundled with the agent, so no URL is given for it.
+pub fn get_libmain_url(abi: &str) -> Option<&'static str> {
+    match abi {
+        "armeabi-v7a" => Some(LIBMAIN_ARMV7_URL),
+        _ => None
+    }
 }
 
 const UNITY_INDEX_URL: &str = "https://raw.githubusercontent.com/Lauriethefish/QuestUnstrippedUnity/main/index.json";
 const UNITY_VER_FORMAT: &str = "https://raw.githubusercontent.com/Lauriethefish/QuestUnstrippedUnity/main/versions/{0}.so";
 
-pub fn get_libunity_url(apk_id: &str, version: &str) -> Result<Option<String>> {
-    let resp = ureq::get(UNITY_INDEX_URL)
+pub fn get_libunity_url(agent: &ureq::Agent, apk_id: &str, version: &str) -> Result<Option<String>> {
+    let resp = agent.get(UNITY_INDEX_URL)
         .call()
         .context("Failed to GET libunity index")?;
 
@@ -84,18 +176,32 @@ pub fn get_libunity_url(apk_id: &str, version: &str) -> Result<Option<String>> {
         None => return Ok(None)
     };
     match app_index.get(version) {
-        Some(unity_version) => Ok(Some(UNITY_VER_FORMAT.replace("{0}", &unity_version))),
+        Some(unity_version) => Ok(Some(UNITY_VER_FORMAT.replace("{0}", unity_version))),
         None => Ok(None)
     }
 }
 
-/// The next section contains the methods used to access the diffs needed to downgrade.
-/// MBF only supports downgrading from the latest version to latest moddable, but this implementation does support having a diff from any version to any other version.
+// The next section contains the methods used to access the diffs needed to downgrade.
+// MBF only supports downgrading from the latest version to latest moddable, but this implementation does support having a diff from any version to any other version.
+// If there is no diff straight to a version, the diffs between the versions in between are applied one after another.
+
+// We just use one github release with a JSON file attached to it that explains the content of the other files attached,
+// since there is no quota on the total size of a release.
 
-/// We just use one github release with a JSON file attached to it that explains the content of the other files attached,
-/// since there is no quota on the total size of a release.
+// The base URLs of the hosts serving the diff repository, in the order they are tried. Each must serve the same files.
+// Users who can't reach these can add their own mirrors with a `SetDiffMirrors` request, which are saved to DIFF_MIRRORS_PATH.
+const DIFF_MIRRORS: &[&str] = &[
+    "https://github.com/Lauriethefish/mbf-diffs/releases/download/1.0.0"
+];
 
-const DIFF_INDEX_STEM: &str = "https://github.com/Lauriethefish/mbf-diffs/releases/download/1.0.0";
+// The diff repository mirrors added by the user, which are tried before the built-in ones.
+const DIFF_MIRRORS_PATH: &str = "/sdcard/ModsBeforeFriday/diff_mirrors.json";
+
+// The mirror that last served a file successfully, which is tried first for later files.
+static WORKING_MIRROR: Mutex<Option<String>> = Mutex::new(None);
+
+// The diff index, once it has been fetched. The index changes rarely, so it is only fetched once per agent process.
+static DIFF_INDEX_CACHE: Mutex<Option<DiffIndex>> = Mutex::new(None);
 
 pub type DiffIndex = Vec<VersionDiffs>;
 
@@ -118,14 +224,312 @@ pub struct Diff {
     pub file_crc: u32,
     pub output_file_name: String,
     pub output_crc: u32,
-    pub output_size: usize
+    pub output_size: usize,
+    // The size of the file before the diff is applied. Older diffs don't give this.
+    #[serde(default)]
+    pub file_size: Option<usize>,
+    // The hex encoded SHA-256 hash of the diff file itself, used to check that it downloaded correctly. Older diffs don't give this.
+    #[serde(default)]
+    pub diff_sha256: Option<String>,
+    // The size of the diff file itself. Older diffs don't give this.
+    #[serde(default)]
+    pub diff_size: Option<usize>
+}
+
+impl VersionDiffs {
+    /// Gets the total size of the diffs needed to downgrade, or None if any diff doesn't give its size.
+    pub fn download_size(&self) -> Option<u64> {
+        self.obb_diffs.iter()
+            .chain(std::iter::once(&self.apk_diff))
+            .map(|diff| diff.diff_size.map(|size| size as u64))
+            .sum()
+    }
+}
+
+/// Gets the diff index, fetching it from the first diff mirror that can be reached if it hasn't already been fetched.
+pub fn get_diff_index(agent: &ureq::Agent) -> Result<DiffIndex, JsonPullError> {
+    if let Some(index) = &*DIFF_INDEX_CACHE.lock().unwrap_or_else(|err| err.into_inner()) {
+        return Ok(index.clone());
+    }
+
+    let mut last_err = None;
+    for mirror in get_diff_mirrors() {
+        match fetch_string(agent, &format!("{mirror}/index.json")) {
+            Ok(index) => {
+                set_working_mirror(&mirror);
+                let (index, health) = parse_tolerant_list(&index, describe_diff)?;
+                record_health(DIFF_INDEX, health);
+                *DIFF_INDEX_CACHE.lock().unwrap_or_else(|err| err.into_inner()) = Some(index.clone());
+                return Ok(index);
+            },
+            Err(JsonPullError::FetchError(err)) => {
+                warn!("Failed to fetch diff index from {mirror}: {err}");
+                last_err = Some(JsonPullError::FetchError(err));
+            },
+            // The mirror was reached, so another mirror would give the same index.
+            Err(err) => return Err(err)
+        }
+    }
+
+    Err(last_err.expect("No diff mirrors given"))
+}
+
+/// Finds every version that can be reached from `from_version` by applying the diffs in the index one after another.
+/// Gives the shortest sequence of diffs needed to reach each version, so versions with a direct diff are reached in one hop.
+pub fn find_downgrade_paths(index: &DiffIndex, from_version: &str) -> BTreeMap<String, Vec<VersionDiffs>> {
+    let mut paths: BTreeMap<String, Vec<VersionDiffs>> = BTreeMap::new();
+    let mut to_visit = VecDeque::from([(from_version.to_string(), Vec::new())]);
+    // Breadth first, so each version is first reached by one of the shortest paths to it.
+    while let Some((version, path)) = to_visit.pop_front() {
+        for diffs in index.iter().filter(|diffs| diffs.from_version == version) {
+            if diffs.to_version == from_version || paths.contains_key(&diffs.to_version) {
+                continue;
+            }
+
+            let mut next_path: Vec<VersionDiffs> = path.clone();
+            next_path.push(diffs.clone());
+            paths.insert(diffs.to_version.clone(), next_path.clone());
+            to_visit.push_back((diffs.to_version.clone(), next_path));
+        }
+    }
+
+    paths
+}
+
+/// Finds the shortest sequence of diffs in the index that downgrades from `from_version` to `to_version`, or None if there isn't one.
+pub fn find_downgrade_path(index: &DiffIndex, from_version: &str, to_version: &str) -> Option<Vec<VersionDiffs>> {
+    find_downgrade_paths(index, from_version).remove(to_version)
+}
+
+/// Gets the base URLs of the diff repository mirrors, in the order they should be tried: the mirror that last worked, then the others.
+/// The mirrors added by the user come before the built-in ones, since they were added because the built-in ones couldn't be reached.
+pub fn get_diff_mirrors() -> Vec<String> {
+    let configured = match load_configured_mirrors() {
+        Ok(configured) => configured,
+        Err(err) => {
+            warn!("Failed to load diff mirrors, so only the built-in mirrors will be used: {err}");
+            Vec::new()
+        }
+    };
+
+    let working = WORKING_MIRROR.lock().unwrap_or_else(|err| err.into_inner()).clone();
+    order_mirrors(configured, working.as_deref())
+}
+
+// Combines the mirrors added by the user with the built-in mirrors, leaving out any duplicates, then moves `working` to the front.
+fn order_mirrors(configured: Vec<String>, working: Option<&str>) -> Vec<String> {
+    let mut mirrors: Vec<String> = Vec::new();
+    for mirror in configured.into_iter().chain(DIFF_MIRRORS.iter().map(|mirror| mirror.to_string())) {
+        if !mirrors.contains(&mirror) {
+            mirrors.push(mirror);
+        }
+    }
+
+    if let Some(index) = working.and_then(|working| mirrors.iter().position(|mirror| mirror == working)) {
+        mirrors[..=index].rotate_right(1);
+    }
+    mirrors
+}
+
+/// Records that `mirror` served a file successfully, so that it is tried first for the rest of this request.
+pub fn set_working_mirror(mirror: &str) {
+    let mut working = WORKING_MIRROR.lock().unwrap_or_else(|err| err.into_inner());
+    if working.as_deref() != Some(mirror) {
+        info!("Using diff mirror {mirror}");
+        *working = Some(mirror.to_string());
+    }
 }
 
-pub fn get_diff_index() -> Result<DiffIndex, JsonPullError> {
-    fetch_json(&format!("{DIFF_INDEX_STEM}/index.json"))
+/// Replaces the mirrors added by the user with `mirrors`, which are tried in the given order before the built-in mirrors.
+/// Each must be the HTTPS base URL of a copy of the diff repository. Gives every mirror, in the order they will be tried.
+pub fn set_configured_mirrors(mirrors: Vec<String>) -> Result<Vec<String>> {
+    let mirrors = mirrors.iter()
+        .map(|mirror| normalise_mirror(mirror))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mirrors_path = Path::new(DIFF_MIRRORS_PATH);
+    if mirrors.is_empty() {
+        if mirrors_path.exists() {
+            std::fs::remove_file(mirrors_path).context("Failed to remove diff mirrors")?;
+        }
+    }   else    {
+        std::fs::create_dir_all(mirrors_path.parent().unwrap()).context("Failed to create diff mirrors directory")?;
+        // Written to a temporary file and then renamed into place so that the mirrors are never left half-written.
+        let temp_path = mirrors_path.with_extension("json.tmp");
+        let mut handle = std::fs::File::create(&temp_path).context("Failed to create diff mirrors")?;
+        handle.write_all(&serde_json::to_vec_pretty(&mirrors)?)?;
+        handle.sync_all()?;
+        std::fs::rename(&temp_path, mirrors_path).context("Failed to save diff mirrors")?;
+    }
+
+    info!("Diff mirrors set to: {}", if mirrors.is_empty() { "built-in only".to_string() } else { mirrors.join(", ") });
+    Ok(order_mirrors(mirrors, None))
 }
 
-pub fn get_diff_url(diff: &Diff) -> String {
-    format!("{DIFF_INDEX_STEM}/{}", diff.diff_name)
+fn load_configured_mirrors() -> Result<Vec<String>> {
+    if !Path::new(DIFF_MIRRORS_PATH).exists() {
+        return Ok(Vec::new());
+    }
+
+    let mirrors_json = std::fs::read(DIFF_MIRRORS_PATH).context("Failed to read diff mirrors")?;
+    serde_json::from_slice(&mirrors_json).context("Diff mirrors were invalid JSON")
 }
 
+// Checks that `mirror` is an HTTPS URL, since the identity of the host must be pinned, and removes any trailing slashes.
+fn normalise_mirror(mirror: &str) -> Result<String> {
+    let mirror = mirror.trim().trim_end_matches('/');
+    match mirror.strip_prefix("https://") {
+        Some(host_and_path) if !host_and_path.is_empty() && !mirror.contains(char::is_whitespace) => Ok(mirror.to_string()),
+        _ => Err(anyhow!("Diff mirror {mirror:?} is not an HTTPS URL"))
+    }
+}
+
+// Describes a diff in the diff index by the versions it goes between, if these can be read.
+fn describe_diff(diff: &serde_json::Value) -> String {
+    let version = |field: &str| diff.get(field).and_then(|version| version.as_str()).unwrap_or("unknown").to_string();
+    format!("{} to {}", version("from_version"), version("to_version"))
+}
+
+/// Gets the URL of the given diff on `mirror`, which should be one of those given by `get_diff_mirrors`.
+pub fn get_diff_url(mirror: &str, diff: &Diff) -> String {
+    format!("{mirror}/{}", diff.diff_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A core mod index as a newer version of the resources repository might give it:
+    // with fields this version doesn't know about, and some entries in a format it can't read.
+    const FUTURE_CORE_MODS: &str = r#"{
+        "1.37.0_9064817954": {
+            "lastUpdated": "2024-07-01T00:00:00Z",
+            "mods": [
+                { "id": "scotland2", "version": "0.1.4", "downloadLink": "https://example.com/sl2.qmod", "sha256": "00ff" },
+                { "id": "beatsaber-hook", "version": "5.1.9", "downloadLink": "https://example.com/bshook.qmod" }
+            ]
+        },
+        "1.40.0_1234": {
+            "mods": [
+                { "id": "scotland2", "version": "0.2.0", "downloadLink": "https://example.com/sl2.qmod" },
+                { "id": "newer-mod", "version": { "major": 1 }, "downloadLinks": ["https://example.com/a.qmod"] }
+            ]
+        },
+        "1.41.0_5678": { "modsByLoader": { "scotland2": [] } },
+        "1.35.0_8016709773": { "mods": [] }
+    }"#;
+
+    fn diff_json(from_version: &str, to_version: &str) -> serde_json::Value {
+        let diff = |name: &str| serde_json::json!({
+            "diff_name": format!("{name}.diff"),
+            "file_name": name,
+            "file_crc": 1,
+            "output_file_name": name,
+            "output_crc": 2,
+            "output_size": 3
+        });
+
+        serde_json::json!({
+            "from_version": from_version,
+            "to_version": to_version,
+            "apk_diff": diff("base.apk"),
+            "obb_diffs": [diff("main.obb")]
+        })
+    }
+
+    // A diff index with an entry with extra fields, one with a field of the wrong type, and one that isn't an entry at all.
+    fn future_diff_index() -> String {
+        let mut with_extra_fields = diff_json("1.37.0_9064817954", "1.35.0_8016709773");
+        with_extra_fields["patch_format"] = "zstd".into();
+        with_extra_fields["apk_diff"]["chunks"] = serde_json::json!([{ "offset": 0 }]);
+
+        let mut corrupt = diff_json("1.40.0_1234", "1.37.0_9064817954");
+        corrupt["apk_diff"]["file_crc"] = "not a number".into();
+
+        serde_json::json!([
+            with_extra_fields,
+            corrupt,
+            "unexpected",
+            diff_json("1.36.2_7040682315", "1.35.0_8016709773")
+        ]).to_string()
+    }
+
+    #[test]
+    fn core_mods_with_unknown_fields_are_read() {
+        let (index, health) = parse_tolerant_map::<VersionedCoreMods>(FUTURE_CORE_MODS).unwrap();
+
+        let mods = &index["1.37.0_9064817954"].mods;
+        assert_eq!(mods.iter().map(|core_mod| core_mod.id.as_str()).collect::<Vec<_>>(), ["scotland2", "beatsaber-hook"]);
+        assert_eq!(mods[1].version, Version::new(5, 1, 9));
+        assert!(index["1.35.0_8016709773"].mods.is_empty());
+        assert_eq!(health.parsed, 2);
+    }
+
+    #[test]
+    fn core_mod_versions_that_cannot_be_read_are_skipped_whole() {
+        let (index, health) = parse_tolerant_map::<VersionedCoreMods>(FUTURE_CORE_MODS).unwrap();
+
+        // One of the core mods for 1.40.0 couldn't be read, so none of them are used.
+        assert!(!index.contains_key("1.40.0_1234"));
+        assert!(!index.contains_key("1.41.0_5678"));
+        assert_eq!(health.skipped, ["1.40.0_1234", "1.41.0_5678"]);
+    }
+
+    #[test]
+    fn diff_entries_that_cannot_be_read_are_skipped() {
+        let (index, health) = parse_tolerant_list::<VersionDiffs>(&future_diff_index(), describe_diff).unwrap();
+
+        assert_eq!(index.iter().map(|diffs| (diffs.from_version.as_str(), diffs.to_version.as_str())).collect::<Vec<_>>(), [
+            ("1.37.0_9064817954", "1.35.0_8016709773"),
+            ("1.36.2_7040682315", "1.35.0_8016709773")
+        ]);
+        assert_eq!(index[0].apk_diff.file_size, None);
+        assert_eq!(health.parsed, 2);
+        assert_eq!(health.skipped, ["1.40.0_1234 to 1.37.0_9064817954", "unknown to unknown"]);
+    }
+
+    #[test]
+    fn skipped_diffs_are_not_used_for_downgrading() {
+        let (index, _) = parse_tolerant_list::<VersionDiffs>(&future_diff_index(), describe_diff).unwrap();
+
+        assert!(find_downgrade_paths(&index, "1.40.0_1234").is_empty());
+        assert_eq!(find_downgrade_path(&index, "1.37.0_9064817954", "1.35.0_8016709773").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn index_that_is_not_json_fails() {
+        assert!(matches!(parse_tolerant_map::<VersionedCoreMods>("<html>"), Err(JsonPullError::ParseError(_))));
+        assert!(matches!(parse_tolerant_list::<VersionDiffs>("{}", describe_diff), Err(JsonPullError::ParseError(_))));
+    }
+
+    #[test]
+    fn configured_mirrors_are_tried_before_the_built_in_ones() {
+        let configured = vec!["https://mirror.example.com/diffs".to_string(), DIFF_MIRRORS[0].to_string()];
+        let mut expected = vec!["https://mirror.example.com/diffs".to_string()];
+        expected.extend(DIFF_MIRRORS.iter().map(|mirror| mirror.to_string()));
+
+        assert_eq!(order_mirrors(configured, None), expected, "duplicates of built-in mirrors should be left out");
+        assert_eq!(order_mirrors(Vec::new(), None), DIFF_MIRRORS);
+    }
+
+    #[test]
+    fn working_mirror_is_tried_first() {
+        let configured = vec!["https://a.example.com".to_string(), "https://b.example.com".to_string()];
+        let mirrors = order_mirrors(configured.clone(), Some("https://b.example.com"));
+        assert_eq!(mirrors[..2], ["https://b.example.com", "https://a.example.com"]);
+        assert_eq!(mirrors.len(), 2 + DIFF_MIRRORS.len());
+
+        let mirrors = order_mirrors(configured, Some(DIFF_MIRRORS[0]));
+        assert_eq!(mirrors[..3], [DIFF_MIRRORS[0], "https://a.example.com", "https://b.example.com"]);
+        // A mirror that is no longer configured is ignored.
+        assert_eq!(order_mirrors(Vec::new(), Some("https://gone.example.com")), DIFF_MIRRORS);
+    }
+
+    #[test]
+    fn mirrors_must_be_https_urls() {
+        assert_eq!(normalise_mirror(" https://mirror.example.com/diffs/ ").unwrap(), "https://mirror.example.com/diffs");
+        for mirror in ["http://mirror.example.com", "mirror.example.com", "https://", "https://mirror example.com", ""] {
+            assert!(normalise_mirror(mirror).is_err(), "{mirror:?} should be refused");
+        }
+    }
+}
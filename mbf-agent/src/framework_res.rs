@@ -0,0 +1,80 @@
+//! Loads the resource IDs of the attributes used when patching the manifest from the device's framework, so that they are
+//! correct for the quest's Android version and include attributes missing from the table bundled with the agent.
+//! The framework's resource table is large, so the IDs read are cached, keyed by the size and modification time of the framework.
+
+use std::{path::Path, time::UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{fs_ops, manifest::ResourceIds, RES_IDS_CACHE_PATH};
+
+const FRAMEWORK_APK_PATH: &str = "/system/framework/framework-res.apk";
+
+#[derive(Serialize, Deserialize, PartialEq)]
+struct CacheKey {
+    size: u64,
+    // Nanoseconds since the UNIX epoch
+    modified: u128
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    key: CacheKey,
+    res_ids: ResourceIds
+}
+
+/// Gets the resource IDs from the device's framework, reading them from the cache if the framework hasn't changed since then.
+/// If the framework can't be read, the table bundled with the agent is used instead.
+pub fn load_resource_ids() -> ResourceIds {
+    match load_from_framework() {
+        Ok(res_ids) => res_ids,
+        Err(err) => {
+            warn!("Failed to read resource IDs from the framework, so using the bundled IDs: {err:?}");
+            ResourceIds::load().expect("Bundled resource IDs should be valid")
+        }
+    }
+}
+
+fn load_from_framework() -> Result<ResourceIds> {
+    let key = get_key()?;
+    if let Some(entry) = load_entry() {
+        if entry.key == key {
+            return Ok(entry.res_ids);
+        }
+    }
+
+    info!("Reading resource IDs from {FRAMEWORK_APK_PATH}");
+    let res_ids = ResourceIds::load_from_framework(fs_ops::open(FRAMEWORK_APK_PATH)?)?;
+    let entry = CacheEntry { key, res_ids };
+    if let Err(err) = save_entry(&entry) {
+        warn!("Failed to cache resource IDs: {err}");
+    }
+
+    Ok(entry.res_ids)
+}
+
+fn get_key() -> Result<CacheKey> {
+    let metadata = std::fs::metadata(FRAMEWORK_APK_PATH).context("Failed to get framework metadata")?;
+    Ok(CacheKey {
+        size: metadata.len(),
+        modified: metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+    })
+}
+
+fn load_entry() -> Option<CacheEntry> {
+    if !Path::new(RES_IDS_CACHE_PATH).exists() {
+        return None;
+    }
+
+    let contents = std::fs::read(RES_IDS_CACHE_PATH).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+fn save_entry(entry: &CacheEntry) -> Result<()> {
+    let mut contents = serde_json::to_vec(entry)?;
+    contents.push(b'\n');
+    std::fs::write(RES_IDS_CACHE_PATH, contents)?;
+    Ok(())
+}
@@ -0,0 +1,278 @@
+//! Wrappers around the filesystem operations in `std::fs` which, on failure, give an `IoFailure` containing the
+//! operation and path(s) involved, so that the frontend can tell the user which file caused a problem.
+
+use std::{fmt::Display, fs::{File, OpenOptions, ReadDir}, io::ErrorKind, ops::Deref, os::fd::AsRawFd, path::{Path, PathBuf}};
+
+use serde::Serialize;
+
+use crate::requests::Response;
+
+/// A filesystem operation carried out by the agent.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub enum IoOp {
+    Copy,
+    Open,
+    Remove,
+    CreateDir,
+    Rename,
+    ReadDir,
+    Link,
+    Map
+}
+
+/// A filesystem operation that failed, along with the path(s) it was carried out on.
+#[derive(Debug)]
+pub struct IoFailure {
+    pub operation: IoOp,
+    pub path: PathBuf,
+    /// The destination path, for copy and rename operations.
+    pub secondary_path: Option<PathBuf>,
+    pub source: std::io::Error
+}
+
+impl IoFailure {
+    pub fn kind(&self) -> ErrorKind {
+        self.source.kind()
+    }
+}
+
+impl Display for IoFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let verb = match self.operation {
+            IoOp::Copy => "copy",
+            IoOp::Open => "open",
+            IoOp::Remove => "remove",
+            IoOp::CreateDir => "create directory",
+            IoOp::Rename => "rename",
+            IoOp::ReadDir => "read directory",
+            IoOp::Link => "link",
+            IoOp::Map => "memory map"
+        };
+
+        write!(f, "Failed to {verb} {:?}", self.path)?;
+        if let Some(secondary_path) = &self.secondary_path {
+            write!(f, " to {secondary_path:?}")?;
+        }
+        write!(f, ": {}", self.source)
+    }
+}
+
+// The source isn't given as the cause, since the message already includes it, and chains of errors would otherwise give it twice.
+// The kind of the source is given by `kind` instead.
+impl std::error::Error for IoFailure {}
+
+impl From<&IoFailure> for Response {
+    fn from(failure: &IoFailure) -> Self {
+        Response::IoFailure {
+            operation: failure.operation,
+            path: failure.path.to_string_lossy().to_string(),
+            secondary_path: failure.secondary_path.as_ref().map(|path| path.to_string_lossy().to_string()),
+            kind: format!("{:?}", failure.kind()),
+            message: failure.source.to_string()
+        }
+    }
+}
+
+fn failure(operation: IoOp, path: &Path, secondary_path: Option<&Path>) -> impl FnOnce(std::io::Error) -> IoFailure {
+    let path = path.to_path_buf();
+    let secondary_path = secondary_path.map(Path::to_path_buf);
+    move |source| IoFailure {
+        operation,
+        path,
+        secondary_path,
+        source
+    }
+}
+
+pub fn copy(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<u64, IoFailure> {
+    std::fs::copy(&from, &to).map_err(failure(IoOp::Copy, from.as_ref(), Some(to.as_ref())))
+}
+
+pub fn rename(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), IoFailure> {
+    std::fs::rename(&from, &to).map_err(failure(IoOp::Rename, from.as_ref(), Some(to.as_ref())))
+}
+
+pub fn hard_link(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), IoFailure> {
+    std::fs::hard_link(&from, &to).map_err(failure(IoOp::Link, from.as_ref(), Some(to.as_ref())))
+}
+
+pub fn remove_file(path: impl AsRef<Path>) -> Result<(), IoFailure> {
+    std::fs::remove_file(&path).map_err(failure(IoOp::Remove, path.as_ref(), None))
+}
+
+pub fn remove_dir_all(path: impl AsRef<Path>) -> Result<(), IoFailure> {
+    std::fs::remove_dir_all(&path).map_err(failure(IoOp::Remove, path.as_ref(), None))
+}
+
+pub fn create_dir_all(path: impl AsRef<Path>) -> Result<(), IoFailure> {
+    std::fs::create_dir_all(&path).map_err(failure(IoOp::CreateDir, path.as_ref(), None))
+}
+
+pub fn read_dir(path: impl AsRef<Path>) -> Result<ReadDir, IoFailure> {
+    std::fs::read_dir(&path).map_err(failure(IoOp::ReadDir, path.as_ref(), None))
+}
+
+/// Opens the file at the given path for reading.
+pub fn open(path: impl AsRef<Path>) -> Result<File, IoFailure> {
+    File::open(&path).map_err(failure(IoOp::Open, path.as_ref(), None))
+}
+
+/// Opens the file at the given path with the given options.
+pub fn open_with(options: &OpenOptions, path: impl AsRef<Path>) -> Result<File, IoFailure> {
+    options.open(&path).map_err(failure(IoOp::Open, path.as_ref(), None))
+}
+
+/// A file mapped read-only into memory.
+/// The mapped pages are backed by the file, so the kernel can drop them under memory pressure rather than killing the agent,
+/// which makes this suitable for files larger than the available RAM.
+///
+/// Reading a page of the mapping after the file has been truncated raises `SIGBUS`, which kills the agent, and any other change
+/// to the file changes the mapped bytes out from under the slice given by `Deref`. Only files created by the agent within its
+/// own temporary directory should be mapped, so that nothing else can modify them while they are mapped.
+pub struct MappedFile {
+    ptr: *mut libc::c_void,
+    len: usize
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.len == 0 {
+            &[]
+        }   else {
+            // SAFETY: the mapping is `len` bytes long, and lives until `self` is dropped.
+            // The slice is only valid while the file is neither truncated nor written to. This holds as `map` is only
+            // used for files owned by the agent, which the agent doesn't modify until the mapping is dropped.
+            unsafe { std::slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            // SAFETY: `ptr` and `len` are those given by (and returned from) `mmap`.
+            unsafe { libc::munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+/// Maps the file at the given path into memory, advising the kernel that it will mostly be read sequentially.
+/// The file must be owned by the agent and must not be modified while mapped. See `MappedFile`.
+pub fn map(path: impl AsRef<Path>) -> Result<MappedFile, IoFailure> {
+    let map_failure = failure(IoOp::Map, path.as_ref(), None);
+    let file = open(&path)?;
+    let len = match file.metadata() {
+        Ok(metadata) => metadata.len() as usize,
+        Err(err) => return Err(map_failure(err))
+    };
+    // `mmap` fails for empty files.
+    if len == 0 {
+        return Ok(MappedFile { ptr: std::ptr::null_mut(), len });
+    }
+
+    // SAFETY: a new read-only mapping is created, which doesn't alias any existing memory.
+    let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ, libc::MAP_PRIVATE, file.as_raw_fd(), 0) };
+    if ptr == libc::MAP_FAILED {
+        return Err(map_failure(std::io::Error::last_os_error()));
+    }
+    // The advice is only a hint, so failing to give it doesn't matter.
+    // SAFETY: `ptr` and `len` describe the mapping just created.
+    unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+
+    Ok(MappedFile { ptr, len })
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{Context, Result};
+
+    use super::*;
+
+    // Gives a path in the temporary directory for a test, which doesn't exist.
+    fn missing_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mbf-fs-ops-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    // Each message is the message given before `IoFailure` was added, followed by the operation, the path(s) and the OS error.
+    #[test]
+    fn messages_include_the_previous_context_and_the_paths() {
+        let (apk, temp_apk) = (missing_path("base.apk"), missing_path("mbf-tmp.apk"));
+        let err = copy(&apk, &temp_apk).context("Failed to copy APK to temp").unwrap_err();
+        assert_eq!(format!("{err:#}"), format!("Failed to copy APK to temp: Failed to copy {apk:?} to {temp_apk:?}: No such file or directory (os error 2)"));
+
+        let err = open(&apk).context("Failed to open APK to patch").unwrap_err();
+        assert_eq!(format!("{err:#}"), format!("Failed to open APK to patch: Failed to open {apk:?}: No such file or directory (os error 2)"));
+
+        let obb_dir = missing_path("obb");
+        let err = read_dir(&obb_dir).context("Failed to read obb directory").unwrap_err();
+        assert_eq!(format!("{err:#}"), format!("Failed to read obb directory: Failed to read directory {obb_dir:?}: No such file or directory (os error 2)"));
+
+        let err = remove_dir_all(&obb_dir).context("Failed to remove directory").unwrap_err();
+        assert_eq!(format!("{err:#}"), format!("Failed to remove directory: Failed to remove {obb_dir:?}: No such file or directory (os error 2)"));
+
+        let pins = missing_path("repository_pins.json");
+        let err = rename(pins.with_extension("json.tmp"), &pins).context("Failed to save repository pins").unwrap_err();
+        assert_eq!(format!("{err:#}"), format!("Failed to save repository pins: Failed to rename {:?} to {pins:?}: No such file or directory (os error 2)",
+            pins.with_extension("json.tmp")));
+    }
+
+    #[test]
+    fn a_file_in_place_of_a_directory_fails_creating_it() {
+        let file = missing_path("not-a-dir");
+        std::fs::write(&file, b"").unwrap();
+        let err = create_dir_all(file.join("export")).context("Failed to create export directory").unwrap_err();
+        assert!(format!("{err:#}").starts_with(&format!("Failed to create export directory: Failed to create directory {:?}: ", file.join("export"))));
+        assert_eq!(err.downcast_ref::<IoFailure>().unwrap().kind(), ErrorKind::NotADirectory);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn failure_is_found_within_a_chain_of_context() {
+        let result: Result<File> = open(missing_path("chain")).context("Failed to open mod archive").context("Failed to import mod");
+        let err = result.unwrap_err();
+        let failure = err.chain().find_map(|cause| cause.downcast_ref::<IoFailure>()).unwrap();
+        assert!(matches!(failure.operation, IoOp::Open));
+        assert_eq!(failure.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn serialized_failure_gives_the_kind_name_and_paths() {
+        let failure = IoFailure {
+            operation: IoOp::Copy,
+            path: PathBuf::from("/data/app/base.apk"),
+            secondary_path: Some(PathBuf::from("/data/local/tmp/mbf-tmp/mbf-tmp.apk")),
+            source: ErrorKind::StorageFull.into()
+        };
+
+        let json = serde_json::to_value(Response::from(&failure)).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "type": "IoFailure",
+            "operation": "Copy",
+            "path": "/data/app/base.apk",
+            "secondary_path": "/data/local/tmp/mbf-tmp/mbf-tmp.apk",
+            "kind": "StorageFull",
+            "message": std::io::Error::from(ErrorKind::StorageFull).to_string()
+        }));
+    }
+
+    #[test]
+    fn serialized_failure_without_a_destination_has_a_null_secondary_path() {
+        let failure = IoFailure {
+            operation: IoOp::Remove,
+            path: PathBuf::from("/sdcard/ModData/com.beatgames.beatsaber/Mods/libchroma.so"),
+            secondary_path: None,
+            source: std::io::Error::from_raw_os_error(libc::EACCES)
+        };
+
+        let json = serde_json::to_value(Response::from(&failure)).unwrap();
+        assert_eq!(json["operation"], "Remove");
+        assert_eq!(json["kind"], "PermissionDenied");
+        assert_eq!(json["secondary_path"], serde_json::Value::Null);
+        assert_eq!(json["message"], "Permission denied (os error 13)");
+    }
+}
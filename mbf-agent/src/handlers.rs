@@ -1,13 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
 
-use crate::{data_fix, download_file_with_attempts, DATAKEEPER_PATH, DOWNLOADS_PATH, PLAYER_DATA_BAK_PATH, PLAYER_DATA_PATH, SONGS_PATH, TEMP_PATH};
-use crate::{axml::AxmlReader, patching, zip::ZipFile};
-use crate::external_res::{get_diff_index, JsonPullError};
-use crate::manifest::{ManifestInfo, ManifestMod};
+use crate::{apk_cache, data_fix, obb_recovery, player_data, download_file_with_attempts, fs_ops, pinning, apk_id, is_beat_saber, DATAKEEPER_PATH, DOWNLOADS_PATH, songs_path, VANILLA_BACKUP_PATH};
+use crate::{patching, setup, text, volumes, working_dir::WorkingDir, zip::ZipFile};
+use crate::axml::{self, AxmlReader};
+use crate::composition::{ApkComposition, CompositionDelta};
+use crate::external_res::{self, get_diff_index, CoreModIndex, JsonPullError, VersionDiffs};
+use crate::manifest::ManifestMod;
 use crate::mod_man::ModManager;
-use crate::requests::{AppInfo, CoreModsInfo, ModModel, Request, Response};
+use crate::patching::{DiffOptions, PatchOptions, SigningKey};
+use crate::requests::{AppInfo, CoreModsInfo, DowngradeOption, ModModel, BuildVariant, PatchOutput, Request, Response, SigningCert, StorageStrategy};
 use anyhow::{anyhow, Context, Result};
 use log::{error, info, warn};
 
@@ -15,22 +18,77 @@ use log::{error, info, warn};
 pub fn handle_request(request: Request) -> Result<Response> {
     match request {
         Request::GetModStatus => handle_get_mod_status(),
-        Request::Patch { downgrade_to , remodding, manifest_mod, allow_no_core_mods} => 
-            handle_patch(downgrade_to, remodding, manifest_mod, allow_no_core_mods),
+        Request::GetSetupStatus => handle_get_setup_status(),
+        Request::GetApkComposition { path, reference } => handle_get_apk_composition(path, reference),
+        Request::GetManifest { path } => handle_get_manifest(path),
+        Request::GetApkEntries { path } => handle_get_apk_entries(path),
+        Request::GetDowngradeOptions => handle_get_downgrade_options(),
+        Request::Patch { downgrade_to , remodding, manifest_mod, allow_no_core_mods, copy_apk_first, sequential_stages, sequential_downloads, working_dir, local_diffs_dir, confirmed_download_size, keep_vanilla_backup, output, extra_permissions, extra_features, signing_cert, allow_signer_change } => {
+            let signing_key = load_signing_key(signing_cert, None)?.allowing_signer_change(allow_signer_change);
+            let manifest_mod = with_extras(manifest_mod, &extra_permissions, &extra_features);
+            match output {
+                PatchOutput::Install => handle_patch(downgrade_to, remodding, manifest_mod, allow_no_core_mods, PatchOptions {
+                    copy_apk_first,
+                    sequential_stages,
+                    keep_vanilla_backup,
+                    signing_key
+                }, DiffOptions {
+                    sequential_downloads,
+                    local_dir: local_diffs_dir.map(PathBuf::from),
+                    confirmed_download_size
+                }, working_dir),
+                PatchOutput::Export { destination, install_modloader } => {
+                    if downgrade_to.is_some() {
+                        return Err(anyhow!("Downgrading is not supported when exporting a patched APK"));
+                    }
+
+                    handle_export(remodding, manifest_mod, destination, install_modloader, working_dir, &signing_key)
+                },
+                PatchOutput::DryRun => {
+                    if downgrade_to.is_some() {
+                        return Err(anyhow!("Downgrading is not supported for a dry run"));
+                    }
+
+                    handle_patch_dry_run(remodding, manifest_mod, copy_apk_first, keep_vanilla_backup, working_dir, &signing_key)
+                }
+            }
+        },
+        Request::PatchApkFile { input, output, manifest_mod, remodding, libunity_path, allow_other_package, signing_cert } => {
+            // The patched file isn't installed by MBF, so a change of certificate is only warned about.
+            let signing_key = load_signing_key(signing_cert, Some(Path::new(&input)))?.allowing_signer_change(true);
+            handle_patch_apk_file(input, output, manifest_mod, remodding, libunity_path, allow_other_package, &signing_key)
+        },
         Request::SetModsEnabled {
-            statuses
-        } => run_mod_action(statuses),
-        Request::QuickFix => handle_quick_fix(),
-        Request::Import { from_path } => handle_import(from_path),
+            statuses,
+            allow_conflicts,
+            force_version_mismatch
+        } => run_mod_action(statuses, allow_conflicts, force_version_mismatch),
+        Request::QuickFix { force_modloader } => handle_quick_fix(force_modloader),
+        Request::Import { from_path, allow_conflicts } => handle_import(from_path, allow_conflicts),
         Request::RemoveMod { id } => handle_remove_mod(id),
-        Request::ImportModUrl { from_url } => handle_import_mod_url(from_url),
-        Request::FixPlayerData => handle_fix_player_data()
+        Request::ImportModUrl { from_url, allow_conflicts } => handle_import_mod_url(from_url, allow_conflicts),
+        Request::FixPlayerData => handle_fix_player_data(),
+        Request::RepairObbs => Ok(Response::RepairedObbs { restored: obb_recovery::recover()? }),
+        Request::RestoreVanilla => handle_restore_vanilla(),
+        Request::BackupPlayerData => handle_backup_player_data(),
+        Request::ListPlayerDataBackups => Ok(Response::PlayerDataBackups { backups: player_data::list_snapshots()? }),
+        Request::RestorePlayerData { id } => handle_restore_player_data(id),
+        Request::ApplyLegacyStorageFallback => handle_apply_legacy_storage(),
+        Request::DiagnoseCrash => handle_diagnose_crash(),
+        Request::TrustRepositoryIdentity { host, identity } => handle_trust_repository_identity(host, identity),
+        Request::SetDiffMirrors { mirrors } => Ok(Response::DiffMirrors {
+            mirrors: external_res::set_configured_mirrors(mirrors).context("Failed to set diff mirrors")?
+        })
     }
 }
 
-fn run_mod_action(statuses: HashMap<String, bool>) -> Result<Response> {
+fn run_mod_action(statuses: HashMap<String, bool>, allow_conflicts: bool, force_version_mismatch: bool) -> Result<Response> {
     let mut mod_manager = ModManager::new();
     mod_manager.load_mods().context("Failed to load installed mods")?;
+    mod_manager.set_allow_conflicts(allow_conflicts);
+    if let Some(app_info) = get_app_info()? {
+        mod_manager.set_game_version(app_info.version, force_version_mismatch);
+    }
 
     for (id, new_status) in statuses {
         let mod_rc = match mod_manager.get_mod(&id) {
@@ -85,84 +143,215 @@ fn handle_get_mod_status() -> Result<Response> {
     })
 }
 
+fn handle_get_setup_status() -> Result<Response> {
+    let facts = setup::get_setup_facts()?;
+    let next_step = setup::get_next_step(&facts);
+    info!("Next setup step: {next_step:?}");
+
+    Ok(Response::SetupStatus {
+        facts,
+        next_step
+    })
+}
+
+fn handle_get_apk_composition(path: Option<String>, reference: Option<String>) -> Result<Response> {
+    if [path.as_ref(), reference.as_ref()].iter().flatten().any(|path| !is_within_sdcard(Path::new(path))) {
+        return Err(anyhow!("APK composition can only be read from files within /sdcard/"));
+    }
+
+    let path = match path {
+        Some(path) => path,
+        None => get_app_info()?.ok_or(anyhow!("Beat Saber is not installed"))?.path
+    };
+    let layout = patching::read_apk_layout(Path::new(&path)).context("Failed to read APK")?;
+    let comparison = match reference {
+        Some(reference) => {
+            let reference_layout = patching::read_apk_layout(Path::new(&reference)).context("Failed to read reference APK")?;
+            Some(CompositionDelta::between(&reference_layout, &layout))
+        },
+        None => None
+    };
+
+    Ok(Response::ApkComposition {
+        path,
+        composition: ApkComposition::from_layout(&layout),
+        comparison
+    })
+}
+
+fn handle_get_apk_entries(path: Option<String>) -> Result<Response> {
+    if path.as_ref().is_some_and(|path| !is_within_sdcard(Path::new(path))) {
+        return Err(anyhow!("Entries can only be listed for APKs within /sdcard/"));
+    }
+
+    let path = match path {
+        Some(path) => path,
+        None => get_app_info()?.ok_or(anyhow!("Beat Saber is not installed"))?.path
+    };
+    let apk = ZipFile::open(fs_ops::open(&path)?).context("Failed to read APK as ZIP")?;
+    let mut entries: Vec<_> = apk.iter_entries().collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(Response::ApkEntries {
+        path,
+        entries
+    })
+}
+
+fn handle_get_manifest(path: Option<String>) -> Result<Response> {
+    if path.as_ref().is_some_and(|path| !is_within_sdcard(Path::new(path))) {
+        return Err(anyhow!("The manifest can only be read from APKs within /sdcard/"));
+    }
+
+    let path = match path {
+        Some(path) => path,
+        None => get_app_info()?.ok_or(anyhow!("Beat Saber is not installed"))?.path
+    };
+    let mut apk = ZipFile::open(fs_ops::open(&path)?).context("Failed to read APK as ZIP")?;
+    let manifest = apk.read_file("AndroidManifest.xml").context("Failed to read manifest")?;
+    let mut manifest_reader = Cursor::new(manifest);
+    let mut axml_reader = AxmlReader::new(&mut manifest_reader).context("Failed to read AXML manifest")?;
+
+    Ok(Response::Manifest {
+        path,
+        xml: axml::to_readable_xml(&mut axml_reader).context("Failed to convert manifest to XML")?
+    })
+}
+
+fn handle_get_downgrade_options() -> Result<Response> {
+    let app_info = get_app_info()?
+        .ok_or(anyhow!("Cannot get downgrade options when app not installed"))?;
+
+    info!("Fetching core mod index");
+    let core_mods = match external_res::fetch_core_mods(apk_id()) {
+        Ok(core_mods) => Some(core_mods),
+        Err(JsonPullError::FetchError(err)) => {
+            warn!("Failed to fetch core mod index, so it isn't known which versions have core mods: {err}");
+            None
+        },
+        Err(JsonPullError::ParseError(err)) => return Err(err)
+    };
+
+    let diff_index = get_diff_index(&pinning::pinned_agent()?)
+        .context("Failed to get downgrading information")?;
+    let options = external_res::find_downgrade_paths(&diff_index, &app_info.version)
+        .into_iter()
+        .map(|(to_version, hops)| DowngradeOption {
+            download_size: hops.iter().map(VersionDiffs::download_size).sum(),
+            core_mods_available: core_mods.as_ref().map(|core_mods| core_mods.contains_key(&to_version)),
+            hops: hops.len(),
+            to_version
+        })
+        .collect();
+
+    Ok(Response::DowngradeOptions {
+        from_version: app_info.version,
+        options
+    })
+}
+
 fn get_mod_models(mod_manager: ModManager) -> Vec<ModModel> {
     mod_manager.get_mods()
-        .map(|mod_info| ModModel::from(&*(**mod_info).borrow()))
+        .map(|mod_info| {
+            let mut model = ModModel::from(&*(**mod_info).borrow());
+            if model.is_enabled {
+                model.conflicts_with = mod_manager.find_conflicts(&model.id)
+                    .into_iter()
+                    .map(|conflict| conflict.other_mod_id)
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .collect();
+            }
+
+            model
+        })
         .collect()
 }
 
 fn get_core_mods_info(apk_version: &str, mod_manager: &ModManager) -> Result<Option<CoreModsInfo>> {
     // Fetch the core mods from the resources repo
     info!("Fetching core mod index");
-    let core_mods = match crate::external_res::fetch_core_mods() {
+    let core_mods = match crate::external_res::fetch_core_mods(apk_id()) {
         Ok(mods) => mods,
         Err(JsonPullError::FetchError(_)) => return Ok(None),
         Err(JsonPullError::ParseError(err)) => return Err(err)
     };
 
     // Check that all core mods are installed with an appropriate version
-    let all_core_mods_installed = match core_mods.get(apk_version) {
-        Some(core_mods) => core_mods.mods
-            .iter()
-            .all(|core_mod| match mod_manager.get_mod(&core_mod.id) {
-                None => false,
-                Some(installed_version) => {
-                    let installed_ref = installed_version.borrow();
-                    installed_ref.manifest().version >= core_mod.version && installed_ref.installed()
-                }
-            }),
-        None => false
-    };
+    let all_core_mods_installed = get_missing_core_mods(&core_mods, apk_version, mod_manager)
+        .is_some_and(|missing| missing.is_empty());
     info!("All core mods installed: {}", all_core_mods_installed);
 
+    // Versions that can't be parsed are left out, rather than failing, in case the index uses a new version format.
     let supported_versions: Vec<String> = core_mods.into_keys().filter(|version| {
-        let mut iter = version.split('.');
-        let _major = iter.next().unwrap();
-        let _minor = iter.next().unwrap();
-
-        _minor.parse::<i64>().expect("Invalid version in core mod index") >= 35
+        version.split('.')
+            .nth(1)
+            .and_then(|minor| minor.parse::<i64>().ok())
+            .is_some_and(|minor| minor >= 35)
     }).collect();
 
-    let downgrade_versions: Vec<String> = get_diff_index()
-        .context("Failed to get downgrading information")?
-        .into_iter()
-        .filter(|diff| diff.from_version == apk_version)
-        .map(|diff| diff.to_version)
+    let diff_index = get_diff_index(&pinning::pinned_agent()?)
+        .context("Failed to get downgrading information")?;
+    let downgrade_versions: Vec<String> = external_res::find_downgrade_paths(&diff_index, apk_version)
+        .into_keys()
         .collect();
 
     Ok(Some(CoreModsInfo {
         supported_versions,
         all_core_mods_installed,
-        downgrade_versions
+        downgrade_versions,
+        metadata_health: external_res::get_metadata_health()
     }))
 }
 
-fn get_app_info() -> Result<Option<AppInfo>> {
+/// Gets the IDs of the core mods for `apk_version` that are not installed, or are installed with an older version than required.
+/// Returns None if there are no core mods for the version.
+pub fn get_missing_core_mods(core_mods: &CoreModIndex, apk_version: &str, mod_manager: &ModManager) -> Option<Vec<String>> {
+    Some(core_mods.get(apk_version)?.mods
+        .iter()
+        .filter(|core_mod| match mod_manager.get_mod(&core_mod.id) {
+            None => true,
+            Some(installed_version) => {
+                let installed_ref = installed_version.borrow();
+                installed_ref.manifest().version < core_mod.version || !installed_ref.installed()
+            }
+        })
+        .map(|core_mod| core_mod.id.clone())
+        .collect())
+}
+
+pub fn get_app_info() -> Result<Option<AppInfo>> {
     let apk_path = match crate::get_apk_path().context("Failed to find APK path")? {
         Some(path) => path,
         None => return Ok(None)
     };
 
-    let apk_reader = std::fs::File::open(&apk_path)?;
-    let mut apk = ZipFile::open(apk_reader).context("Failed to read APK as ZIP")?;
+    let summary = apk_cache::get_summary(&apk_path)?;
+    let modloader = summary.loader_installed;
 
-    let modloader = patching::get_modloader_installed(&mut apk)?;
-
-    let manifest = apk.read_file("AndroidManifest.xml").context("Failed to read manifest")?;
-    let mut manifest_reader = Cursor::new(manifest);
-
-    let mut axml_reader = AxmlReader::new(&mut manifest_reader)?;
-    let info = ManifestInfo::read(&mut axml_reader)?;
+    let installer = crate::get_installer_package()?;
+    let build_variant = patching::classify_build(installer.as_deref(), modloader.is_some());
+    info!("Build variant: {build_variant:?} (installed by {}, modded: {})",
+        installer.as_deref().unwrap_or("nothing"),
+        modloader.is_some());
 
     Ok(Some(AppInfo {
+        package_id: apk_id().to_string(),
         loader_installed: modloader,
-        version: info.package_version,
+        version: summary.package_version,
+        version_code: summary.version_code,
+        build_variant,
+        storage_strategy: if summary.legacy_storage {
+            StorageStrategy::Legacy
+        }   else {
+            StorageStrategy::ManageExternalStorage
+        },
         path: apk_path
     }))    
 }
 
-fn handle_import_mod_url(from_url: String) -> Result<Response> {
-    std::fs::create_dir_all(DOWNLOADS_PATH)?;
+fn handle_import_mod_url(from_url: String, allow_conflicts: bool) -> Result<Response> {
+    fs_ops::create_dir_all(DOWNLOADS_PATH)?;
     let download_path = Path::new(DOWNLOADS_PATH).join("import_from_url.qmod");
 
     info!("Downloading {}", from_url);
@@ -171,21 +360,23 @@ fn handle_import_mod_url(from_url: String) -> Result<Response> {
     // Load the installed mods.
     let mut mod_manager = ModManager::new();
     mod_manager.load_mods()?;
+    mod_manager.set_allow_conflicts(allow_conflicts);
     
     // Attempt to import the downloaded file as a qmod, removing the temporary file if this fails.
     match handle_import_qmod(mod_manager, download_path.clone()) {
         Ok(resp) => Ok(resp),
         Err(err) => {
-            std::fs::remove_file(download_path)?;
+            fs_ops::remove_file(download_path)?;
             Err(err)
         }
     }
 }
 
-fn handle_import(from_path: String) -> Result<Response> {
+fn handle_import(from_path: String, allow_conflicts: bool) -> Result<Response> {
     // Load the installed mods.
     let mut mod_manager = ModManager::new();
     mod_manager.load_mods()?;
+    mod_manager.set_allow_conflicts(allow_conflicts);
 
     info!("Attempting to import from {from_path}");
     let path: PathBuf = from_path.into();
@@ -207,7 +398,7 @@ fn handle_import(from_path: String) -> Result<Response> {
     match import_result {
         Ok(resp) => Ok(resp),
         Err(err) => {
-            match std::fs::remove_file(path) {
+            match fs_ops::remove_file(path) {
                 Ok(_) => {},
                 Err(err) => warn!("Failed to remove temporary file: {err}")
             }
@@ -232,8 +423,8 @@ fn handle_import_qmod(mut mod_manager: ModManager, from_path: PathBuf) -> Result
     let installed_mods = get_mod_models(mod_manager); // Drops the mod_manager/the mod file handles
 
     // Copy to a new patch in the mods directory
-    std::fs::copy(&from_path, new_path)?;
-    std::fs::remove_file(from_path)?;
+    fs_ops::copy(&from_path, new_path)?;
+    fs_ops::remove_file(from_path)?;
 
     Ok(Response::ImportedMod {
         imported_id: id,
@@ -246,27 +437,28 @@ fn handle_import_qmod(mut mod_manager: ModManager, from_path: PathBuf) -> Result
 fn attempt_file_copy(from_path: PathBuf, file_ext: String, mod_manager: ModManager) -> Result<Response> {
     for m in mod_manager.get_mods() {
         let mod_ref = (**m).borrow();
-        match mod_ref.manifest()
+        if let Some(copy_ext) = mod_ref.manifest()
             .copy_extensions.iter()
-            .filter(|ext| ext.extension.eq_ignore_ascii_case(&file_ext))
-            .next() 
+            .find(|ext| ext.extension.eq_ignore_ascii_case(&file_ext))
         {
-            Some(copy_ext) => {
-                info!("Copying to {}", copy_ext.destination);
-                let dest_folder = Path::new(&copy_ext.destination);
-                std::fs::create_dir_all(dest_folder).context("Failed to create destination folder")?;
-                let dest_path = dest_folder.join(from_path.file_name().unwrap());
-
-                // Rename is not used as these may be in separate volumes.
-                std::fs::copy(&from_path, &dest_path).context("Failed to copy file")?;
-                std::fs::remove_file(&from_path)?;
-
-                return Ok(Response::ImportedFileCopy {
-                    copied_to: dest_path.to_string_lossy().to_string(),
-                    mod_id: mod_ref.manifest().id.to_string()
-                })
-            },
-            None => {}
+            info!("Copying to {}", copy_ext.destination);
+            let dest_folder = Path::new(&copy_ext.destination);
+            fs_ops::create_dir_all(dest_folder).context("Failed to create destination folder")?;
+            let dest_path = dest_folder.join(from_path.file_name().unwrap());
+
+            // Rename is not used as these may be in separate volumes.
+            fs_ops::copy(&from_path, &dest_path).context("Failed to copy file")?;
+            fs_ops::remove_file(&from_path)?;
+            if text::is_json_path(&dest_path) {
+                if let Err(err) = text::normalise_file(&dest_path) {
+                    warn!("Failed to normalise copied file: {err}");
+                }
+            }
+
+            return Ok(Response::ImportedFileCopy {
+                copied_to: dest_path.to_string_lossy().to_string(),
+                mod_id: mod_ref.manifest().id.to_string()
+            })
         }
     }
 
@@ -274,27 +466,21 @@ fn attempt_file_copy(from_path: PathBuf, file_ext: String, mod_manager: ModManag
 }
 
 fn attempt_song_import(from_path: PathBuf) -> Result<Response> {
-    let song_handle = std::fs::File::open(&from_path)?;
+    let song_handle = fs_ops::open(&from_path)?;
     let mut zip = ZipFile::open(song_handle).context("Song was invalid ZIP file")?;
 
     if zip.contains_file("info.dat") || zip.contains_file("Info.dat") {
-        let extract_path = Path::new(SONGS_PATH).join(from_path.file_stem().expect("Must have file stem"));
+        let extract_path = Path::new(&songs_path()).join(from_path.file_stem().expect("Must have file stem"));
 
         if extract_path.exists() {
-            std::fs::remove_dir_all(&extract_path).context("Failed to delete existing song")?;
+            fs_ops::remove_dir_all(&extract_path).context("Failed to delete existing song")?;
         }
 
-        std::fs::create_dir_all(&extract_path)?;
-        let entry_names = zip.iter_entry_names()
-            // TODO: This is not nice for performance
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
-        for entry_name in entry_names {
-            zip.extract_file_to(&entry_name, extract_path.join(&entry_name))?;
-        }
+        fs_ops::create_dir_all(&extract_path)?;
+        zip.extract_to(&extract_path, |_| true, &mut |_| {}).context("Failed to extract song")?;
 
         drop(zip);
-        std::fs::remove_file(from_path)?;
+        fs_ops::remove_file(from_path)?;
         Ok(Response::ImportedSong)
     }   else {
         Err(anyhow!("ZIP file was not a song; Unclear know how to import it"))
@@ -311,7 +497,7 @@ fn handle_remove_mod(id: String) -> Result<Response> {
     })
 }
 
-fn handle_quick_fix() -> Result<Response> {
+fn handle_quick_fix(force_modloader: bool) -> Result<Response> {
     let app_info = get_app_info()?
         .ok_or(anyhow!("Cannot quick fix when app is not installed"))?;
 
@@ -320,13 +506,14 @@ fn handle_quick_fix() -> Result<Response> {
 
     // Reinstall missing core mods and overwrite the modloader with the one contained within the executable.
     install_core_mods(&mut mod_manager, app_info)?;
-    patching::install_modloader()?;
+    patching::install_modloader(force_modloader)?;
     Ok(Response::Mods {
         installed_mods: get_mod_models(mod_manager)
     })
 }
 
 fn handle_fix_player_data() -> Result<Response> {
+    require_beat_saber("fix player data")?;
     patching::kill_app()?; // Kill app, in case it's still stuck in a hanging state
 
     let mut did_work = false;
@@ -336,14 +523,17 @@ fn handle_fix_player_data() -> Result<Response> {
         did_work = true;
     }
     
-    if Path::new(PLAYER_DATA_PATH).exists() {
+    let app_paths = volumes::get_app_paths()?;
+    let player_data_path = app_paths.player_data();
+    if player_data_path.exists() {
         info!("Backing up player data");
         patching::backup_player_data()?;
 
-        info!("Removing (potentially faulty) PlayerData.dat at {}", PLAYER_DATA_PATH);
-        std::fs::remove_file(PLAYER_DATA_PATH).context("Failed to delete faulty player data")?;
-        if Path::new(PLAYER_DATA_BAK_PATH).exists() {
-            std::fs::remove_file(PLAYER_DATA_BAK_PATH)?;
+        info!("Removing (potentially faulty) PlayerData.dat at {player_data_path:?}");
+        fs_ops::remove_file(&player_data_path).context("Failed to delete faulty player data")?;
+        let backup_path = app_paths.player_data_backup();
+        if backup_path.exists() {
+            fs_ops::remove_file(backup_path)?;
         }
         did_work = true;
     }   else {
@@ -355,36 +545,124 @@ fn handle_fix_player_data() -> Result<Response> {
     })
 }
 
-fn handle_patch(downgrade_to: Option<String>, repatch: bool, manifest_mod: ManifestMod, allow_no_core_mods: bool) -> Result<Response> {
+fn handle_backup_player_data() -> Result<Response> {
+    require_beat_saber("back up player data")?;
+    let app_paths = volumes::get_app_paths()?;
+    let backup = player_data::create_snapshot(&app_paths.data_dir).context("Failed to back up player data")?;
+    info!("Backed up {} player data files to {}", backup.files.len(), backup.path);
+
+    Ok(Response::BackedUpPlayerData { backup })
+}
+
+fn handle_restore_player_data(id: String) -> Result<Response> {
+    require_beat_saber("restore player data")?;
+    let app_paths = volumes::get_app_paths()?;
+    // The game would otherwise overwrite the restored files when it next saves.
+    patching::kill_app()?;
+
+    let restored = player_data::restore_snapshot(&app_paths.data_dir, &id).context("Failed to restore player data")?;
+    info!("Restored player data files: {}", restored.join(", "));
+    Ok(Response::RestoredPlayerData { restored })
+}
+
+// Fails if the game isn't Beat Saber, since the player data files that are handled are specific to it.
+fn require_beat_saber(action: &str) -> Result<()> {
+    if is_beat_saber() {
+        Ok(())
+    }   else {
+        Err(anyhow!("Cannot {action} for {}, since only Beat Saber's player data is supported", apk_id()))
+    }
+}
+
+// Adds the permissions and features requested on top of those needed to mod the game to the manifest mod.
+fn with_extras(manifest_mod: ManifestMod, extra_permissions: &[String], extra_features: &[String]) -> ManifestMod {
+    let manifest_mod = extra_permissions.iter()
+        .fold(manifest_mod, |manifest_mod, permission| manifest_mod.with_extra_permission(permission));
+    extra_features.iter()
+        .fold(manifest_mod, |manifest_mod, feature| manifest_mod.with_extra_feature(feature))
+}
+
+// Loads the key that patched APKs are signed with: the given certificate, if any, otherwise the one that the APK at `apk_path`
+// (or the installed APK, if None) was signed with by MBF, which is the certificate generated for this device unless it was patched by an older version.
+fn load_signing_key(signing_cert: Option<SigningCert>, apk_path: Option<&Path>) -> Result<SigningKey> {
+    let pem_data = match signing_cert {
+        Some(SigningCert::Path(path)) => {
+            if !is_within_sdcard(Path::new(&path)) {
+                return Err(anyhow!("The signing certificate must be within /sdcard/"));
+            }
+            std::fs::read(&path).with_context(|| format!("Failed to read signing certificate from {path}"))?
+        },
+        Some(SigningCert::Pem(pem)) => pem.into_bytes(),
+        None => return Ok(match apk_path {
+            Some(apk_path) => SigningKey::default_for(apk_path),
+            None => match crate::get_apk_path()? {
+                Some(installed_path) => SigningKey::default_for(Path::new(&installed_path)),
+                None => SigningKey::for_device()
+            }
+        })
+    };
+
+    let signing_key = SigningKey::from_pem(pem_data)?;
+    info!("Signing with a custom certificate, with SHA-256 {}", signing_key.sha256()?);
+    Ok(signing_key)
+}
+
+fn handle_patch(downgrade_to: Option<String>,
+    repatch: bool,
+    manifest_mod: ManifestMod,
+    allow_no_core_mods: bool,
+    options: PatchOptions,
+    diff_options: DiffOptions,
+    working_dir: Option<String>) -> Result<Response> {
     let app_info = get_app_info()?
         .ok_or(anyhow!("Cannot patch when app not installed"))?;
 
-    std::fs::create_dir_all(TEMP_PATH)?;
+    if app_info.build_variant == BuildVariant::UnknownSideload {
+        if downgrade_to.is_some() {
+            return Err(anyhow!("Beat Saber was sideloaded rather than installed from the store, so it cannot be downgraded: the diffs only apply to the store version.
+                Reinstall Beat Saber from the store and try again"));
+        }
+
+        warn!("Beat Saber was sideloaded rather than installed from the store. Patching will be attempted, but may fail if this isn't the store version");
+    }
+
+    let working_dir = WorkingDir::resolve(working_dir.as_deref())?;
 
     // Either downgrade or just patch the current APK depending on the caller's choice.
     let patching_result = if let Some(to_version) = downgrade_to {
-        let diff_index = get_diff_index()
+        let diff_index = get_diff_index(&pinning::pinned_agent()?)
             .context("Failed to get diff index to downgrade")?;
-        let version_diffs = diff_index.into_iter()
-            .filter(|diff| diff.from_version == app_info.version && diff.to_version == to_version)
-            .next()
-            .ok_or(anyhow!("No diff existed to go from {} to {}", app_info.version, to_version))?;
+        let hops = external_res::find_downgrade_path(&diff_index, &app_info.version, &to_version)
+            .ok_or(anyhow!("No diffs existed to go from {} to {}", app_info.version, to_version))?;
+        if hops.len() > 1 {
+            let versions: Vec<&str> = hops.iter().map(|hop| hop.to_version.as_str()).collect();
+            info!("No diff goes straight to {to_version}, so downgrading via {}", versions.join(" -> "));
+        }
 
-        patching::downgrade_and_mod_apk(Path::new(TEMP_PATH), &app_info, version_diffs, manifest_mod)
+        patching::downgrade_and_mod_apk(working_dir.path(), &app_info, hops, manifest_mod, diff_options, &options)
             .context("Failed to downgrade and patch APK")
     }   else {
-        patching::mod_current_apk(Path::new(TEMP_PATH), &app_info, manifest_mod, repatch)
+        patching::mod_current_apk(working_dir.path(),
+            &app_info,
+            manifest_mod,
+            repatch,
+            &options)
             .context("Failed to patch APK")
     };
 
     // No matter what, make sure that all temporary files are gone.
-    std::fs::remove_dir_all(TEMP_PATH)?;
+    working_dir.remove()?;
 
-    if let Err(err) = patching_result {
-        return Err(err).context("Failed to patch")
-    }
+    let outcome = match patching_result {
+        Ok(outcome) => outcome,
+        Err(err) => return Err(err).context("Failed to patch")
+    };
+    let patched_app_info = get_app_info()?
+        .ok_or(anyhow!("Beat Saber should be installed after patching"))?;
+    let manifest_permissions = patching::read_manifest_permissions(Path::new(&patched_app_info.path))
+        .context("Failed to read permissions of patched APK")?;
 
-    patching::install_modloader().context("Failed to save modloader")?;
+    patching::install_modloader(false).context("Failed to save modloader")?;
 
     let mut mod_manager = ModManager::new();
     
@@ -393,8 +671,7 @@ fn handle_patch(downgrade_to: Option<String>, repatch: bool, manifest_mod: Manif
         mod_manager.wipe_all_mods().context("Failed to wipe existing mods")?;
         mod_manager.load_mods()?; // Should load no mods.
     
-        match install_core_mods(&mut mod_manager, get_app_info()?
-            .ok_or(anyhow!("Beat Saber should be installed after patching"))?) {
+        match install_core_mods(&mut mod_manager, patched_app_info) {
                 Ok(_) => info!("Successfully installed all core mods"),
                 Err(err) => if allow_no_core_mods {
                     warn!("Failed to install core mods: {err}")
@@ -404,32 +681,161 @@ fn handle_patch(downgrade_to: Option<String>, repatch: bool, manifest_mod: Manif
             }
     }
     
-    Ok(Response::Mods { installed_mods: get_mod_models(mod_manager) })
+    Ok(Response::Patched {
+        installed_mods: get_mod_models(mod_manager),
+        patch_kind: outcome.patch_kind,
+        manifest_permissions,
+        signature: outcome.signature
+    })
+}
+
+fn handle_patch_apk_file(input: String,
+    output: String,
+    manifest_mod: ManifestMod,
+    manifest_only: bool,
+    libunity_path: Option<String>,
+    allow_other_package: bool,
+    signing_key: &SigningKey) -> Result<Response> {
+    let paths = [Some(&input), Some(&output), libunity_path.as_ref()];
+    if paths.iter().flatten().any(|path| !is_within_sdcard(Path::new(path))) {
+        return Err(anyhow!("APK files can only be patched within /sdcard/"));
+    }
+    if input == output {
+        return Err(anyhow!("The patched APK must be saved to a different file to the input APK"));
+    }
+
+    let patched = patching::patch_apk_file(Path::new(&input),
+        Path::new(&output),
+        libunity_path.map(PathBuf::from),
+        manifest_mod,
+        manifest_only,
+        allow_other_package,
+        signing_key
+    ).context("Failed to patch APK file")?;
+
+    info!("Saved patched APK to {output}");
+    Ok(Response::PatchedApkFile {
+        output,
+        size: patched.exported.size,
+        input_crc32: patched.input_crc32,
+        crc32: patched.exported.crc32,
+        signer_sha256: patched.exported.signer_sha256,
+        reproducibility_digest: patched.exported.reproducibility_digest,
+        patch_kind: patched.exported.patch_kind,
+        manifest_modified: patched.report.manifest_modified,
+        written_files: patched.report.written_files,
+        removed_files: patched.report.removed_files
+    })
+}
+
+// Checks that the path is within /sdcard/, and doesn't escape it with `..`.
+fn is_within_sdcard(path: &Path) -> bool {
+    path.starts_with("/sdcard/") && !path.components().any(|component| component == std::path::Component::ParentDir)
+}
+
+fn handle_export(manifest_only: bool,
+    manifest_mod: ManifestMod,
+    destination: String,
+    install_modloader: bool,
+    working_dir: Option<String>,
+    signing_key: &SigningKey) -> Result<Response> {
+    // Only allow exporting to the quest's storage, so that it's easy for the user to find the APK
+    let destination_path = Path::new(&destination);
+    if !is_within_sdcard(destination_path) {
+        return Err(anyhow!("Patched APKs can only be exported to /sdcard/"));
+    }
+
+    let app_info = get_app_info()?
+        .ok_or(anyhow!("Cannot patch when app not installed"))?;
+
+    let working_dir = WorkingDir::resolve(working_dir.as_deref())?;
+    let export_result = patching::export_patched_apk(working_dir.path(), &app_info, manifest_mod, manifest_only, destination_path, signing_key);
+    // No matter what, make sure that all temporary files are gone.
+    working_dir.remove()?;
+
+    let exported = export_result.context("Failed to export patched APK")?;
+    if install_modloader {
+        patching::install_modloader(false).context("Failed to save modloader")?;
+    }
+
+    info!("Exported patched APK to {destination}");
+    Ok(Response::ExportedApk {
+        path: destination,
+        size: exported.size,
+        crc32: exported.crc32,
+        signer_sha256: exported.signer_sha256,
+        reproducibility_digest: exported.reproducibility_digest,
+        patch_kind: exported.patch_kind
+    })
+}
+
+fn handle_patch_dry_run(manifest_only: bool,
+    manifest_mod: ManifestMod,
+    copy_apk_first: bool,
+    keep_vanilla_backup: bool,
+    working_dir: Option<String>,
+    signing_key: &SigningKey) -> Result<Response> {
+    let app_info = get_app_info()?
+        .ok_or(anyhow!("Cannot patch when app not installed"))?;
+
+    // The working directory isn't created, so the free space is read from the closest directory containing it that exists.
+    let working_dir = WorkingDir::get_path(working_dir.as_deref())?;
+    let existing_dir = working_dir.ancestors()
+        .find(|dir| dir.exists())
+        .ok_or(anyhow!("No part of the working directory {working_dir:?} exists"))?;
+    let available_space = crate::get_free_space(existing_dir)?;
+
+    let dry_run = patching::dry_run_patch(&app_info, manifest_mod, manifest_only, copy_apk_first, signing_key)
+        .context("Dry run of patching failed")?;
+    let vanilla_backup_size = if keep_vanilla_backup && app_info.loader_installed.is_none() {
+        Some(std::fs::metadata(&app_info.path)?.len())
+    }   else {
+        None
+    };
+
+    if dry_run.required_space > available_space {
+        warn!("Patching needs {} bytes free in {working_dir:?}, but only {available_space} bytes are free", dry_run.required_space);
+    }
+    Ok(Response::PatchDryRun {
+        version: app_info.version,
+        libunity_available: dry_run.libunity_available,
+        patch_kind: dry_run.patch_kind,
+        manifest_modified: dry_run.plan.manifest_modified,
+        replaced_files: dry_run.plan.replaced_files,
+        added_files: dry_run.plan.added_files,
+        removed_files: dry_run.plan.removed_files,
+        obbs: dry_run.obbs,
+        working_dir: working_dir.to_string_lossy().to_string(),
+        required_space: dry_run.required_space,
+        available_space,
+        vanilla_backup_size
+    })
 }
 
 fn install_core_mods(mod_manager: &mut ModManager, app_info: AppInfo) -> Result<()> {
     info!("Preparing core mods");
-    let core_mod_index = crate::external_res::fetch_core_mods()?;
+    let core_mod_index = crate::external_res::fetch_core_mods(apk_id())?;
 
-    let core_mods = core_mod_index.get(&app_info.version)
-        .ok_or(anyhow!("No core mods existed for {}", app_info.version))?;
+    let core_mods = match core_mod_index.get(&app_info.version) {
+        Some(core_mods) => core_mods,
+        None if external_res::was_skipped(external_res::CORE_MODS_INDEX, &app_info.version) =>
+            return Err(anyhow!("The core mods for {} could not be read. Update MBF to install them", app_info.version)),
+        None => return Err(anyhow!("No core mods existed for {}", app_info.version))
+    };
 
 
     for core_mod in &core_mods.mods {
         // Check if there is already an existing mod.
-        match mod_manager.get_mod(&core_mod.id) {
-            Some(existing) => {
-                let existing_ref = existing.borrow();
-                if existing_ref.manifest().version >= core_mod.version {
-                    info!("Core mod {} was already installed with new enough version: {}", core_mod.id, existing_ref.manifest().version);
-                    continue;
-                }
-            },
-            None => {}
+        if let Some(existing) = mod_manager.get_mod(&core_mod.id) {
+            let existing_ref = existing.borrow();
+            if existing_ref.manifest().version >= core_mod.version {
+                info!("Core mod {} was already installed with new enough version: {}", core_mod.id, existing_ref.manifest().version);
+                continue;
+            }
         }
 
         info!("Downloading {} v{}", core_mod.id, core_mod.version);
-        let save_path = mod_manager.mods_path().as_ref()
+        let save_path = mod_manager.mods_path()
             .join(format!("{}-v{}-CORE.qmod", core_mod.id, core_mod.version));
 
         download_file_with_attempts(&save_path, &core_mod.download_url).context("Failed to download core mod")?;
@@ -444,4 +850,79 @@ fn install_core_mods(mod_manager: &mut ModManager, app_info: AppInfo) -> Result<
     }
     
     Ok(())
-}
\ No newline at end of file
+}
+
+fn handle_trust_repository_identity(host: String, identity: String) -> Result<Response> {
+    pinning::trust_identity(&host, &identity).context("Failed to trust repository identity")?;
+    Ok(Response::TrustedRepositoryIdentity)
+}
+
+fn handle_apply_legacy_storage() -> Result<Response> {
+    let app_info = get_app_info()?
+        .ok_or(anyhow!("Cannot apply storage fallback when app not installed"))?;
+    if app_info.loader_installed.is_none() {
+        return Err(anyhow!("The app must be patched before the storage fallback can be applied"));
+    }
+
+    let working_dir = WorkingDir::resolve(None)?;
+
+    // Only the manifest needs changing, so this uses the same path as remodding.
+    let patching_result = patching::mod_current_apk(working_dir.path(),
+        &app_info,
+        ManifestMod::new().legacy_storage(true),
+        true,
+        &PatchOptions {
+            copy_apk_first: false,
+            sequential_stages: false,
+            keep_vanilla_backup: false,
+            signing_key: SigningKey::default_for(Path::new(&app_info.path))
+        }
+    );
+    working_dir.remove()?;
+
+    patching_result.context("Failed to apply legacy storage fallback")?;
+    Ok(Response::AppliedLegacyStorage)
+}
+
+fn handle_restore_vanilla() -> Result<Response> {
+    let app_info = get_app_info()?
+        .ok_or(anyhow!("Cannot restore the vanilla game when app not installed"))?;
+    if app_info.loader_installed.is_none() {
+        return Err(anyhow!("Beat Saber is not modded, so there is no need to restore the vanilla game"));
+    }
+
+    if !Path::new(VANILLA_BACKUP_PATH).exists() {
+        warn!("No vanilla APK was kept when Beat Saber was patched, so the vanilla game cannot be restored by MBF.
+            Uninstall Beat Saber and reinstall it from the store instead");
+        return Ok(Response::RestoredVanilla { backup_existed: false });
+    }
+
+    // The OBBs are kept, so they must match the vanilla APK.
+    let backup_version = apk_cache::read_summary(VANILLA_BACKUP_PATH).context("Failed to read vanilla APK")?.package_version;
+    if backup_version != app_info.version {
+        return Err(anyhow!("The vanilla APK that was kept is for {backup_version}, but {} is installed, so the vanilla game cannot be restored.
+            Uninstall Beat Saber and reinstall it from the store instead", app_info.version));
+    }
+
+    let working_dir = WorkingDir::resolve(None)?;
+    let restore_result = patching::restore_vanilla(working_dir.path(), &app_info);
+    working_dir.remove()?;
+    restore_result.context("Failed to restore the vanilla game")?;
+
+    info!("Restored the vanilla game. Your mods have been left in place, so they will be loaded again if the game is patched");
+    Ok(Response::RestoredVanilla { backup_existed: true })
+}
+
+fn handle_diagnose_crash() -> Result<Response> {
+    let crash = crate::crash::get_latest_crash().context("Failed to find crash")?;
+    let (diagnosis, remediation) = match crash.as_ref().and_then(crate::crash::diagnose) {
+        Some((diagnosis, remediation)) => (Some(diagnosis), Some(remediation)),
+        None => (None, None)
+    };
+
+    Ok(Response::CrashDiagnosis {
+        crash,
+        diagnosis,
+        remediation
+    })
+}
@@ -0,0 +1,357 @@
+//! Works out why a file that is about to be downgraded doesn't match the checksum in its diff, so that users whose install
+//! is merely damaged aren't told that their game might be pirated, and users with a modified install aren't told to keep retrying.
+//! Before patching, the signature of the installed APK is also verified, so that tampered installs can be warned about.
+
+use std::{fmt::Display, fs::File, path::Path, time::Instant};
+
+use log::{info, warn};
+use rsa::sha2::{Digest, Sha256};
+use serde::{Deserialize, Serialize};
+
+use mbf_patcher::MOD_TAG_PATH;
+
+use crate::{fs_ops, patching::{self, SigningKey}, requests::BuildVariant, zip::{signing::V2Verification, ZipFile}};
+
+// Entries that are added to the APK when it is modified, and so are never in a store build.
+const MODIFICATION_MARKERS: &[&str] = &[
+    MOD_TAG_PATH,
+    "BMBF.modded",
+    "lib/arm64-v8a/libmainloader.so",
+    "lib/arm64-v8a/libmodloader.so"
+];
+
+/// The likely reason that a file didn't match the checksum in its diff.
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub enum IntegrityClass {
+    /// The install came from the store and hasn't been modified, so the file was most likely damaged.
+    LikelyCorrupt,
+    /// The APK was signed by MBF, or contains files that store builds never do.
+    LikelyModifiedInstall,
+    /// There wasn't enough evidence either way.
+    Indeterminate
+}
+
+/// Who signed the installed APK.
+#[derive(Serialize, Clone, Debug)]
+pub enum SignerKind {
+    /// Signed with a certificate MBF signs patched APKs with.
+    KnownModified {
+        name: String
+    },
+    Unknown,
+    /// The APK had no V2 signature, or it could not be read.
+    Unavailable
+}
+
+/// The evidence that an `IntegrityClass` was decided from.
+#[derive(Serialize, Clone, Debug)]
+pub struct IntegrityEvidence {
+    /// The SHA-256 hash of the first certificate the installed APK is signed with, hex encoded.
+    pub signer_sha256: Option<String>,
+    pub signer: SignerKind,
+    /// The package name of the app that installed Beat Saber, if any.
+    pub installer: Option<String>,
+    /// Entries within the installed APK that are only present in modified builds.
+    pub modification_markers: Vec<String>,
+    /// Whether the file is the size of the expected vanilla file. None if the diff doesn't give the expected size.
+    pub size_matches: Option<bool>
+}
+
+/// Given when a file about to be downgraded doesn't match the checksum in its diff.
+#[derive(Debug)]
+pub struct DiffCrcMismatch {
+    pub file_name: String,
+    pub actual_crc: u32,
+    pub expected_crc: u32,
+    pub class: IntegrityClass,
+    pub evidence: IntegrityEvidence
+}
+
+impl Display for DiffCrcMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CRC {} of {} did not match expected value of {}. ", self.actual_crc, self.file_name, self.expected_crc)?;
+        match self.class {
+            IntegrityClass::LikelyCorrupt => write!(f, "Your installation appears to be damaged, so MBF can't downgrade it. \
+                Uninstall Beat Saber and download it again from the store to fix this issue"),
+            IntegrityClass::LikelyModifiedInstall => write!(f, "Your installation of Beat Saber has been modified by something other than MBF. \
+                MBF only supports legitimate copies of Beat Saber installed from the store"),
+            IntegrityClass::Indeterminate => write!(f, "Your installation doesn't match the version MBF expected, so it can't be downgraded. \
+                Uninstalling Beat Saber and installing it again from the store may fix this issue")
+        }
+    }
+}
+
+impl std::error::Error for DiffCrcMismatch {}
+
+/// Given when applying a diff produced a file that doesn't match the checksum or size of the expected output.
+/// Unlike `DiffCrcMismatch`, this means the installed file was fine, but the diff or the write of its output was not.
+#[derive(Debug)]
+pub struct DiffOutputMismatch {
+    pub file_name: String,
+    pub actual_crc: u32,
+    pub expected_crc: u32,
+    pub actual_size: u64,
+    pub expected_size: u64
+}
+
+impl Display for DiffOutputMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Downgrading produced a corrupt {}: CRC {} (expected {}), size {} bytes (expected {}). \
+            Your installation was not changed", self.file_name, self.actual_crc, self.expected_crc, self.actual_size, self.expected_size)
+    }
+}
+
+impl std::error::Error for DiffOutputMismatch {}
+
+/// How the installed APK is signed, which the frontend warns about if the APK appears to have been tampered with.
+/// The certificate that store builds are signed with isn't known, so a validly signed APK can't be confirmed to be a store build.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum SignatureStatus {
+    /// Validly signed with a certificate other than those MBF signs with, as store builds and legitimately re-signed builds are.
+    Signed {
+        // The SHA-256 hash of the signer's certificate, hex encoded.
+        signer_sha256: String
+    },
+    /// Signed by MBF, since the APK has been patched before.
+    PatchedByMbf,
+    /// Contains files added by another modding tool, so will be migrated to MBF's modloader when patched.
+    /// Other tools re-sign the APK (sometimes only with a V1 signature), so the signature isn't taken as a sign of piracy.
+    MigrationCandidate {
+        modification_markers: Vec<String>,
+        // The SHA-256 hash of the signer's certificate, hex encoded, if the APK has a valid V2 signature.
+        signer_sha256: Option<String>
+    },
+    /// The APK has no V2 signature, or was modified after it was signed, and wasn't modded by another tool.
+    /// This suggests a tampered or pirated install, which often crashes once modded.
+    Unofficial {
+        // Why the signature isn't official, to show to the user.
+        reason: String
+    },
+    /// The signature couldn't be checked, e.g. as it uses an unsupported algorithm.
+    Unverifiable {
+        reason: String
+    }
+}
+
+/// Verifies the V2 signature of the APK at `apk_path`, and works out whether it was signed by MBF or modded by another tool.
+/// Fails only if the APK couldn't be read, in which case it shouldn't be assumed to be unofficial.
+pub fn check_signature(apk_path: &Path) -> anyhow::Result<SignatureStatus> {
+    let mut apk = ZipFile::open(fs_ops::open(apk_path)?)?;
+    let verify_start = Instant::now();
+    let verification = apk.verify_v2_signature()?;
+    info!("Verified APK signature in {:.1}s", verify_start.elapsed().as_secs_f32());
+
+    // APKs signed with a certificate given by the user record its hash in their mod tag.
+    let tag_signer = patching::read_mod_tag(&mut apk).and_then(|tag| tag.signing_cert_sha256);
+    let modification_markers = get_modification_markers(&apk);
+    Ok(get_signature_status(verification, modification_markers,
+        |sha256| is_mbf_signer(sha256) || tag_signer.as_deref() == Some(sha256)))
+}
+
+// Decides the status of an APK from the verification of its signature and the modification markers it contains.
+// `signed_by_mbf` is given the hash of the signer's certificate if the signature is valid, and returns true if MBF signed with it.
+fn get_signature_status(verification: V2Verification,
+    modification_markers: Vec<String>,
+    signed_by_mbf: impl Fn(&str) -> bool) -> SignatureStatus {
+    let signer_sha256 = match &verification {
+        V2Verification::Valid { signer_cert } => Some(Sha256::digest(signer_cert).iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>()),
+        _ => None
+    };
+    if signer_sha256.as_deref().is_some_and(&signed_by_mbf) {
+        return SignatureStatus::PatchedByMbf;
+    }
+    if !modification_markers.is_empty() {
+        return SignatureStatus::MigrationCandidate { modification_markers, signer_sha256 };
+    }
+
+    match verification {
+        V2Verification::Valid { .. } => SignatureStatus::Signed {
+            signer_sha256: signer_sha256.expect("Valid signature should have a signer")
+        },
+        V2Verification::Unsigned => SignatureStatus::Unofficial {
+            reason: "the APK has no V2 signature".to_string()
+        },
+        V2Verification::Invalid { reason } => SignatureStatus::Unofficial {
+            reason: format!("the APK was modified after it was signed ({reason})")
+        },
+        V2Verification::Unsupported { algorithms } => SignatureStatus::Unverifiable {
+            reason: format!("the APK is signed with unsupported algorithms {algorithms:x?}")
+        }
+    }
+}
+
+/// Gathers evidence about the installed APK at `installed_apk` and classifies why `file_name` had the wrong CRC.
+/// `size_matches` should be given if the diff records the expected size of the file.
+/// Failing to gather a piece of evidence is logged, since the mismatch should still be reported.
+pub fn classify_mismatch(installed_apk: &Path, file_name: &str, actual_crc: u32, expected_crc: u32, size_matches: Option<bool>) -> DiffCrcMismatch {
+    info!("Checking why {file_name} didn't match its diff");
+    let installer = crate::get_installer_package().unwrap_or_else(|err| {
+        warn!("Failed to get installer package: {err}");
+        None
+    });
+
+    let (signer_sha256, modification_markers) = match fs_ops::open(installed_apk).map_err(anyhow::Error::from).and_then(ZipFile::open) {
+        Ok(mut apk) => (get_signer_sha256(&mut apk), get_modification_markers(&apk)),
+        Err(err) => {
+            warn!("Failed to open installed APK: {err}");
+            (None, Vec::new())
+        }
+    };
+
+    let evidence = IntegrityEvidence {
+        signer: classify_signer(signer_sha256.as_deref()),
+        signer_sha256,
+        installer,
+        modification_markers,
+        size_matches
+    };
+    info!("Integrity evidence: {evidence:?}");
+
+    DiffCrcMismatch {
+        file_name: file_name.to_string(),
+        actual_crc,
+        expected_crc,
+        class: classify(&evidence),
+        evidence
+    }
+}
+
+/// Decides the likely reason for a checksum mismatch from the given evidence.
+pub fn classify(evidence: &IntegrityEvidence) -> IntegrityClass {
+    if matches!(evidence.signer, SignerKind::KnownModified { .. }) || !evidence.modification_markers.is_empty() {
+        return IntegrityClass::LikelyModifiedInstall;
+    }
+
+    let from_store = patching::classify_build(evidence.installer.as_deref(), false) == BuildVariant::OfficialStore;
+    match evidence.signer {
+        // The store's certificate isn't known, so an unknown signer is expected for a store install.
+        SignerKind::Unknown if from_store => IntegrityClass::LikelyCorrupt,
+        _ => IntegrityClass::Indeterminate
+    }
+}
+
+// Works out whether the given certificate hash is that of a certificate MBF signs APKs with.
+fn classify_signer(signer_sha256: Option<&str>) -> SignerKind {
+    match signer_sha256 {
+        Some(sha256) if is_mbf_signer(sha256) => SignerKind::KnownModified { name: "ModsBeforeFriday".to_string() },
+        Some(_) => SignerKind::Unknown,
+        None => SignerKind::Unavailable
+    }
+}
+
+// Returns true if the given certificate hash is that of a certificate MBF signs APKs with.
+fn is_mbf_signer(sha256: &str) -> bool {
+    // APKs patched by older versions of MBF were signed with the debug certificate, rather than the device's own.
+    let mbf_signers = [SigningKey::for_device().sha256().ok(), SigningKey::debug().sha256().ok()];
+    mbf_signers.iter().flatten().any(|signer| signer == sha256)
+}
+
+/// Gets the hex encoded SHA-256 hash of the first certificate the APK is signed with, or None if it couldn't be read.
+pub fn get_signer_sha256(apk: &mut ZipFile<File>) -> Option<String> {
+    match apk.get_v2_signer_certs() {
+        Ok(certs) => certs.first().map(|cert| Sha256::digest(cert).iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()),
+        Err(err) => {
+            warn!("Failed to read APK signature: {err}");
+            None
+        }
+    }
+}
+
+fn get_modification_markers(apk: &ZipFile<File>) -> Vec<String> {
+    MODIFICATION_MARKERS.iter()
+        .filter(|marker| apk.contains_file(marker))
+        .map(|marker| marker.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Cursor};
+
+    use super::*;
+    use crate::zip::{signing::{self, SigningConfig}, FileCompression};
+
+    const DEBUG_CERT_PEM: &[u8] = include_bytes!("debug_cert.pem");
+
+    // Writes an APK with the given extra entries to a temporary file, signing it with the debug certificate if `signed` is true.
+    fn write_apk(name: &str, entries: &[&str], signed: bool) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("mbf-signature-test-{}-{name}.apk", std::process::id()));
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let mut zip = ZipFile::create(file);
+        zip.write_file("classes.dex", &mut Cursor::new(b"dex\n035".repeat(100)), FileCompression::Deflate).unwrap();
+        for entry in entries {
+            zip.write_file(entry, &mut Cursor::new(b"{}"), FileCompression::Deflate).unwrap();
+        }
+
+        if signed {
+            let (cert, priv_key) = signing::load_cert_and_priv_key(DEBUG_CERT_PEM).unwrap();
+            zip.save_and_sign(&priv_key, &cert, SigningConfig { v1: false, v2: true, v3: false }).unwrap();
+        }   else {
+            zip.save().unwrap();
+        }
+        path
+    }
+
+    fn valid(signer_cert: &[u8]) -> V2Verification {
+        V2Verification::Valid { signer_cert: signer_cert.to_vec() }
+    }
+
+    #[test]
+    fn unsigned_apk_is_unofficial() {
+        let path = write_apk("unsigned", &[], false);
+        assert_eq!(check_signature(&path).unwrap(), SignatureStatus::Unofficial { reason: "the APK has no V2 signature".to_string() });
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn apk_modified_after_signing_is_unofficial() {
+        let path = write_apk("tampered", &[], true);
+        let mut contents = std::fs::read(&path).unwrap();
+        // Changes the first byte of the deflated data of classes.dex, which the signature covers.
+        let data_offset = 30 + "classes.dex".len();
+        contents[data_offset] ^= 0x01;
+        std::fs::write(&path, contents).unwrap();
+
+        match check_signature(&path).unwrap() {
+            SignatureStatus::Unofficial { reason } => assert!(reason.starts_with("the APK was modified after it was signed"), "{reason}"),
+            status => panic!("Expected tampered APK to be unofficial, got {status:?}")
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn apk_modded_by_another_tool_is_a_migration_candidate() {
+        // Other tools may give only a V1 signature, so an APK without a V2 signature is still migrated rather than warned about.
+        let path = write_apk("other-tool", &["BMBF.modded", "lib/arm64-v8a/libmodloader.so"], false);
+        assert_eq!(check_signature(&path).unwrap(), SignatureStatus::MigrationCandidate {
+            modification_markers: vec!["BMBF.modded".to_string(), "lib/arm64-v8a/libmodloader.so".to_string()],
+            signer_sha256: None
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn validly_signed_apk_is_classified_by_its_signer() {
+        let cert_sha256: String = Sha256::digest(b"cert").iter().map(|byte| format!("{byte:02x}")).collect();
+
+        assert_eq!(get_signature_status(valid(b"cert"), Vec::new(), |_| false), SignatureStatus::Signed { signer_sha256: cert_sha256.clone() });
+        assert_eq!(get_signature_status(valid(b"cert"), Vec::new(), |sha256| sha256 == cert_sha256), SignatureStatus::PatchedByMbf);
+        // An APK signed by MBF has a mod tag, which is a modification marker, but it is patched by MBF rather than migrated.
+        assert_eq!(get_signature_status(valid(b"cert"), vec![MOD_TAG_PATH.to_string()], |sha256| sha256 == cert_sha256),
+            SignatureStatus::PatchedByMbf);
+        assert_eq!(get_signature_status(valid(b"cert"), vec![MOD_TAG_PATH.to_string()], |_| false), SignatureStatus::MigrationCandidate {
+            modification_markers: vec![MOD_TAG_PATH.to_string()],
+            signer_sha256: Some(cert_sha256)
+        });
+    }
+
+    #[test]
+    fn unsupported_signature_is_unverifiable() {
+        let status = get_signature_status(V2Verification::Unsupported { algorithms: vec![0x201] }, Vec::new(), |_| true);
+        assert!(matches!(status, SignatureStatus::Unverifiable { .. }), "{status:?}");
+    }
+}
@@ -1,39 +1,67 @@
 mod requests;
-mod zip;
-mod manifest;
-mod axml;
 mod patching;
 mod external_res;
 mod mod_man;
 mod handlers;
 mod data_fix;
+mod crash;
+mod pinning;
+mod reports;
+mod fs_ops;
+mod setup;
+mod text;
+mod capabilities;
+mod storage_guard;
+mod volumes;
+mod apk_cache;
+mod integrity;
+mod commands;
+mod working_dir;
+mod obb_recovery;
+mod package_manager;
+mod player_data;
+mod framework_res;
+#[cfg(test)]
+mod test_harness;
 
 use crate::requests::Request;
-use anyhow::{Context, Result};
-use const_format::formatcp;
+use mbf_patcher::{axml, composition, dex, manifest, zip};
+use anyhow::{anyhow, Context, Result};
 use log::{error, info, warn, Level};
 use requests::Response;
-use serde::{Deserialize, Serialize};
-use std::{fs::OpenOptions, io::{BufRead, BufReader, Read, Write}, panic, path::Path, process::Command, time::{Duration, Instant}};
+use rsa::sha2::{Digest, Sha256};
+use std::{collections::HashMap, fs::{File, OpenOptions}, io::{BufRead, BufReader, Read, Seek, SeekFrom, Write}, os::unix::fs::FileExt, panic, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Mutex, OnceLock}, time::{Duration, Instant}};
 
 // Directories accessed by the agent, in one place so that they can be easily changed.
-pub const APK_ID: &str = "com.beatgames.beatsaber";
+// The directories within ModData depend on the package ID of the game being modded, so are given by the functions below.
+pub const BEAT_SABER_ID: &str = "com.beatgames.beatsaber";
 pub const QMODS_DIR: &str = "/sdcard/ModsBeforeFriday/Mods";
-pub const MODLOADER_DIR: &str = formatcp!("/sdcard/ModData/{APK_ID}/Modloader");
-pub const LATE_MODS_DIR: &str = formatcp!("{MODLOADER_DIR}/mods");
-pub const EARLY_MODS_DIR: &str = formatcp!("{MODLOADER_DIR}/early_mods");
-pub const LIBS_DIR: &str = formatcp!("{MODLOADER_DIR}/libs");
-pub const APP_DATA_PATH: &str = formatcp!("/sdcard/Android/data/{APK_ID}/files");
-pub const PLAYER_DATA_PATH: &str = formatcp!("{APP_DATA_PATH}/PlayerData.dat");
-pub const PLAYER_DATA_BAK_PATH: &str = formatcp!("{APP_DATA_PATH}/PlayerData.dat.bak");
-pub const APP_OBB_PATH: &str = formatcp!("/sdcard/Android/obb/{APK_ID}/");
 
 pub const DATAKEEPER_PATH: &str = "/sdcard/ModData/com.beatgames.beatsaber/Mods/datakeeper/PlayerData.dat";
 pub const DATA_BACKUP_PATH: &str = "/sdcard/ModsBeforeFriday/PlayerData.backup.dat";
+// The game's settings and local scores (along with another copy of PlayerData.dat), backed up before it is reinstalled.
+pub const PLAYER_DATA_BACKUP_DIR: &str = "/sdcard/ModsBeforeFriday/PlayerDataBackup";
+// Snapshots of the same files made on demand, each in a directory named after the time it was made.
+pub const PLAYER_DATA_SNAPSHOTS_DIR: &str = "/sdcard/ModsBeforeFriday/backups";
+pub const PINS_PATH: &str = "/sdcard/ModsBeforeFriday/repository_pins.json";
+// The number of files in each directory MBF owns, recorded after each operation so that mass deletion by other apps can be detected.
+pub const FILE_COUNTS_PATH: &str = "/sdcard/ModsBeforeFriday/mod_data_counts.json";
+// Lists the OBBs backed up while patching until they have been restored, so they can be put back if patching is interrupted.
+pub const OBB_RECOVERY_PATH: &str = "/sdcard/ModsBeforeFriday/obb_recovery.json";
+// The vanilla APK saved when the game was first patched, so that the vanilla game can be restored.
+pub const VANILLA_BACKUP_PATH: &str = "/sdcard/ModsBeforeFriday/vanilla.apk";
 
-pub const SONGS_PATH: &str = formatcp!("/sdcard/ModData/{APK_ID}/Mods/SongCore/CustomLevels");
 pub const DOWNLOADS_PATH: &str = "/data/local/tmp/mbf-downloads";
 pub const TEMP_PATH: &str = "/data/local/tmp/mbf-tmp";
+pub const APK_CACHE_PATH: &str = "/data/local/tmp/mbf-apk-cache.json";
+// The CRC-32s of OBB files that were searched by content, so that they aren't read again if downgrading is retried.
+pub const CRC_CACHE_PATH: &str = "/data/local/tmp/mbf-crc-cache.json";
+// The signature status of the installed APK, since verifying the signature reads the whole APK.
+pub const SIGNATURE_CACHE_PATH: &str = "/data/local/tmp/mbf-signature-cache.json";
+// The certificate and key generated to sign patched APKs on this device, so every APK patched on it can update the last.
+pub const DEVICE_KEY_PATH: &str = "/data/local/tmp/mbf-signing-key.pem";
+// The attribute resource IDs read from the device's framework, which are slow to read as the framework's resource table is large.
+pub const RES_IDS_CACHE_PATH: &str = "/data/local/tmp/mbf-res-ids-cache.json";
 
 // The number of attempts for all downloads before considering them failed and therefore failing the relevant operation.
 pub const DOWNLOAD_ATTEMPTS: u32 = 3;
@@ -41,12 +69,74 @@ pub const DOWNLOAD_ATTEMPTS: u32 = 3;
 pub const REQUEST_TIMEOUT_READ_SECS: u64 = 20;
 // The number of seconds between download progress updates.
 pub const PROGRESS_UPDATE_INTERVAL: f32 = 2.0;
+// Downloads at least this large are split into segments which are fetched over separate connections, if the server supports range requests.
+// Some ISPs throttle each connection, so this can make large diffs download several times faster.
+pub const SEGMENTED_DOWNLOAD_THRESHOLD: u64 = 64 * 1024 * 1024;
+// The maximum number of connections used by all running downloads together.
+// A download is only split into segments if the downloads running alongside it leave at least two connections for it.
+pub const MAX_DOWNLOAD_CONNECTIONS: u64 = 3;
+
+// The progress of each file being downloaded by `download_concurrently` as (bytes downloaded, total bytes), keyed by destination path,
+// so that the progress of concurrent downloads is reported as one percentage. None unless `download_concurrently` is running.
+static GROUP_PROGRESS: Mutex<Option<HashMap<PathBuf, (u64, u64)>>> = Mutex::new(None);
+// Set to stop all running downloads, once one of a group of concurrent downloads has failed.
+static DOWNLOADS_CANCELLED: AtomicBool = AtomicBool::new(false);
+// The number of threads of `download_concurrently` that are still downloading, each of which uses at least one connection.
+static RUNNING_DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+// The package ID of the game being modded, if a request gave one other than Beat Saber's.
+static APK_ID: OnceLock<String> = OnceLock::new();
+
+/// Gets the package ID of the game being modded, which is Beat Saber unless the request gave another package.
+pub fn apk_id() -> &'static str {
+    APK_ID.get().map(String::as_str).unwrap_or(BEAT_SABER_ID)
+}
+
+/// Sets the package ID of the game being modded, which must be done before any request is handled, since most paths depend on it.
+pub fn set_apk_id(package_id: String) -> Result<()> {
+    // The ID is used within paths and commands, so only the characters allowed in a package name are accepted.
+    if package_id.is_empty() || !package_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_') {
+        return Err(anyhow!("Invalid package ID {package_id:?}"));
+    }
+
+    APK_ID.set(package_id).map_err(|_| anyhow!("Package ID was already set"))
+}
+
+/// True if the game being modded is Beat Saber. Handling PlayerData.dat (and the datakeeper mod) is specific to Beat Saber.
+pub fn is_beat_saber() -> bool {
+    apk_id() == BEAT_SABER_ID
+}
+
+pub fn mod_data_dir() -> String {
+    format!("/sdcard/ModData/{}", apk_id())
+}
+
+pub fn modloader_dir() -> String {
+    format!("{}/Modloader", mod_data_dir())
+}
+
+pub fn late_mods_dir() -> String {
+    format!("{}/mods", modloader_dir())
+}
+
+pub fn early_mods_dir() -> String {
+    format!("{}/early_mods", modloader_dir())
+}
+
+pub fn libs_dir() -> String {
+    format!("{}/libs", modloader_dir())
+}
+
+pub fn songs_path() -> String {
+    format!("{}/Mods/SongCore/CustomLevels", mod_data_dir())
+}
+
+pub fn reports_path() -> String {
+    format!("{}/mbf_reports", mod_data_dir())
+}
 
 
 pub fn get_apk_path() -> Result<Option<String>> {
-    let pm_output = Command::new("pm")
-        .args(["path", APK_ID])
-        .output()
+    let pm_output = commands::run("pm", &["path", apk_id()])
         .context("Failed to get APK path")?;
     if 8 > pm_output.stdout.len() {
         // App not installed
@@ -58,66 +148,371 @@ pub fn get_apk_path() -> Result<Option<String>> {
     }
 }
 
+// Gets the package name of the app that installed the game, or None if it was installed directly (e.g. by sideloading with ADB).
+pub fn get_installer_package() -> Result<Option<String>> {
+    let pm_output = commands::run("pm", &["list", "packages", "-i", apk_id()])
+        .context("Failed to get installer package")?;
+
+    Ok(parse_installer_package(&String::from_utf8_lossy(&pm_output.stdout), apk_id()))
+}
+
+// Finds the installer of the package with ID `package_id` in the output of `pm list packages -i`.
+fn parse_installer_package(pm_output: &str, package_id: &str) -> Option<String> {
+    // Each line is of the form "package:<package ID>  installer=<installer ID>"
+    // The package ID is matched exactly, as `pm list packages` also lists packages with IDs that contain the given ID.
+    for line in pm_output.lines() {
+        if let Some((package, installer)) = line.trim()
+            .strip_prefix("package:")
+            .and_then(|rest| rest.split_once("installer="))
+        {
+            if package.trim() == package_id {
+                return match installer.trim() {
+                    "null" | "" => None,
+                    installer => Some(installer.to_string())
+                };
+            }
+        }
+    }
+
+    None
+}
+
+// Gets the number of bytes available to the agent on the filesystem containing `path`.
+pub fn get_free_space(path: impl AsRef<Path>) -> Result<u64> {
+    let c_path = std::ffi::CString::new(path.as_ref().as_os_str().as_encoded_bytes())
+        .context("Path contained a null byte")?;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to get free space");
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
 fn download_file_with_attempts(to: impl AsRef<Path>, url: &str) -> Result<()> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout_read(Duration::from_secs(REQUEST_TIMEOUT_READ_SECS))
+        .build();
+
+    download_file_with_agent(&agent, to, &[url.to_string()], None)?;
+    Ok(())
+}
+
+// Downloads the file using an agent that checks the identity of the server against the saved pins.
+// This should be used for files from the diff and libunity repositories.
+fn download_pinned_file_with_attempts(to: impl AsRef<Path>, url: &str) -> Result<()> {
+    download_file_with_agent(&pinning::pinned_agent()?, to, &[url.to_string()], None)?;
+    Ok(())
+}
+
+// Downloads the file in the same way as `download_pinned_file_with_attempts`, but from several mirrors of the same file,
+// moving on to the next mirror with each attempt. If `sha256` is given, an attempt is counted as failed if the downloaded file
+// doesn't have that hex encoded SHA-256 hash. Gives the URL that the file was downloaded from.
+fn download_pinned_file_from_mirrors<'a>(to: impl AsRef<Path>, urls: &'a [String], sha256: Option<&str>) -> Result<&'a str> {
+    download_file_with_agent(&pinning::pinned_agent()?, to, urls, sha256)
+}
+
+/// Calls `download` for each of `items`, running up to `concurrency` at once, and reports their combined progress.
+/// If any download fails, the others are cancelled and the error from the failed download is given.
+pub fn download_concurrently<T: Sync>(items: &[T], concurrency: usize, download: impl Fn(&T) -> Result<()> + Sync) -> Result<()> {
+    *GROUP_PROGRESS.lock().unwrap_or_else(|err| err.into_inner()) = Some(HashMap::new());
+    let next_item = AtomicUsize::new(0);
+    let first_error = Mutex::new(None);
+    let threads = concurrency.clamp(1, items.len().max(1));
+    RUNNING_DOWNLOADS.store(threads, Ordering::Relaxed);
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                loop {
+                    let index = next_item.fetch_add(1, Ordering::Relaxed);
+                    if index >= items.len() || DOWNLOADS_CANCELLED.load(Ordering::Relaxed) {
+                        break;
+                    }
+
+                    if let Err(err) = download(&items[index]) {
+                        // Any later errors are likely to just be because the downloads were cancelled, so only the first is kept.
+                        first_error.lock().unwrap_or_else(|err| err.into_inner()).get_or_insert(err);
+                        DOWNLOADS_CANCELLED.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                }
+                // Once this thread has nothing left to download, the remaining downloads can use its connection.
+                RUNNING_DOWNLOADS.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    });
+
+    DOWNLOADS_CANCELLED.store(false, Ordering::Relaxed);
+    *GROUP_PROGRESS.lock().unwrap_or_else(|err| err.into_inner()) = None;
+    match first_error.into_inner().unwrap_or_else(|err| err.into_inner()) {
+        Some(err) => Err(err),
+        None => Ok(())
+    }
+}
+
+// Fails if the running downloads have been cancelled.
+fn check_cancelled() -> Result<()> {
+    if DOWNLOADS_CANCELLED.load(Ordering::Relaxed) {
+        Err(anyhow!("Download cancelled, since another download failed"))
+    }   else {
+        Ok(())
+    }
+}
+
+// Logs the progress of the download to `to`, combined with the progress of any other downloads started by `download_concurrently`.
+fn log_progress(to: &Path, downloaded: u64, total: u64) {
+    let (downloaded, total) = match GROUP_PROGRESS.lock().unwrap_or_else(|err| err.into_inner()).as_mut() {
+        Some(group) => {
+            group.insert(to.to_path_buf(), (downloaded, total));
+            group.values().fold((0, 0), |(group_downloaded, group_total), (downloaded, total)| (group_downloaded + downloaded, group_total + total))
+        },
+        None => (downloaded, total)
+    };
+
+    info!("Progress: {:.2}%", (downloaded as f32 / total as f32) * 100.0);
+}
+
+// Downloads the file, trying each of `urls` in turn (which must all serve the same file) until an attempt succeeds.
+// Gives the URL that the file was downloaded from.
+fn download_file_with_agent<'a>(agent: &ureq::Agent, to: impl AsRef<Path>, urls: &'a [String], expected_sha256: Option<&str>) -> Result<&'a str> {
+    // Every URL is tried at least once.
+    let max_attempts = (DOWNLOAD_ATTEMPTS as usize).max(urls.len());
     let mut attempt = 0;
+    // The number of bytes at the start of the file that were downloaded by the last attempt, which the next attempt can resume from.
+    let mut resume_from = 0;
     loop {
+        let url = &urls[attempt % urls.len()];
         attempt += 1;
-        match download_file_one_attempt(&to, url) {
-            Ok(_) => return Ok(()),
-            Err(err) => if attempt == 3 {
+        let result = download_file_one_attempt(agent, &to, url, &mut resume_from).and_then(|_| match expected_sha256 {
+            Some(expected) => check_sha256(to.as_ref(), expected),
+            None => Ok(())
+        });
+        match result {
+            Ok(_) => return Ok(url),
+            Err(err) => if attempt >= max_attempts || DOWNLOADS_CANCELLED.load(Ordering::Relaxed) {
                 return Err(err).context("Failed to download file after maximum attempts")
             }   else    {
+                // If the whole file was downloaded but had the wrong hash, it has been removed so must be downloaded again in full.
+                if !to.as_ref().exists() {
+                    resume_from = 0;
+                }
                 warn!("Failed to download file {url}: {err}. Trying again...")
             }
         }
     }
 }
 
-fn download_file_one_attempt(to: impl AsRef<Path>, url: &str) -> Result<()> {
-    let agent = ureq::AgentBuilder::new()
-        .timeout_read(Duration::from_secs(REQUEST_TIMEOUT_READ_SECS))
-        .build();
+// Downloads the file at `url` to `to`. If `resume_from` is non-zero, the download continues from that byte if the server supports it.
+// `resume_from` is updated with the number of contiguous bytes written so far, so that a failed attempt can be resumed.
+fn download_file_one_attempt(agent: &ureq::Agent, to: impl AsRef<Path>, url: &str, resume_from: &mut u64) -> Result<()> {
+    if *resume_from > 0 {
+        let resp = agent.get(url)
+            .set("Range", &format!("bytes={}-", *resume_from))
+            .call()
+            .context("Failed to request file")?;
+
+        if resp.status() == 206 {
+            info!("Resuming download from byte {}", *resume_from);
+            let content_len = get_content_len(&resp);
+            return save_response(resp, to, content_len, resume_from);
+        }
+
+        // The server sent the whole file, so start again from the beginning.
+        warn!("Server does not support resuming downloads, so downloading from the start");
+        *resume_from = 0;
+        let content_len = get_content_len(&resp);
+        return save_response(resp, to, content_len, resume_from);
+    }
 
     let resp = agent.get(url)
         .call()
         .context("Failed to request file")?;
 
-    let content_len = match resp.header("Content-Length") {
+    let content_len = get_content_len(&resp);
+    let supports_ranges = resp.header("Accept-Ranges").is_some_and(|ranges| ranges.eq_ignore_ascii_case("bytes"));
+
+    let segments = get_segment_count(RUNNING_DOWNLOADS.load(Ordering::Relaxed));
+    if let (true, Some(length)) = (supports_ranges, content_len) {
+        if length as u64 >= SEGMENTED_DOWNLOAD_THRESHOLD && segments > 1 {
+            // Close this connection, since each segment is fetched with its own request.
+            drop(resp);
+            match download_segmented(agent, to.as_ref(), url, length as u64, segments) {
+                Ok(_) => return Ok(()),
+                Err(err) => {
+                    warn!("Segmented download failed: {err}. Downloading over a single connection instead");
+                    let resp = agent.get(url)
+                        .call()
+                        .context("Failed to request file")?;
+                    return save_response(resp, to, content_len, resume_from);
+                }
+            }
+        }
+    }
+
+    save_response(resp, to, content_len, resume_from)
+}
+
+// Gives the number of segments that a download can be split into while `running_downloads` other downloads are also running
+// (including itself), so that no more than MAX_DOWNLOAD_CONNECTIONS connections are open at once.
+fn get_segment_count(running_downloads: usize) -> u64 {
+    MAX_DOWNLOAD_CONNECTIONS / running_downloads.max(1) as u64
+}
+
+fn get_content_len(resp: &ureq::Response) -> Option<usize> {
+    match resp.header("Content-Length") {
         Some(length) => length.parse::<usize>().ok(),
         None => None
-    };
+    }
+}
+
+// Checks that the file at `path` has the given hex encoded SHA-256 hash, removing the file if it doesn't.
+fn check_sha256(path: &Path, expected: &str) -> Result<()> {
+    let actual = file_sha256(path)?;
+    if actual.eq_ignore_ascii_case(expected) {
+        Ok(())
+    }   else {
+        fs_ops::remove_file(path)?;
+        Err(anyhow!("Downloaded file had SHA-256 {actual}, but {expected} was expected. It may have been truncated"))
+    }
+}
+
+// Gets the hex encoded SHA-256 hash of the file at `path`.
+fn file_sha256(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut BufReader::new(fs_ops::open(path)?), &mut hasher)?;
+    Ok(hasher.finalize().iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+// Saves the body of `resp` to the file at `to`, logging progress if the length of the body is known.
+// If `written` is non-zero, the body is appended to the first `written` bytes of the file, rather than replacing it.
+// `written` is updated as the body is saved.
+fn save_response(resp: ureq::Response, to: impl AsRef<Path>, content_len: Option<usize>, written: &mut u64) -> Result<()> {
     let mut resp_body = resp.into_reader();
 
+    let start = *written;
     let mut writer = OpenOptions::new()
         .write(true)
-        .truncate(true)
+        .truncate(start == 0)
         .create(true)
-        .open(to).context("Failed to create destination file")?;
+        .open(&to).context("Failed to create destination file")?;
+    // Anything written after the first `start` bytes was not downloaded contiguously, so is overwritten.
+    writer.set_len(start).context("Failed to truncate destination file")?;
+    writer.seek(SeekFrom::Start(start))?;
 
     match content_len {
         Some(length) => {
             // Update the frontend with some indication of progress of the download.
             // This will do nothing for small downloads, since they should take less than 5 seconds to complete.
+            // The progress includes any part of the file downloaded before resuming, so that it doesn't jump backwards.
+            let total_len = start + length as u64;
             let mut last_progress_update = Instant::now();
+            let to = to.as_ref();
             copy_stream_progress(&mut resp_body, &mut writer, &mut |bytes_copied| {
+                *written = start + bytes_copied as u64;
                 let now = Instant::now();
                 if now.duration_since(last_progress_update).as_secs_f32() > PROGRESS_UPDATE_INTERVAL {
                     last_progress_update = now;
-                    info!("Progress: {:.2}%", (bytes_copied as f32 / length as f32) * 100.0);
+                    log_progress(to, *written, total_len);
                 }
             })?;
         },
         None => {
             warn!("No Content-Length header, so cannot report download progress");
-            std::io::copy(&mut resp_body, &mut writer)?;
+            copy_stream_progress(&mut resp_body, &mut writer, &mut |bytes_copied| *written = start + bytes_copied as u64)?;
         }
     }
-   
 
     Ok(())
 }
 
-fn copy_stream_progress<T: FnMut(usize) -> ()>(from: &mut impl Read,
+// Downloads the file at `url`, which is `length` bytes long, to `to` by fetching `segments` ranges of the file concurrently.
+fn download_segmented(agent: &ureq::Agent, to: &Path, url: &str, length: u64, segments: u64) -> Result<()> {
+    info!("Downloading file over {segments} connections");
+    let file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(to).context("Failed to create destination file")?;
+    file.set_len(length).context("Failed to allocate destination file")?;
+
+    let segment_len = length.div_ceil(segments);
+    let downloaded = AtomicU64::new(0);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..segments)
+            .map(|segment| (segment * segment_len, ((segment + 1) * segment_len).min(length)))
+            .filter(|(start, end)| start < end)
+            .map(|(start, end)| {
+                let (file, downloaded) = (&file, &downloaded);
+                scope.spawn(move || download_segment(agent, url, file, start, end, downloaded))
+            })
+            .collect();
+
+        // Combine the progress of all segments into one progress update.
+        let mut last_progress_update = Instant::now();
+        while !handles.iter().all(|handle| handle.is_finished()) {
+            std::thread::sleep(Duration::from_millis(100));
+            if last_progress_update.elapsed().as_secs_f32() > PROGRESS_UPDATE_INTERVAL {
+                last_progress_update = Instant::now();
+                log_progress(to, downloaded.load(Ordering::Relaxed), length);
+            }
+        }
+
+        handles.into_iter()
+            .try_for_each(|handle| handle.join().expect("Download segment panicked"))
+    })
+}
+
+// Downloads bytes `start..end` of the file at `url`, writing them at the same position within `file`.
+// If the connection fails, the segment is resumed from the last byte written.
+fn download_segment(agent: &ureq::Agent, url: &str, file: &File, start: u64, end: u64, downloaded: &AtomicU64) -> Result<()> {
+    let mut position = start;
+    let mut attempt = 0;
+    while position < end {
+        attempt += 1;
+        match download_range(agent, url, file, &mut position, end, downloaded) {
+            Ok(_) => {},
+            Err(err) if attempt < DOWNLOAD_ATTEMPTS && !DOWNLOADS_CANCELLED.load(Ordering::Relaxed) => warn!("Failed to download segment at {position}: {err}. Trying again..."),
+            Err(err) => return Err(err).context("Failed to download segment after maximum attempts")
+        }
+    }
+
+    Ok(())
+}
+
+fn download_range(agent: &ureq::Agent, url: &str, file: &File, position: &mut u64, end: u64, downloaded: &AtomicU64) -> Result<()> {
+    let resp = agent.get(url)
+        .set("Range", &format!("bytes={}-{}", *position, end - 1))
+        .call()
+        .context("Failed to request segment")?;
+    // A server that ignores the range will send the whole file, which can't be split into segments.
+    if resp.status() != 206 {
+        return Err(anyhow!("Server did not return partial content (status {})", resp.status()));
+    }
+
+    let mut reader = resp.into_reader().take(end - *position);
+    let mut buffer = vec![0u8; 65536];
+    loop {
+        check_cancelled()?;
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+
+        file.write_all_at(&buffer[0..bytes_read], *position)?;
+        *position += bytes_read as u64;
+        downloaded.fetch_add(bytes_read as u64, Ordering::Relaxed);
+    }
+
+    if *position < end {
+        return Err(anyhow!("Connection closed before the segment was complete"));
+    }
+    Ok(())
+}
+
+fn copy_stream_progress<T: FnMut(usize)>(from: &mut impl Read,
     to: &mut impl Write,
     progress: &mut T
     ) -> Result<()> {
@@ -125,8 +520,9 @@ fn copy_stream_progress<T: FnMut(usize) -> ()>(from: &mut impl Read,
 
     let mut total_read = 0;
     loop {
+        check_cancelled()?;
         let bytes_read = from.read(&mut buffer)?;
-        to.write(&buffer[0..bytes_read])?;
+        to.write_all(&buffer[0..bytes_read])?;
 
         if bytes_read == 0  {
             break Ok(());
@@ -137,15 +533,6 @@ fn copy_stream_progress<T: FnMut(usize) -> ()>(from: &mut impl Read,
     } 
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(rename_all = "camelCase")]
-struct ModTag {
-    patcher_name: String,
-    patcher_version: Option<String>,
-    modloader_name: String,
-    modloader_version: Option<String>
-}
-
 struct ResponseLogger {}
 
 impl log::Log for ResponseLogger {
@@ -154,6 +541,10 @@ impl log::Log for ResponseLogger {
     }
 
     fn log(&self, record: &log::Record) {
+        if record.level() == Level::Warn {
+            reports::record_warning(&record.args().to_string());
+        }
+
         // Ignore errors, logging should be infallible and we don't want to panic
         let _result = write_response(Response::LogMsg {
             message: format!("{}", record.args()),
@@ -188,19 +579,334 @@ fn main() -> Result<()> {
     let mut reader = BufReader::new(std::io::stdin());
     let mut line = String::new();
     reader.read_line(&mut line)?;
-    let req: Request = serde_json::from_str(&line)?;
+    let mut request_json: serde_json::Value = serde_json::from_str(&line)?;
+    // Any request can give the package ID of the game to mod, since where mods are saved depends on it.
+    if let Some(package_id) = request_json.as_object_mut().and_then(|request| request.remove("package_id")) {
+        set_apk_id(serde_json::from_value(package_id).context("Package ID was not a string")?)?;
+    }
+    let req: Request = serde_json::from_value(request_json)?;
 
     // Set a panic hook that writes the panic as a JSON Log
     // (we don't do this in catch_unwind as we get an `Any` there, which doesn't implement Display)
     panic::set_hook(Box::new(|info| error!("Request failed due to a panic!: {info}")));
 
-    match std::panic::catch_unwind(|| handlers::handle_request(req)) {
-        Ok(resp) => match resp {
+    // If a previous patch was interrupted after the OBBs were removed, put them back before doing anything else.
+    // `RepairObbs` does this itself, so that it can give the OBBs that were restored.
+    if !matches!(req, Request::RepairObbs) && obb_recovery::is_pending() {
+        warn!("Patching was interrupted before the OBB files were restored, so restoring them now");
+        match obb_recovery::recover() {
+            Ok(restored) => info!("Restored {} OBB files", restored.len()),
+            Err(err) => warn!("Failed to restore OBB files: {err:?}")
+        }
+    }
+
+    let operation = reports::Operation::from_request(&req);
+
+    // If a panic occurs, it will be outputted by the hook above
+    let result = std::panic::catch_unwind(|| handlers::handle_request(req));
+    if let Some(operation) = operation {
+        // Only read-only requests have no operation, and most other requests may have changed the contents of ModData, even if they failed.
+        if operation.changes_mod_data() {
+            storage_guard::protect_mod_data();
+        }
+
+        operation.save_report(match &result {
+            Ok(Ok(resp)) => reports::Outcome::Succeeded(resp),
+            Ok(Err(err)) => reports::Outcome::Failed(err),
+            Err(_) => reports::Outcome::Panicked
+        });
+    }
+
+    if let Ok(resp) = result {
+        match resp {
             Ok(resp) => write_response(resp)?,
-            Err(err) => error!("{err:?}")
-        },
-        Err(_) => {} // Panic will be outputted above
-    };
+            Err(err) => {
+                error!("{err:?}");
+                for message in failed_command_messages(&commands::get_transcripts()) {
+                    error!("{message}");
+                }
+
+                // If the request failed as a repository's identity changed, let the frontend know so that it can ask the user to confirm the change.
+                if let Some(change) = pinning::take_identity_change() {
+                    write_response(Response::RepositoryIdentityChanged {
+                        host: change.host,
+                        old: change.old,
+                        new: change.new
+                    })?;
+                }
+
+                if let Some(mismatch) = err.chain().find_map(|cause| cause.downcast_ref::<integrity::DiffCrcMismatch>()) {
+                    write_response(Response::IntegrityCheckFailed {
+                        file_name: mismatch.file_name.clone(),
+                        class: mismatch.class,
+                        evidence: mismatch.evidence.clone()
+                    })?;
+                }
+
+                if let Some(failure) = err.chain().find_map(|cause| cause.downcast_ref::<package_manager::PmFailure>()) {
+                    write_response(Response::PackageManagerFailed {
+                        command: failure.command.to_string(),
+                        kind: failure.kind,
+                        code: failure.code.clone(),
+                        output: failure.output.clone(),
+                        hint: failure.kind.hint().to_string()
+                    })?;
+                }
+
+                if let Some(shortage) = err.chain().find_map(|cause| cause.downcast_ref::<patching::InsufficientStorage>()) {
+                    write_response(Response::InsufficientStorage {
+                        purpose: shortage.purpose.clone(),
+                        path: shortage.path.to_string_lossy().to_string(),
+                        required: shortage.required,
+                        available: shortage.available
+                    })?;
+                }
+
+                if let Some(change) = err.chain().find_map(|cause| cause.downcast_ref::<patching::SignerChangeNeeded>()) {
+                    write_response(Response::SignerChangeConfirmationNeeded {
+                        previous_sha256: change.previous_sha256.clone(),
+                        signer_sha256: change.signer_sha256.clone()
+                    })?;
+                }
+
+                if let Some(confirmation) = err.chain().find_map(|cause| cause.downcast_ref::<patching::DownloadConfirmationNeeded>()) {
+                    write_response(Response::DownloadConfirmationNeeded {
+                        total_size: confirmation.total_size,
+                        downloads: confirmation.downloads.clone()
+                    })?;
+                }
+
+                if let Some(failure) = err.chain().find_map(|cause| cause.downcast_ref::<fs_ops::IoFailure>()) {
+                    write_response(Response::from(failure))?;
+                }
+            }
+        }
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
+// Gives the messages logged alongside an error for the given commands.
+// Only failed commands are included, to keep the error small. The operation report includes every command.
+fn failed_command_messages(transcripts: &[commands::Transcript]) -> Vec<String> {
+    commands::failed_transcripts(transcripts)
+        .map(|transcript| format!("Command failed: {transcript}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKAGE_ID: &str = "com.beatgames.beatsaber";
+
+    #[test]
+    fn installer_is_read_for_the_exact_package() {
+        // `pm list packages` matches by substring, so other packages are listed too, in no particular order.
+        let output = "package:com.beatgames.beatsaber.demo  installer=com.android.shell\n\
+            package:com.beatgames.beatsaber  installer=com.oculus.ocms\n";
+
+        assert_eq!(parse_installer_package(output, PACKAGE_ID).as_deref(), Some("com.oculus.ocms"));
+    }
+
+    #[test]
+    fn null_installer_is_none() {
+        assert_eq!(parse_installer_package("package:com.beatgames.beatsaber  installer=null\n", PACKAGE_ID), None);
+        assert_eq!(parse_installer_package("package:com.beatgames.beatsaber installer=\n", PACKAGE_ID), None);
+    }
+
+    #[test]
+    fn crlf_and_indented_output_is_parsed() {
+        let output = "\r\n  package:com.beatgames.beatsaber  installer=com.android.shell\r\n";
+        assert_eq!(parse_installer_package(output, PACKAGE_ID).as_deref(), Some("com.android.shell"));
+    }
+
+    #[test]
+    fn missing_package_has_no_installer() {
+        assert_eq!(parse_installer_package("", PACKAGE_ID), None);
+        assert_eq!(parse_installer_package("package:com.beatgames.beatsaber.demo  installer=com.oculus.ocms\n", PACKAGE_ID), None);
+        // Older versions of `pm` ignore `-i`, giving no installer at all.
+        assert_eq!(parse_installer_package("package:com.beatgames.beatsaber\n", PACKAGE_ID), None);
+    }
+
+    #[test]
+    fn concurrent_downloads_share_the_connection_limit() {
+        // Downloads outside of `download_concurrently` aren't counted, so can use every connection.
+        assert_eq!(get_segment_count(0), MAX_DOWNLOAD_CONNECTIONS);
+        assert_eq!(get_segment_count(1), MAX_DOWNLOAD_CONNECTIONS);
+        for running in 1..=5 {
+            let segments = get_segment_count(running).max(1);
+            assert!(segments * running as u64 <= MAX_DOWNLOAD_CONNECTIONS.max(running as u64),
+                "{running} downloads of {segments} segments each use too many connections");
+        }
+        // Three diffs downloaded at once are each fetched over a single connection.
+        assert!(get_segment_count(3) <= 1);
+    }
+
+    // Installs `apk_path` with the `pm` of `device`, giving the result and the transcripts of the `pm` commands run.
+    fn install_with_fake_pm(device: &test_harness::FakeDevice, apk_path: &Path) -> (Result<()>, Vec<commands::Transcript>) {
+        let already_run = commands::get_transcripts().len();
+        let result = package_manager::install(apk_path);
+        let transcripts: Vec<commands::Transcript> = commands::get_transcripts().split_off(already_run)
+            .into_iter()
+            .filter(|transcript| transcript.argv[0] == "pm")
+            .collect();
+        assert_eq!(device.calls("pm").len(), transcripts.len(), "every pm command should have been run on the fake device");
+        (result, transcripts)
+    }
+
+    #[test]
+    fn only_failed_pm_transcripts_are_inline_but_the_report_has_every_one() {
+        let device = test_harness::FakeDevice::builder("install-failing")
+            .pm_response("install-write", 1, "Failure [INSTALL_FAILED_INVALID_APK: Failed to parse]")
+            .build();
+        let apk_path = device.path("data/local/tmp/base.apk");
+        std::fs::write(&apk_path, b"not really an APK").unwrap();
+
+        let (result, transcripts) = install_with_fake_pm(&device, &apk_path);
+        let err = result.expect_err("install-write failed, so installing should fail");
+        assert!(err.chain().any(|cause| cause.downcast_ref::<package_manager::PmFailure>().is_some()));
+
+        let messages = failed_command_messages(&transcripts);
+        assert_eq!(messages.len(), 1, "only the failed command should be given: {messages:?}");
+        assert!(messages[0].starts_with("Command failed: $ pm install-write -S 17 42 base.apk -"), "{}", messages[0]);
+        assert!(messages[0].contains("exit code 1)"));
+        assert!(messages[0].contains("Failure [INSTALL_FAILED_INVALID_APK: Failed to parse]"));
+
+        let mut report = String::new();
+        reports::write_transcripts(&mut report, &transcripts).unwrap();
+        for command in ["install-create", "install-write", "install-abandon"] {
+            assert!(report.contains(&format!("$ pm {command}")), "the report should include pm {command}: {report}");
+        }
+
+        drop(device);
+        let device = test_harness::FakeDevice::builder("install-succeeding").build();
+        let apk_path = device.path("data/local/tmp/base.apk");
+        std::fs::write(&apk_path, b"not really an APK").unwrap();
+        let (result, transcripts) = install_with_fake_pm(&device, &apk_path);
+        result.expect("every pm command succeeded, so installing should succeed");
+        assert!(failed_command_messages(&transcripts).is_empty());
+
+        let mut report = String::new();
+        reports::write_transcripts(&mut report, &transcripts).unwrap();
+        assert!(report.contains("$ pm install-commit 42"), "the report should include every command: {report}");
+    }
+
+    // Serves `body` over HTTP on localhost, recording the `Range` header of each request.
+    // The responses to the requests numbered in `cut_requests` (counting from 0) stop halfway through.
+    // If `honour_ranges` is false, ranges are ignored and the whole body is sent with status 200.
+    struct RangeServer {
+        url: String,
+        ranges: std::sync::Arc<Mutex<Vec<Option<String>>>>
+    }
+
+    fn serve_with_ranges(body: Vec<u8>, cut_requests: &'static [usize], honour_ranges: bool) -> RangeServer {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file.bin", listener.local_addr().unwrap());
+        let ranges = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let recorded = ranges.clone();
+        // The server thread is left running until the tests exit.
+        std::thread::spawn(move || for (request, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            let range = BufReader::new(&stream).lines()
+                .map(|line| line.unwrap())
+                .take_while(|line| !line.is_empty())
+                .find_map(|line| line.strip_prefix("Range: bytes=").map(str::to_string));
+            recorded.lock().unwrap().push(range.clone());
+
+            let (start, end) = match range.as_deref().and_then(|range| range.split_once('-')).filter(|_| honour_ranges) {
+                Some((start, end)) => (start.parse().unwrap(), if end.is_empty() { body.len() } else { end.parse::<usize>().unwrap() + 1 }),
+                None => (0, body.len())
+            };
+            let status = if honour_ranges && range.is_some() { "206 Partial Content" } else { "200 OK" };
+            let header = format!("HTTP/1.1 {status}\r\nContent-Length: {}\r\nContent-Range: bytes {start}-{}/{}\r\nConnection: close\r\n\r\n",
+                end - start, end - 1, body.len());
+            stream.write_all(header.as_bytes()).unwrap();
+            let sent_end = if cut_requests.contains(&request) { start + (end - start) / 2 } else { end };
+            // The client may already have given up on a cut response.
+            let _ = stream.write_all(&body[start..sent_end]);
+        });
+
+        RangeServer { url, ranges }
+    }
+
+    fn range_test_body() -> Vec<u8> {
+        (0..200_000u32).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn range_test_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mbf-range-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn interrupted_download_is_resumed_from_the_bytes_already_written() {
+        let body = range_test_body();
+        let server = serve_with_ranges(body.clone(), &[0], true);
+        let path = range_test_path("resumed");
+        let mut hasher = Sha256::new();
+        hasher.update(&body);
+        let sha256: String = hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect();
+
+        download_file_with_agent(&ureq::agent(), &path, std::slice::from_ref(&server.url), Some(&sha256)).unwrap();
+        assert!(std::fs::read(&path).unwrap() == body, "the resumed download should match the original file");
+        assert_eq!(*server.ranges.lock().unwrap(), [None, Some(format!("{}-", body.len() / 2))]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn download_restarts_if_the_server_ignores_the_range() {
+        let body = range_test_body();
+        let server = serve_with_ranges(body.clone(), &[0], false);
+        let path = range_test_path("restarted");
+
+        download_file_with_agent(&ureq::agent(), &path, std::slice::from_ref(&server.url), None).unwrap();
+        assert!(std::fs::read(&path).unwrap() == body, "the part downloaded before restarting should have been replaced");
+        assert_eq!(server.ranges.lock().unwrap().len(), 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn interrupted_segment_is_resumed_from_its_last_byte() {
+        let body = range_test_body();
+        let server = serve_with_ranges(body.clone(), &[0], true);
+        let path = range_test_path("segmented");
+
+        download_segmented(&ureq::agent(), &path, &server.url, body.len() as u64, 3).unwrap();
+        assert!(std::fs::read(&path).unwrap() == body, "the segments should have been written at their offsets");
+        let ranges = server.ranges.lock().unwrap();
+        assert_eq!(ranges.len(), 4, "one segment should have been resumed: {ranges:?}");
+        let segment_len = body.len().div_ceil(3);
+        let starts: Vec<usize> = ranges.iter()
+            .map(|range| range.as_ref().unwrap().split_once('-').unwrap().0.parse().unwrap())
+            .collect();
+        assert!(starts.iter().any(|start| start % segment_len == segment_len / 2), "the resumed segment should start halfway through: {ranges:?}");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn segmented_download_fails_if_the_server_ignores_ranges() {
+        let body = range_test_body();
+        let server = serve_with_ranges(body.clone(), &[], false);
+        let path = range_test_path("unsegmented");
+
+        let err = download_segmented(&ureq::agent(), &path, &server.url, body.len() as u64, 3).unwrap_err();
+        assert!(format!("{err:#}").contains("Server did not return partial content (status 200)"), "{err:#}");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn download_with_the_wrong_hash_is_retried_in_full_and_removed() {
+        let server = serve_with_ranges(range_test_body(), &[], true);
+        let path = range_test_path("wrong-hash");
+        let expected = "00".repeat(32);
+
+        let err = download_file_with_agent(&ureq::agent(), &path, std::slice::from_ref(&server.url), Some(&expected)).unwrap_err();
+        assert!(format!("{err:#}").contains(&format!("but {expected} was expected")), "{err:#}");
+        assert!(!path.exists(), "a file with the wrong hash should not be left behind");
+        // The file is removed after each mismatch, so nothing is resumed.
+        assert_eq!(*server.ranges.lock().unwrap(), vec![None; DOWNLOAD_ATTEMPTS as usize]);
+    }
+}
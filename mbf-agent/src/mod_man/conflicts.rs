@@ -0,0 +1,189 @@
+//! Works out how the files that a mod would install overlap with those installed by other mods.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use semver::Version;
+
+/// The files that a mod installs (or would install if it is incoming), with the ID and version of the mod.
+pub struct ModFiles<'a> {
+    pub id: &'a str,
+    pub version: &'a Version,
+    /// The path of each file and the CRC-32 of its contents within the QMOD (None if the QMOD doesn't contain the file)
+    pub files: HashMap<PathBuf, Option<u32>>
+}
+
+/// How a file that a mod would install relates to the same file installed by another mod.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverlapKind {
+    /// Both mods install the same contents, so the file is shared.
+    Identical,
+    /// The file is installed by the same or an older version of the same mod, which the incoming mod replaces.
+    Upgrade,
+    /// The file is installed by a newer version of the same mod.
+    Downgrade,
+    /// The file is installed by a different mod with different contents.
+    /// Files missing from either QMOD are treated as conflicting, since we can't tell if their contents match.
+    Conflict
+}
+
+/// A file that an incoming mod would install which is already installed by another mod.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileOverlap {
+    pub path: PathBuf,
+    /// The ID of the installed mod that the file overlaps with.
+    pub other_mod_id: String,
+    pub other_version: Version,
+    pub kind: OverlapKind
+}
+
+/// Classifies each file that `incoming` would install which is also installed by one of the `installed` mods.
+/// Library files are matched by the ID of the mod that owns them, so only a version of the same mod can upgrade or downgrade them.
+/// The overlaps are sorted by path, then by the ID of the other mod.
+pub fn classify_overlaps(incoming: &ModFiles, installed: &[ModFiles]) -> Vec<FileOverlap> {
+    let mut overlaps = Vec::new();
+    for other in installed {
+        for (path, other_crc) in &other.files {
+            let crc = match incoming.files.get(path) {
+                Some(crc) => crc,
+                None => continue
+            };
+
+            let kind = if crc.is_some() && crc == other_crc {
+                OverlapKind::Identical
+            }   else if other.id != incoming.id {
+                OverlapKind::Conflict
+            }   else if incoming.version >= other.version {
+                OverlapKind::Upgrade
+            }   else    {
+                OverlapKind::Downgrade
+            };
+
+            overlaps.push(FileOverlap {
+                path: path.clone(),
+                other_mod_id: other.id.to_string(),
+                other_version: other.version.clone(),
+                kind
+            });
+        }
+    }
+
+    overlaps.sort_by(|a, b| (&a.path, &a.other_mod_id).cmp(&(&b.path, &b.other_mod_id)));
+    overlaps
+}
+
+/// Lists the given overlaps for an error message or log, e.g. `"/libs/a.so" (installed by b v1.0.0)`.
+pub fn describe_overlaps<'a>(overlaps: impl IntoIterator<Item = &'a FileOverlap>) -> String {
+    overlaps.into_iter()
+        .map(|overlap| format!("{:?} (installed by {} v{})", overlap.path, overlap.other_mod_id, overlap.other_version))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mod_files<'a>(id: &'a str, version: &'a Version, files: &[(&str, Option<u32>)]) -> ModFiles<'a> {
+        ModFiles {
+            id,
+            version,
+            files: files.iter().map(|(path, crc)| (PathBuf::from(path), *crc)).collect()
+        }
+    }
+
+    fn kinds(overlaps: &[FileOverlap]) -> Vec<(&str, &str, OverlapKind)> {
+        overlaps.iter()
+            .map(|overlap| (overlap.path.to_str().unwrap(), overlap.other_mod_id.as_str(), overlap.kind))
+            .collect()
+    }
+
+    #[test]
+    fn files_only_installed_by_one_mod_do_not_overlap() {
+        let version = Version::new(1, 0, 0);
+        let incoming = mod_files("a", &version, &[("/libs/liba.so", Some(1))]);
+        let installed = [mod_files("b", &version, &[("/libs/libb.so", Some(2))])];
+
+        assert!(classify_overlaps(&incoming, &installed).is_empty());
+    }
+
+    #[test]
+    fn same_contents_are_identical() {
+        let version = Version::new(1, 0, 0);
+        let incoming = mod_files("a", &version, &[("/libs/libshared.so", Some(7))]);
+        let installed = [mod_files("b", &version, &[("/libs/libshared.so", Some(7))])];
+
+        assert_eq!(kinds(&classify_overlaps(&incoming, &installed)),
+            [("/libs/libshared.so", "b", OverlapKind::Identical)]);
+    }
+
+    #[test]
+    fn newer_version_of_the_same_mod_is_an_upgrade() {
+        let old = Version::new(1, 0, 0);
+        let new = Version::new(1, 2, 0);
+        let incoming = mod_files("a", &new, &[("/libs/liba.so", Some(2))]);
+        let installed = [mod_files("a", &old, &[("/libs/liba.so", Some(1))])];
+
+        assert_eq!(kinds(&classify_overlaps(&incoming, &installed)),
+            [("/libs/liba.so", "a", OverlapKind::Upgrade)]);
+    }
+
+    #[test]
+    fn rebuild_of_the_same_version_is_an_upgrade() {
+        let version = Version::new(1, 0, 0);
+        let incoming = mod_files("a", &version, &[("/libs/liba.so", Some(2))]);
+        let installed = [mod_files("a", &version, &[("/libs/liba.so", Some(1))])];
+
+        assert_eq!(classify_overlaps(&incoming, &installed)[0].kind, OverlapKind::Upgrade);
+    }
+
+    #[test]
+    fn older_version_of_the_same_mod_is_a_downgrade() {
+        let old = Version::new(0, 9, 0);
+        let new = Version::new(1, 0, 0);
+        let incoming = mod_files("a", &old, &[("/libs/liba.so", Some(1))]);
+        let installed = [mod_files("a", &new, &[("/libs/liba.so", Some(2))])];
+
+        let overlaps = classify_overlaps(&incoming, &installed);
+        assert_eq!(kinds(&overlaps), [("/libs/liba.so", "a", OverlapKind::Downgrade)]);
+        assert_eq!(overlaps[0].other_version, new);
+    }
+
+    #[test]
+    fn different_contents_from_another_mod_conflict() {
+        let older = Version::new(1, 0, 0);
+        let newer = Version::new(2, 0, 0);
+        // Versions of different mods are unrelated, so a higher version doesn't make it an upgrade.
+        let incoming = mod_files("a", &newer, &[("/sdcard/config.json", Some(1))]);
+        let installed = [mod_files("b", &older, &[("/sdcard/config.json", Some(2))])];
+
+        assert_eq!(kinds(&classify_overlaps(&incoming, &installed)),
+            [("/sdcard/config.json", "b", OverlapKind::Conflict)]);
+    }
+
+    #[test]
+    fn missing_files_conflict() {
+        let version = Version::new(1, 0, 0);
+        let incoming = mod_files("a", &version, &[("/libs/lib.so", None)]);
+        let installed = [mod_files("b", &version, &[("/libs/lib.so", None)])];
+
+        assert_eq!(classify_overlaps(&incoming, &installed)[0].kind, OverlapKind::Conflict);
+    }
+
+    #[test]
+    fn each_installed_mod_is_classified_separately() {
+        let version = Version::new(1, 0, 0);
+        let incoming = mod_files("a", &version, &[("/libs/lib.so", Some(1)), ("/mods/a.so", Some(3))]);
+        let installed = [
+            mod_files("c", &version, &[("/libs/lib.so", Some(2))]),
+            mod_files("b", &version, &[("/libs/lib.so", Some(1))]),
+        ];
+
+        let overlaps = classify_overlaps(&incoming, &installed);
+        assert_eq!(kinds(&overlaps), [
+            ("/libs/lib.so", "b", OverlapKind::Identical),
+            ("/libs/lib.so", "c", OverlapKind::Conflict)
+        ]);
+        assert_eq!(describe_overlaps(overlaps.iter().filter(|overlap| overlap.kind == OverlapKind::Conflict)),
+            "\"/libs/lib.so\" (installed by c v1.0.0)");
+    }
+}
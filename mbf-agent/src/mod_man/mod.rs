@@ -1,13 +1,18 @@
+mod conflicts;
 mod manifest;
+mod package_version;
 use std::{cell::RefCell, collections::{HashMap, HashSet}, fs::File, path::{Path, PathBuf}, rc::Rc};
 
+pub use conflicts::{classify_overlaps, describe_overlaps, FileOverlap, ModFiles, OverlapKind};
+
 use log::{error, info, warn};
 pub use manifest::*;
+pub use package_version::{PackageVersion, VersionSupport};
 
 use anyhow::{Context, Result, anyhow};
 use semver::Version;
 
-use crate::{download_file_with_attempts, zip::ZipFile, EARLY_MODS_DIR, LATE_MODS_DIR, LIBS_DIR, QMODS_DIR};
+use crate::{download_file_with_attempts, fs_ops, reports, text, zip::ZipFile, early_mods_dir, late_mods_dir, libs_dir, QMODS_DIR};
 
 pub struct Mod {
     manifest: ModInfo,
@@ -25,27 +30,104 @@ impl Mod {
         &self.manifest
     }
 
+    // Gets the paths of the files that this mod copies to the quest when installed,
+    // along with the CRC-32 of the file within the QMOD that is copied there (None if the QMOD doesn't contain the file)
+    fn get_destination_files(&self, dirs: &ModDirs) -> HashMap<PathBuf, Option<u32>> {
+        let mut files = HashMap::new();
+        for (file_list, dir) in [
+            (&self.manifest.mod_files, &dirs.early_mods),
+            (&self.manifest.library_files, &dirs.libs),
+            (&self.manifest.late_mod_files, &dirs.late_mods)
+        ] {
+            for file in file_list {
+                files.insert(dir.join(get_so_name(file)), self.zip.get_crc32(file));
+            }
+        }
+
+        for file_copy in &self.manifest.file_copies {
+            files.insert(PathBuf::from(&file_copy.destination), self.zip.get_crc32(&file_copy.name));
+        }
+
+        files
+    }
+
+    // Gets the files that this mod installs, for classifying how they overlap with those of other mods.
+    fn files(&self, dirs: &ModDirs) -> ModFiles<'_> {
+        ModFiles {
+            id: &self.manifest.id,
+            version: &self.manifest.version,
+            files: self.get_destination_files(dirs)
+        }
+    }
+}
+
+// The directories that QMODs are loaded from, and that their files are installed to.
+struct ModDirs {
+    qmods: PathBuf,
+    early_mods: PathBuf,
+    late_mods: PathBuf,
+    libs: PathBuf
+}
+
+impl ModDirs {
+    // The directories used by the modloader of the installed game.
+    fn for_game() -> Self {
+        Self {
+            qmods: QMODS_DIR.into(),
+            early_mods: early_mods_dir().into(),
+            late_mods: late_mods_dir().into(),
+            libs: libs_dir().into()
+        }
+    }
 }
 
 pub struct ModManager {
     mods: HashMap<String, Rc<RefCell<Mod>>>,
+    dirs: ModDirs,
+    allow_conflicts: bool,
+    game_version: Option<String>,
+    allow_version_mismatch: bool
 }
 
 impl ModManager {
     pub fn new() -> Self {    
+        Self::with_dirs(ModDirs::for_game())
+    }
+
+    fn with_dirs(dirs: ModDirs) -> Self {
         Self {
-            mods: HashMap::new()
+            mods: HashMap::new(),
+            dirs,
+            allow_conflicts: false,
+            game_version: None,
+            allow_version_mismatch: false
         }
     }
 
-    pub fn mods_path(&self) -> impl AsRef<Path> {
-        QMODS_DIR
+    /// If true, mods will be installed even if they overwrite files installed by another mod with different contents,
+    /// and older versions of installed mods can replace them. Either is logged as a warning and noted in the report of the operation.
+    /// Files shared with other installed mods are kept when a mod is uninstalled, so forcing a conflict doesn't leave the other mod missing files.
+    /// By default, installing a conflicting mod or loading a downgrade fails.
+    pub fn set_allow_conflicts(&mut self, allow_conflicts: bool) {
+        self.allow_conflicts = allow_conflicts;
+    }
+
+    /// Sets the installed game version that each mod's `packageVersion` is checked against. By default, it isn't checked.
+    /// Unless `allow_mismatch` is true, installing a mod (or dependency) that doesn't support the version fails,
+    /// as does installing one whose `packageVersion` couldn't be parsed.
+    pub fn set_game_version(&mut self, game_version: String, allow_mismatch: bool) {
+        self.game_version = Some(game_version);
+        self.allow_version_mismatch = allow_mismatch;
+    }
+
+    pub fn mods_path(&self) -> &Path {
+        &self.dirs.qmods
     }
 
     // Removes a directory and all its files recursively, if that directory already exists.
     fn remove_dir_if_exists(path: impl AsRef<Path>) -> Result<()> {
         if path.as_ref().exists() {
-            std::fs::remove_dir_all(path).context("Failed to remove directory")?;
+            fs_ops::remove_dir_all(path).context("Failed to remove directory")?;
         }
 
         Ok(())
@@ -55,11 +137,11 @@ impl ModManager {
     pub fn wipe_all_mods(&mut self) -> Result<()> {
         // Wipe absolutely everything: clean slate
         self.mods.clear();
-        Self::remove_dir_if_exists(LATE_MODS_DIR)?;
-        Self::remove_dir_if_exists(EARLY_MODS_DIR)?;
-        Self::remove_dir_if_exists(LIBS_DIR)?;
-        Self::remove_dir_if_exists(QMODS_DIR)?;
-        create_mods_dir()?;
+        Self::remove_dir_if_exists(&self.dirs.late_mods)?;
+        Self::remove_dir_if_exists(&self.dirs.early_mods)?;
+        Self::remove_dir_if_exists(&self.dirs.libs)?;
+        Self::remove_dir_if_exists(&self.dirs.qmods)?;
+        self.create_mods_dir()?;
         Ok(())
     }
 
@@ -73,10 +155,10 @@ impl ModManager {
     
     /// Loads any mods from the QMODs directory that have not yet been loaded.
     pub fn load_mods(&mut self) -> Result<()> {
-        create_mods_dir()?;
+        self.create_mods_dir()?;
         self.mods.clear();
     
-        for stat in std::fs::read_dir(QMODS_DIR)? {
+        for stat in fs_ops::read_dir(&self.dirs.qmods)? {
             let entry = match stat {
                 Ok(entry) => entry,
                 Err(_) => continue // Ignore innacessible mods
@@ -96,7 +178,7 @@ impl ModManager {
                 Err(err) => {
                     warn!("Failed to load mod from {mod_path:?}: {err}");
                     // Attempt to delete the invalid mod
-                    match std::fs::remove_file(&mod_path) {
+                    match fs_ops::remove_file(&mod_path) {
                         Ok(_) => info!("Deleted invalid mod"),
                         Err(err) => warn!("Failed to delete invalid mod at {mod_path:?}: {err}")
                     }
@@ -108,15 +190,24 @@ impl ModManager {
         Ok(())
     }
 
+    fn create_mods_dir(&self) -> Result<()> {
+        fs_ops::create_dir_all(&self.dirs.qmods)?;
+        fs_ops::create_dir_all(&self.dirs.late_mods)?;
+        fs_ops::create_dir_all(&self.dirs.early_mods)?;
+        fs_ops::create_dir_all(&self.dirs.libs)?;
+
+        Ok(())
+    }
+
     fn load_mod_from(from: PathBuf) -> Result<Mod> {
-        let mod_file = std::fs::File::open(&from).context("Failed to open mod archive")?;
+        let mod_file = fs_ops::open(&from).context("Failed to open mod archive")?;
         let mut zip = ZipFile::open(mod_file).context("Mod was invalid ZIP archive")?;
 
         let json_data = zip.read_file("mod.json").context("Mod had no mod.json manifest")?;
 
         let manifest = serde_json::from_slice(&json_data)?;
         Ok(Mod {
-            manifest: manifest,
+            manifest,
             installed: false, // Must call update_mods_status
             zip,
             loaded_from: from
@@ -125,9 +216,9 @@ impl ModManager {
 
     /// Checks whether or not each loaded mod is installed.
     pub fn update_mods_status(&mut self) -> Result<()> {
-        let early_mod_files = list_dir_files(EARLY_MODS_DIR)?;
-        let late_mod_files = list_dir_files(LATE_MODS_DIR)?;
-        let libraries = list_dir_files(LIBS_DIR)?;
+        let early_mod_files = list_dir_files(&self.dirs.early_mods)?;
+        let late_mod_files = list_dir_files(&self.dirs.late_mods)?;
+        let libraries = list_dir_files(&self.dirs.libs)?;
     
         for r#mod in self.mods.values() {
             let mut mod_info = (**r#mod).borrow_mut();
@@ -153,6 +244,7 @@ impl ModManager {
 
         let to_install = (*mod_rc).borrow();
         info!("Installing {} v{}", to_install.manifest.id, to_install.manifest.version);
+        self.check_package_version(&to_install.manifest)?;
 
         for dep in &to_install.manifest.dependencies {
             match self.mods.get(&dep.id) {
@@ -161,7 +253,7 @@ impl ModManager {
                     if !dep.version_range.matches(&dep_ref.manifest.version) {
                         info!("Dependency {} is out of date, got version {} but need {}", dep.id, dep_ref.manifest.version, dep.version_range);
                         drop(dep_ref);
-                        self.install_dependency(&dep)?;
+                        self.install_dependency(dep)?;
                     }   else if !dep_ref.installed {
                         // Must install the dependency
                         info!("Dependency {} was not installed, reinstalling", dep.id);
@@ -171,22 +263,62 @@ impl ModManager {
                 },
                 None => {
                     info!("Dependency {} was not found: installing now", dep.id);
-                    self.install_dependency(&dep)?;
+                    self.install_dependency(dep)?;
                 }
             }
         }
         drop(to_install);
 
+        let overlaps = self.find_overlaps(id);
+        let shared = overlaps.iter().filter(|overlap| overlap.kind == OverlapKind::Identical).count();
+        if shared > 0 {
+            info!("{id} shares {shared} identical files with other installed mods");
+        }
+
+        let conflicts = overlaps.iter().filter(|overlap| overlap.kind == OverlapKind::Conflict).collect::<Vec<_>>();
+        if !conflicts.is_empty() {
+            let conflicts_list = describe_overlaps(conflicts);
+            if self.allow_conflicts {
+                warn!("Installing {id} even though it will overwrite files from other mods: {conflicts_list}");
+                reports::record_note(&format!("Installed {id} despite it overwriting files from other mods: {conflicts_list}"));
+            }   else    {
+                return Err(anyhow!("{id} would overwrite files from other mods with different contents: {conflicts_list}"));
+            }
+        }
+
         self.install_unchecked(&mut (*mod_rc).borrow_mut())?;
         Ok(())
     }
 
+    // Checks that the given mod supports the game version, if set, giving an error if it doesn't and mismatches aren't allowed.
+    fn check_package_version(&self, manifest: &ModInfo) -> Result<()> {
+        let game_version = match &self.game_version {
+            Some(version) => version,
+            None => return Ok(())
+        };
+
+        let constraint = manifest.package_version.as_deref();
+        let problem = match PackageVersion::parse(constraint).supports(game_version) {
+            VersionSupport::Supported => return Ok(()),
+            VersionSupport::Unsupported => format!("{} is for Beat Saber {}, not {game_version}", manifest.id, constraint.unwrap_or_default()),
+            VersionSupport::Unknown => format!("{} gives its game version as {:?}, which could not be understood", manifest.id, constraint.unwrap_or_default())
+        };
+
+        if self.allow_version_mismatch {
+            warn!("{problem}. Installing anyway, as version mismatches are allowed");
+            reports::record_note(&format!("Installed {} v{} despite a version mismatch: {problem}", manifest.id, manifest.version));
+            Ok(())
+        }   else {
+            Err(anyhow!("{problem}. Installing it may crash the game"))
+        }
+    }
+
     /// Installs a mod without handling dependencies
     /// i.e. just copies the necessary files.
     fn install_unchecked(&self, to_install: &mut Mod) -> Result<()> {
-        copy_stated_files(&mut to_install.zip, &to_install.manifest.mod_files, EARLY_MODS_DIR)?;
-        copy_stated_files(&mut to_install.zip, &to_install.manifest.library_files, LIBS_DIR)?;
-        copy_stated_files(&mut to_install.zip, &to_install.manifest.late_mod_files, LATE_MODS_DIR)?;
+        copy_stated_files(&mut to_install.zip, &to_install.manifest.mod_files, &self.dirs.early_mods)?;
+        copy_stated_files(&mut to_install.zip, &to_install.manifest.library_files, &self.dirs.libs)?;
+        copy_stated_files(&mut to_install.zip, &to_install.manifest.late_mod_files, &self.dirs.late_mods)?;
 
         for file_copy in &to_install.manifest.file_copies {
             if !to_install.zip.contains_file(&file_copy.name) {
@@ -195,14 +327,19 @@ impl ModManager {
             }
 
             let dest_path = Path::new(&file_copy.destination);
-            match dest_path.parent() {
-                Some(parent) => std::fs::create_dir_all(parent)
-                    .context("Failed to create destination directory for file copy")?,
-                None => {}
+            if let Some(parent) = dest_path.parent() {
+                fs_ops::create_dir_all(parent)
+                    .context("Failed to create destination directory for file copy")?;
             }
 
             to_install.zip.extract_file_to(&file_copy.name, &file_copy.destination)
                 .context("Failed to extract file copy")?;
+            // Config files may have been created on Windows, but will be parsed by mods that don't expect a BOM or CRLF line endings.
+            if text::is_json_path(dest_path) {
+                if let Err(err) = text::normalise_file(dest_path) {
+                    warn!("Failed to normalise file copy {}: {err}", file_copy.name);
+                }
+            }
         }
         to_install.installed = true;
 
@@ -212,32 +349,42 @@ impl ModManager {
     /// Uninstalls a mod without handling dependencies
     /// i.e. just deletes the necessary files.
     fn uninstall_unchecked(&self, id: &str) -> Result<()> {
-        // Gather a set of all library SOs being used by other mods.
-        let mut retained_libs = HashSet::new();
+        // Gather a set of all library SOs being used by other mods,
+        // and any other files installed by other installed mods (whether identical or overwritten because conflicts were allowed)
+        let mut retained_files = HashSet::new();
         for (other_id, other_mod) in &self.mods {
             if other_id == id {
                 continue;
             }
 
-            for lib_path in other_mod.borrow()
+            let other_ref = other_mod.borrow();
+            for lib_path in other_ref
                 .manifest
                 .library_files
                 .iter() 
             {
-                retained_libs.insert(get_so_name(&lib_path).to_string());
+                retained_files.insert(self.dirs.libs.join(get_so_name(lib_path)));
+            }
+
+            if other_ref.installed {
+                retained_files.extend(other_ref.get_destination_files(&self.dirs).into_keys());
             }
         }
 
         let mut to_remove = (**self.mods.get(id).unwrap()).borrow_mut();
-        delete_file_names(&to_remove.manifest.mod_files, HashSet::new(), EARLY_MODS_DIR)?;
-        delete_file_names(&to_remove.manifest.late_mod_files, HashSet::new(), LATE_MODS_DIR)?;
-        // Only delete libraries not in use (!)
-        delete_file_names(&to_remove.manifest.library_files, retained_libs, LIBS_DIR)?;
+        // Only delete files not in use (!)
+        delete_file_names(&to_remove.manifest.mod_files, &retained_files, &self.dirs.early_mods)?;
+        delete_file_names(&to_remove.manifest.late_mod_files, &retained_files, &self.dirs.late_mods)?;
+        delete_file_names(&to_remove.manifest.library_files, &retained_files, &self.dirs.libs)?;
         
         for copy in &to_remove.manifest.file_copies {
             let dest_path = Path::new(&copy.destination);
+            if retained_files.contains(dest_path) {
+                continue;
+            }
+
             if dest_path.exists() {
-                std::fs::remove_file(dest_path).context("Failed to delete copied file")?;
+                fs_ops::remove_file(dest_path).context("Failed to delete copied file")?;
             }
         }
         to_remove.installed = false;
@@ -262,7 +409,7 @@ impl ModManager {
             }
 
             let m_ref = (**m).borrow();
-            if m_ref.installed && m_ref.manifest.dependencies.iter().any(|dep| &dep.id == id) {
+            if m_ref.installed && m_ref.manifest.dependencies.iter().any(|dep| dep.id == id) {
                 info!("Uninstalling dependant mod {}", other_id);
                 drop(m_ref);
                 self.uninstall_mod(other_id)?;
@@ -283,7 +430,7 @@ impl ModManager {
         };
 
         info!("Downloading dependency from {}", link);
-        download_file_with_attempts(&save_path, &link).context("Failed to download dependency")?;
+        download_file_with_attempts(&save_path, link).context("Failed to download dependency")?;
 
 
         // TODO: check ID matches
@@ -291,7 +438,7 @@ impl ModManager {
             Ok(_) => {},
             Err(err) => {
                 // Adding the dependency failed so it has been dropped. Therefore, delete the saved dependancy.
-                std::fs::remove_file(save_path)?;
+                fs_ops::remove_file(save_path)?;
 
                 return Err(err);
             }
@@ -306,7 +453,8 @@ impl ModManager {
 
         // Check that upgrading the mod to the new version is actually safe
         let id = loaded_mod.manifest.id.clone();
-        if self.mods.contains_key(&id) {
+        if let Some(existing) = self.mods.get(&id) {
+            self.check_not_downgrade(&loaded_mod, &existing.borrow())?;
             if !self.check_dependency_compatibility(&id, &loaded_mod.manifest.version) {
                 return Err(anyhow!("Could not upgrade {} to v{}", id, loaded_mod.manifest.version))
             }
@@ -327,7 +475,6 @@ impl ModManager {
         let mut i = 0;
         loop {
             let path = self.mods_path()
-                .as_ref()
                 .join(Path::new(&format!("{id}_{i}.qmod")));
 
             if !path.exists() {
@@ -351,39 +498,90 @@ impl ModManager {
                 }
                 self.mods.remove(id);
 
-                std::fs::remove_file(path_to_delete)?;
+                fs_ops::remove_file(path_to_delete)?;
                 Ok(())
             },
             None => Ok(())
         }
     }
 
+    // Checks that `loaded_mod` isn't an older version of the installed `existing` mod, giving an error if it is and conflicts aren't allowed.
+    fn check_not_downgrade(&self, loaded_mod: &Mod, existing: &Mod) -> Result<()> {
+        if !existing.installed {
+            return Ok(());
+        }
+
+        let overlaps = classify_overlaps(&loaded_mod.files(&self.dirs), &[existing.files(&self.dirs)]);
+        let downgrades = overlaps.iter().filter(|overlap| overlap.kind == OverlapKind::Downgrade).collect::<Vec<_>>();
+        if downgrades.is_empty() {
+            if overlaps.iter().any(|overlap| overlap.kind == OverlapKind::Upgrade) {
+                info!("Upgrading {} from v{} to v{}", existing.manifest.id, existing.manifest.version, loaded_mod.manifest.version);
+            }
+            return Ok(());
+        }
+
+        let problem = format!("{} v{} is older than the installed version, and would replace {}",
+            loaded_mod.manifest.id, loaded_mod.manifest.version, describe_overlaps(downgrades));
+        if self.allow_conflicts {
+            warn!("{problem}. Downgrading anyway, as conflicts are allowed");
+            reports::record_note(&format!("Downgraded despite the installed version being newer: {problem}"));
+            Ok(())
+        }   else    {
+            Err(anyhow!("{problem}"))
+        }
+    }
+
+    /// Classifies each file that the mod with the given ID would install which is also installed by another installed mod.
+    pub fn find_overlaps(&self, id: &str) -> Vec<FileOverlap> {
+        let incoming = match self.mods.get(id) {
+            Some(m) => (**m).borrow(),
+            None => return Vec::new()
+        };
+
+        let others = self.mods.iter()
+            .filter(|(other_id, _)| *other_id != id)
+            .map(|(_, other_mod)| (**other_mod).borrow())
+            .filter(|other_ref| other_ref.installed)
+            .collect::<Vec<_>>();
+        let installed = others.iter()
+            .map(|other_ref| other_ref.files(&self.dirs))
+            .collect::<Vec<_>>();
+
+        classify_overlaps(&incoming.files(&self.dirs), &installed)
+    }
+
+    /// Finds the files that the mod with the given ID would install which are also installed by another installed mod, with different contents.
+    /// Files with identical contents (e.g. shared libraries) are not considered to conflict.
+    pub fn find_conflicts(&self, id: &str) -> Vec<FileOverlap> {
+        self.find_overlaps(id)
+            .into_iter()
+            .filter(|overlap| overlap.kind == OverlapKind::Conflict)
+            .collect()
+    }
+
     // Checks that upgrading the dependency with ID dep_id to new_version will not result in an incompatibility with an existing installed mod.
     // Returns false if any mod has an incompatibility
     // Logs any issues discovered.
     fn check_dependency_compatibility(&self, dep_id: &str, new_version: &Version) -> bool {
         let mut all_compatible = true;
-        for (_, existing_mod) in &self.mods {
+        for existing_mod in self.mods.values() {
             let mod_ref = (**existing_mod).borrow();
             // We don't care about uninstalled mods, since they have no invariants to preserve.
             if !mod_ref.installed {
                 continue;
             }
 
-            match mod_ref.manifest.dependencies
+            if let Some(existing_dep) = mod_ref.manifest.dependencies
                 .iter()
-                .filter(|existing_dep| existing_dep.id == dep_id)
-                .next() 
+                .find(|existing_dep| existing_dep.id == dep_id)
             {
-                Some(existing_dep) => 
                 if !existing_dep.version_range.matches(new_version) {
                     all_compatible = false;
                     error!("Cannot upgrade {dep_id} to {new_version}: Mod {} depends on range {}", 
                         mod_ref.manifest.id,
                         existing_dep.version_range
                     )
-                },
-                None => {}
+                }
             }
         }
 
@@ -392,22 +590,21 @@ impl ModManager {
 }
 
 fn get_so_name(path: &str) -> &str {
-    path.split('/').last().unwrap()
+    path.split('/').next_back().unwrap()
 }
 
 // Deletes the files corresponding to the given SO files in a QMOD from the given folder
 // (i.e. will only consider the SO file name, not the full path in the ZIP)
-// `exclude` is a set of all of the SO file names that must not be deleted, (as they are being used by another mod).
-fn delete_file_names(file_paths: &[String], exclude: HashSet<String>, within: impl AsRef<Path>) -> Result<()> {
+// `exclude` is a set of all of the paths that must not be deleted, (as they are being used by another mod).
+fn delete_file_names(file_paths: &[String], exclude: &HashSet<PathBuf>, within: impl AsRef<Path>) -> Result<()> {
     for path in file_paths {
-        let file_name = get_so_name(path);
-        if exclude.contains(file_name) {
+        let stored_path = within.as_ref().join(get_so_name(path));
+        if exclude.contains(&stored_path) {
             continue;
         }
 
-        let stored_path = within.as_ref().join(file_name);
         if stored_path.exists() {
-            std::fs::remove_file(stored_path)?;
+            fs_ops::remove_file(stored_path)?;
         }
     }
 
@@ -422,7 +619,7 @@ fn copy_stated_files(zip: &mut ZipFile<File>, files: &[String], to: impl AsRef<P
             continue;
         }
 
-        let file_name = file.split('/').last().unwrap();
+        let file_name = file.split('/').next_back().unwrap();
         let copy_to = to.as_ref().join(file_name);
 
         zip.extract_file_to(file, copy_to).context("Failed to extract mod SO")?;
@@ -431,18 +628,208 @@ fn copy_stated_files(zip: &mut ZipFile<File>, files: &[String], to: impl AsRef<P
     Ok(())
 }
 
-fn create_mods_dir() -> Result<()> {
-    std::fs::create_dir_all(QMODS_DIR)?;
-    std::fs::create_dir_all(LATE_MODS_DIR)?;
-    std::fs::create_dir_all(EARLY_MODS_DIR)?;
-    std::fs::create_dir_all(LIBS_DIR)?;
-
-    Ok(())
-}
-
 fn list_dir_files(path: impl AsRef<Path>) -> Result<HashSet<String>> {
-    Ok(std::fs::read_dir(&path)?.filter_map(|file| match file {
+    Ok(fs_ops::read_dir(&path)?.filter_map(|file| match file {
         Ok(file) => file.file_name().into_string().ok(),
         Err(_) => None
     }).collect())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::zip::FileCompression;
+
+    fn test_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("mbf-mod-man-{}-{name}", std::process::id()));
+        if root.exists() {
+            std::fs::remove_dir_all(&root).unwrap();
+        }
+        root
+    }
+
+    fn test_manager(root: &Path) -> ModManager {
+        ModManager::with_dirs(ModDirs {
+            qmods: root.join("qmods"),
+            early_mods: root.join("early_mods"),
+            late_mods: root.join("mods"),
+            libs: root.join("libs")
+        })
+    }
+
+    // Writes a QMOD to the QMODs directory that installs `lib_contents` as libshared.so, and `copy_contents` to `copy_to`.
+    fn write_qmod(root: &Path, file_name: &str, id: &str, version: &str, lib_contents: &[u8], copy_contents: &[u8]) {
+        let copy_to = root.join("shared.txt");
+        let manifest = serde_json::json!({
+            "_QPVersion": "1.1.0",
+            "name": id,
+            "id": id,
+            "author": "MBF",
+            "version": version,
+            "libraryFiles": ["lib/libshared.so"],
+            "fileCopies": [{ "name": "shared.txt", "destination": copy_to }]
+        });
+
+        std::fs::create_dir_all(root.join("qmods")).unwrap();
+        let mut zip = ZipFile::create(File::create(root.join("qmods").join(file_name)).unwrap());
+        for (name, contents) in [
+            ("mod.json", serde_json::to_vec(&manifest).unwrap()),
+            ("lib/libshared.so", lib_contents.to_vec()),
+            ("shared.txt", copy_contents.to_vec())
+        ] {
+            zip.write_file(name, &mut Cursor::new(contents), FileCompression::Deflate).unwrap();
+        }
+        zip.save().unwrap();
+    }
+
+    #[test]
+    fn conflicting_mod_is_refused_unless_forced() {
+        let root = test_root("conflicts");
+        write_qmod(&root, "first.qmod", "first", "1.0.0", b"first lib", b"first copy");
+        write_qmod(&root, "second.qmod", "second", "1.0.0", b"second lib", b"second copy");
+
+        let mut mod_manager = test_manager(&root);
+        mod_manager.load_mods().unwrap();
+        mod_manager.install_mod("first").unwrap();
+
+        let err = mod_manager.install_mod("second").unwrap_err().to_string();
+        assert!(err.starts_with("second would overwrite files"), "{err}");
+        assert!(err.contains("installed by first v1.0.0"), "{err}");
+        assert!(!(*mod_manager.get_mod("second").unwrap()).borrow().installed());
+        assert_eq!(std::fs::read(root.join("libs/libshared.so")).unwrap(), b"first lib");
+
+        mod_manager.set_allow_conflicts(true);
+        mod_manager.install_mod("second").unwrap();
+        assert_eq!(std::fs::read(root.join("shared.txt")).unwrap(), b"second copy");
+
+        // Both mods involved in the forced conflict are flagged.
+        let mut conflict_paths = mod_manager.find_conflicts("first")
+            .into_iter()
+            .map(|conflict| (conflict.path, conflict.other_mod_id))
+            .collect::<Vec<_>>();
+        conflict_paths.sort();
+        assert_eq!(conflict_paths, [
+            (root.join("libs/libshared.so"), "second".to_string()),
+            (root.join("shared.txt"), "second".to_string())
+        ]);
+        assert_eq!(mod_manager.find_conflicts("second").len(), 2);
+
+        // The shared files are kept until neither mod is installed.
+        mod_manager.uninstall_mod("second").unwrap();
+        assert!(root.join("shared.txt").exists());
+        assert!(root.join("libs/libshared.so").exists());
+        assert!(mod_manager.find_conflicts("first").is_empty());
+
+        mod_manager.uninstall_mod("first").unwrap();
+        assert!(!root.join("shared.txt").exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn identical_files_are_shared() {
+        let root = test_root("identical");
+        write_qmod(&root, "first.qmod", "first", "1.0.0", b"same lib", b"same copy");
+        write_qmod(&root, "second.qmod", "second", "1.0.0", b"same lib", b"same copy");
+
+        let mut mod_manager = test_manager(&root);
+        mod_manager.load_mods().unwrap();
+        mod_manager.install_mod("first").unwrap();
+        mod_manager.install_mod("second").unwrap();
+        assert!(mod_manager.find_overlaps("second").iter().all(|overlap| overlap.kind == OverlapKind::Identical));
+
+        mod_manager.uninstall_mod("first").unwrap();
+        assert_eq!(std::fs::read(root.join("shared.txt")).unwrap(), b"same copy");
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn older_version_is_refused_unless_forced() {
+        let root = test_root("downgrade");
+        write_qmod(&root, "installed.qmod", "example", "1.1.0", b"new lib", b"new copy");
+
+        let mut mod_manager = test_manager(&root);
+        mod_manager.load_mods().unwrap();
+        mod_manager.install_mod("example").unwrap();
+
+        write_qmod(&root, "older.qmod", "example", "1.0.0", b"old lib", b"old copy");
+        let err = mod_manager.try_load_new_mod(root.join("qmods/older.qmod")).unwrap_err().to_string();
+        assert!(err.starts_with("example v1.0.0 is older than the installed version"), "{err}");
+        assert_eq!((*mod_manager.get_mod("example").unwrap()).borrow().manifest().version, Version::new(1, 1, 0));
+
+        mod_manager.set_allow_conflicts(true);
+        mod_manager.try_load_new_mod(root.join("qmods/older.qmod")).unwrap();
+        assert_eq!((*mod_manager.get_mod("example").unwrap()).borrow().manifest().version, Version::new(1, 0, 0));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn newer_version_replaces_installed_version() {
+        let root = test_root("upgrade");
+        write_qmod(&root, "installed.qmod", "example", "1.0.0", b"old lib", b"old copy");
+
+        let mut mod_manager = test_manager(&root);
+        mod_manager.load_mods().unwrap();
+        mod_manager.install_mod("example").unwrap();
+
+        write_qmod(&root, "newer.qmod", "example", "1.1.0", b"new lib", b"new copy");
+        mod_manager.try_load_new_mod(root.join("qmods/newer.qmod")).unwrap();
+        mod_manager.install_mod("example").unwrap();
+        assert_eq!(std::fs::read(root.join("libs/libshared.so")).unwrap(), b"new lib");
+        assert!(!root.join("qmods/installed.qmod").exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    // Writes a QMOD with the given `packageVersion` that installs a single early mod, `lib<id>.so`.
+    fn write_qmod_for_game(root: &Path, id: &str, package_version: &str) {
+        let so_name = format!("lib{id}.so");
+        let manifest = serde_json::json!({
+            "_QPVersion": "1.1.0",
+            "name": id,
+            "id": id,
+            "author": "MBF",
+            "version": "1.0.0",
+            "packageVersion": package_version,
+            "modFiles": [so_name]
+        });
+
+        std::fs::create_dir_all(root.join("qmods")).unwrap();
+        let mut zip = ZipFile::create(File::create(root.join("qmods").join(format!("{id}.qmod"))).unwrap());
+        zip.write_file("mod.json", &mut Cursor::new(serde_json::to_vec(&manifest).unwrap()), FileCompression::Deflate).unwrap();
+        zip.write_file(&so_name, &mut Cursor::new(b"\x7fELF".to_vec()), FileCompression::Store).unwrap();
+        zip.save().unwrap();
+    }
+
+    #[test]
+    fn mod_for_another_game_version_is_refused_unless_forced() {
+        let root = test_root("version-mismatch");
+        write_qmod_for_game(&root, "old-mod", "1.28.0_4124311467");
+        write_qmod_for_game(&root, "unparseable-mod", "latest");
+        write_qmod_for_game(&root, "matching-mod", "^1.37.0");
+
+        let mut mod_manager = test_manager(&root);
+        mod_manager.load_mods().unwrap();
+        mod_manager.set_game_version("1.37.0_9064817954".to_string(), false);
+
+        let err = mod_manager.install_mod("old-mod").unwrap_err().to_string();
+        assert_eq!(err, "old-mod is for Beat Saber 1.28.0_4124311467, not 1.37.0_9064817954. Installing it may crash the game");
+        let err = mod_manager.install_mod("unparseable-mod").unwrap_err().to_string();
+        assert!(err.starts_with("unparseable-mod gives its game version as \"latest\", which could not be understood"), "{err}");
+        assert!(!root.join("early_mods/libold-mod.so").exists());
+
+        mod_manager.install_mod("matching-mod").unwrap();
+        assert!(root.join("early_mods/libmatching-mod.so").exists());
+
+        mod_manager.set_game_version("1.37.0_9064817954".to_string(), true);
+        mod_manager.install_mod("old-mod").unwrap();
+        mod_manager.install_mod("unparseable-mod").unwrap();
+        assert!(root.join("early_mods/libold-mod.so").exists());
+        assert!(root.join("early_mods/libunparseable-mod.so").exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
+
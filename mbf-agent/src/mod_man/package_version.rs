@@ -0,0 +1,202 @@
+//! Parses the `packageVersion` of a mod, which gives the version(s) of the game the mod supports.
+//! Most mods give the exact version string of the game, but lists, wildcards and semver ranges are also used.
+
+use semver::{Version, VersionReq};
+
+// Characters that a semver range can start with. A constraint containing any part starting with one of these is parsed as a range.
+const RANGE_OPERATORS: &[char] = &['^', '~', '>', '<', '='];
+
+/// The game versions supported by a mod.
+#[derive(Clone, PartialEq, Debug)]
+pub enum PackageVersion {
+    /// `*`, or no version given: the mod claims to support every version.
+    Any,
+    /// A single version, e.g. `1.28.0_4124311467`. The build suffix after the `_` may be omitted.
+    Exact(String),
+    /// Several versions separated by commas, `||` or whitespace, any of which are supported.
+    AnyOf(Vec<String>),
+    /// A semver range such as `^1.28.0` or `>=1.28.0, <1.30.0`, which is matched against the game version without its build suffix.
+    Range(VersionReq),
+    /// The constraint couldn't be parsed.
+    Unknown(String)
+}
+
+/// Whether a mod supports a particular game version.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VersionSupport {
+    Supported,
+    Unsupported,
+    /// The mod's constraint couldn't be parsed, so whether it is supported isn't known.
+    Unknown
+}
+
+impl PackageVersion {
+    /// Parses the given `packageVersion`, treating a missing version as `Any`.
+    pub fn parse(constraint: Option<&str>) -> Self {
+        let constraint = match constraint.map(str::trim) {
+            None | Some("") | Some("*") => return Self::Any,
+            Some(constraint) => constraint
+        };
+
+        let parts: Vec<&str> = constraint.split([',', '|', ' ', '\t'])
+            .filter(|part| !part.is_empty())
+            .collect();
+        if parts.iter().any(|part| part.starts_with(RANGE_OPERATORS)) {
+            // Commas separate the comparators of a range, so the whole constraint is parsed as one range.
+            return match VersionReq::parse(constraint) {
+                Ok(range) => Self::Range(range),
+                Err(_) => Self::Unknown(constraint.to_string())
+            };
+        }
+
+        if parts.contains(&"*") {
+            return Self::Any;
+        }
+        if let Some(invalid) = parts.iter().find(|part| !is_version_string(part)) {
+            return Self::Unknown(invalid.to_string());
+        }
+
+        match parts.as_slice() {
+            [version] => Self::Exact(version.to_string()),
+            versions => Self::AnyOf(versions.iter().map(|version| version.to_string()).collect())
+        }
+    }
+
+    /// Checks whether `game_version`, e.g. `1.28.0_4124311467`, satisfies this constraint.
+    pub fn supports(&self, game_version: &str) -> VersionSupport {
+        let supported = match self {
+            Self::Any => true,
+            Self::Exact(version) => versions_match(version, game_version),
+            Self::AnyOf(versions) => versions.iter().any(|version| versions_match(version, game_version)),
+            Self::Range(range) => match Version::parse(strip_build(game_version)) {
+                Ok(version) => range.matches(&version),
+                Err(_) => return VersionSupport::Unknown
+            },
+            Self::Unknown(_) => return VersionSupport::Unknown
+        };
+
+        if supported {
+            VersionSupport::Supported
+        }   else {
+            VersionSupport::Unsupported
+        }
+    }
+}
+
+// Compares a version from a constraint with the game version. If the constraint omits the build suffix, so is the game version.
+fn versions_match(version: &str, game_version: &str) -> bool {
+    if version.contains('_') {
+        version == game_version
+    }   else {
+        version == strip_build(game_version)
+    }
+}
+
+// Removes the build number suffix from a game version, e.g. `1.28.0_4124311467` becomes `1.28.0`.
+fn strip_build(game_version: &str) -> &str {
+    game_version.split('_').next().unwrap_or(game_version)
+}
+
+// Checks that the given string is of the form used for game versions: dot separated numbers optionally followed by `_` and a build number.
+fn is_version_string(version: &str) -> bool {
+    let (numbers, build) = match version.split_once('_') {
+        Some((numbers, build)) => (numbers, Some(build)),
+        None => (version, None)
+    };
+
+    numbers.split('.').all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+        && build.is_none_or(|build| !build.is_empty() && build.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GAME_VERSION: &str = "1.37.0_9064817954";
+
+    fn parse(constraint: &str) -> PackageVersion {
+        PackageVersion::parse(Some(constraint))
+    }
+
+    fn supports(constraint: &str, game_version: &str) -> VersionSupport {
+        parse(constraint).supports(game_version)
+    }
+
+    #[test]
+    fn missing_or_wildcard_versions_are_any() {
+        assert_eq!(PackageVersion::parse(None), PackageVersion::Any);
+        for constraint in ["", "  ", "*", " * ", "1.37.0, *"] {
+            assert_eq!(parse(constraint), PackageVersion::Any, "{constraint:?}");
+        }
+        assert_eq!(PackageVersion::Any.supports("anything"), VersionSupport::Supported);
+    }
+
+    #[test]
+    fn exact_versions_are_parsed() {
+        assert_eq!(parse("1.37.0_9064817954"), PackageVersion::Exact("1.37.0_9064817954".to_string()));
+        assert_eq!(parse(" 1.37.0 "), PackageVersion::Exact("1.37.0".to_string()));
+        assert_eq!(parse("1.28.0_4124311467"), PackageVersion::Exact("1.28.0_4124311467".to_string()));
+    }
+
+    #[test]
+    fn exact_versions_match_with_or_without_build() {
+        assert_eq!(supports("1.37.0_9064817954", GAME_VERSION), VersionSupport::Supported);
+        assert_eq!(supports("1.37.0", GAME_VERSION), VersionSupport::Supported);
+        // A different build of the same version is a different game binary.
+        assert_eq!(supports("1.37.0_1111111111", GAME_VERSION), VersionSupport::Unsupported);
+        assert_eq!(supports("1.36.2", GAME_VERSION), VersionSupport::Unsupported);
+        // Versions are compared as strings, so a prefix isn't a match.
+        assert_eq!(supports("1.37", GAME_VERSION), VersionSupport::Unsupported);
+    }
+
+    #[test]
+    fn lists_are_parsed_with_each_separator() {
+        let expected = PackageVersion::AnyOf(vec!["1.36.2".to_string(), "1.37.0_9064817954".to_string()]);
+        for constraint in ["1.36.2,1.37.0_9064817954", "1.36.2, 1.37.0_9064817954", "1.36.2 1.37.0_9064817954",
+            "1.36.2 || 1.37.0_9064817954", "1.36.2\t1.37.0_9064817954"] {
+            assert_eq!(parse(constraint), expected, "{constraint:?}");
+        }
+
+        assert_eq!(supports("1.36.2, 1.37.0", GAME_VERSION), VersionSupport::Supported);
+        assert_eq!(supports("1.35.0, 1.36.2", GAME_VERSION), VersionSupport::Unsupported);
+    }
+
+    #[test]
+    fn ranges_are_parsed() {
+        for constraint in ["^1.37.0", "~1.37", ">=1.35.0", ">=1.35.0, <1.38.0", "<1.40.0", "=1.37.0"] {
+            assert!(matches!(parse(constraint), PackageVersion::Range(_)), "{constraint:?}");
+            assert_eq!(supports(constraint, GAME_VERSION), VersionSupport::Supported, "{constraint:?}");
+        }
+
+        for constraint in ["^1.38.0", ">=1.35.0, <1.37.0", "<1.37.0", "~1.36"] {
+            assert_eq!(supports(constraint, GAME_VERSION), VersionSupport::Unsupported, "{constraint:?}");
+        }
+    }
+
+    #[test]
+    fn ranges_against_non_semver_game_versions_are_unknown() {
+        // Some old versions of the game had four parts.
+        assert_eq!(supports("^1.0.0", "1.0.0.1_123"), VersionSupport::Unknown);
+        assert_eq!(supports(">=1.35.0", "1.37"), VersionSupport::Unknown);
+    }
+
+    #[test]
+    fn malformed_constraints_are_unknown() {
+        for (constraint, invalid) in [
+            ("latest", "latest"),
+            ("1.37.0-beta", "1.37.0-beta"),
+            ("1..37", "1..37"),
+            ("1.37.0_", "1.37.0_"),
+            ("1.37.0_123-456", "1.37.0_123-456"),
+            ("v1.37.0", "v1.37.0"),
+            ("1.36.2, Beat Saber", "Beat")
+        ] {
+            assert_eq!(parse(constraint), PackageVersion::Unknown(invalid.to_string()), "{constraint:?}");
+            assert_eq!(supports(constraint, GAME_VERSION), VersionSupport::Unknown, "{constraint:?}");
+        }
+
+        for constraint in [">= banana", "^", ">=1.35.0 <"] {
+            assert!(matches!(parse(constraint), PackageVersion::Unknown(_)), "{constraint:?}");
+        }
+    }
+}
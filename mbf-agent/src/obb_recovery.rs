@@ -0,0 +1,217 @@
+//! Records the OBBs backed up while patching, so that they can be put back if the agent is killed or the quest restarts before
+//! patching finishes. Otherwise, the OBBs would be left in the working directory, and the game would ask for them to be downloaded.
+
+use std::{io::Write, path::{Path, PathBuf}};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{fs_ops, OBB_RECOVERY_PATH};
+
+/// An OBB that has been backed up, and may have been removed from its original location.
+#[derive(Serialize, Deserialize, Debug)]
+struct ObbBackup {
+    original: PathBuf,
+    backup: PathBuf
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct RecoveryManifest {
+    obbs: Vec<ObbBackup>
+}
+
+/// Records the given backups (each of an original path and its backup path) before the original OBBs are removed.
+/// Once this has been called, the backups are treated as the correct contents of the OBBs until `clear` is called.
+pub fn record_backups(backups: &[(PathBuf, PathBuf)]) -> Result<()> {
+    save_manifest(Path::new(OBB_RECOVERY_PATH), &RecoveryManifest {
+        obbs: backups.iter()
+            .map(|(original, backup)| ObbBackup { original: original.clone(), backup: backup.clone() })
+            .collect()
+    })
+}
+
+/// Forgets the recorded backups, once every OBB has been restored.
+pub fn clear() -> Result<()> {
+    clear_manifest(Path::new(OBB_RECOVERY_PATH))
+}
+
+fn clear_manifest(manifest_path: &Path) -> Result<()> {
+    if manifest_path.exists() {
+        fs_ops::remove_file(manifest_path)?;
+    }
+
+    Ok(())
+}
+
+/// Returns true if there are backups recorded that haven't been restored, in which case they must not be removed.
+pub fn is_pending() -> bool {
+    Path::new(OBB_RECOVERY_PATH).exists()
+}
+
+/// Restores any recorded OBBs that are missing from their original location, or were only partly restored.
+/// Gives the file names of the OBBs that were restored. The record is cleared if every OBB could be put back.
+pub fn recover() -> Result<Vec<String>> {
+    recover_from(Path::new(OBB_RECOVERY_PATH))
+}
+
+fn recover_from(manifest_path: &Path) -> Result<Vec<String>> {
+    if !manifest_path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let manifest_json = std::fs::read(manifest_path).context("Failed to read OBB recovery record")?;
+    let manifest: RecoveryManifest = serde_json::from_slice(&manifest_json).context("OBB recovery record was invalid JSON")?;
+
+    let mut restored = Vec::new();
+    for obb in &manifest.obbs {
+        let file_name = obb.original.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let backup_len = match std::fs::metadata(&obb.backup) {
+            Ok(metadata) => metadata.len(),
+            // Backups are removed once they have been restored.
+            Err(_) => {
+                if !obb.original.exists() {
+                    warn!("OBB {file_name} is missing and its backup at {:?} no longer exists. Reinstall Beat Saber to download it again", obb.backup);
+                }
+                continue;
+            }
+        };
+
+        // An OBB is copied back in order, so one that was only partly restored is shorter than its backup.
+        let original_len = std::fs::metadata(&obb.original).map(|metadata| metadata.len()).ok();
+        if original_len != Some(backup_len) {
+            info!("Restoring OBB {file_name} from {:?}", obb.backup);
+            restore_obb(obb)?;
+            restored.push(file_name);
+        }
+    }
+
+    clear_manifest(manifest_path)?;
+    Ok(restored)
+}
+
+// Moves the backup of an OBB back to its original location, copying it if it is on a different filesystem.
+fn restore_obb(obb: &ObbBackup) -> Result<()> {
+    if let Some(parent) = obb.original.parent() {
+        fs_ops::create_dir_all(parent)?;
+    }
+
+    if fs_ops::rename(&obb.backup, &obb.original).is_err() {
+        fs_ops::copy(&obb.backup, &obb.original).context("Failed to restore OBB")?;
+        fs_ops::remove_file(&obb.backup)?;
+    }
+
+    Ok(())
+}
+
+// Saves the record to a temporary file and then renames it into place so that it is never left half-written.
+fn save_manifest(manifest_path: &Path, manifest: &RecoveryManifest) -> Result<()> {
+    fs_ops::create_dir_all(manifest_path.parent().unwrap())?;
+
+    let temp_path = manifest_path.with_extension("json.tmp");
+    let mut handle = std::fs::File::create(&temp_path).context("Failed to create OBB recovery record")?;
+    handle.write_all(&serde_json::to_vec_pretty(manifest)?)?;
+    handle.write_all(b"\n")?;
+    handle.sync_all()?;
+    fs_ops::rename(&temp_path, manifest_path).context("Failed to save OBB recovery record")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Creates a directory for the OBBs and recovery record of a test, giving the path of the record.
+    fn recovery_test_dir(name: &str) -> (PathBuf, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("mbf-obb-recovery-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for subdir in ["obb", "backup"] {
+            std::fs::create_dir_all(dir.join(subdir)).unwrap();
+        }
+        let manifest_path = dir.join("obb_recovery.json");
+        (dir, manifest_path)
+    }
+
+    // Records a backup of each named OBB, writing the given contents to each backup that exists.
+    fn record(dir: &Path, manifest_path: &Path, obbs: &[(&str, Option<&[u8]>)]) {
+        let mut manifest = RecoveryManifest::default();
+        for (name, backup_contents) in obbs {
+            let backup = dir.join("backup").join(name);
+            if let Some(contents) = backup_contents {
+                std::fs::write(&backup, contents).unwrap();
+            }
+            manifest.obbs.push(ObbBackup { original: dir.join("obb").join(name), backup });
+        }
+        save_manifest(manifest_path, &manifest).unwrap();
+    }
+
+    #[test]
+    fn partly_restored_and_missing_obbs_are_restored_and_the_record_cleared() {
+        let (dir, manifest_path) = recovery_test_dir("partial");
+        let contents = b"main obb contents".repeat(100);
+        record(&dir, &manifest_path, &[("main.obb", Some(&contents)), ("patch.obb", Some(b"patch obb")), ("dlc.obb", Some(b"dlc obb"))]);
+        // The copy of the main OBB back was cut off, and the DLC OBB was restored before the agent stopped.
+        std::fs::write(dir.join("obb/main.obb"), &contents[..contents.len() / 3]).unwrap();
+        std::fs::rename(dir.join("backup/dlc.obb"), dir.join("obb/dlc.obb")).unwrap();
+
+        let mut restored = recover_from(&manifest_path).unwrap();
+        restored.sort();
+        assert_eq!(restored, ["main.obb", "patch.obb"]);
+        assert!(std::fs::read(dir.join("obb/main.obb")).unwrap() == contents);
+        assert_eq!(std::fs::read(dir.join("obb/patch.obb")).unwrap(), b"patch obb");
+        assert_eq!(std::fs::read(dir.join("obb/dlc.obb")).unwrap(), b"dlc obb");
+        assert!(!manifest_path.exists(), "the record should be cleared once every OBB is restored");
+        assert_eq!(recover_from(&manifest_path).unwrap(), Vec::<String>::new());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn obb_with_a_missing_backup_is_skipped() {
+        let (dir, manifest_path) = recovery_test_dir("missing-backup");
+        record(&dir, &manifest_path, &[("main.obb", None), ("patch.obb", None), ("dlc.obb", Some(b"dlc obb"))]);
+        std::fs::write(dir.join("obb/main.obb"), b"already restored").unwrap();
+
+        // The patch OBB can't be put back, which is logged, but the others are still restored.
+        assert_eq!(recover_from(&manifest_path).unwrap(), ["dlc.obb"]);
+        assert_eq!(std::fs::read(dir.join("obb/main.obb")).unwrap(), b"already restored");
+        assert!(!dir.join("obb/patch.obb").exists());
+        assert!(!manifest_path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_record_fails_and_is_kept() {
+        let (dir, manifest_path) = recovery_test_dir("corrupt");
+        record(&dir, &manifest_path, &[("main.obb", Some(b"main obb"))]);
+        let json = std::fs::read(&manifest_path).unwrap();
+        std::fs::write(&manifest_path, &json[..json.len() / 2]).unwrap();
+
+        let err = recover_from(&manifest_path).unwrap_err();
+        assert_eq!(err.to_string(), "OBB recovery record was invalid JSON");
+        // The backup may still be needed, so nothing that refers to it is removed.
+        assert!(manifest_path.exists());
+        assert_eq!(std::fs::read(dir.join("backup/main.obb")).unwrap(), b"main obb");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clearing_after_a_successful_restore_removes_the_record() {
+        let (dir, manifest_path) = recovery_test_dir("clear");
+        record(&dir, &manifest_path, &[("main.obb", Some(b"main obb"))]);
+        assert!(manifest_path.exists());
+        // Not left half-written, since it is renamed into place.
+        assert!(!manifest_path.with_extension("json.tmp").exists());
+
+        clear_manifest(&manifest_path).unwrap();
+        assert!(!manifest_path.exists());
+        clear_manifest(&manifest_path).unwrap();
+        assert_eq!(recover_from(&manifest_path).unwrap(), Vec::<String>::new());
+        assert!(!dir.join("obb/main.obb").exists(), "nothing should be restored once the record is cleared");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
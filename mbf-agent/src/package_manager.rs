@@ -0,0 +1,258 @@
+//! Installs and uninstalls apps with `pm`, turning its failures into errors that give the reason and what the user can do about it.
+//! `pm` often exits successfully even if installing failed, so its output is checked instead.
+
+use std::{fmt::Display, path::Path, process::Output};
+
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::{commands, fs_ops};
+
+/// Why a `pm` command failed, worked out from the failure code it printed.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PmFailureKind {
+    /// `INSTALL_FAILED_INSUFFICIENT_STORAGE`
+    InsufficientStorage,
+    /// `INSTALL_FAILED_VERSION_DOWNGRADE`: a newer version of the app is still installed.
+    VersionDowngrade,
+    /// `INSTALL_FAILED_UPDATE_INCOMPATIBLE` and similar: the app is still installed with a different signature.
+    SignatureMismatch,
+    /// `INSTALL_PARSE_FAILED_*` and `INSTALL_FAILED_INVALID_APK`: the APK couldn't be read, e.g. as it was badly patched.
+    InvalidApk,
+    /// `INSTALL_FAILED_USER_RESTRICTED` and `INSTALL_FAILED_VERIFICATION_FAILURE`: the quest refused to install the APK.
+    Blocked,
+    /// The app to uninstall isn't installed.
+    NotInstalled,
+    Other
+}
+
+impl PmFailureKind {
+    // Classifies the failure code printed by `pm`, e.g. `INSTALL_FAILED_INSUFFICIENT_STORAGE`.
+    fn from_code(code: &str) -> Self {
+        match code {
+            "INSTALL_FAILED_INSUFFICIENT_STORAGE" => Self::InsufficientStorage,
+            "INSTALL_FAILED_VERSION_DOWNGRADE" => Self::VersionDowngrade,
+            "INSTALL_FAILED_UPDATE_INCOMPATIBLE" | "INSTALL_FAILED_SHARED_USER_INCOMPATIBLE" | "INSTALL_FAILED_ALREADY_EXISTS" => Self::SignatureMismatch,
+            "INSTALL_FAILED_INVALID_APK" => Self::InvalidApk,
+            "INSTALL_FAILED_USER_RESTRICTED" | "INSTALL_FAILED_VERIFICATION_FAILURE" => Self::Blocked,
+            code if code.starts_with("INSTALL_PARSE_FAILED_") => Self::InvalidApk,
+            _ => Self::Other
+        }
+    }
+
+    /// What the user can do to fix the failure.
+    pub fn hint(&self) -> &'static str {
+        match self {
+            Self::InsufficientStorage => "Free up some space on your quest, then try again.",
+            Self::VersionDowngrade | Self::SignatureMismatch => "Uninstall Beat Saber (your OBB files and player data are backed up), then try again.",
+            Self::InvalidApk => "The modded APK could not be read by your quest. Report this along with the logs.",
+            Self::Blocked => "Check that developer mode is enabled on your quest, then try again.",
+            Self::NotInstalled => "Beat Saber is not installed.",
+            Self::Other => "Restart your quest, then try again. If this keeps happening, report it along with the logs."
+        }
+    }
+}
+
+/// Given when a `pm` command ran but reported that it failed.
+#[derive(Debug)]
+pub struct PmFailure {
+    /// The `pm` subcommand, e.g. `install`.
+    pub command: &'static str,
+    pub kind: PmFailureKind,
+    /// The failure code printed by `pm`, if any, e.g. `INSTALL_FAILED_INSUFFICIENT_STORAGE`.
+    pub code: Option<String>,
+    pub exit_code: Option<i32>,
+    /// Everything printed by `pm`.
+    pub output: String
+}
+
+impl Display for PmFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pm {} failed", self.command)?;
+        if let Some(code) = &self.code {
+            write!(f, " with {code}")?;
+        }
+        write!(f, " (exit code {}): {}", self.exit_code.map(|code| code.to_string()).unwrap_or("none".to_string()), self.output)
+    }
+}
+
+impl std::error::Error for PmFailure {}
+
+/// Installs the APK at `apk_path` (replacing the installed app, if it has the same signature), failing with a `PmFailure` if `pm` doesn't
+/// report success. The APK is streamed into an install session, since plain `pm install` fails for large APKs on some firmware.
+/// If a session can't be created, plain `pm install` is used instead.
+pub fn install(apk_path: &Path) -> Result<()> {
+    let apk_size = std::fs::metadata(apk_path)?.len().to_string();
+    let session_id = match create_session(&apk_size) {
+        Ok(session_id) => session_id,
+        Err(err) => {
+            warn!("Failed to create install session, so installing directly: {err}");
+            return check_output("install", commands::run("pm", &["install", &apk_path.to_string_lossy()])
+                .context("Failed to run pm install")?).map(|_| ());
+        }
+    };
+
+    info!("Streaming APK into install session {session_id}");
+    let result = commands::run_with_input("pm", &["install-write", "-S", &apk_size, &session_id, "base.apk", "-"], fs_ops::open(apk_path)?)
+        .context("Failed to run pm install-write")
+        .and_then(|output| check_output("install-write", output))
+        .and_then(|_| check_output("install-commit", commands::run("pm", &["install-commit", &session_id])
+            .context("Failed to run pm install-commit")?));
+
+    if result.is_err() {
+        // The session is left open if it wasn't committed, so it is freed rather than taking up space until the quest restarts.
+        let _ = commands::run("pm", &["install-abandon", &session_id]);
+    }
+    result.map(|_| ())
+}
+
+// Creates an install session for an APK of the given size, giving the ID of the session.
+fn create_session(apk_size: &str) -> Result<String> {
+    let output = check_output("install-create", commands::run("pm", &["install-create", "-S", apk_size])
+        .context("Failed to run pm install-create")?)?;
+
+    // `pm` prints `Success: created install session [<id>]`
+    output.split_once('[')
+        .and_then(|(_, after)| after.split_once(']'))
+        .map(|(session_id, _)| session_id.to_string())
+        .filter(|session_id| !session_id.is_empty() && session_id.chars().all(|c| c.is_ascii_digit()))
+        .ok_or(anyhow!("pm install-create gave no session ID: {output}"))
+}
+
+/// Uninstalls the app with the given package ID, failing with a `PmFailure` if `pm` doesn't report success.
+pub fn uninstall(package_id: &str) -> Result<()> {
+    check_output("uninstall", commands::run("pm", &["uninstall", package_id]).context("Failed to run pm uninstall")?)?;
+    Ok(())
+}
+
+// Checks the output of the given `pm` subcommand, giving everything it printed if it worked.
+// `pm` prints a line starting with `Success` if the command worked, or `Failure [<code>: <message>]` otherwise.
+fn check_output(command: &'static str, output: Output) -> Result<String> {
+    let pm_output = format!("{}\n{}", String::from_utf8_lossy(&output.stdout).trim(), String::from_utf8_lossy(&output.stderr).trim())
+        .trim()
+        .to_string();
+
+    if output.status.success() && pm_output.lines().any(|line| line.trim().starts_with("Success")) {
+        return Ok(pm_output);
+    }
+
+    let code = parse_failure_code(&pm_output);
+    let kind = match &code {
+        Some(code) => PmFailureKind::from_code(code),
+        None if pm_output.contains("Unknown package") || pm_output.contains("not installed") => PmFailureKind::NotInstalled,
+        None => PmFailureKind::Other
+    };
+
+    Err(PmFailure {
+        command,
+        kind,
+        code,
+        exit_code: output.status.code(),
+        output: pm_output
+    }.into())
+}
+
+// Finds the code within a line of the form `Failure [INSTALL_FAILED_X: message]`, or `Failure [DELETE_FAILED_X]` for uninstalling.
+fn parse_failure_code(pm_output: &str) -> Option<String> {
+    let (_, after) = pm_output.split_once("Failure [")?;
+    let code: String = after.chars()
+        .take_while(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || *c == '_')
+        .collect();
+
+    if code.is_empty() {
+        None
+    }   else {
+        Some(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+    use crate::test_harness::FakeDevice;
+
+    fn pm_output(exit_code: i32, stdout: &str) -> Output {
+        Output {
+            status: std::process::ExitStatus::from_raw(exit_code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new()
+        }
+    }
+
+    fn check_failure(exit_code: i32, stdout: &str) -> PmFailure {
+        check_output("install", pm_output(exit_code, stdout))
+            .expect_err("pm should have failed")
+            .downcast()
+            .expect("failure should be a PmFailure")
+    }
+
+    #[test]
+    fn failure_codes_are_mapped_to_their_kind() {
+        let cases = [
+            ("Failure [INSTALL_FAILED_INSUFFICIENT_STORAGE]", "INSTALL_FAILED_INSUFFICIENT_STORAGE", PmFailureKind::InsufficientStorage),
+            ("Failure [INSTALL_FAILED_VERSION_DOWNGRADE: Downgrade detected: Update version code 1130 is older than current 1200]",
+                "INSTALL_FAILED_VERSION_DOWNGRADE", PmFailureKind::VersionDowngrade),
+            ("Failure [INSTALL_FAILED_UPDATE_INCOMPATIBLE: Package com.beatgames.beatsaber signatures do not match previously installed version; ignoring!]",
+                "INSTALL_FAILED_UPDATE_INCOMPATIBLE", PmFailureKind::SignatureMismatch),
+            ("Failure [INSTALL_FAILED_SHARED_USER_INCOMPATIBLE]", "INSTALL_FAILED_SHARED_USER_INCOMPATIBLE", PmFailureKind::SignatureMismatch),
+            ("Failure [INSTALL_FAILED_SOMETHING_NEW: message]", "INSTALL_FAILED_SOMETHING_NEW", PmFailureKind::Other),
+        ];
+
+        for (stdout, code, kind) in cases {
+            let failure = check_failure(1, stdout);
+            assert_eq!(failure.code.as_deref(), Some(code), "{stdout}");
+            assert_eq!(failure.kind, kind, "{stdout}");
+            assert_eq!(failure.exit_code, Some(1));
+            assert_eq!(failure.output, stdout);
+        }
+    }
+
+    #[test]
+    fn failure_is_found_after_other_output_and_despite_a_clean_exit() {
+        let failure = check_failure(0, "Performing Streamed Install\nFailure [INSTALL_FAILED_INSUFFICIENT_STORAGE]\n");
+        assert_eq!(failure.kind, PmFailureKind::InsufficientStorage);
+        assert_eq!(failure.exit_code, Some(0));
+        assert_eq!(failure.to_string(), "pm install failed with INSTALL_FAILED_INSUFFICIENT_STORAGE (exit code 0): \
+            Performing Streamed Install\nFailure [INSTALL_FAILED_INSUFFICIENT_STORAGE]");
+    }
+
+    #[test]
+    fn non_zero_exit_with_no_output_is_a_failure() {
+        let failure = check_failure(255, "");
+        assert_eq!(failure.kind, PmFailureKind::Other);
+        assert_eq!(failure.code, None);
+        assert_eq!(failure.exit_code, Some(255));
+        assert_eq!(failure.output, "");
+        assert_eq!(failure.to_string(), "pm install failed (exit code 255): ");
+
+        // Printing success isn't enough if pm then exits with an error.
+        assert_eq!(check_failure(1, "Success").kind, PmFailureKind::Other);
+    }
+
+    #[test]
+    fn success_gives_the_output() {
+        assert_eq!(check_output("install", pm_output(0, "Performing Streamed Install\nSuccess\n")).unwrap(),
+            "Performing Streamed Install\nSuccess");
+    }
+
+    #[test]
+    fn failed_install_is_abandoned() {
+        let device = FakeDevice::builder("pm-install-failing")
+            .pm_response("install-write", 1, "Failure [INSTALL_FAILED_INSUFFICIENT_STORAGE: not enough space]")
+            .build();
+        let apk_path = device.path("base.apk");
+        std::fs::write(&apk_path, b"not really an APK").unwrap();
+
+        let failure: PmFailure = install(&apk_path).unwrap_err().downcast().unwrap();
+        assert_eq!(failure.command, "install-write");
+        assert_eq!(failure.kind, PmFailureKind::InsufficientStorage);
+        assert_eq!(device.calls("pm"), [
+            "install-create -S 17".to_string(),
+            "install-write -S 17 42 base.apk -".to_string(),
+            "install-abandon 42".to_string(),
+        ]);
+    }
+}
@@ -0,0 +1,321 @@
+//! Trust-on-first-use pinning of the hosts that serve the diffs and unstripped libunity.so files.
+//! The first time the agent connects to one of these hosts, the SHA-256 hash of the server certificate's public key (SPKI) is saved.
+//! On later connections, a different key causes the connection to fail until the user confirms that the change is expected.
+//! (e.g. if the CDN rotated its certificate, as opposed to the network intercepting TLS traffic)
+
+use std::{collections::HashMap, fmt::Write as _, io::Write, path::Path, sync::{Arc, Mutex}, time::Duration};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use rsa::sha2::{Digest, Sha256};
+use rustls::{client::{danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier}, WebPkiServerVerifier}, pki_types::{CertificateDer, ServerName, UnixTime}, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use serde::{Deserialize, Serialize};
+
+use crate::{fs_ops, PINS_PATH, REQUEST_TIMEOUT_READ_SECS};
+
+/// The pins saved for each host, along with the option to disable pinning entirely.
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct PinStore {
+    pins: HashMap<String, Pin>,
+    /// Escape hatch for users who knowingly connect via a proxy that intercepts TLS traffic.
+    /// When true, the hashes are not checked or saved. Regular certificate validation still applies.
+    #[serde(default)]
+    disable_pinning: bool
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Pin {
+    /// Hex encoded SHA-256 hash of the DER encoded SubjectPublicKeyInfo of the server certificate.
+    spki_sha256: String,
+    /// The hash that was pinned before the user last accepted a changed identity, if any.
+    #[serde(default)]
+    previous: Option<String>
+}
+
+// What connecting to a host that presented a public key with a particular hash means for its pin.
+#[derive(PartialEq, Debug)]
+enum PinCheck {
+    // Pinning is disabled, so the hash wasn't checked.
+    Disabled,
+    Matched,
+    // The host hadn't been connected to before, so its hash is now pinned.
+    Pinned,
+    // The hash differs from the one pinned, which is left unchanged until the user trusts the new one.
+    Changed {
+        old: String
+    }
+}
+
+impl PinStore {
+    // Checks `spki_sha256` against the pin for `host`, pinning it if `host` has no pin.
+    fn check(&mut self, host: &str, spki_sha256: &str) -> PinCheck {
+        if self.disable_pinning {
+            return PinCheck::Disabled;
+        }
+
+        match self.pins.get(host) {
+            Some(pin) if pin.spki_sha256 == spki_sha256 => PinCheck::Matched,
+            Some(pin) => PinCheck::Changed { old: pin.spki_sha256.clone() },
+            None => {
+                self.pins.insert(host.to_string(), Pin {
+                    spki_sha256: spki_sha256.to_string(),
+                    previous: None
+                });
+                PinCheck::Pinned
+            }
+        }
+    }
+
+    // Replaces the pin for `host`, unpinning its previous hash (which is kept to show that its identity was changed).
+    fn trust(&mut self, host: &str, spki_sha256: &str) {
+        let previous = self.pins.get(host).map(|pin| pin.spki_sha256.clone());
+        self.pins.insert(host.to_string(), Pin {
+            spki_sha256: spki_sha256.to_string(),
+            previous
+        });
+    }
+
+    fn changed_hosts(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = self.pins.iter()
+            .filter(|(_, pin)| pin.previous.is_some())
+            .map(|(host, _)| host.clone())
+            .collect();
+        hosts.sort();
+        hosts
+    }
+}
+
+/// Details of a host that presented a different public key to the one pinned.
+pub struct IdentityChange {
+    pub host: String,
+    pub old: String,
+    pub new: String
+}
+
+// The most recent identity change detected by this process, which is reported to the frontend if the request then fails.
+static IDENTITY_CHANGE: Mutex<Option<IdentityChange>> = Mutex::new(None);
+
+/// Takes the most recent identity change detected while handling the current request, if there was one.
+pub fn take_identity_change() -> Option<IdentityChange> {
+    IDENTITY_CHANGE.lock().unwrap_or_else(|err| err.into_inner()).take()
+}
+
+/// Creates a ureq agent that checks the pins of the hosts it connects to, saving pins for any new hosts.
+pub fn pinned_agent() -> Result<ureq::Agent> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec()
+    };
+    let inner = WebPkiServerVerifier::builder(Arc::new(roots))
+        .build()
+        .context("Failed to create certificate verifier")?;
+
+    let verifier = PinningVerifier {
+        inner,
+        store: Mutex::new(load_pins(Path::new(PINS_PATH))?)
+    };
+
+    let tls_config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(ureq::AgentBuilder::new()
+        .timeout_read(Duration::from_secs(REQUEST_TIMEOUT_READ_SECS))
+        .tls_config(Arc::new(tls_config))
+        .build())
+}
+
+/// Replaces the pin for `host` with `spki_sha256`, which should be the `new` hash from an `IdentityChange`.
+pub fn trust_identity(host: &str, spki_sha256: &str) -> Result<()> {
+    let pins_path = Path::new(PINS_PATH);
+    let mut store = load_pins(pins_path)?;
+    info!("Trusting new identity {spki_sha256} for {host}");
+    store.trust(host, spki_sha256);
+    save_pins(&store, pins_path)
+}
+
+/// Returns the hosts which have had their identity changed by the user accepting a new pin.
+/// If a download from one of these hosts then turns out to be corrupt, this is a strong sign that the connection was intercepted.
+pub fn get_changed_hosts() -> Vec<String> {
+    match load_pins(Path::new(PINS_PATH)) {
+        Ok(store) => store.changed_hosts(),
+        Err(_) => Vec::new()
+    }
+}
+
+fn load_pins(pins_path: &Path) -> Result<PinStore> {
+    if !pins_path.exists() {
+        return Ok(PinStore::default());
+    }
+
+    let pins_json = std::fs::read(pins_path).context("Failed to read repository pins")?;
+    serde_json::from_slice(&pins_json).context("Repository pins were invalid JSON")
+}
+
+// Saves the pins to a temporary file and then renames it into place so that the pins are never left half-written.
+fn save_pins(store: &PinStore, pins_path: &Path) -> Result<()> {
+    fs_ops::create_dir_all(pins_path.parent().unwrap())?;
+
+    let temp_path = pins_path.with_extension("json.tmp");
+    let mut handle = std::fs::File::create(&temp_path).context("Failed to create repository pins")?;
+    handle.write_all(&serde_json::to_vec_pretty(store)?)?;
+    handle.write_all(b"\n")?;
+    handle.sync_all()?;
+    fs_ops::rename(&temp_path, pins_path).context("Failed to save repository pins")?;
+
+    Ok(())
+}
+
+// Calculates the hex encoded SHA-256 hash of the SubjectPublicKeyInfo of the given certificate.
+fn spki_sha256(cert: &CertificateDer<'_>) -> Result<String> {
+    let cert: rasn_pkix::Certificate = rasn::der::decode(cert.as_ref())
+        .map_err(|err| anyhow::anyhow!("Failed to parse server certificate: {err}"))?;
+    let spki = rasn::der::encode(&cert.tbs_certificate.subject_public_key_info)
+        .map_err(|err| anyhow::anyhow!("Failed to encode public key: {err}"))?;
+
+    let mut hash = String::new();
+    for byte in Sha256::digest(spki) {
+        write!(hash, "{byte:02x}")?;
+    }
+    Ok(hash)
+}
+
+#[derive(Debug)]
+struct PinningVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    store: Mutex<PinStore>
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(&self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        // Pinning is in addition to the regular certificate checks, not a replacement for them.
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        let mut store = self.store.lock().unwrap_or_else(|err| err.into_inner());
+        let host = server_name.to_str().to_string();
+        let hash = spki_sha256(end_entity).map_err(|err| rustls::Error::General(err.to_string()))?;
+
+        match store.check(&host, &hash) {
+            PinCheck::Disabled | PinCheck::Matched => Ok(verified),
+            PinCheck::Changed { old } => {
+                warn!("The identity of {host} has changed since it was first used!
+                    This could be because the server's certificate was replaced, or because your network is intercepting the connection.");
+                *IDENTITY_CHANGE.lock().unwrap_or_else(|err| err.into_inner()) = Some(IdentityChange {
+                    host: host.clone(),
+                    old,
+                    new: hash
+                });
+
+                Err(rustls::Error::General(format!("Identity of {host} did not match the pinned identity")))
+            },
+            PinCheck::Pinned => {
+                info!("Pinning identity of {host}");
+                // Failing to save the pin shouldn't prevent the download, it just means the host will be pinned next time instead.
+                if let Err(err) = save_pins(&store, Path::new(PINS_PATH)) {
+                    warn!("Failed to save repository pins: {err}");
+                }
+                Ok(verified)
+            }
+        }
+    }
+
+    fn verify_tls12_signature(&self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(&self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "raw.githubusercontent.com";
+
+    #[test]
+    fn first_connection_pins_the_host() {
+        let mut store = PinStore::default();
+        assert_eq!(store.check(HOST, "aaaa"), PinCheck::Pinned);
+        assert_eq!(store.pins[HOST].spki_sha256, "aaaa");
+        assert_eq!(store.pins[HOST].previous, None);
+        assert_eq!(store.check(HOST, "aaaa"), PinCheck::Matched);
+    }
+
+    #[test]
+    fn changed_key_is_reported_without_replacing_the_pin() {
+        let mut store = PinStore::default();
+        store.check(HOST, "aaaa");
+
+        assert_eq!(store.check(HOST, "bbbb"), PinCheck::Changed { old: "aaaa".to_string() });
+        // Until the user trusts the new key, every connection presenting it fails.
+        assert_eq!(store.check(HOST, "bbbb"), PinCheck::Changed { old: "aaaa".to_string() });
+        assert_eq!(store.pins[HOST].spki_sha256, "aaaa");
+        assert!(store.changed_hosts().is_empty());
+    }
+
+    #[test]
+    fn trusting_a_new_key_unpins_the_old_one() {
+        let mut store = PinStore::default();
+        store.check(HOST, "aaaa");
+        store.check("other.example.com", "cccc");
+
+        store.trust(HOST, "bbbb");
+        assert_eq!(store.check(HOST, "bbbb"), PinCheck::Matched);
+        assert_eq!(store.check(HOST, "aaaa"), PinCheck::Changed { old: "bbbb".to_string() });
+        assert_eq!(store.pins[HOST].previous.as_deref(), Some("aaaa"));
+        assert_eq!(store.changed_hosts(), [HOST]);
+    }
+
+    #[test]
+    fn nothing_is_checked_or_pinned_when_disabled() {
+        let mut store = PinStore { disable_pinning: true, ..Default::default() };
+        assert_eq!(store.check(HOST, "aaaa"), PinCheck::Disabled);
+        assert!(store.pins.is_empty());
+    }
+
+    #[test]
+    fn pins_are_saved_and_loaded_again() {
+        let dir = std::env::temp_dir().join(format!("mbf-pins-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let pins_path = dir.join("repository_pins.json");
+        assert!(load_pins(&pins_path).unwrap().pins.is_empty(), "a missing file should give no pins");
+
+        let mut store = PinStore::default();
+        store.check(HOST, "aaaa");
+        store.trust(HOST, "bbbb");
+        save_pins(&store, &pins_path).unwrap();
+        assert!(!pins_path.with_extension("json.tmp").exists());
+
+        let mut loaded = load_pins(&pins_path).unwrap();
+        assert_eq!(loaded.check(HOST, "bbbb"), PinCheck::Matched);
+        assert_eq!(loaded.changed_hosts(), [HOST]);
+
+        std::fs::write(&pins_path, b"{not json").unwrap();
+        assert!(load_pins(&pins_path).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pins_saved_by_older_versions_are_loaded() {
+        let store: PinStore = serde_json::from_str(r#"{"pins":{"raw.githubusercontent.com":{"spki_sha256":"aaaa"}}}"#).unwrap();
+        assert!(!store.disable_pinning);
+        assert_eq!(store.pins[HOST].previous, None);
+    }
+}
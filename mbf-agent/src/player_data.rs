@@ -0,0 +1,259 @@
+//! Backs up the files the game keeps in its data directory, i.e. its settings and local scores as well as PlayerData.dat,
+//! since these are all removed when the game is uninstalled while patching. They are restored once the game has been reinstalled.
+//! Snapshots of the same files can also be made on demand, e.g. before trying a mod that may corrupt the player data.
+
+use std::{fs::OpenOptions, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::{fs_ops, reports, DATAKEEPER_PATH, PLAYER_DATA_BACKUP_DIR, PLAYER_DATA_SNAPSHOTS_DIR};
+
+// The names of the files within the data directory (or its subdirectories) that are backed up.
+// Anything else, e.g. caches, is skipped so that backing up stays fast.
+const BACKED_UP_FILES: &[&str] = &["PlayerData.dat", "settings.cfg", "LocalLeaderboards.dat", "LocalDailyLeaderboards.dat", "AvatarData.dat"];
+// Copying PlayerData.dat back into the data directory gives it permissions that stop the game opening it, which causes a black screen.
+// It is backed up here too, but is restored by the datakeeper mod from `DATAKEEPER_PATH` instead.
+const NOT_RESTORED_FILES: &[&str] = &["PlayerData.dat"];
+
+/// Copies the backed up files within `data_dir` to `PLAYER_DATA_BACKUP_DIR`, keeping their paths relative to `data_dir`,
+/// and gives the number of files backed up. The previous backup is only replaced once every file has been copied,
+/// and is kept if there are no files to back up, e.g. if the game was reinstalled from the store since it was made.
+pub fn backup(data_dir: &Path) -> Result<usize> {
+    let mut relative_paths = Vec::new();
+    if data_dir.exists() {
+        find_backed_up_files(data_dir, Path::new(""), &mut relative_paths)?;
+    }
+    if relative_paths.is_empty() {
+        info!("No player data files to back up");
+        return Ok(0);
+    }
+
+    let sources: Vec<(PathBuf, PathBuf)> = relative_paths.iter()
+        .map(|relative_path| (data_dir.join(relative_path), relative_path.clone()))
+        .collect();
+    save_backup(&sources, Path::new(PLAYER_DATA_BACKUP_DIR))?;
+
+    info!("Backed up {} player data files to {PLAYER_DATA_BACKUP_DIR}", relative_paths.len());
+    Ok(relative_paths.len())
+}
+
+/// Copies the backed up files that are missing from `data_dir` back into it, creating directories as needed,
+/// and gives the paths (relative to `data_dir`) of the files that were restored.
+/// Files that exist are left alone, since the game keeps its data if it was installed over the existing app.
+/// A file that can't be restored gives a warning, rather than stopping the others from being restored.
+pub fn restore_missing(data_dir: &Path) -> Result<Vec<String>> {
+    let backup_dir = Path::new(PLAYER_DATA_BACKUP_DIR);
+    if !backup_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut relative_paths = Vec::new();
+    find_backed_up_files(backup_dir, Path::new(""), &mut relative_paths)?;
+
+    let mut restored = Vec::new();
+    for relative_path in relative_paths {
+        let restore_path = data_dir.join(&relative_path);
+        let not_restored = relative_path.file_name()
+            .is_some_and(|name| NOT_RESTORED_FILES.iter().any(|not_restored| name == *not_restored));
+        if not_restored || restore_path.exists() {
+            continue;
+        }
+
+        let name = relative_path.to_string_lossy().to_string();
+        let result = fs_ops::create_dir_all(restore_path.parent().unwrap())
+            .and_then(|_| fs_ops::copy(backup_dir.join(&relative_path), &restore_path));
+        match result {
+            Ok(_) => restored.push(name),
+            Err(err) => warn!("Failed to restore player data file {name}: {err}. It is still backed up in {PLAYER_DATA_BACKUP_DIR}")
+        }
+    }
+
+    Ok(restored)
+}
+
+/// A snapshot of the player data files, made by a `BackupPlayerData` request.
+#[derive(Serialize, Clone, Debug)]
+pub struct PlayerDataBackup {
+    /// The name of the snapshot's directory within `PLAYER_DATA_SNAPSHOTS_DIR`, which is the time it was made (in UTC).
+    pub id: String,
+    pub path: String,
+    /// When the snapshot was made, in seconds since the UNIX epoch.
+    pub created: u64,
+    /// The total size of the files, in bytes.
+    pub size: u64,
+    /// The paths of the files, relative to the data directory.
+    pub files: Vec<String>
+}
+
+/// Saves a snapshot of the player data files within `data_dir` to a new directory within `PLAYER_DATA_SNAPSHOTS_DIR`.
+/// If the datakeeper mod has a more recent PlayerData.dat than `data_dir`, its copy is saved instead, since the game uses it.
+pub fn create_snapshot(data_dir: &Path) -> Result<PlayerDataBackup> {
+    let mut relative_paths = Vec::new();
+    if data_dir.exists() {
+        find_backed_up_files(data_dir, Path::new(""), &mut relative_paths)?;
+    }
+    let mut sources: Vec<(PathBuf, PathBuf)> = relative_paths.into_iter()
+        .map(|relative_path| (data_dir.join(&relative_path), relative_path))
+        .collect();
+
+    let datakeeper_path = Path::new(DATAKEEPER_PATH);
+    if datakeeper_path.exists() {
+        match sources.iter_mut().find(|(_, relative_path)| relative_path == Path::new("PlayerData.dat")) {
+            Some((source, _)) => if get_modified_secs(datakeeper_path)? > get_modified_secs(source)? {
+                info!("Using PlayerData.dat from datakeeper, since it is more recent");
+                *source = datakeeper_path.to_path_buf();
+            },
+            None => sources.push((datakeeper_path.to_path_buf(), PathBuf::from("PlayerData.dat")))
+        }
+    }
+    if sources.is_empty() {
+        return Err(anyhow!("There is no player data to back up"));
+    }
+
+    // Two snapshots made within the same second are given different IDs.
+    let time_id = reports::format_time(SystemTime::now()).replace([':', ' '], "-");
+    let snapshots_dir = Path::new(PLAYER_DATA_SNAPSHOTS_DIR);
+    let mut id = time_id.clone();
+    let mut suffix = 1;
+    while snapshots_dir.join(&id).exists() {
+        suffix += 1;
+        id = format!("{time_id}-{suffix}");
+    }
+
+    save_backup(&sources, &snapshots_dir.join(&id))?;
+    read_snapshot(&id)
+}
+
+/// Lists the snapshots within `PLAYER_DATA_SNAPSHOTS_DIR`, newest first.
+pub fn list_snapshots() -> Result<Vec<PlayerDataBackup>> {
+    let snapshots_dir = Path::new(PLAYER_DATA_SNAPSHOTS_DIR);
+    if !snapshots_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut snapshots = Vec::new();
+    for entry in fs_ops::read_dir(snapshots_dir)?.flatten() {
+        let id = entry.file_name().to_string_lossy().to_string();
+        // Snapshots that weren't finished are left in temporary directories.
+        if !entry.file_type()?.is_dir() || id.ends_with(".tmp") {
+            continue;
+        }
+
+        match read_snapshot(&id) {
+            Ok(snapshot) => snapshots.push(snapshot),
+            Err(err) => warn!("Failed to read player data backup {id}: {err:#}")
+        }
+    }
+
+    snapshots.sort_by(|a, b| b.created.cmp(&a.created).then_with(|| b.id.cmp(&a.id)));
+    Ok(snapshots)
+}
+
+/// Copies the files in the snapshot with the given ID back into `data_dir`, giving the paths of the files restored.
+/// PlayerData.dat is checked to be valid JSON before any files are copied, so a snapshot with a corrupt PlayerData.dat is never restored.
+/// Files that already exist are written to in place, rather than replaced, so that they keep the permissions the game gave them.
+/// PlayerData.dat is also copied to the datakeeper directory, since a new PlayerData.dat can't be created in `data_dir`
+/// without giving it permissions that stop the game opening it.
+pub fn restore_snapshot(data_dir: &Path, id: &str) -> Result<Vec<String>> {
+    let snapshot = list_snapshots()?.into_iter()
+        .find(|snapshot| snapshot.id == id)
+        .ok_or(anyhow!("No player data backup exists with ID {id}"))?;
+    let snapshot_dir = Path::new(&snapshot.path);
+
+    if snapshot.files.iter().any(|file| file == "PlayerData.dat") {
+        let player_data = std::fs::read(snapshot_dir.join("PlayerData.dat")).context("Failed to read backed up PlayerData.dat")?;
+        serde_json::from_slice::<serde_json::Value>(&player_data)
+            .context("The backed up PlayerData.dat is not valid JSON, so the backup cannot be restored")?;
+    }
+
+    let mut restored = Vec::new();
+    for file in &snapshot.files {
+        let source = snapshot_dir.join(file);
+        let restore_path = data_dir.join(file);
+        let result = if file == "PlayerData.dat" {
+            write_in_place(&source, Path::new(DATAKEEPER_PATH))
+                .and_then(|_| if restore_path.exists() { write_in_place(&source, &restore_path) } else { Ok(()) })
+        }   else    {
+            write_in_place(&source, &restore_path)
+        };
+
+        match result {
+            Ok(_) => restored.push(file.clone()),
+            Err(err) => warn!("Failed to restore player data file {file}: {err:#}")
+        }
+    }
+
+    Ok(restored)
+}
+
+// Reads the details of the snapshot with the given ID.
+fn read_snapshot(id: &str) -> Result<PlayerDataBackup> {
+    let snapshot_dir = Path::new(PLAYER_DATA_SNAPSHOTS_DIR).join(id);
+    let mut relative_paths = Vec::new();
+    find_backed_up_files(&snapshot_dir, Path::new(""), &mut relative_paths)?;
+
+    let size = relative_paths.iter()
+        .map(|relative_path| Ok(std::fs::metadata(snapshot_dir.join(relative_path))?.len()))
+        .sum::<Result<u64>>()?;
+    Ok(PlayerDataBackup {
+        id: id.to_string(),
+        path: snapshot_dir.to_string_lossy().to_string(),
+        created: get_modified_secs(&snapshot_dir)?,
+        size,
+        files: relative_paths.iter().map(|relative_path| relative_path.to_string_lossy().to_string()).collect()
+    })
+}
+
+// Copies each source file to its relative path within `backup_dir`, replacing anything already there.
+// The files are copied to a temporary directory first, so that `backup_dir` is never left with only some of the files.
+fn save_backup(sources: &[(PathBuf, PathBuf)], backup_dir: &Path) -> Result<()> {
+    let temp_dir = backup_dir.with_extension("tmp");
+    if temp_dir.exists() {
+        fs_ops::remove_dir_all(&temp_dir)?;
+    }
+    for (source, relative_path) in sources {
+        let backup_path = temp_dir.join(relative_path);
+        fs_ops::create_dir_all(backup_path.parent().unwrap())?;
+        fs_ops::copy(source, &backup_path)?;
+    }
+
+    if backup_dir.exists() {
+        fs_ops::remove_dir_all(backup_dir)?;
+    }
+    fs_ops::rename(&temp_dir, backup_dir).context("Failed to save player data backup")?;
+    Ok(())
+}
+
+// Replaces the contents of the file at `path` with those of `source`, creating it (and its directory) if it doesn't exist.
+fn write_in_place(source: &Path, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs_ops::create_dir_all(parent)?;
+    }
+
+    let mut source = fs_ops::open(source)?;
+    let mut handle = fs_ops::open_with(OpenOptions::new().write(true).create(true).truncate(true), path)?;
+    std::io::copy(&mut source, &mut handle)?;
+    Ok(())
+}
+
+fn get_modified_secs(path: &Path) -> Result<u64> {
+    Ok(std::fs::metadata(path)?.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+}
+
+// Finds the files within `dir` with one of the names in `BACKED_UP_FILES`, adding their paths relative to `dir` (which is
+// `relative_dir` within the directory being searched) to `found`. Subdirectories are searched, but links are not followed.
+fn find_backed_up_files(dir: &Path, relative_dir: &Path, found: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs_ops::read_dir(dir)?.flatten() {
+        let file_type = entry.file_type()?;
+        let relative_path = relative_dir.join(entry.file_name());
+        if file_type.is_dir() {
+            find_backed_up_files(&entry.path(), &relative_path, found)?;
+        }   else if file_type.is_file() && BACKED_UP_FILES.iter().any(|name| entry.file_name() == *name) {
+            found.push(relative_path);
+        }
+    }
+
+    Ok(())
+}
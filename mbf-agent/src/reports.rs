@@ -0,0 +1,680 @@
+//! Writes a plain text summary of each operation carried out by the agent to the quest's storage,
+//! so that users can check what happened from the in-headset file manager without reconnecting to MBF.
+
+use std::{fmt::Write as _, io::ErrorKind, path::Path, sync::Mutex, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use anyhow::Result;
+use log::warn;
+
+use crate::{commands, composition::CompositionDelta, fs_ops::IoFailure, integrity::{DiffCrcMismatch, DiffOutputMismatch, IntegrityClass}, package_manager::PmFailure, patching::{DownloadConfirmationNeeded, InsufficientStorage, SignerChangeNeeded}, requests::{ModModel, PatchOutput, Request, Response}, zip::ZipError, reports_path};
+
+// The maximum number of items from any list that will be included within a report.
+const MAX_LIST_ITEMS: usize = 20;
+// The number of dated reports kept within the `operations` folder.
+const MAX_DATED_REPORTS: usize = 30;
+// If the wall clock and monotonic clock disagree on how long an operation took by more than this, the wall clock is assumed to have changed.
+// The quest's clock is often wrong after it boots, until it is corrected over the network.
+const CLOCK_JUMP_TOLERANCE: Duration = Duration::from_secs(30);
+
+// Warnings logged while handling the current request, to be included in its report.
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// Notable changes made while handling the current request which aren't warnings, to be included in its report.
+static NOTES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+// The breakdown of how patching changed the size of the APK, if the current request patched one.
+static SIZE_CHANGE: Mutex<Option<CompositionDelta>> = Mutex::new(None);
+
+/// Records a warning logged during the current operation.
+pub fn record_warning(message: &str) {
+    WARNINGS.lock().unwrap_or_else(|err| err.into_inner()).push(message.to_string());
+}
+
+/// Records a change made during the current operation that the user may want to know about, e.g. normalising a file.
+pub fn record_note(message: &str) {
+    NOTES.lock().unwrap_or_else(|err| err.into_inner()).push(message.to_string());
+}
+
+/// Records how patching changed the size of the APK, to be included in the report of the current operation.
+pub fn record_size_change(delta: CompositionDelta) {
+    *SIZE_CHANGE.lock().unwrap_or_else(|err| err.into_inner()) = Some(delta);
+}
+
+/// The details of an operation, taken from the request before it is handled.
+pub struct Operation {
+    name: &'static str,
+    details: Vec<String>,
+    start_time: SystemTime,
+    start_instant: Instant,
+    game_version_before: Option<String>,
+    changes_mod_data: bool
+}
+
+// Everything recorded while an operation was carried out, which is rendered within its report once it has finished.
+struct Finished<'a> {
+    outcome: Outcome<'a>,
+    end_time: SystemTime,
+    // Measured with the monotonic clock, since the wall clock may have changed during the operation.
+    duration: Duration,
+    game_version_after: Option<String>,
+    warnings: Vec<String>,
+    notes: Vec<String>,
+    size_change: Option<CompositionDelta>,
+    transcripts: Vec<commands::Transcript>
+}
+
+/// The outcome of an operation, for the purposes of reporting.
+pub enum Outcome<'a> {
+    Succeeded(&'a Response),
+    Failed(&'a anyhow::Error),
+    Panicked
+}
+
+impl Operation {
+    /// Creates the operation for `request`, or returns None if the request is read-only and so does not need a report.
+    pub fn from_request(request: &Request) -> Option<Self> {
+        let (name, details) = match request {
+            Request::GetModStatus | Request::GetSetupStatus | Request::GetApkComposition { .. } | Request::GetManifest { .. } | Request::GetApkEntries { .. } | Request::GetDowngradeOptions | Request::DiagnoseCrash | Request::ListPlayerDataBackups => return None,
+            // A dry run changes nothing, so saving a report would be the only change it made.
+            Request::Patch { output: PatchOutput::DryRun, .. } => return None,
+            Request::SetModsEnabled { statuses, .. } => ("Set mods enabled", truncate_list(statuses.iter()
+                .map(|(id, enabled)| format!("{} {id}", if *enabled { "Enable" } else { "Disable" }))
+                .collect())),
+            Request::RemoveMod { id } => ("Remove mod", vec![format!("Mod ID: {id}")]),
+            Request::Import { from_path, .. } => ("Import file", vec![format!("From: {from_path}")]),
+            Request::ImportModUrl { from_url, .. } => ("Import mod from URL", vec![format!("From: {}", redact_url(from_url))]),
+            Request::Patch { downgrade_to, remodding, allow_no_core_mods, copy_apk_first, sequential_stages, sequential_downloads, working_dir, local_diffs_dir, confirmed_download_size, keep_vanilla_backup, output, allow_signer_change, .. } => {
+                let mut details = Vec::new();
+                if let PatchOutput::Export { destination, .. } = output {
+                    details.push(format!("Exporting to: {destination}"));
+                }
+                if let Some(version) = downgrade_to {
+                    details.push(format!("Downgrading to: {version}"));
+                }
+                details.push(format!("Remodding: {remodding}"));
+                details.push(format!("Allow no core mods: {allow_no_core_mods}"));
+                details.push(format!("Copy APK first: {copy_apk_first}"));
+                details.push(format!("Sequential stages: {sequential_stages}"));
+                details.push(format!("Keep vanilla backup: {keep_vanilla_backup}"));
+                if *allow_signer_change {
+                    details.push("Allowed signer change".to_string());
+                }
+                if downgrade_to.is_some() {
+                    details.push(format!("Sequential downloads: {sequential_downloads}"));
+                    if let Some(local_diffs_dir) = local_diffs_dir {
+                        details.push(format!("Local diffs directory: {local_diffs_dir}"));
+                    }
+                    if let Some(confirmed_download_size) = confirmed_download_size {
+                        details.push(format!("Confirmed download size: {confirmed_download_size} bytes"));
+                    }
+                }
+                if let Some(working_dir) = working_dir {
+                    details.push(format!("Working directory: {working_dir}"));
+                }
+                ("Patch", details)
+            },
+            Request::PatchApkFile { input, output, remodding, allow_other_package, .. } => ("Patch APK file", vec![
+                format!("Input: {input}"),
+                format!("Output: {output}"),
+                format!("Remodding: {remodding}"),
+                format!("Allow other package: {allow_other_package}")
+            ]),
+            Request::FixPlayerData => ("Fix player data", Vec::new()),
+            Request::RepairObbs => ("Repair OBBs", Vec::new()),
+            Request::RestoreVanilla => ("Restore vanilla game", Vec::new()),
+            Request::BackupPlayerData => ("Back up player data", Vec::new()),
+            Request::RestorePlayerData { id } => ("Restore player data", vec![format!("Backup ID: {id}")]),
+            Request::QuickFix { force_modloader } => ("Quick fix", vec![format!("Force modloader: {force_modloader}")]),
+            Request::ApplyLegacyStorageFallback => ("Apply legacy storage fallback", Vec::new()),
+            Request::TrustRepositoryIdentity { host, .. } => ("Trust repository identity", vec![format!("Host: {host}")]),
+            Request::SetDiffMirrors { mirrors } => ("Set diff mirrors", mirrors.iter().map(|mirror| format!("Mirror: {mirror}")).collect())
+        };
+
+        // The game version is only likely to change while patching, so avoid reading the APK otherwise.
+        let game_version_before = match request {
+            Request::Patch { .. } | Request::ApplyLegacyStorageFallback => get_game_version(),
+            _ => None
+        };
+
+        Some(Self {
+            name,
+            details,
+            start_time: SystemTime::now(),
+            start_instant: Instant::now(),
+            game_version_before,
+            // Patching an APK file only writes to its output file, and the diff mirrors aren't mod data.
+            changes_mod_data: !matches!(request, Request::PatchApkFile { .. } | Request::SetDiffMirrors { .. })
+        })
+    }
+
+    /// Whether the operation may have changed the contents of ModData.
+    pub fn changes_mod_data(&self) -> bool {
+        self.changes_mod_data
+    }
+
+    /// Saves the report for this operation as `LAST_OPERATION.txt`, and within the `operations` folder.
+    /// Any error doing so is logged, since failing to save the report should never fail the operation.
+    pub fn save_report(self, outcome: Outcome) {
+        if let Err(err) = self.try_save_report(outcome) {
+            warn!("Failed to save operation report: {err}");
+        }
+    }
+
+    fn try_save_report(self, outcome: Outcome) -> Result<()> {
+        let report = self.render(Finished {
+            outcome,
+            end_time: SystemTime::now(),
+            duration: self.start_instant.elapsed(),
+            game_version_after: match self.game_version_before {
+                Some(_) => get_game_version(),
+                None => None
+            },
+            warnings: std::mem::take(&mut *WARNINGS.lock().unwrap_or_else(|err| err.into_inner())),
+            notes: std::mem::take(&mut *NOTES.lock().unwrap_or_else(|err| err.into_inner())),
+            size_change: SIZE_CHANGE.lock().unwrap_or_else(|err| err.into_inner()).take(),
+            transcripts: commands::get_transcripts()
+        })?;
+
+        let reports_path = reports_path();
+        let reports_path = Path::new(&reports_path);
+        let dated_reports_path = reports_path.join("operations");
+        std::fs::create_dir_all(&dated_reports_path)?;
+        std::fs::write(reports_path.join("LAST_OPERATION.txt"), &report)?;
+
+        let dated_name = format!("{}.txt", format_time(self.start_time).replace([':', ' '], "-"));
+        std::fs::write(dated_reports_path.join(dated_name), &report)?;
+        remove_old_reports(&dated_reports_path)?;
+
+        Ok(())
+    }
+
+    // Renders the report of this operation.
+    fn render(&self, finished: Finished) -> Result<String> {
+        let Finished { outcome, end_time, duration, game_version_after, warnings, notes, size_change, transcripts } = finished;
+        let mut report = String::new();
+        writeln!(report, "ModsBeforeFriday operation report")?;
+        writeln!(report)?;
+        writeln!(report, "Operation: {}", self.name)?;
+        for detail in &self.details {
+            writeln!(report, "  {detail}")?;
+        }
+        writeln!(report, "Started: {}", format_time(self.start_time))?;
+        writeln!(report, "Finished: {}", format_time(end_time))?;
+        writeln!(report, "Took: {:.1}s", duration.as_secs_f32())?;
+        if let Some(jump) = get_clock_jump(self.start_time, end_time, duration) {
+            writeln!(report, "The quest's clock changed by {jump} during this operation, so the times above may be inaccurate")?;
+        }
+
+        if let Some(version_before) = &self.game_version_before {
+            writeln!(report, "Game version before: {version_before}")?;
+            writeln!(report, "Game version after: {}", game_version_after.as_deref().unwrap_or("Not installed"))?;
+        }
+
+        writeln!(report)?;
+        write_outcome(&mut report, &outcome)?;
+
+        if let Some(size_change) = size_change {
+            writeln!(report)?;
+            writeln!(report, "APK size change from patching:")?;
+            write!(report, "{size_change}")?;
+        }
+
+        if !notes.is_empty() {
+            writeln!(report)?;
+            writeln!(report, "Notes:")?;
+            for note in truncate_list(notes) {
+                writeln!(report, "- {note}")?;
+            }
+        }
+
+        if !warnings.is_empty() {
+            writeln!(report)?;
+            writeln!(report, "Warnings:")?;
+            for warning in truncate_list(warnings) {
+                writeln!(report, "- {}", warning.trim())?;
+            }
+        }
+
+        write_transcripts(&mut report, &transcripts)?;
+        Ok(report)
+    }
+}
+
+/// Writes the transcript of each command run during an operation, as included in its report.
+/// Every command is included, rather than a truncated list, since these are most useful when something unexpected happened.
+pub fn write_transcripts(report: &mut String, transcripts: &[commands::Transcript]) -> Result<()> {
+    if !transcripts.is_empty() {
+        writeln!(report)?;
+        writeln!(report, "Commands run:")?;
+        for transcript in transcripts {
+            write!(report, "{transcript}")?;
+        }
+    }
+    Ok(())
+}
+
+fn write_outcome(report: &mut String, outcome: &Outcome) -> Result<()> {
+    match outcome {
+        Outcome::Succeeded(response) => {
+            writeln!(report, "Outcome: Succeeded")?;
+            write_response(report, response)?;
+        },
+        Outcome::Failed(err) => {
+            writeln!(report, "Outcome: FAILED")?;
+            writeln!(report, "Error: {}", redact_urls(&format!("{err:#}")))?;
+            writeln!(report)?;
+            writeln!(report, "Suggestion: {}", get_suggestion(err))?;
+        },
+        Outcome::Panicked => {
+            writeln!(report, "Outcome: FAILED (the agent crashed)")?;
+            writeln!(report)?;
+            writeln!(report, "Suggestion: Reconnect to ModsBeforeFriday and try again. If this keeps happening, report it along with the logs.")?;
+        }
+    }
+
+    Ok(())
+}
+
+// Writes a summary of the result of a successful operation
+fn write_response(report: &mut String, response: &Response) -> Result<()> {
+    match response {
+        Response::ModStatus { app_info, installed_mods, .. } => {
+            writeln!(report, "Game version: {}", app_info.as_ref().map(|info| info.version.as_str()).unwrap_or("Not installed"))?;
+            write_mods(report, installed_mods)?;
+        },
+        Response::Mods { installed_mods } => write_mods(report, installed_mods)?,
+        Response::Patched { installed_mods, patch_kind, signature, .. } => {
+            writeln!(report, "Patch kind: {patch_kind:?}")?;
+            if let Some(signature) = signature {
+                writeln!(report, "Signature before patching: {signature:?}")?;
+            }
+            write_mods(report, installed_mods)?;
+        },
+        Response::ImportedMod { installed_mods, imported_id } => {
+            writeln!(report, "Imported mod: {imported_id}")?;
+            write_mods(report, installed_mods)?;
+        },
+        Response::ImportedFileCopy { copied_to, mod_id } => writeln!(report, "Copied file to {copied_to} for mod {mod_id}")?,
+        Response::ImportedSong => writeln!(report, "Imported song")?,
+        Response::LogMsg { .. } => {}
+        Response::RepairedObbs { restored } => writeln!(report, "Restored {} OBB files", restored.len())?,
+        Response::FixedPlayerData { existed } => writeln!(report, "{}", if *existed {
+            "Moved PlayerData.dat out of the game's files"
+        }   else {
+            "There was no PlayerData.dat to fix"
+        })?,
+        Response::RestoredVanilla { backup_existed } => writeln!(report, "{}", if *backup_existed {
+            "Restored the vanilla game"
+        }   else {
+            "There was no vanilla APK to restore"
+        })?,
+        Response::PatchDryRun { required_space, available_space, .. } =>
+            writeln!(report, "Dry run of patching: {required_space} bytes needed, {available_space} bytes free")?,
+        Response::BackedUpPlayerData { backup } => writeln!(report, "Backed up {} player data files to {} ({} bytes)",
+            backup.files.len(), backup.path, backup.size)?,
+        Response::PlayerDataBackups { backups } => writeln!(report, "Found {} player data backups", backups.len())?,
+        Response::RestoredPlayerData { restored } => writeln!(report, "Restored {} player data files", restored.len())?,
+        Response::RepositoryIdentityChanged { host, .. } => writeln!(report, "The identity of {host} changed")?,
+        Response::TrustedRepositoryIdentity => writeln!(report, "Trusted new repository identity")?,
+        Response::DiffMirrors { mirrors } => writeln!(report, "Diff mirrors are now: {}", mirrors.join(", "))?,
+        Response::AppliedLegacyStorage => writeln!(report, "The game now uses legacy storage")?,
+        Response::ExportedApk { path, size, .. } => writeln!(report, "Exported patched APK to {path} ({size} bytes)")?,
+        Response::PatchedApkFile { output, size, .. } => writeln!(report, "Saved patched APK to {output} ({size} bytes)")?,
+        Response::CrashDiagnosis { crash, .. } => writeln!(report, "{}", if crash.is_some() {
+            "Found crash"
+        }   else {
+            "No crash found"
+        })?,
+        Response::IoFailure { .. } => {}
+        Response::SetupStatus { next_step, .. } => writeln!(report, "Next setup step: {next_step:?}")?,
+        Response::ApkComposition { path, .. } => writeln!(report, "Read composition of {path}")?,
+        Response::Manifest { path, .. } => writeln!(report, "Read manifest of {path}")?,
+        Response::ApkEntries { path, entries } => writeln!(report, "Listed {} entries of {path}", entries.len())?,
+        Response::DowngradeOptions { options, .. } => writeln!(report, "Found {} downgrade options", options.len())?,
+        Response::InsufficientStorage { purpose, required, available, .. } =>
+            writeln!(report, "Not enough space to {purpose}: {required} bytes needed, {available} bytes free")?,
+        Response::PackageManagerFailed { command, output, .. } => writeln!(report, "pm {command} failed: {output}")?,
+        Response::DownloadConfirmationNeeded { total_size, .. } => writeln!(report, "Download of {total_size} bytes needs confirming")?,
+        Response::SignerChangeConfirmationNeeded { previous_sha256, signer_sha256 } =>
+            writeln!(report, "Change of signer from {previous_sha256} to {signer_sha256} needs confirming")?,
+        Response::IntegrityCheckFailed { .. } => {}
+    }
+
+    Ok(())
+}
+
+fn write_mods(report: &mut String, mods: &[ModModel]) -> Result<()> {
+    writeln!(report, "Installed mods ({}):", mods.len())?;
+    for line in truncate_list(mods.iter()
+        .map(|m| format!("{} v{} ({})", m.id, m.version, if m.is_enabled { "enabled" } else { "disabled" }))
+        .collect()) {
+        writeln!(report, "- {line}")?;
+    }
+
+    Ok(())
+}
+
+// Gives the first thing that the user should try in order to fix the given error.
+fn get_suggestion(err: &anyhow::Error) -> &'static str {
+    let io_failure = err.chain().find_map(|cause| cause.downcast_ref::<IoFailure>());
+    match io_failure.map(IoFailure::kind) {
+        Some(ErrorKind::StorageFull) => return "Free up some space on your quest, then try again.",
+        Some(ErrorKind::PermissionDenied) => return "Restart your quest, then try again.",
+        _ => {}
+    }
+
+    if let Some(mismatch) = err.chain().find_map(|cause| cause.downcast_ref::<DiffCrcMismatch>()) {
+        return match mismatch.class {
+            IntegrityClass::LikelyCorrupt => "Your installation appears to be damaged. Uninstall Beat Saber and download it again from the store.",
+            IntegrityClass::LikelyModifiedInstall => "Only legitimate copies of Beat Saber installed from the store are supported.",
+            IntegrityClass::Indeterminate => "Uninstall Beat Saber and install it again from the store, then try again."
+        };
+    }
+
+    if err.chain().any(|cause| cause.is::<ZipError>()) {
+        return "Your game files appear to be corrupt. Uninstall Beat Saber and install it again from the store, then try again.";
+    }
+
+    if err.chain().any(|cause| cause.is::<InsufficientStorage>()) {
+        return "Free up some space on your quest, then try again.";
+    }
+
+    if let Some(failure) = err.chain().find_map(|cause| cause.downcast_ref::<PmFailure>()) {
+        return failure.kind.hint();
+    }
+
+    if err.chain().any(|cause| cause.is::<DownloadConfirmationNeeded>()) {
+        return "Confirm the size of the download, then try again.";
+    }
+
+    if err.chain().any(|cause| cause.is::<SignerChangeNeeded>()) {
+        return "Sign the game with the certificate it was last signed with, or confirm that its data can be lost, then try again.";
+    }
+
+    if err.chain().any(|cause| cause.is::<DiffOutputMismatch>()) {
+        return "Make sure your quest has plenty of free space, then try again. If this keeps happening, report it along with the logs, since the downgrade files may be damaged.";
+    }
+
+    let message = format!("{err:#}").to_lowercase();
+    if message.contains("download") || message.contains("request") {
+        "Check that your quest is connected to the internet, then try again."
+    }   else if message.contains("crc") || message.contains("corrupt") {
+        "Your installation may be corrupt. Reinstall Beat Saber, then try again."
+    }   else {
+        "Reconnect to ModsBeforeFriday and try again. If this keeps happening, report it along with the logs."
+    }
+}
+
+// Limits the given list to MAX_LIST_ITEMS, adding a line saying how many items were removed.
+fn truncate_list(mut items: Vec<String>) -> Vec<String> {
+    if items.len() > MAX_LIST_ITEMS {
+        let removed = items.len() - MAX_LIST_ITEMS;
+        items.truncate(MAX_LIST_ITEMS);
+        items.push(format!("... and {removed} more"));
+    }
+
+    items
+}
+
+// Removes the query string from a URL, since this could contain an access token.
+fn redact_url(url: &str) -> &str {
+    match url.split_once('?') {
+        Some((without_query, _)) => without_query,
+        None => url
+    }
+}
+
+/// Removes the query strings from any URLs within the given message.
+pub fn redact_urls(message: &str) -> String {
+    message.split(' ')
+        .map(|word| if word.contains("://") {
+            // Punctuation after a URL, e.g. the colon after the context of an error, isn't part of its query string so is kept.
+            let url = word.trim_end_matches([':', ',', ';', ')']);
+            format!("{}{}", redact_url(url), &word[url.len()..])
+        }   else {
+            word.to_string()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Deletes the oldest dated reports until only MAX_DATED_REPORTS remain.
+fn remove_old_reports(dated_reports_path: &Path) -> Result<()> {
+    let mut reports: Vec<_> = std::fs::read_dir(dated_reports_path)?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect();
+    if reports.len() <= MAX_DATED_REPORTS {
+        return Ok(());
+    }
+
+    // Reports are named by their start time, so sorting by name gives the oldest first.
+    reports.sort();
+    for old_report in &reports[0..reports.len() - MAX_DATED_REPORTS] {
+        std::fs::remove_file(old_report)?;
+    }
+
+    Ok(())
+}
+
+fn get_game_version() -> Option<String> {
+    match crate::handlers::get_app_info() {
+        Ok(info) => info.map(|info| info.version),
+        Err(err) => {
+            warn!("Failed to get game version for report: {err}");
+            None
+        }
+    }
+}
+
+// Compares the time elapsed according to the wall clock with the actual duration of an operation.
+// Returns a description of the change, e.g. `-3600s`, if the wall clock jumped forwards or backwards.
+fn get_clock_jump(start_time: SystemTime, end_time: SystemTime, duration: Duration) -> Option<String> {
+    let wall_secs = match end_time.duration_since(start_time) {
+        Ok(elapsed) => elapsed.as_secs_f64(),
+        Err(err) => -err.duration().as_secs_f64() // The clock went backwards
+    };
+
+    let jump_secs = wall_secs - duration.as_secs_f64();
+    if jump_secs.abs() > CLOCK_JUMP_TOLERANCE.as_secs_f64() {
+        Some(format!("{jump_secs:+.0}s"))
+    }   else {
+        None
+    }
+}
+
+/// Formats the given time as `YYYY-MM-DD HH:MM:SS` (in UTC).
+pub fn format_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Convert the number of days since the epoch to a civil date (see http://howardhinnant.github.io/date_algorithms.html)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}", secs_of_day / 3600, (secs_of_day / 60) % 60, secs_of_day % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{integrity::SignatureStatus, patching::PatchKind};
+
+    use super::*;
+
+    const START_SECS: u64 = 1_760_000_000; // 2025-10-09 08:53:20
+
+    fn operation(name: &'static str, details: &[&str], game_version_before: Option<&str>) -> Operation {
+        Operation {
+            name,
+            details: details.iter().map(|detail| detail.to_string()).collect(),
+            start_time: UNIX_EPOCH + Duration::from_secs(START_SECS),
+            start_instant: Instant::now(),
+            game_version_before: game_version_before.map(str::to_string),
+            changes_mod_data: true
+        }
+    }
+
+    // The details of an operation that took 95 seconds, with nothing else recorded.
+    fn finished(outcome: Outcome) -> Finished {
+        Finished {
+            outcome,
+            end_time: UNIX_EPOCH + Duration::from_secs(START_SECS + 95),
+            duration: Duration::from_secs(95),
+            game_version_after: None,
+            warnings: Vec::new(),
+            notes: Vec::new(),
+            size_change: None,
+            transcripts: Vec::new()
+        }
+    }
+
+    fn mod_model(id: &str, is_enabled: bool) -> ModModel {
+        ModModel {
+            id: id.to_string(),
+            name: id.to_string(),
+            version: "1.2.3".parse().unwrap(),
+            game_version: Some("1.37.0".to_string()),
+            description: None,
+            is_enabled,
+            conflicts_with: Vec::new()
+        }
+    }
+
+    #[test]
+    fn successful_patch_report() {
+        let response = Response::Patched {
+            installed_mods: vec![mod_model("core-mod", true), mod_model("other-mod", false)],
+            patch_kind: PatchKind::Fresh,
+            manifest_permissions: Vec::new(),
+            signature: Some(SignatureStatus::PatchedByMbf)
+        };
+        let report = operation("Patch", &["Remodding: false"], Some("1.37.0")).render(Finished {
+            game_version_after: Some("1.37.0".to_string()),
+            notes: vec!["Saved about 30.0s by not copying the 1500MB APK before patching".to_string()],
+            transcripts: vec![commands::Transcript {
+                argv: vec!["pm".to_string(), "install-commit".to_string(), "42".to_string()],
+                caller: "src/package_manager.rs:100:58".to_string(),
+                duration: Duration::from_millis(1500),
+                timeout: None,
+                status: Ok(0),
+                stdout: "Success\n".to_string(),
+                stderr: String::new()
+            }],
+            ..finished(Outcome::Succeeded(&response))
+        }).unwrap();
+
+        assert_eq!(report, "\
+ModsBeforeFriday operation report
+
+Operation: Patch
+  Remodding: false
+Started: 2025-10-09 08:53:20
+Finished: 2025-10-09 08:54:55
+Took: 95.0s
+Game version before: 1.37.0
+Game version after: 1.37.0
+
+Outcome: Succeeded
+Patch kind: Fresh
+Signature before patching: PatchedByMbf
+Installed mods (2):
+- core-mod v1.2.3 (enabled)
+- other-mod v1.2.3 (disabled)
+
+Notes:
+- Saved about 30.0s by not copying the 1500MB APK before patching
+
+Commands run:
+$ pm install-commit 42 (from src/package_manager.rs:100:58, took 1.50s, exit code 0)
+stdout:
+  Success
+");
+    }
+
+    #[test]
+    fn failed_report_with_warnings() {
+        let err = anyhow::Error::new(InsufficientStorage {
+            purpose: "patch APK".to_string(),
+            path: "/data/local/tmp/mbf-tmp".into(),
+            required: 3_000_000_000,
+            available: 1_000_000_000
+        }).context("Failed to fetch https://example.com/libunity.so?token=secret");
+        let report = operation("Patch", &[], Some("1.37.0")).render(Finished {
+            warnings: (0..MAX_LIST_ITEMS + 3).map(|i| format!("  Warning {i}\n")).collect(),
+            ..finished(Outcome::Failed(&err))
+        }).unwrap();
+
+        let warnings: String = (0..MAX_LIST_ITEMS).map(|i| format!("- Warning {i}\n")).collect();
+        assert_eq!(report, format!("\
+ModsBeforeFriday operation report
+
+Operation: Patch
+Started: 2025-10-09 08:53:20
+Finished: 2025-10-09 08:54:55
+Took: 95.0s
+Game version before: 1.37.0
+Game version after: Not installed
+
+Outcome: FAILED
+Error: Failed to fetch https://example.com/libunity.so: Not enough space to patch APK: need 2000MB more on \"/data/local/tmp/mbf-tmp\" (3000MB is needed, but only 1000MB is free)
+
+Suggestion: Free up some space on your quest, then try again.
+
+Warnings:
+{warnings}- ... and 3 more
+"));
+        assert!(!report.contains("secret"));
+    }
+
+    #[test]
+    fn cancelled_report() {
+        let err = anyhow::anyhow!("Download cancelled, since another download failed").context("Failed to download diffs");
+        let report = operation("Patch", &["Downgrading to: 1.28.0"], None).render(finished(Outcome::Failed(&err))).unwrap();
+
+        assert_eq!(report, "\
+ModsBeforeFriday operation report
+
+Operation: Patch
+  Downgrading to: 1.28.0
+Started: 2025-10-09 08:53:20
+Finished: 2025-10-09 08:54:55
+Took: 95.0s
+
+Outcome: FAILED
+Error: Failed to download diffs: Download cancelled, since another download failed
+
+Suggestion: Check that your quest is connected to the internet, then try again.
+");
+    }
+
+    #[test]
+    fn crashed_report_notes_a_clock_change() {
+        let report = operation("Remove mod", &["Mod ID: core-mod"], None).render(Finished {
+            // The quest's clock was corrected by an hour while the operation ran.
+            end_time: UNIX_EPOCH + Duration::from_secs(START_SECS + 3600 + 95),
+            ..finished(Outcome::Panicked)
+        }).unwrap();
+
+        assert_eq!(report, "\
+ModsBeforeFriday operation report
+
+Operation: Remove mod
+  Mod ID: core-mod
+Started: 2025-10-09 08:53:20
+Finished: 2025-10-09 09:54:55
+Took: 95.0s
+The quest's clock changed by +3600s during this operation, so the times above may be inaccurate
+
+Outcome: FAILED (the agent crashed)
+
+Suggestion: Reconnect to ModsBeforeFriday and try again. If this keeps happening, report it along with the logs.
+");
+    }
+}
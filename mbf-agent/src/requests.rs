@@ -1,18 +1,28 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use semver::Version;
 use serde::{Deserialize, Serialize};
 
-use crate::{manifest::ManifestMod, mod_man::Mod};
+use crate::{composition::{ApkComposition, CompositionDelta}, crash::{CrashDiagnosis, CrashRemediation, CrashSummary}, external_res::MetadataHealth, fs_ops::IoOp, integrity::{IntegrityClass, IntegrityEvidence, SignatureStatus}, manifest::ManifestMod, mod_man::Mod, package_manager::PmFailureKind, patching::{DiffDownload, PatchKind, PlannedObb}, player_data::PlayerDataBackup, setup::{SetupFacts, SetupStep}, zip::EntryInfo};
 
 #[derive(Serialize)]
 pub struct AppInfo {
+    /// The package ID of the game, which is Beat Saber unless another was given with the request.
+    pub package_id: String,
     pub loader_installed: Option<ModLoader>,
     pub version: String,
+    // The `android:versionCode` of the installed APK, if it has one.
+    pub version_code: Option<u32>,
+    pub storage_strategy: StorageStrategy,
+    pub build_variant: BuildVariant,
     #[serde(skip_serializing)]
     pub path: String
 }
 
+// Used for request fields that are on unless the frontend turns them off.
+fn default_true() -> bool {
+    true
+}
 
 #[derive(Deserialize)]
 #[serde(tag = "type")]
@@ -24,13 +34,50 @@ pub enum Request {
     /// - The core mods that need to be installed.
     /// - Whether the modloader is in the correct place
     GetModStatus,
+    /// Checks the game, modloader, core mods and storage permission, and works out which setup step the user is on.
+    /// Gives a `SetupStatus` response.
+    GetSetupStatus,
+    /// Breaks down what the space within the APK at `path` (which must be within /sdcard) is used for, or the installed APK if `path` is None.
+    /// If `reference` is given, e.g. the vanilla APK the patched APK was made from, the change in size from `reference` is also broken down.
+    /// Only the central directory of each APK is read, so this is fast even for large APKs.
+    /// Gives an `ApkComposition` response.
+    GetApkComposition {
+        #[serde(default)]
+        path: Option<String>,
+        #[serde(default)]
+        reference: Option<String>
+    },
+    /// Dumps the manifest of the APK at `path` (which must be within /sdcard), or the installed APK if `path` is None,
+    /// as readable XML for debugging.
+    /// Gives a `Manifest` response.
+    GetManifest {
+        #[serde(default)]
+        path: Option<String>
+    },
+    /// Lists the entries of the APK at `path` (which must be within /sdcard), or the installed APK if `path` is None,
+    /// with their sizes, compression methods, CRCs and modification times, for debugging.
+    /// Only the central directory is read.
+    /// Gives an `ApkEntries` response.
+    GetApkEntries {
+        #[serde(default)]
+        path: Option<String>
+    },
+    /// Lists the versions that the installed game can be downgraded to.
+    /// Gives a `DowngradeOptions` response.
+    GetDowngradeOptions,
     /// Installs or uninstalls any number of mods.
     /// This will also attempt to download and install dependencies, upgrade dependencies and will uninstall any
     /// depending mods of mods that have been disabled.
     /// 
     /// Returns a `Mods` response.
     SetModsEnabled {
-        statuses: HashMap<String, bool>
+        statuses: HashMap<String, bool>,
+        /// If true, mods will be installed even if they overwrite files from another mod with different contents.
+        #[serde(default)]
+        allow_conflicts: bool,
+        /// If true, mods will be installed even if their `packageVersion` doesn't match the installed game version.
+        #[serde(default)]
+        force_version_mismatch: bool
     },
     
     // TODO: Make these lists to allow importing multiple mods at once?
@@ -45,12 +92,18 @@ pub enum Request {
     /// Returns an ImportedFileCopy message if the file type was copied by a mod copy extension.
     /// Returns an ImportedSong message if the file type was copied to the songs folder.
     Import {
-        from_path: String
+        from_path: String,
+        /// If true, a mod is imported even if it is older than the installed version of the same mod.
+        #[serde(default)]
+        allow_conflicts: bool
     },
     /// Downloads the file from the given URL and then attempts to import it as a mod (only).
     /// Returns an ImportedMod message.
     ImportModUrl {
         from_url: String,
+        /// If true, the mod is imported even if it is older than the installed version of the same mod.
+        #[serde(default)]
+        allow_conflicts: bool
     },
 
     /// - Patches Beat Saber to add support for modloaders.
@@ -58,6 +111,7 @@ pub enum Request {
     /// - Saves the modloader to the appropriate locatioon on the Quest.
     /// - Wipes any existing mods.
     /// - Installs the core mods for the current version.
+    ///
     /// Returns a `Mods` response to update the frontend with the newly installed core mods.
     Patch {
         downgrade_to: Option<String>,
@@ -73,7 +127,56 @@ pub enum Request {
         // or libmainloader (easier) so that these can be easily updated.
         remodding: bool,
         // If this is true, patching will not be failed if core mods cannot be found for the version.
-        allow_no_core_mods: bool
+        allow_no_core_mods: bool,
+        // If this is true, the installed APK will be copied to a temporary location before it is patched,
+        // rather than the patched APK being written directly from the installed APK.
+        // This is slower, but is kept as a fallback in case patching from the installed APK causes issues.
+        // Has no effect when downgrading, since the downgraded APK is always a new file.
+        #[serde(default)]
+        copy_apk_first: bool,
+        // If this is true, libunity.so is downloaded before the game files are backed up, rather than at the same time.
+        // This is slower, but is kept as a fallback in case doing both at once causes issues.
+        // Has no effect when downgrading.
+        #[serde(default)]
+        sequential_stages: bool,
+        // If this is true, the diffs needed to downgrade are downloaded one at a time, rather than several at once.
+        // This is slower, but is kept as a fallback for unreliable connections.
+        #[serde(default)]
+        sequential_downloads: bool,
+        // The directory to save temporary files to while patching, which must be outside the game's OBB and data directories.
+        // A `mbf-tmp` directory is created within it, and removed once patching finishes. If None, /data/local/tmp is used.
+        #[serde(default)]
+        working_dir: Option<String>,
+        // A directory containing diffs downloaded ahead of time, e.g. on a PC, with the same names as in the diff index.
+        // When downgrading, diffs found here with the correct hash are used, and only the others are downloaded.
+        #[serde(default)]
+        local_diffs_dir: Option<String>,
+        // If given, downgrading only goes ahead if the diffs that need to be downloaded total at most this many bytes.
+        // Otherwise, the request fails before anything is downloaded and a `DownloadConfirmationNeeded` response is sent,
+        // so the user can confirm the size before the request is sent again with it. Send 0 to always be asked.
+        #[serde(default)]
+        confirmed_download_size: Option<u64>,
+        // If this is true, the vanilla APK is kept in /sdcard/ModsBeforeFriday when the game is first patched (or downgraded),
+        // so that a `RestoreVanilla` request can put the vanilla game back. This takes up as much space as the APK.
+        #[serde(default = "default_true")]
+        keep_vanilla_backup: bool,
+        // Whether to install the patched APK, or save it to a file.
+        #[serde(default)]
+        output: PatchOutput,
+        // Permissions to add to the manifest on top of those needed to mod the game, e.g. `android.permission.RECORD_AUDIO` for voice mods.
+        // These are recorded in `modded.json`. Permissions already in the manifest are not added again.
+        #[serde(default)]
+        extra_permissions: Vec<String>,
+        // `uses-feature` names to add to the manifest, which are recorded in the same way.
+        #[serde(default)]
+        extra_features: Vec<String>,
+        // The certificate to sign the patched APK with. If None, the certificate generated for this device is used.
+        #[serde(default)]
+        signing_cert: Option<SigningCert>,
+        // Unless this is true, patching fails if the installed APK is modded and would be signed with a different certificate to the one
+        // it was last signed with, since reinstalling it would lose the game's data, and a `SignerChangeConfirmationNeeded` response is sent.
+        #[serde(default)]
+        allow_signer_change: bool
     },
 
     // Attempts to fix a blackscreen issue by removing PlayerData.dat from `/sdcard/...../files/`.
@@ -83,10 +186,113 @@ pub enum Request {
     // Gives a `FixedPlayerData` response.
     FixPlayerData,
 
+    /// Restores any OBB files left in the working directory by a patch that was interrupted before it could restore them.
+    /// This is also done automatically before any other request is handled.
+    /// Gives a `RepairedObbs` response.
+    RepairObbs,
+
+    /// Replaces the modded game with the vanilla APK kept when it was patched, keeping its OBBs and backing up its player data.
+    /// This only works if the game hasn't been updated or downgraded since, since the OBBs must match the vanilla APK.
+    /// Gives a `RestoredVanilla` response.
+    RestoreVanilla,
+
+    /// Saves a snapshot of the player data files (PlayerData.dat, settings and local scores) to /sdcard/ModsBeforeFriday/backups.
+    /// Gives a `BackedUpPlayerData` response.
+    BackupPlayerData,
+    /// Gives a `PlayerDataBackups` response listing the snapshots saved by `BackupPlayerData`.
+    ListPlayerDataBackups,
+    /// Copies the files in the snapshot with the given ID back into the game's data directory, once PlayerData.dat has been checked.
+    /// Gives a `RestoredPlayerData` response.
+    RestorePlayerData {
+        id: String
+    },
+
+    /// Patches the APK file at `input` in the same way as `Patch`, saving the result to `output`. Both must be within /sdcard.
+    /// The installed app (if any), its OBBs and the modloader are left untouched, so this can be used to prepare an APK
+    /// for another device, even if the game isn't installed on this one.
+    /// Gives a `PatchedApkFile` response.
+    PatchApkFile {
+        input: String,
+        output: String,
+        manifest_mod: ManifestMod,
+        // If this is true, only the manifest is patched, as when remodding.
+        #[serde(default)]
+        remodding: bool,
+        // The unstripped libunity.so to add, which must be within /sdcard. None if it isn't needed, or isn't available.
+        #[serde(default)]
+        libunity_path: Option<String>,
+        // If this is true, an APK for a package other than Beat Saber will be patched, with a warning.
+        #[serde(default)]
+        allow_other_package: bool,
+        // The certificate to sign the patched APK with. If None, the certificate generated for this device is used.
+        #[serde(default)]
+        signing_cert: Option<SigningCert>
+    },
+
     /// Reinstalls any core mods that are misssing/out of date and overwrites the modloader in case it is corrupt.
     /// Should fix most issues with any installation.
     /// Returns a `Mods` response containing the newly installed mods.
-    QuickFix,
+    QuickFix {
+        /// If true, the modloader is overwritten even if it is marked as a custom build.
+        #[serde(default)]
+        force_modloader: bool
+    },
+
+    /// Repatches the installed app to use legacy external storage, instead of MANAGE_EXTERNAL_STORAGE,
+    /// then grants it the classic storage permissions. 
+    /// Should only be used when MANAGE_EXTERNAL_STORAGE cannot be granted on the device.
+    /// Gives an `AppliedLegacyStorage` response.
+    ApplyLegacyStorageFallback,
+
+    /// Finds the most recent native crash of Beat Saber and attempts to work out why it happened.
+    /// Gives a `CrashDiagnosis` response.
+    DiagnoseCrash,
+
+    /// Replaces the saved identity of a diff/libunity repository host with `identity`.
+    /// Should be sent once the user confirms a `RepositoryIdentityChanged` response, with the `host` and `new` identity given there.
+    /// Gives a `TrustedRepositoryIdentity` response.
+    TrustRepositoryIdentity {
+        host: String,
+        identity: String
+    },
+
+    /// Replaces the diff repository mirrors added by the user, which are tried in order before the built-in mirrors
+    /// when fetching the diff index and diffs. Each must be the HTTPS base URL of a copy of the diff repository.
+    /// Send an empty list to only use the built-in mirrors. Gives a `DiffMirrors` response.
+    SetDiffMirrors {
+        mirrors: Vec<String>
+    }
+}
+
+/// What to do with the APK once it has been patched.
+#[derive(Deserialize, Default)]
+pub enum PatchOutput {
+    /// Reinstall the game with the patched APK, restoring its OBBs and installing core mods.
+    #[default]
+    Install,
+    /// Save the patched APK to `destination` (which must be within /sdcard) without changing the installed game.
+    /// Downgrading is not supported when exporting.
+    /// Gives an `ExportedApk` response.
+    Export {
+        destination: String,
+        // Whether to save the modloader to the quest, which isn't needed if the APK is going to be installed on another device.
+        #[serde(default)]
+        install_modloader: bool
+    },
+    /// Work out what patching would change without changing anything, i.e. without downloading, installing or moving any files.
+    /// Downgrading is not supported for a dry run.
+    /// Gives a `PatchDryRun` response.
+    DryRun
+}
+
+/// A certificate and RSA private key to sign patched APKs with, in PEM format.
+/// The game must be signed with the same certificate each time it is patched, otherwise its data is lost when it is reinstalled.
+#[derive(Deserialize)]
+pub enum SigningCert {
+    /// The path of a PEM file containing the certificate and key, which must be within /sdcard.
+    Path(String),
+    /// The contents of the PEM file.
+    Pem(String)
 }
 
 #[derive(Serialize)]
@@ -95,7 +301,21 @@ pub struct CoreModsInfo {
     pub supported_versions: Vec<String>,
     /// The versions of Beat Saber that can be reached by downgrading the game.
     pub downgrade_versions: Vec<String>,
-    pub all_core_mods_installed: bool
+    pub all_core_mods_installed: bool,
+    /// How much of the core mod and diff indices could be read, keyed by index name.
+    pub metadata_health: BTreeMap<&'static str, MetadataHealth>
+}
+
+/// A version that the installed game can be downgraded to.
+#[derive(Serialize)]
+pub struct DowngradeOption {
+    pub to_version: String,
+    /// The number of diffs applied one after another to reach the version. This is more than 1 if there is no diff straight to it.
+    pub hops: usize,
+    /// The total size of the diffs that need to be downloaded, or None if the diff index doesn't give the size of every diff.
+    pub download_size: Option<u64>,
+    /// Whether there are core mods for the version, or None if the core mod index couldn't be fetched.
+    pub core_mods_available: Option<bool>
 }
 
 #[derive(Serialize)]
@@ -107,7 +327,28 @@ pub enum LogLevel {
     Trace
 }
 
-#[derive(Serialize)]
+/// Where the installed app is likely to have come from, based on how it was installed and whether it has been modded.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BuildVariant {
+    /// Installed by a store app, i.e. not sideloaded, and not modded.
+    OfficialStore,
+    /// Modded by MBF or another tool, so the original source of the app is unknown.
+    PreviouslyModded,
+    /// Sideloaded (e.g. via ADB) without being modded, so it may not match the store build.
+    UnknownSideload
+}
+
+/// The method that the installed app uses to access the quest's storage.
+#[derive(Serialize, PartialEq)]
+pub enum StorageStrategy {
+    /// MANAGE_EXTERNAL_STORAGE, granted via appops. This is the default.
+    ManageExternalStorage,
+    /// requestLegacyExternalStorage with the classic READ/WRITE_EXTERNAL_STORAGE permissions.
+    /// Used for devices where MANAGE_EXTERNAL_STORAGE cannot be granted.
+    Legacy
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum ModLoader {
     Scotland2,
     QuestLoader,
@@ -133,6 +374,17 @@ pub enum Response {
     Mods {
         installed_mods: Vec<ModModel>
     },
+    // Given once the installed game has been patched and reinstalled.
+    Patched {
+        installed_mods: Vec<ModModel>,
+        // Whether the APK was modded for the first time, refreshed, upgraded or migrated from another modloader.
+        patch_kind: PatchKind,
+        // Every permission in the manifest of the installed APK, including any extra permissions that were requested.
+        manifest_permissions: Vec<String>,
+        // How the installed APK was signed before it was patched, which should be warned about if it is `Unofficial`.
+        // None if the APK was downgraded, since the downgraded files are checked against the diffs instead.
+        signature: Option<SignatureStatus>
+    },
     ImportedMod {
         installed_mods: Vec<ModModel>,
         imported_id: String  
@@ -150,9 +402,173 @@ pub enum Response {
         message: String,
         level: LogLevel
     },
+    RepairedObbs {
+        // The file names of the OBBs that were restored.
+        restored: Vec<String>
+    },
     FixedPlayerData {
         // True if a PlayerData.dat existed to fix, false if the request did nothing.
         existed: bool
+    },
+    RestoredVanilla {
+        // True if a vanilla APK had been kept to restore, false if the request did nothing.
+        backup_existed: bool
+    },
+    PatchDryRun {
+        version: String,
+        // Whether an unstripped libunity.so exists for the version. None if it isn't needed, since only the manifest would be patched.
+        libunity_available: Option<bool>,
+        patch_kind: PatchKind,
+        manifest_modified: bool,
+        // The files within the APK that would be replaced, added or removed.
+        replaced_files: Vec<String>,
+        added_files: Vec<String>,
+        removed_files: Vec<String>,
+        // The OBB files that would be backed up, then moved back once the game has been reinstalled.
+        obbs: Vec<PlannedObb>,
+        working_dir: String,
+        // The space needed within the working directory, in bytes. This assumes that the OBBs are copied, and excludes libunity.so.
+        required_space: u64,
+        // The space free in the filesystem containing the working directory.
+        available_space: u64,
+        // The size of the vanilla APK that would be kept in /sdcard/ModsBeforeFriday, if it would be kept.
+        vanilla_backup_size: Option<u64>
+    },
+    BackedUpPlayerData {
+        backup: PlayerDataBackup
+    },
+    PlayerDataBackups {
+        // Newest first.
+        backups: Vec<PlayerDataBackup>
+    },
+    RestoredPlayerData {
+        // The paths of the files that were restored, relative to the data directory.
+        restored: Vec<String>
+    },
+    // Sent after the request fails because the public key of a repository host didn't match the one saved when it was first used.
+    // The user should confirm that this is expected before sending a `TrustRepositoryIdentity` request.
+    // This will be sent after the error that caused the request to fail.
+    RepositoryIdentityChanged {
+        host: String,
+        // The SHA-256 hashes of the old and new public keys, hex encoded.
+        old: String,
+        new: String
+    },
+    TrustedRepositoryIdentity,
+    DiffMirrors {
+        // Every mirror, including the built-in ones, in the order they are tried.
+        mirrors: Vec<String>
+    },
+    AppliedLegacyStorage,
+    ExportedApk {
+        path: String,
+        size: u64,
+        crc32: u32,
+        // The SHA-256 hash of the certificate the APK was signed with, hex encoded.
+        signer_sha256: String,
+        // The SHA-256 hash of the APK excluding its signing block, hex encoded.
+        // Exporting the same APK with the same version of MBF gives the same digest on any device.
+        reproducibility_digest: String,
+        patch_kind: PatchKind
+    },
+    PatchedApkFile {
+        output: String,
+        size: u64,
+        input_crc32: u32,
+        crc32: u32,
+        // The SHA-256 hash of the certificate the APK was signed with, hex encoded.
+        signer_sha256: String,
+        reproducibility_digest: String,
+        patch_kind: PatchKind,
+        manifest_modified: bool,
+        written_files: Vec<String>,
+        removed_files: Vec<String>
+    },
+    CrashDiagnosis {
+        // None if no crash could be found.
+        crash: Option<CrashSummary>,
+        // None if the crash didn't match any known cause.
+        diagnosis: Option<CrashDiagnosis>,
+        remediation: Option<CrashRemediation>
+    },
+    // Sent after the request fails because of a filesystem operation, so that the frontend can tell the user which file caused the problem.
+    // This will be sent after the error that caused the request to fail.
+    IoFailure {
+        operation: IoOp,
+        path: String,
+        // The destination path, for copy and rename operations.
+        secondary_path: Option<String>,
+        // The name of the `std::io::ErrorKind`, e.g. `PermissionDenied`, `StorageFull` or `NotFound`
+        kind: String,
+        message: String
+    },
+    SetupStatus {
+        facts: SetupFacts,
+        next_step: SetupStep
+    },
+    ApkComposition {
+        path: String,
+        composition: ApkComposition,
+        // None if no reference APK was given.
+        comparison: Option<CompositionDelta>
+    },
+    Manifest {
+        path: String,
+        // The manifest converted to indented text XML. This isn't guaranteed to be valid XML.
+        xml: String
+    },
+    ApkEntries {
+        path: String,
+        // Sorted by name.
+        entries: Vec<EntryInfo>
+    },
+    DowngradeOptions {
+        // The installed version of the game.
+        from_version: String,
+        options: Vec<DowngradeOption>
+    },
+    // Sent after the request fails because a `pm install` or `pm uninstall` command failed, giving the reason and what the user can do about it.
+    // This will be sent after the error that caused the request to fail.
+    PackageManagerFailed {
+        command: String,
+        kind: PmFailureKind,
+        // The failure code printed by `pm`, e.g. `INSTALL_FAILED_INSUFFICIENT_STORAGE`.
+        code: Option<String>,
+        // Everything printed by `pm`.
+        output: String,
+        hint: String
+    },
+    // Sent after the request fails because there wasn't enough free space to patch, before anything was changed.
+    // This will be sent after the error that caused the request to fail.
+    InsufficientStorage {
+        // What the space was needed for, e.g. `back up game files`.
+        purpose: String,
+        // The directory the space was needed in.
+        path: String,
+        // In bytes.
+        required: u64,
+        available: u64
+    },
+    // Sent after a `Patch` request fails because the diffs needed to downgrade add up to more than `confirmed_download_size`.
+    // This will be sent after the error that caused the request to fail.
+    DownloadConfirmationNeeded {
+        // The total size of the diffs whose size is known.
+        total_size: u64,
+        downloads: Vec<DiffDownload>
+    },
+    // Sent after a `Patch` request fails because the installed APK would be signed with a different certificate and `allow_signer_change` wasn't set.
+    // This will be sent after the error that caused the request to fail.
+    SignerChangeConfirmationNeeded {
+        // The SHA-256 hashes of the certificate the APK was last signed with, and the one it would now be signed with, hex encoded.
+        previous_sha256: String,
+        signer_sha256: String
+    },
+    // Sent after the request fails because a file to be downgraded didn't match its diff, giving the likely reason why.
+    // This will be sent after the error that caused the request to fail.
+    IntegrityCheckFailed {
+        file_name: String,
+        class: IntegrityClass,
+        evidence: IntegrityEvidence
     }
 }
 
@@ -164,7 +580,11 @@ pub struct ModModel {
     pub version: Version,
     pub game_version: Option<String>,
     pub description: Option<String>,
-    pub is_enabled: bool
+    pub is_enabled: bool,
+    /// The IDs of the other installed mods that install a file to the same path, with different contents.
+    /// Always empty if this mod is not enabled.
+    #[serde(default)]
+    pub conflicts_with: Vec<String>
 }
 
 impl From<&Mod> for ModModel {
@@ -175,7 +595,8 @@ impl From<&Mod> for ModModel {
             version: value.manifest().version.clone(),
             game_version: value.manifest().package_version.clone(),
             description: value.manifest().description.clone(),
-            is_enabled: value.installed()
+            is_enabled: value.installed(),
+            conflicts_with: Vec::new()
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,265 @@
+//! Works out which step of setting up mods the user is on, so that the frontend doesn't need to piece this together itself.
+
+use std::{collections::{BTreeMap, HashMap}, path::Path};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use semver::Version;
+use serde::Serialize;
+
+use crate::{capabilities::{self, Capability, Support}, external_res::{self, JsonPullError, MetadataHealth}, handlers, mod_man::ModManager, patching, pinning, requests::{AppInfo, StorageStrategy}, storage_guard::{self, MassDeletion, StorageCleaner}, apk_id, early_mods_dir, late_mods_dir, libs_dir};
+
+/// The facts about the current installation that the next setup step is decided from.
+#[derive(Serialize)]
+pub struct SetupFacts {
+    /// None if the game is not installed.
+    pub app_info: Option<AppInfo>,
+    /// Whether core mods exist for the installed version.
+    /// None if the game is not installed, or the core mod index could not be fetched.
+    pub version_supported: Option<bool>,
+    /// The versions that the installed version can be downgraded to.
+    pub downgrade_versions: Vec<String>,
+    /// True if the modloader is installed and matches the version bundled with the agent.
+    pub modloader_up_to_date: bool,
+    /// Whether the folders that the modloader loads mods from exist.
+    pub mod_data_present: bool,
+    /// The core mods for the installed version which are not installed or are out of date.
+    /// None if the game is not installed or the required core mods are not known.
+    pub missing_core_mods: Option<Vec<String>>,
+    /// None if the game is not installed.
+    pub storage_permission_granted: Option<bool>,
+    /// The forms of system commands supported by the quest's firmware.
+    pub capabilities: HashMap<Capability, Support>,
+    /// Apps and settings on the quest that may delete mod files.
+    pub storage_cleaners: Vec<StorageCleaner>,
+    /// Directories that have had most of their files deleted since MBF last modified them.
+    pub mass_deletions: Vec<MassDeletion>,
+    /// How much of the core mod and diff indices could be read, keyed by index name.
+    pub metadata_health: BTreeMap<&'static str, MetadataHealth>
+}
+
+/// The next thing the user needs to do to finish setting up mods.
+#[derive(Serialize, PartialEq, Debug)]
+pub enum SetupStep {
+    InstallGame,
+    DowngradeRequired {
+        to: String
+    },
+    /// The installed version has no core mods and can't be downgraded to one that does.
+    UnsupportedVersion,
+    PatchRequired,
+    /// The modloader or the folders it loads mods from are missing, which a `QuickFix` request will fix.
+    ModloaderMissing,
+    CoreModsMissing {
+        list: Vec<String>
+    },
+    PermissionRepairNeeded,
+    ReadyToPlay
+}
+
+/// Gathers the facts about the current installation.
+/// The core mod and diff indices are fetched in the background while the installation is checked.
+pub fn get_setup_facts() -> Result<SetupFacts> {
+    std::thread::scope(|scope| {
+        let core_mods = scope.spawn(|| external_res::fetch_core_mods(apk_id()));
+        let diff_index = scope.spawn(|| -> Result<_> {
+            Ok(external_res::get_diff_index(&pinning::pinned_agent()?)?)
+        });
+
+        // Checked before loading mods, since loading mods creates these folders.
+        let mod_data_present = [late_mods_dir(), early_mods_dir(), libs_dir()].iter()
+            .all(|dir| Path::new(dir).exists());
+
+        info!("Searching for Beat Saber app");
+        let app_info = handlers::get_app_info()?;
+        let modloader_up_to_date = patching::is_modloader_up_to_date()?;
+        let storage_permission_granted = match &app_info {
+            Some(app_info) => Some(patching::is_storage_permission_granted(app_info.storage_strategy == StorageStrategy::Legacy)?),
+            None => None
+        };
+
+        let mut mod_manager = ModManager::new();
+        mod_manager.load_mods().context("Failed to load installed mods")?;
+
+        let core_mods = match core_mods.join().expect("Core mod fetch panicked") {
+            Ok(core_mods) => Some(core_mods),
+            Err(JsonPullError::FetchError(err)) => {
+                warn!("Failed to fetch core mods: {err}");
+                None
+            },
+            Err(JsonPullError::ParseError(err)) => return Err(err)
+        };
+        let downgrade_versions = match (&app_info, diff_index.join().expect("Diff index fetch panicked")) {
+            (Some(app_info), Ok(diff_index)) => external_res::find_downgrade_paths(&diff_index, &app_info.version)
+                .into_keys()
+                .collect(),
+            (None, _) => Vec::new(),
+            (Some(_), Err(err)) => {
+                warn!("Failed to fetch downgrading information: {err}");
+                Vec::new()
+            }
+        };
+
+        let (version_supported, missing_core_mods) = match (&app_info, &core_mods) {
+            (Some(app_info), Some(core_mods)) => (
+                Some(core_mods.contains_key(&app_info.version)),
+                handlers::get_missing_core_mods(core_mods, &app_info.version, &mod_manager)
+            ),
+            _ => (None, None)
+        };
+
+        let storage_cleaners = storage_guard::detect_cleaners();
+        for cleaner in &storage_cleaners {
+            warn!("{} is known to delete mod files and songs. Consider disabling or uninstalling it", cleaner.name());
+        }
+        let mass_deletions = storage_guard::get_mass_deletions();
+        if !mass_deletions.is_empty() {
+            let culprits: Vec<&str> = storage_cleaners.iter().map(StorageCleaner::name).collect();
+            warn!("Most of the files in {} have been deleted since MBF last modified them. {}",
+                mass_deletions.iter().map(|deletion| deletion.path.as_str()).collect::<Vec<_>>().join(", "),
+                if culprits.is_empty() {
+                    "Another app may have deleted them".to_string()
+                }   else {
+                    format!("They were likely deleted by {}", culprits.join(" or "))
+                });
+        }
+
+        Ok(SetupFacts {
+            app_info,
+            version_supported,
+            downgrade_versions,
+            modloader_up_to_date,
+            mod_data_present,
+            missing_core_mods,
+            storage_permission_granted,
+            capabilities: capabilities::get_all().clone(),
+            storage_cleaners,
+            mass_deletions,
+            metadata_health: external_res::get_metadata_health()
+        })
+    })
+}
+
+/// Decides the next setup step from the given facts.
+pub fn get_next_step(facts: &SetupFacts) -> SetupStep {
+    let app_info = match &facts.app_info {
+        Some(app_info) => app_info,
+        None => return SetupStep::InstallGame
+    };
+
+    // If the core mod index couldn't be fetched, assume the version is supported so that the user isn't told to downgrade for no reason.
+    // Mods can't be used with an unsupported version whether or not it's patched.
+    if facts.version_supported == Some(false) {
+        return match get_newest_version(&facts.downgrade_versions) {
+            Some(to) => SetupStep::DowngradeRequired { to },
+            None => SetupStep::UnsupportedVersion
+        };
+    }
+
+    if app_info.loader_installed.is_none() {
+        SetupStep::PatchRequired
+    }   else if !facts.modloader_up_to_date || !facts.mod_data_present {
+        SetupStep::ModloaderMissing
+    }   else if facts.storage_permission_granted == Some(false) {
+        SetupStep::PermissionRepairNeeded
+    }   else {
+        match &facts.missing_core_mods {
+            Some(missing) if !missing.is_empty() => SetupStep::CoreModsMissing { list: missing.clone() },
+            _ => SetupStep::ReadyToPlay
+        }
+    }
+}
+
+// Finds the newest of the given game versions, ignoring the build number suffix.
+fn get_newest_version(versions: &[String]) -> Option<String> {
+    versions.iter()
+        .max_by_key(|version| Version::parse(version.split('_').next().unwrap_or(version)).ok())
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requests::{BuildVariant, ModLoader};
+
+    // The facts for a patched installation with everything set up, which each case then changes.
+    fn ready_facts() -> SetupFacts {
+        SetupFacts {
+            app_info: Some(AppInfo {
+                package_id: "com.beatgames.beatsaber".to_string(),
+                loader_installed: Some(ModLoader::Scotland2),
+                version: "1.37.0_9064817954".to_string(),
+                version_code: Some(1130),
+                storage_strategy: StorageStrategy::ManageExternalStorage,
+                build_variant: BuildVariant::PreviouslyModded,
+                path: "/data/app/base.apk".to_string()
+            }),
+            version_supported: Some(true),
+            downgrade_versions: Vec::new(),
+            modloader_up_to_date: true,
+            mod_data_present: true,
+            missing_core_mods: Some(Vec::new()),
+            storage_permission_granted: Some(true),
+            capabilities: HashMap::new(),
+            storage_cleaners: Vec::new(),
+            mass_deletions: Vec::new(),
+            metadata_health: BTreeMap::new()
+        }
+    }
+
+    // Changes one fact, so that each case can list the ways it differs from a ready installation.
+    type Change = fn(&mut SetupFacts);
+
+    fn unpatched(facts: &mut SetupFacts) {
+        facts.app_info.as_mut().unwrap().loader_installed = None;
+    }
+
+    fn unsupported(facts: &mut SetupFacts) {
+        facts.version_supported = Some(false);
+    }
+
+    fn downgradable(facts: &mut SetupFacts) {
+        facts.downgrade_versions = vec!["1.28.0_4124311467".to_string(), "1.35.0_8016709773".to_string(), "1.9.0".to_string()];
+    }
+
+    fn missing_core_mods(facts: &mut SetupFacts) {
+        facts.missing_core_mods = Some(vec!["bsml".to_string()]);
+    }
+
+    #[test]
+    fn next_step_for_each_combination_of_facts() {
+        let downgrade = || SetupStep::DowngradeRequired { to: "1.35.0_8016709773".to_string() };
+        let core_mods = || SetupStep::CoreModsMissing { list: vec!["bsml".to_string()] };
+        let cases: Vec<(&str, Vec<Change>, SetupStep)> = vec![
+            ("ready", vec![], SetupStep::ReadyToPlay),
+            ("not installed", vec![unsupported, unpatched, |facts| facts.app_info = None], SetupStep::InstallGame),
+            ("unsupported with no downgrade", vec![unsupported], SetupStep::UnsupportedVersion),
+            ("unsupported and unpatched", vec![unsupported, unpatched], SetupStep::UnsupportedVersion),
+            ("unsupported with downgrades", vec![unsupported, downgradable, unpatched, missing_core_mods], downgrade()),
+            ("supported with downgrades", vec![downgradable], SetupStep::ReadyToPlay),
+            ("core mod index unreachable", vec![|facts| facts.version_supported = None, |facts| facts.missing_core_mods = None], SetupStep::ReadyToPlay),
+            ("unpatched", vec![unpatched, |facts| facts.modloader_up_to_date = false, missing_core_mods], SetupStep::PatchRequired),
+            ("modloader outdated", vec![|facts| facts.modloader_up_to_date = false, missing_core_mods], SetupStep::ModloaderMissing),
+            ("mod folders deleted", vec![|facts| facts.mod_data_present = false], SetupStep::ModloaderMissing),
+            ("permission revoked", vec![|facts| facts.storage_permission_granted = Some(false), missing_core_mods], SetupStep::PermissionRepairNeeded),
+            ("permission unknown", vec![|facts| facts.storage_permission_granted = None], SetupStep::ReadyToPlay),
+            ("core mods missing", vec![missing_core_mods], core_mods()),
+            ("other loader with core mods missing", vec![|facts| facts.app_info.as_mut().unwrap().loader_installed = Some(ModLoader::QuestLoader), missing_core_mods], core_mods()),
+        ];
+
+        for (name, changes, expected) in cases {
+            let mut facts = ready_facts();
+            for change in changes {
+                change(&mut facts);
+            }
+            assert_eq!(get_next_step(&facts), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn newest_version_ignores_the_build_number() {
+        let versions = ["1.28.0_4124311467", "1.9.0", "1.35.0_1", "not a version"].map(str::to_string);
+        assert_eq!(get_newest_version(&versions).as_deref(), Some("1.35.0_1"));
+        assert_eq!(get_newest_version(&[]), None);
+    }
+}
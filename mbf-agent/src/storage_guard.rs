@@ -0,0 +1,261 @@
+//! Detects apps and settings that are known to delete mod files, and records how many files the directories MBF owns contain,
+//! so that if mods or songs vanish some time after patching, the user can be told what likely deleted them.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::{commands, fs_ops, early_mods_dir, FILE_COUNTS_PATH, late_mods_dir, libs_dir, mod_data_dir, QMODS_DIR, songs_path};
+
+// Stops the media scanner from indexing the directory, and so stops cleaner apps that use the media index from treating
+// mod files and songs as junk media.
+const NO_MEDIA_NAME: &str = ".nomedia";
+
+// The directories that a `.nomedia` file is added to. This applies to all subdirectories too.
+fn protected_dirs() -> Vec<String> {
+    vec![mod_data_dir(), "/sdcard/ModsBeforeFriday".to_string()]
+}
+
+// The directories whose number of entries are recorded. Only direct children are counted, since the songs folder can be very large.
+fn counted_dirs() -> Vec<String> {
+    vec![late_mods_dir(), early_mods_dir(), libs_dir(), songs_path(), QMODS_DIR.to_string()]
+}
+
+// A directory is considered to have been mass-deleted if it had at least this many entries...
+const MASS_DELETION_MIN_ENTRIES: usize = 4;
+// ...and at most this fraction of them remain.
+const MASS_DELETION_REMAINING_FRACTION: f32 = 0.25;
+
+struct KnownCleaner {
+    package_id: &'static str,
+    name: &'static str
+}
+
+// Apps that users have sideloaded which are known to delete files within ModData.
+const KNOWN_CLEANERS: &[KnownCleaner] = &[
+    KnownCleaner { package_id: "com.cleanmaster.mguard", name: "Clean Master" },
+    KnownCleaner { package_id: "com.piriform.ccleaner", name: "CCleaner" },
+    KnownCleaner { package_id: "com.avast.android.cleaner", name: "Avast Cleanup" },
+    KnownCleaner { package_id: "com.google.android.apps.nbu.files", name: "Files by Google" },
+    KnownCleaner { package_id: "com.estrongs.android.pop", name: "ES File Explorer" },
+    KnownCleaner { package_id: "com.lionmobi.powerclean", name: "Power Clean" }
+];
+
+struct CleanerSetting {
+    namespace: &'static str,
+    key: &'static str,
+    enabled_value: &'static str,
+    name: &'static str
+}
+
+// OS settings that cause storage to be cleaned automatically.
+const CLEANER_SETTINGS: &[CleanerSetting] = &[
+    CleanerSetting {
+        namespace: "secure",
+        key: "automatic_storage_manager_enabled",
+        enabled_value: "1",
+        name: "Android storage manager"
+    }
+];
+
+/// Something on the quest that may delete mod files without the user realising.
+#[derive(Serialize, Clone, Debug)]
+pub enum StorageCleaner {
+    /// An installed app known to delete files within ModData that it considers junk.
+    App {
+        package_id: String,
+        name: String
+    },
+    /// An OS setting that automatically deletes files to free up space is enabled.
+    AutoClean {
+        setting: String,
+        name: String
+    }
+}
+
+impl StorageCleaner {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::App { name, .. } | Self::AutoClean { name, .. } => name
+        }
+    }
+}
+
+/// A directory owned by MBF that has had most of its contents deleted since it was last modified by MBF.
+#[derive(Serialize, Clone, Debug)]
+pub struct MassDeletion {
+    pub path: String,
+    pub previous_count: usize,
+    pub current_count: usize
+}
+
+/// Finds any apps or settings on the quest that may delete mod files.
+/// Failing to check is logged rather than returned, since this only gives advice.
+pub fn detect_cleaners() -> Vec<StorageCleaner> {
+    let mut cleaners = Vec::new();
+
+    match commands::run("pm", &["list", "packages"]) {
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let installed: Vec<&str> = stdout.lines()
+                .filter_map(|line| line.trim().strip_prefix("package:"))
+                .collect();
+
+            cleaners.extend(KNOWN_CLEANERS.iter()
+                .filter(|cleaner| installed.contains(&cleaner.package_id))
+                .map(|cleaner| StorageCleaner::App {
+                    package_id: cleaner.package_id.to_string(),
+                    name: cleaner.name.to_string()
+                }));
+        },
+        Err(err) => warn!("Failed to list installed packages: {err}")
+    }
+
+    for setting in CLEANER_SETTINGS {
+        match commands::run("settings", &["get", setting.namespace, setting.key]) {
+            Ok(output) if String::from_utf8_lossy(&output.stdout).trim() == setting.enabled_value => {
+                cleaners.push(StorageCleaner::AutoClean {
+                    setting: setting.key.to_string(),
+                    name: setting.name.to_string()
+                });
+            },
+            Ok(_) => {},
+            Err(err) => warn!("Failed to check setting {}: {err}", setting.key)
+        }
+    }
+
+    cleaners
+}
+
+/// Gets the number of entries in each counted directory. Directories that don't exist are counted as empty.
+pub fn get_file_counts() -> HashMap<String, usize> {
+    counted_dirs().into_iter()
+        .map(|dir| (dir.clone(), std::fs::read_dir(&dir).map(|entries| entries.count()).unwrap_or(0)))
+        .collect()
+}
+
+/// Loads the file counts recorded after MBF last modified ModData, or None if they have never been recorded.
+pub fn load_file_counts() -> Option<HashMap<String, usize>> {
+    let contents = std::fs::read(FILE_COUNTS_PATH).ok()?;
+    match serde_json::from_slice(&contents) {
+        Ok(counts) => Some(counts),
+        Err(err) => {
+            warn!("Recorded file counts were invalid: {err}");
+            None
+        }
+    }
+}
+
+/// Finds the directories in `current` that have had most of their entries deleted since `previous` was recorded.
+pub fn find_mass_deletions(previous: &HashMap<String, usize>, current: &HashMap<String, usize>) -> Vec<MassDeletion> {
+    let mut deletions: Vec<MassDeletion> = previous.iter()
+        .filter_map(|(path, &previous_count)| {
+            let current_count = current.get(path).copied().unwrap_or(0);
+            let mass_deleted = previous_count >= MASS_DELETION_MIN_ENTRIES
+                && (current_count as f32) <= previous_count as f32 * MASS_DELETION_REMAINING_FRACTION;
+
+            mass_deleted.then(|| MassDeletion {
+                path: path.clone(),
+                previous_count,
+                current_count
+            })
+        })
+        .collect();
+
+    deletions.sort_by(|a, b| a.path.cmp(&b.path));
+    deletions
+}
+
+/// Finds the directories that have had most of their entries deleted since MBF last modified ModData.
+pub fn get_mass_deletions() -> Vec<MassDeletion> {
+    match load_file_counts() {
+        Some(previous) => find_mass_deletions(&previous, &get_file_counts()),
+        None => Vec::new()
+    }
+}
+
+/// Adds `.nomedia` files to the directories MBF owns, and records the number of files they contain so that mass deletion
+/// can be detected later. This should be called after any operation that may have changed the contents of ModData.
+/// Any error is logged, since failing to do this should never fail the operation.
+pub fn protect_mod_data() {
+    if let Err(err) = try_protect_mod_data() {
+        warn!("Failed to protect mod data: {err}");
+    }
+}
+
+fn try_protect_mod_data() -> Result<()> {
+    for dir in protected_dirs() {
+        let no_media_path = Path::new(&dir).join(NO_MEDIA_NAME);
+        if Path::new(&dir).exists() && !no_media_path.exists() {
+            info!("Adding {no_media_path:?}");
+            fs_ops::open_with(std::fs::OpenOptions::new().create(true).append(true), &no_media_path)?;
+        }
+    }
+
+    let current = get_file_counts();
+    // Warn now, since the deletion will not be detectable once the new counts are saved.
+    for deletion in load_file_counts().map(|previous| find_mass_deletions(&previous, &current)).unwrap_or_default() {
+        warn!("{} had {} entries but now has {}. These may have been deleted by another app",
+            deletion.path, deletion.previous_count, deletion.current_count);
+    }
+
+    if let Some(parent) = Path::new(FILE_COUNTS_PATH).parent() {
+        fs_ops::create_dir_all(parent)?;
+    }
+    let mut saved_counts = serde_json::to_vec_pretty(&current)?;
+    saved_counts.push(b'\n');
+    std::fs::write(FILE_COUNTS_PATH, saved_counts).context("Failed to save file counts")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_harness::FakeDevice;
+
+    #[test]
+    fn known_cleaners_are_found_in_the_package_list() {
+        // The package list has trailing whitespace and carriage returns on some firmware.
+        let packages = "package:com.oculus.vrshell\n\
+            package:com.piriform.ccleaner \r\n\
+            \tpackage:com.google.android.apps.nbu.files\n\
+            package:com.avast.android.cleaner.extra\n\
+            com.cleanmaster.mguard\n";
+        let device = FakeDevice::builder("storage-cleaners")
+            .pm_response("list", 0, packages)
+            .build();
+
+        let names: Vec<String> = detect_cleaners().iter()
+            .filter(|cleaner| matches!(cleaner, StorageCleaner::App { .. }))
+            .map(|cleaner| cleaner.name().to_string())
+            .collect();
+        assert_eq!(names, ["CCleaner", "Files by Google"]);
+        assert_eq!(device.calls("pm"), ["list packages"]);
+    }
+
+    #[test]
+    fn failing_to_list_packages_finds_no_cleaners() {
+        let _device = FakeDevice::builder("storage-cleaners-failing")
+            .pm_response("list", 1, "Error: could not access the Package Manager")
+            .build();
+        assert!(!detect_cleaners().iter().any(|cleaner| matches!(cleaner, StorageCleaner::App { .. })));
+    }
+
+    #[test]
+    fn only_directories_that_lost_most_of_their_entries_are_mass_deletions() {
+        let counts = |entries: &[(&str, usize)]| entries.iter()
+            .map(|(path, count)| (path.to_string(), *count))
+            .collect::<HashMap<String, usize>>();
+        let previous = counts(&[("songs", 40), ("libs", 8), ("mods", 3), ("early_mods", 10), ("qmods", 12)]);
+        let current = counts(&[("songs", 10), ("libs", 3), ("mods", 0), ("early_mods", 11)]);
+
+        let deletions: Vec<(String, usize, usize)> = find_mass_deletions(&previous, &current).into_iter()
+            .map(|deletion| (deletion.path, deletion.previous_count, deletion.current_count))
+            .collect();
+        // A quarter remaining still counts, a directory that is now missing counts as empty, and directories with too few
+        // entries to tell are ignored.
+        assert_eq!(deletions, [("qmods".to_string(), 12, 0), ("songs".to_string(), 40, 10)]);
+    }
+}
@@ -0,0 +1,263 @@
+//! An off-device harness for tests of whole flows, such as patching an APK: a scratch directory tree standing in for the quest's
+//! storage, fake `pm`, `appops` and `getprop` commands which record how they were called, and a miniature game APK.
+//! The fake commands are found on `PATH`, which is shared by every test, so only one `FakeDevice` exists at a time.
+
+use std::{ffi::OsString, io::Cursor, os::unix::fs::PermissionsExt, path::{Path, PathBuf}, sync::{Mutex, MutexGuard}};
+
+use crate::{axml::{AttributeValue, Attribute, AxmlWriter, Event}, zip::{signing::{self, SigningConfig}, FileCompression, ZipFile}};
+
+// Held by the `FakeDevice` in use, so that tests using one don't change `PATH` at the same time.
+static DEVICE_LOCK: Mutex<()> = Mutex::new(());
+
+// The directories created within the root of every fake device.
+const DEVICE_DIRS: &[&str] = &["data/app", "data/local/tmp", "sdcard/Android/obb", "sdcard/Android/data", "sdcard/Download"];
+
+// The programs faked by a `FakeDevice`, which are written to `bin`.
+const FAKE_PROGRAMS: &[&str] = &["pm", "appops", "getprop"];
+
+const DEBUG_CERT_PEM: &[u8] = include_bytes!("debug_cert.pem");
+
+/// Sets up a `FakeDevice`, with how each of its commands responds.
+pub struct FakeDeviceBuilder {
+    name: String,
+    // The subcommand, exit code and output of each `pm` subcommand that doesn't give the default response.
+    pm_responses: Vec<(String, i32, String)>,
+    props: Vec<(String, String)>,
+    appops_fails: bool
+}
+
+impl FakeDeviceBuilder {
+    /// Makes `pm <subcommand>` print `output` and exit with `exit_code`. Other subcommands print `Success`.
+    pub fn pm_response(mut self, subcommand: &str, exit_code: i32, output: &str) -> Self {
+        self.pm_responses.push((subcommand.to_string(), exit_code, output.to_string()));
+        self
+    }
+
+    /// Makes `getprop <name>` print `value`. `ro.build.version.sdk` is 32 unless given, and other properties are empty.
+    pub fn prop(mut self, name: &str, value: &str) -> Self {
+        self.props.retain(|(existing, _)| existing != name);
+        self.props.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Makes every `appops` command fail, as it does on firmware that doesn't support the operation.
+    pub fn failing_appops(mut self) -> Self {
+        self.appops_fails = true;
+        self
+    }
+
+    /// Creates the directory tree and fake commands, and puts the commands first on `PATH` until the device is dropped.
+    pub fn build(self) -> FakeDevice {
+        let lock = DEVICE_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let root = std::env::temp_dir().join(format!("mbf-fake-device-{}-{}", std::process::id(), self.name));
+        let _ = std::fs::remove_dir_all(&root);
+        for dir in DEVICE_DIRS {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+
+        let bin = root.join("bin");
+        std::fs::create_dir_all(&bin).unwrap();
+        let mut pm_cases = self.pm_responses.iter()
+            .map(|(subcommand, code, output)| format!("{subcommand}) echo {}; exit {code};;\n", quote(output)))
+            .collect::<String>();
+        pm_cases.push_str("install-create) echo 'Success: created install session [42]';;\n*) echo Success;;\n");
+        let prop_cases = self.props.iter()
+            .map(|(name, value)| format!("{name}) echo {};;\n", quote(value)))
+            .collect::<String>() + "*) echo;;\n";
+        let appops_cases = if self.appops_fails { "*) echo 'Error: Unknown operation string'; exit 1;;\n" } else { "*) ;;\n" };
+        for (program, cases) in FAKE_PROGRAMS.iter().zip([pm_cases, appops_cases.to_string(), prop_cases]) {
+            write_fake_program(&bin, program, &cases);
+        }
+
+        let old_path = std::env::var_os("PATH").unwrap_or_default();
+        let mut paths = vec![bin];
+        paths.extend(std::env::split_paths(&old_path));
+        std::env::set_var("PATH", std::env::join_paths(paths).unwrap());
+        FakeDevice { root, old_path, _lock: lock }
+    }
+}
+
+/// A quest simulated within a temporary directory, removed once dropped.
+pub struct FakeDevice {
+    root: PathBuf,
+    old_path: OsString,
+    _lock: MutexGuard<'static, ()>
+}
+
+impl FakeDevice {
+    /// Starts setting up a fake device, whose files are kept in a directory with the given name.
+    pub fn builder(name: &str) -> FakeDeviceBuilder {
+        FakeDeviceBuilder {
+            name: name.to_string(),
+            pm_responses: Vec::new(),
+            props: vec![("ro.build.version.sdk".to_string(), "32".to_string())],
+            appops_fails: false
+        }
+    }
+
+    /// Gives the path of a file on the device, e.g. `sdcard/Download/base.apk`.
+    pub fn path(&self, relative: &str) -> PathBuf {
+        self.root.join(relative)
+    }
+
+    /// Gives the arguments that `program` (one of `pm`, `appops` or `getprop`) was run with, in the order it was run.
+    pub fn calls(&self, program: &str) -> Vec<String> {
+        match std::fs::read_to_string(self.root.join("bin").join(format!("{program}.log"))) {
+            Ok(log) => log.lines().map(str::to_string).collect(),
+            Err(_) => Vec::new()
+        }
+    }
+
+    /// Gives every file on the device other than the fake commands, relative to its root and sorted, to check what was written.
+    pub fn files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        list_files(&self.root, &mut files);
+        let mut files: Vec<PathBuf> = files.into_iter()
+            .map(|path| path.strip_prefix(&self.root).unwrap().to_path_buf())
+            .filter(|path| !path.starts_with("bin"))
+            .collect();
+        files.sort();
+        files
+    }
+}
+
+impl Drop for FakeDevice {
+    fn drop(&mut self) {
+        std::env::set_var("PATH", &self.old_path);
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+// Writes a shell script called `program` to `bin`, which appends its arguments to `<program>.log` and then runs the given `case` arms.
+fn write_fake_program(bin: &Path, program: &str, cases: &str) {
+    let log_path = bin.join(format!("{program}.log"));
+    let script = format!("#!/bin/sh\necho \"$*\" >> {}\ncase \"$1\" in\n{cases}esac\n", quote(&log_path.to_string_lossy()));
+    let path = bin.join(program);
+    std::fs::write(&path, script).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+// Quotes `text` for a shell script.
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+fn list_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            list_files(&path, files);
+        }   else {
+            files.push(path);
+        }
+    }
+}
+
+/// The number of filler assets in a fake APK, so that patching copies a realistic number of entries.
+pub const FILLER_ASSETS: usize = 100;
+
+/// Writes a miniature but structurally faithful game APK to `path`: a binary manifest for `package_id` at `version`, a stub
+/// libmain.so and libunity.so for each of `abis`, a classes.dex, a resources.arsc and `FILLER_ASSETS` assets.
+/// It is signed with the debug certificate (V2 only), since that is quick to sign with.
+pub fn write_fake_apk(path: &Path, package_id: &str, version: &str, abis: &[&str]) {
+    let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path).unwrap();
+    let mut zip = ZipFile::create(file);
+    zip.write_file("AndroidManifest.xml", &mut Cursor::new(fake_manifest(package_id, version)), FileCompression::Deflate).unwrap();
+    zip.write_file("classes.dex", &mut Cursor::new(b"dex\n035\0"), FileCompression::Deflate).unwrap();
+    zip.write_file("resources.arsc", &mut Cursor::new(b"\x02\x00\x0c\x00\x0c\x00\x00\x00\x00\x00\x00\x00"), FileCompression::Store).unwrap();
+    for abi in abis {
+        for lib in ["libmain.so", "libunity.so"] {
+            let contents = format!("stub {lib} for {abi}").into_bytes();
+            zip.write_file(&format!("lib/{abi}/{lib}"), &mut Cursor::new(contents), FileCompression::Store).unwrap();
+        }
+    }
+    for i in 0..FILLER_ASSETS {
+        let contents = format!("filler asset {i}\n").repeat(i + 1);
+        zip.write_file(&format!("assets/bin/Data/filler{i}"), &mut Cursor::new(contents), FileCompression::Deflate).unwrap();
+    }
+
+    let (cert, priv_key) = signing::load_cert_and_priv_key(DEBUG_CERT_PEM).unwrap();
+    zip.save_and_sign(&priv_key, &cert, SigningConfig { v1: false, v2: true, v3: false }).unwrap();
+}
+
+// Writes a binary manifest with the given package and version, and an application for a manifest mod to add to.
+fn fake_manifest(package_id: &str, version: &str) -> Vec<u8> {
+    let mut output = Cursor::new(Vec::new());
+    let mut writer = AxmlWriter::new(&mut output);
+    let attribute = |name: &str, value: &str| Attribute {
+        name: name.into(),
+        namespace: None,
+        resource_id: None,
+        value: AttributeValue::String(value.into())
+    };
+    writer.write_event(Event::StartElement {
+        attributes: vec![attribute("package", package_id), attribute("versionName", version)],
+        name: "manifest".into(),
+        namespace: None,
+        line_num: 1
+    });
+    writer.write_event(Event::StartElement { attributes: Vec::new(), name: "application".into(), namespace: None, line_num: 2 });
+    for name in ["application", "manifest"] {
+        writer.write_event(Event::EndElement { line_num: 3, namespace: None, name: name.into() });
+    }
+    writer.finish().unwrap();
+    output.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{axml::AxmlReader, commands, manifest::ManifestInfo};
+
+    use super::*;
+
+    #[test]
+    fn fake_commands_respond_as_configured_and_record_their_arguments() {
+        let device = FakeDevice::builder("commands")
+            .pm_response("install-write", 1, "Failure [INSTALL_FAILED_INSUFFICIENT_STORAGE]")
+            .prop("ro.product.model", "Quest 3")
+            .failing_appops()
+            .build();
+
+        let output = commands::run("pm", &["install-write", "-S", "5"]).unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "Failure [INSTALL_FAILED_INSUFFICIENT_STORAGE]\n");
+        let output = commands::run("pm", &["install-create"]).unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "Success: created install session [42]\n");
+        assert_eq!(commands::run("getprop", &["ro.product.model"]).unwrap().stdout, b"Quest 3\n");
+        assert_eq!(commands::run("getprop", &["ro.build.version.sdk"]).unwrap().stdout, b"32\n");
+        assert!(!commands::run("appops", &["get", "com.beatgames.beatsaber"]).unwrap().status.success());
+
+        assert_eq!(device.calls("pm"), ["install-write -S 5", "install-create"]);
+        assert_eq!(device.calls("getprop"), ["ro.product.model", "ro.build.version.sdk"]);
+        assert!(device.files().is_empty(), "running commands should write no files: {:?}", device.files());
+    }
+
+    #[test]
+    fn path_is_restored_once_the_device_is_dropped() {
+        let device = FakeDevice::builder("path").build();
+        let root = device.path("");
+        assert!(std::env::var_os("PATH").is_some_and(|path| std::env::split_paths(&path).any(|dir| dir == root.join("bin"))));
+        drop(device);
+
+        let _lock = DEVICE_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        assert!(!std::env::split_paths(&std::env::var_os("PATH").unwrap_or_default()).any(|dir| dir == root.join("bin")));
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn fake_apk_is_a_signed_game_apk() {
+        let device = FakeDevice::builder("apk").build();
+        let apk_path = device.path("sdcard/Download/base.apk");
+        write_fake_apk(&apk_path, "com.beatgames.beatsaber", "1.37.0", &["arm64-v8a"]);
+
+        let mut zip = ZipFile::open(std::fs::File::open(&apk_path).unwrap()).unwrap();
+        assert!(matches!(zip.verify_v2_signature().unwrap(), signing::V2Verification::Valid { .. }));
+        let mut manifest = Cursor::new(zip.read_file("AndroidManifest.xml").unwrap());
+        let info = ManifestInfo::read(&mut AxmlReader::new(&mut manifest).unwrap()).unwrap();
+        assert_eq!(info.package_id.as_deref(), Some("com.beatgames.beatsaber"));
+        assert_eq!(info.package_version, "1.37.0");
+        assert!(zip.contains_file("lib/arm64-v8a/libmain.so") && zip.contains_file("lib/arm64-v8a/libunity.so"));
+        assert_eq!(zip.iter_entry_names().filter(|name| name.starts_with("assets/")).count(), FILLER_ASSETS);
+        assert_eq!(device.files(), [PathBuf::from("sdcard/Download/base.apk")]);
+    }
+}
@@ -0,0 +1,236 @@
+//! Normalisation of text files that will be parsed by mods.
+//! Some mods fail to parse files with a UTF-8 BOM or CRLF line endings, which are common in files created on Windows.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+
+use crate::reports;
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16_LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16_BE_BOM: &[u8] = &[0xFE, 0xFF];
+
+/// Text converted to UTF-8 without a BOM, with LF line endings and a trailing newline.
+pub struct NormalisedText {
+    pub contents: Vec<u8>,
+    /// Descriptions of the changes made. Empty if the text was already normalised.
+    pub changes: Vec<&'static str>
+}
+
+/// Normalises the given text, or returns None if `contents` does not appear to be text, in which case it should be left as is.
+/// Normalising text that is already normalised gives exactly the same bytes.
+pub fn normalise(contents: &[u8]) -> Option<NormalisedText> {
+    let mut changes = Vec::new();
+    let text = if let Some(utf16) = contents.strip_prefix(UTF16_LE_BOM) {
+        changes.push("converted from UTF-16");
+        decode_utf16(utf16, u16::from_le_bytes)?
+    }   else if let Some(utf16) = contents.strip_prefix(UTF16_BE_BOM) {
+        changes.push("converted from UTF-16");
+        decode_utf16(utf16, u16::from_be_bytes)?
+    }   else {
+        let utf8 = match contents.strip_prefix(UTF8_BOM) {
+            Some(without_bom) => {
+                changes.push("removed BOM");
+                without_bom
+            },
+            None => contents
+        };
+
+        // Null bytes are very unlikely in text, but common in binary files.
+        if utf8.contains(&0) {
+            return None;
+        }
+        String::from_utf8(utf8.to_vec()).ok()?
+    };
+
+    // Text saved with a BOM more than once (e.g. by editors re-encoding a file) would otherwise keep a BOM after normalising.
+    let mut text = match text.trim_start_matches('\u{FEFF}') {
+        trimmed if trimmed.len() != text.len() => {
+            if !changes.contains(&"removed BOM") {
+                changes.push("removed BOM");
+            }
+            trimmed.to_string()
+        },
+        _ => text
+    };
+    // UTF-16 text decodes successfully even if it's actually binary data.
+    if text.contains('\0') {
+        return None;
+    }
+
+    // A trailing CR becomes a CRLF line ending once the trailing newline is added, so this is checked first.
+    if !text.is_empty() && !text.ends_with('\n') {
+        changes.push("added trailing newline");
+        text.push('\n');
+    }
+    if text.contains("\r\n") {
+        changes.push("converted CRLF line endings to LF");
+        // All CRs before each LF are removed, since removing one at a time would leave a CRLF from "\r\r\n"
+        text = text.split('\n')
+            .map(|line| line.trim_end_matches('\r'))
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+
+    Some(NormalisedText {
+        contents: text.into_bytes(),
+        changes
+    })
+}
+
+fn decode_utf16(bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) -> Option<String> {
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+
+    let units = bytes.chunks_exact(2).map(|pair| from_bytes([pair[0], pair[1]]));
+    char::decode_utf16(units).collect::<Result<String, _>>().ok()
+}
+
+/// Normalises the text file at `path` in place, recording any changes made in the operation report.
+/// Files that don't appear to be text are left unchanged.
+pub fn normalise_file(path: &Path) -> Result<()> {
+    let contents = std::fs::read(path).context("Failed to read file to normalise")?;
+    let normalised = match normalise(&contents) {
+        Some(normalised) => normalised,
+        None => {
+            warn!("{path:?} does not appear to be a text file, so its encoding and line endings have not been checked");
+            return Ok(());
+        }
+    };
+
+    if !normalised.changes.is_empty() {
+        let description = format!("Normalised {path:?}: {}", normalised.changes.join(", "));
+        info!("{description}");
+        reports::record_note(&description);
+        std::fs::write(path, normalised.contents).context("Failed to save normalised file")?;
+    }
+
+    Ok(())
+}
+
+/// Returns true if the file at `path` will be parsed as JSON and so should be normalised.
+pub fn is_json_path(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    // Pieces that random text is built from, chosen to include everything that normalising handles.
+    const PIECES: &[&str] = &["{", "}", "\"key\": ", "1", " ", "\t", "é", "日本", "🎵", "\n", "\r", "\r\n", "\u{FEFF}"];
+
+    fn random_text(rng: &mut StdRng) -> String {
+        let len = rng.gen_range(0..24);
+        (0..len).map(|_| PIECES[rng.gen_range(0..PIECES.len())]).collect()
+    }
+
+    fn utf16(text: &str, bom: &[u8], to_bytes: fn(u16) -> [u8; 2]) -> Vec<u8> {
+        let mut bytes = bom.to_vec();
+        for unit in text.encode_utf16() {
+            bytes.extend(to_bytes(unit));
+        }
+        bytes
+    }
+
+    // Encodes the text in one of the ways that files created on Windows may be.
+    fn random_encoding(rng: &mut StdRng, text: &str) -> Vec<u8> {
+        match rng.gen_range(0..4) {
+            0 => text.as_bytes().to_vec(),
+            1 => [UTF8_BOM, text.as_bytes()].concat(),
+            2 => utf16(text, UTF16_LE_BOM, u16::to_le_bytes),
+            _ => utf16(text, UTF16_BE_BOM, u16::to_be_bytes)
+        }
+    }
+
+    fn normalised(contents: &[u8]) -> Vec<u8> {
+        normalise(contents).expect("Text should be normalised").contents
+    }
+
+    #[test]
+    fn normalising_is_idempotent() {
+        let mut rng = StdRng::seed_from_u64(226);
+        for _ in 0..5000 {
+            let text = random_text(&mut rng);
+            let contents = random_encoding(&mut rng, &text);
+
+            let once = normalise(&contents).unwrap_or_else(|| panic!("{contents:?} was not treated as text"));
+            let twice = normalise(&once.contents).unwrap();
+            assert_eq!(once.contents, twice.contents, "{contents:?}");
+            assert!(twice.changes.is_empty(), "{contents:?} needed {:?} the second time", twice.changes);
+
+            let output = std::str::from_utf8(&once.contents).unwrap();
+            assert!(!output.starts_with('\u{FEFF}'), "{contents:?}");
+            assert!(!output.contains("\r\n"), "{contents:?}");
+            assert!(output.is_empty() || output.ends_with('\n'), "{contents:?}");
+        }
+    }
+
+    #[test]
+    fn normalised_text_is_unchanged() {
+        let mut rng = StdRng::seed_from_u64(2260);
+        for _ in 0..5000 {
+            let mut text = random_text(&mut rng).replace(['\r', '\u{FEFF}'], "");
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+
+            let result = normalise(text.as_bytes()).unwrap();
+            assert_eq!(result.contents, text.as_bytes());
+            assert!(result.changes.is_empty(), "{text:?} needed {:?}", result.changes);
+        }
+    }
+
+    #[test]
+    fn bom_is_removed() {
+        let result = normalise(b"\xEF\xBB\xBF{\"a\": 1}\n").unwrap();
+        assert_eq!(result.contents, b"{\"a\": 1}\n");
+        assert_eq!(result.changes, ["removed BOM"]);
+
+        assert_eq!(normalised(b"\xEF\xBB\xBF\xEF\xBB\xBF{}\n"), b"{}\n");
+    }
+
+    #[test]
+    fn crlf_is_converted() {
+        let result = normalise(b"{\r\n  \"a\": 1\r\n}").unwrap();
+        assert_eq!(result.contents, b"{\n  \"a\": 1\n}\n");
+        assert_eq!(result.changes, ["added trailing newline", "converted CRLF line endings to LF"]);
+
+        // Lone CRs are not line endings, other than a trailing one that would become one.
+        assert_eq!(normalised(b"a\rb\r"), b"a\rb\n");
+    }
+
+    #[test]
+    fn utf16_is_converted() {
+        let le = utf16("{\"name\": \"Café\"}\r\n", UTF16_LE_BOM, u16::to_le_bytes);
+        let result = normalise(&le).unwrap();
+        assert_eq!(result.contents, "{\"name\": \"Café\"}\n".as_bytes());
+        assert_eq!(result.changes, ["converted from UTF-16", "converted CRLF line endings to LF"]);
+
+        let be = utf16("日本\n", UTF16_BE_BOM, u16::to_be_bytes);
+        assert_eq!(normalised(&be), "日本\n".as_bytes());
+    }
+
+    #[test]
+    fn utf16_without_bom_is_not_treated_as_text() {
+        // Files saved as UTF-16 but named .json have no other way of being detected, so are left alone.
+        let unmarked = utf16("{}\n", &[], u16::to_le_bytes);
+        assert!(normalise(&unmarked).is_none());
+    }
+
+    #[test]
+    fn binary_files_are_not_treated_as_text() {
+        assert!(normalise(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").is_none());
+        assert!(normalise(b"PK\x03\x04\x14\0\0\0").is_none());
+        assert!(normalise(b"\x7fELF\x02\x01\x01\0").is_none());
+        // Binary data that happens to start with a UTF-16 BOM
+        assert!(normalise(b"\xFF\xFE\0\0\x01\x02").is_none());
+        assert!(normalise(b"\xFF\xFE\x01").is_none());
+        // An unpaired surrogate
+        assert!(normalise(b"\xFF\xFE\x00\xD8a\x00").is_none());
+    }
+}
@@ -0,0 +1,203 @@
+//! Finds the storage volume(s) holding the game's OBBs and data.
+//! These are normally within the emulated internal storage at `/sdcard`, but can be on an adopted or external volume
+//! mounted at `/storage/<uuid>`, in which case the OBBs and data may even be on different volumes.
+
+use std::{path::{Path, PathBuf}, process::Command, sync::OnceLock};
+
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+
+use crate::{fs_ops, apk_id};
+
+const INTERNAL_STORAGE: &str = "/sdcard";
+const STORAGE_MOUNTS: &str = "/storage";
+// Entries within `/storage` that are not the mount points of other volumes.
+const NON_VOLUME_MOUNTS: &[&str] = &["emulated", "self"];
+const PROBE_FILE_NAME: &str = ".mbf-probe";
+
+/// The paths within shared storage that the game uses.
+#[derive(Debug)]
+pub struct AppPaths {
+    pub obb_dir: PathBuf,
+    /// The `files` directory within the game's data directory.
+    pub data_dir: PathBuf
+}
+
+impl AppPaths {
+    pub fn player_data(&self) -> PathBuf {
+        self.data_dir.join("PlayerData.dat")
+    }
+
+    pub fn player_data_backup(&self) -> PathBuf {
+        self.data_dir.join("PlayerData.dat.bak")
+    }
+}
+
+static APP_PATHS: OnceLock<AppPaths> = OnceLock::new();
+
+/// Gets the paths the game uses, finding the volume(s) they are on if this hasn't been done yet.
+/// Gives an error naming the volume if the game is installed on a volume that is not available.
+pub fn get_app_paths() -> Result<&'static AppPaths> {
+    if let Some(paths) = APP_PATHS.get() {
+        return Ok(paths);
+    }
+
+    let paths = resolve_app_paths().context("Failed to find the storage used by Beat Saber")?;
+    info!("Beat Saber's OBBs are in {:?} and its data is in {:?}", paths.obb_dir, paths.data_dir);
+    Ok(APP_PATHS.get_or_init(|| paths))
+}
+
+fn resolve_app_paths() -> Result<AppPaths> {
+    let dumpsys_output = Command::new("dumpsys")
+        .args(["package", apk_id()])
+        .output()
+        .context("Failed to get package details")?;
+    let volume_uuid = parse_volume_uuid(&String::from_utf8_lossy(&dumpsys_output.stdout));
+
+    let package_root = match &volume_uuid {
+        Some(uuid) => {
+            let root = get_volume_root(uuid);
+            if std::fs::read_dir(&root).is_err() {
+                return Err(anyhow!("Beat Saber is installed on storage volume {uuid}, but {root:?} could not be read. \
+                    Check that the volume is mounted"));
+            }
+            root
+        },
+        None => PathBuf::from(INTERNAL_STORAGE)
+    };
+
+    // The package's own volume is checked first, then any other volumes, since the OBBs may have been moved separately.
+    let mut roots = vec![package_root.clone()];
+    roots.extend(get_other_volume_roots().into_iter().filter(|root| *root != package_root));
+    if package_root != Path::new(INTERNAL_STORAGE) {
+        roots.push(PathBuf::from(INTERNAL_STORAGE));
+    }
+
+    Ok(AppPaths {
+        obb_dir: find_dir(&roots, &package_root, &format!("Android/obb/{}", apk_id()), true)?,
+        // The data directory is never probed, since a data directory created by the agent rather than the game has the wrong
+        // permissions for the game to use.
+        data_dir: find_dir(&roots, &package_root, &format!("Android/data/{}/files", apk_id()), false)?
+    })
+}
+
+// Finds the `volumeUuid` of the package in the output of `dumpsys package`, or None if the package is on internal storage.
+fn parse_volume_uuid(dumpsys_output: &str) -> Option<String> {
+    let uuid = dumpsys_output.lines()
+        .find_map(|line| line.trim().strip_prefix("volumeUuid="))?
+        .trim();
+
+    match uuid {
+        "" | "null" => None,
+        uuid => Some(uuid.to_string())
+    }
+}
+
+fn get_volume_root(uuid: &str) -> PathBuf {
+    Path::new(STORAGE_MOUNTS).join(uuid)
+}
+
+// Gets the mount points of every volume other than internal storage.
+fn get_other_volume_roots() -> Vec<PathBuf> {
+    match std::fs::read_dir(STORAGE_MOUNTS) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok())
+            .filter(|entry| !NON_VOLUME_MOUNTS.iter().any(|name| entry.file_name() == *name))
+            .map(|entry| entry.path())
+            .collect(),
+        Err(err) => {
+            warn!("Failed to list storage volumes: {err}");
+            Vec::new()
+        }
+    }
+}
+
+// Finds the first of the given volume roots with a non-empty directory at `relative_path`, since the game will have created files there.
+// If none have, the directory on `default_root` is used, after checking that it can be written to if `probe` is true.
+fn find_dir(roots: &[PathBuf], default_root: &Path, relative_path: &str, probe: bool) -> Result<PathBuf> {
+    let existing = roots.iter()
+        .map(|root| root.join(relative_path))
+        .find(|dir| std::fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some()));
+    if let Some(dir) = existing {
+        return Ok(dir);
+    }
+
+    let dir = default_root.join(relative_path);
+    if probe {
+        probe_writable(&dir).with_context(|| format!("{dir:?} could not be written to"))?;
+    }
+    Ok(dir)
+}
+
+// Checks that files can be created in `dir` by creating and removing a probe file.
+fn probe_writable(dir: &Path) -> Result<()> {
+    fs_ops::create_dir_all(dir)?;
+    let probe_path = dir.join(PROBE_FILE_NAME);
+    std::fs::write(&probe_path, []).context("Failed to create probe file")?;
+    fs_ops::remove_file(&probe_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An excerpt of `dumpsys package` for a package, with its volume UUID set to `uuid`.
+    fn dumpsys_output(uuid: &str) -> String {
+        format!("Packages:\n  Package [com.beatgames.beatsaber] (5f3c2a1):\n    userId=10123\n    pkg=Package{{8d2e1f0 com.beatgames.beatsaber}}\n\
+            \x20   codePath=/data/app/~~abc==/com.beatgames.beatsaber-def==\n    volumeUuid={uuid}\n    versionCode=1130 minSdk=29 targetSdk=32\n")
+    }
+
+    fn temp_roots(name: &str, count: usize) -> (PathBuf, Vec<PathBuf>) {
+        let base = std::env::temp_dir().join(format!("mbf-volumes-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let roots: Vec<PathBuf> = (0..count).map(|i| base.join(format!("volume{i}"))).collect();
+        for root in &roots {
+            std::fs::create_dir_all(root).unwrap();
+        }
+        (base, roots)
+    }
+
+    #[test]
+    fn volume_uuid_is_read_from_dumpsys() {
+        assert_eq!(parse_volume_uuid(&dumpsys_output("b4af-19e2")).as_deref(), Some("b4af-19e2"));
+        assert_eq!(parse_volume_uuid(&dumpsys_output("null")), None);
+        assert_eq!(parse_volume_uuid(&dumpsys_output("")), None);
+        assert_eq!(parse_volume_uuid("Unable to find package: com.beatgames.beatsaber\n"), None);
+        assert_eq!(get_volume_root("b4af-19e2"), Path::new("/storage/b4af-19e2"));
+    }
+
+    #[test]
+    fn first_volume_with_files_in_the_directory_is_used() {
+        let (base, roots) = temp_roots("existing", 3);
+        let relative = "Android/obb/com.beatgames.beatsaber";
+        // An empty directory is skipped, since the game hasn't put anything there.
+        std::fs::create_dir_all(roots[0].join(relative)).unwrap();
+        std::fs::create_dir_all(roots[2].join(relative)).unwrap();
+        std::fs::write(roots[2].join(relative).join("main.obb"), b"obb").unwrap();
+
+        assert_eq!(find_dir(&roots, &roots[0], relative, true).unwrap(), roots[2].join(relative));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn default_volume_is_probed_if_no_volume_has_the_directory() {
+        let (base, roots) = temp_roots("default", 2);
+        let relative = "Android/obb/com.beatgames.beatsaber";
+
+        let dir = find_dir(&roots, &roots[1], relative, true).unwrap();
+        assert_eq!(dir, roots[1].join(relative));
+        assert!(dir.is_dir(), "probing should create the directory");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0, "the probe file should be removed");
+
+        // The data directory isn't probed, so isn't created.
+        let data_dir = find_dir(&roots, &roots[0], "Android/data/com.beatgames.beatsaber/files", false).unwrap();
+        assert!(!data_dir.exists());
+
+        // A default volume that can't be written to is an error, rather than patching with the OBBs nowhere.
+        let not_a_dir = base.join("file");
+        std::fs::write(&not_a_dir, b"").unwrap();
+        let err = find_dir(&roots, &not_a_dir, relative, true).unwrap_err();
+        assert!(err.to_string().ends_with("could not be written to"), "{err}");
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
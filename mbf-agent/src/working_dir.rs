@@ -0,0 +1,137 @@
+//! The directory that temporary files (OBB backups, the patched APK, libunity.so and diffs) are saved to while patching.
+//! This is `TEMP_PATH` by default, but users whose data partition is nearly full can choose a directory on another volume.
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+
+use crate::{fs_ops, obb_recovery, volumes, modloader_dir, QMODS_DIR, TEMP_PATH};
+
+// The name of the directory created within a user-specified working directory.
+// A subdirectory is always used, since the working directory is removed once patching finishes.
+const WORKING_DIR_NAME: &str = "mbf-tmp";
+// Written to check that files can be created within the working directory.
+const PROBE_FILE_NAME: &str = ".mbf-write-probe";
+
+/// A working directory that has been checked to be usable.
+pub struct WorkingDir {
+    path: PathBuf
+}
+
+impl WorkingDir {
+    /// Creates and validates the working directory: `TEMP_PATH` if `requested` is None, or a directory within `requested` otherwise.
+    /// Fails if `requested` isn't an absolute path, is within a directory that patching modifies, or can't be written to.
+    pub fn resolve(requested: Option<&str>) -> Result<Self> {
+        let path = Self::get_path(requested)?;
+        fs_ops::create_dir_all(&path).context("Failed to create working directory")?;
+        let probe_path = path.join(PROBE_FILE_NAME);
+        std::fs::write(&probe_path, [0u8])
+            .and_then(|_| std::fs::remove_file(&probe_path))
+            .with_context(|| format!("Working directory {path:?} is not writable"))?;
+
+        if requested.is_some() {
+            info!("Using working directory {path:?}");
+        }
+        Ok(Self { path })
+    }
+
+    /// Gives the path that `resolve` would use for the working directory, without creating it or checking that it can be written to.
+    /// Fails if `requested` isn't an absolute path, or is within a directory that patching modifies.
+    pub fn get_path(requested: Option<&str>) -> Result<PathBuf> {
+        match requested {
+            Some(requested) => {
+                let requested = Path::new(requested);
+                if !requested.is_absolute() || requested.components().any(|component| component == Component::ParentDir) {
+                    return Err(anyhow!("The working directory must be an absolute path without `..`"));
+                }
+
+                let path = requested.join(WORKING_DIR_NAME);
+                check_not_modified_by_patching(&path)?;
+                Ok(path)
+            },
+            None => Ok(PathBuf::from(TEMP_PATH))
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Removes the working directory and everything within it.
+    /// The directory is kept if it may contain OBB backups that haven't been restored, so that they can be recovered later.
+    pub fn remove(self) -> Result<()> {
+        self.remove_unless_obbs_pending(obb_recovery::is_pending())
+    }
+
+    fn remove_unless_obbs_pending(self, obbs_pending: bool) -> Result<()> {
+        if obbs_pending {
+            warn!("Keeping working directory {:?}, since it may contain OBB files that could not be restored", self.path);
+            return Ok(());
+        }
+
+        fs_ops::remove_dir_all(&self.path)?;
+        Ok(())
+    }
+}
+
+// Checks that `path` isn't within a directory that is modified by patching, since the working directory could otherwise
+// be removed while patching, e.g. when the OBB directory is removed by reinstalling the game.
+fn check_not_modified_by_patching(path: &Path) -> Result<()> {
+    let app_paths = volumes::get_app_paths()?;
+    // The whole of the game's data directory is removed when it is uninstalled, not just its `files` directory.
+    let app_data_dir = app_paths.data_dir.parent().unwrap_or(&app_paths.data_dir);
+    let modloader_dir = modloader_dir();
+    check_not_within(path, &[app_paths.obb_dir.as_path(), app_data_dir, Path::new(&modloader_dir), Path::new(QMODS_DIR)])
+}
+
+fn check_not_within(path: &Path, modified_dirs: &[&Path]) -> Result<()> {
+    match modified_dirs.iter().find(|dir| path.starts_with(dir)) {
+        Some(dir) => Err(anyhow!("The working directory cannot be within {dir:?}, since patching modifies it")),
+        None => Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_working_dir(name: &str) -> WorkingDir {
+        let path = std::env::temp_dir().join(format!("mbf-working-dir-{}-{name}", std::process::id())).join(WORKING_DIR_NAME);
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(path.join("main.obb"), b"backed up obb").unwrap();
+        WorkingDir { path }
+    }
+
+    #[test]
+    fn working_dir_is_kept_while_obbs_are_pending_recovery() {
+        let working_dir = temp_working_dir("pending");
+        let path = working_dir.path().to_path_buf();
+        working_dir.remove_unless_obbs_pending(true).unwrap();
+        assert_eq!(std::fs::read(path.join("main.obb")).unwrap(), b"backed up obb", "the OBB backup should be kept for recovery");
+
+        WorkingDir { path: path.clone() }.remove_unless_obbs_pending(false).unwrap();
+        assert!(!path.exists());
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn requested_working_dir_must_be_absolute() {
+        for requested in ["sdcard/tmp", "./tmp", "/sdcard/../data/tmp"] {
+            let err = WorkingDir::get_path(Some(requested)).unwrap_err();
+            assert_eq!(err.to_string(), "The working directory must be an absolute path without `..`", "{requested}");
+        }
+    }
+
+    #[test]
+    fn working_dir_cannot_be_within_a_modified_dir() {
+        let modified_dirs = [Path::new("/sdcard/Android/obb/com.beatgames.beatsaber"), Path::new("/sdcard/Android/data/com.beatgames.beatsaber")];
+        let err = check_not_within(Path::new("/sdcard/Android/obb/com.beatgames.beatsaber/mbf-tmp"), &modified_dirs).unwrap_err();
+        assert_eq!(err.to_string(), "The working directory cannot be within \"/sdcard/Android/obb/com.beatgames.beatsaber\", since patching modifies it");
+        assert!(check_not_within(Path::new("/sdcard/Android/data/com.beatgames.beatsaber/files/mbf-tmp"), &modified_dirs).is_err());
+
+        // Only whole path components are compared, so a directory whose name merely starts with a modified one can be used.
+        check_not_within(Path::new("/sdcard/Android/obb/com.beatgames.beatsaber.backup/mbf-tmp"), &modified_dirs).unwrap();
+        check_not_within(Path::new("/storage/1234-ABCD/mbf-tmp"), &modified_dirs).unwrap();
+    }
+}
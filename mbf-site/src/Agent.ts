@@ -1,6 +1,6 @@
 import { AdbSync, AdbSyncWriteOptions, Adb, encodeUtf8 } from '@yume-chan/adb';
 import { ConsumableReadableStream, Consumable, DecodeUtf8Stream, ConcatStringStream } from '@yume-chan/stream-extra';
-import { Request, Response, LogMsg, ModStatus, Mods, ImportedMod, ImportResult, FixedPlayerData } from "./Messages";
+import { Request, Response, LogMsg, ModStatus, Mods, Patched, ImportedMod, ImportResult, FixedPlayerData } from "./Messages";
 import { ManifestMod, Mod } from './Models';
 
 const AgentPath: string = "/data/local/tmp/mbf-agent";
@@ -304,7 +304,7 @@ export async function patchApp(device: Adb,
           downgrade_versions: []
       },
       modloader_present: true,
-      installed_mods: (response as Mods).installed_mods
+      installed_mods: (response as Patched).installed_mods
   };
 }
 
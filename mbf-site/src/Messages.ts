@@ -9,20 +9,76 @@ export interface Patch {
     manifest_mod: ManifestMod,
     downgrade_to: string | null,
     allow_no_core_mods: boolean
-    remodding: boolean
+    remodding: boolean,
+    copy_apk_first?: boolean,
+    sequential_stages?: boolean,
+    sequential_downloads?: boolean,
+    working_dir?: string | null,
+    local_diffs_dir?: string | null,
+    confirmed_download_size?: number | null,
+    keep_vanilla_backup?: boolean,
+    output?: PatchOutput,
+    // Permissions and `uses-feature` names to add on top of those needed to mod the game, which are recorded in modded.json.
+    extra_permissions?: string[],
+    extra_features?: string[],
+    // The certificate to sign the patched APK with, instead of the certificate generated for this device.
+    signing_cert?: SigningCert | null,
+    // Patch the installed APK even if it would be signed with a different certificate, which loses the game's data,
+    // rather than failing with `SignerChangeConfirmationNeeded`.
+    allow_signer_change?: boolean
 }
 
+export type PatchOutput = "Install" | { Export: { destination: string, install_modloader?: boolean } } | "DryRun";
+
+export interface PatchApkFile {
+    type: 'PatchApkFile',
+    input: string,
+    output: string,
+    manifest_mod: ManifestMod,
+    remodding?: boolean,
+    libunity_path?: string | null,
+    allow_other_package?: boolean,
+    signing_cert?: SigningCert | null
+}
+
+// A PEM file (within /sdcard) or PEM data containing a certificate and its RSA private key.
+export type SigningCert = { Path: string } | { Pem: string };
+
 export interface FixPlayerData {
     type: 'FixPlayerData',
 }
 
+export interface RepairObbs {
+    type: 'RepairObbs'
+}
+
+export interface RestoreVanilla {
+    type: 'RestoreVanilla'
+}
+
+export interface BackupPlayerData {
+    type: 'BackupPlayerData'
+}
+
+export interface ListPlayerDataBackups {
+    type: 'ListPlayerDataBackups'
+}
+
+export interface RestorePlayerData {
+    type: 'RestorePlayerData',
+    id: string
+}
+
 export interface SetModsEnabled {
     type: 'SetModsEnabled',
-    statuses: { [id: string]: boolean } 
+    statuses: { [id: string]: boolean },
+    allow_conflicts?: boolean,
+    force_version_mismatch?: boolean
 }
 
 export interface QuickFix {
-    type: 'QuickFix'
+    type: 'QuickFix',
+    force_modloader?: boolean
 }
 
 export interface RemoveMod {
@@ -32,28 +88,111 @@ export interface RemoveMod {
 
 export interface Import {
     type: 'Import',
-    from_path: string
+    from_path: string,
+    allow_conflicts?: boolean
 }
 
 export interface ImportModUrl {
     type: 'ImportModUrl',
-    from_url: string
+    from_url: string,
+    allow_conflicts?: boolean
+}
+
+export interface TrustRepositoryIdentity {
+    type: 'TrustRepositoryIdentity',
+    host: string,
+    identity: string
+}
+
+// Replaces the diff mirrors added by the user, which are tried in order before the built-in ones. Send an empty list to only use the built-in ones.
+export interface SetDiffMirrors {
+    type: 'SetDiffMirrors',
+    mirrors: string[]
+}
+
+export interface ApplyLegacyStorageFallback {
+    type: 'ApplyLegacyStorageFallback'
+}
+
+export interface DiagnoseCrash {
+    type: 'DiagnoseCrash'
+}
+
+export interface GetSetupStatus {
+    type: 'GetSetupStatus'
+}
+
+export interface GetApkComposition {
+    type: 'GetApkComposition',
+    path?: string | null,
+    reference?: string | null
+}
+
+export interface GetManifest {
+    type: 'GetManifest',
+    path?: string | null
+}
+
+export interface GetApkEntries {
+    type: 'GetApkEntries',
+    path?: string | null
+}
+
+export interface GetDowngradeOptions {
+    type: 'GetDowngradeOptions'
 }
 
-export type Request = GetModStatus | 
+// Any request can give the package ID of the game to mod. Beat Saber is modded if it isn't given.
+export type Request = (GetModStatus | 
     Patch | 
+    PatchApkFile | 
     SetModsEnabled | 
     QuickFix | 
     RemoveMod | 
     Import | 
     ImportModUrl | 
-    FixPlayerData;
+    FixPlayerData |
+    RepairObbs |
+    RestoreVanilla |
+    BackupPlayerData |
+    ListPlayerDataBackups |
+    RestorePlayerData |
+    TrustRepositoryIdentity |
+    SetDiffMirrors |
+    ApplyLegacyStorageFallback |
+    DiagnoseCrash |
+    GetSetupStatus |
+    GetApkComposition |
+    GetManifest |
+    GetApkEntries |
+    GetDowngradeOptions) & { package_id?: string };
 
 export interface Mods {
     type: 'Mods',
     installed_mods: Mod[]
 }
 
+// Whether the APK was modded for the first time, refreshed (already modded by MBF, so only re-signed),
+// upgraded (modded with Scotland2 but an older libmain.so) or migrated from another modloader.
+export type PatchKind = "Fresh" | "Refresh" | "Upgrade" | { Migration: { from: ModLoader } };
+
+export interface Patched {
+    type: 'Patched',
+    installed_mods: Mod[],
+    patch_kind: PatchKind,
+    // Every permission in the manifest of the installed APK.
+    manifest_permissions: string[],
+    // How the installed APK was signed before patching. `Unofficial` should be warned about, as the install may be tampered with or pirated.
+    // Null if the APK was downgraded.
+    signature: SignatureStatus | null
+}
+
+export type SignatureStatus = { Signed: { signer_sha256: string } }
+    | "PatchedByMbf"
+    | { MigrationCandidate: { modification_markers: string[], signer_sha256: string | null } }
+    | { Unofficial: { reason: string } }
+    | { Unverifiable: { reason: string } };
+
 export interface ImportedMod {
     type: 'ImportedMod',
     installed_mods: Mod[],
@@ -70,11 +209,340 @@ export interface ImportedSong {
     type: 'ImportedSong'
 }
 
+export interface RepairedObbs {
+    type: 'RepairedObbs',
+    restored: string[]
+}
+
 export interface FixedPlayerData {
     type: 'FixedPlayerData',
     existed: boolean
 }
 
+export interface RestoredVanilla {
+    type: 'RestoredVanilla',
+    backup_existed: boolean
+}
+
+export interface PlayerDataBackup {
+    id: string,
+    path: string,
+    // Seconds since the UNIX epoch
+    created: number,
+    size: number,
+    files: string[]
+}
+
+export interface BackedUpPlayerData {
+    type: 'BackedUpPlayerData',
+    backup: PlayerDataBackup
+}
+
+export interface PlayerDataBackups {
+    type: 'PlayerDataBackups',
+    // Newest first
+    backups: PlayerDataBackup[]
+}
+
+export interface RestoredPlayerData {
+    type: 'RestoredPlayerData',
+    restored: string[]
+}
+
+export interface RepositoryIdentityChanged {
+    type: 'RepositoryIdentityChanged',
+    host: string,
+    old: string,
+    new: string
+}
+
+export interface TrustedRepositoryIdentity {
+    type: 'TrustedRepositoryIdentity'
+}
+
+export interface DiffMirrors {
+    type: 'DiffMirrors',
+    // Every mirror, including the built-in ones, in the order they are tried.
+    mirrors: string[]
+}
+
+export interface AppliedLegacyStorage {
+    type: 'AppliedLegacyStorage'
+}
+
+export interface ExportedApk {
+    type: 'ExportedApk',
+    path: string,
+    size: number,
+    crc32: number,
+    signer_sha256: string,
+    reproducibility_digest: string,
+    patch_kind: PatchKind
+}
+
+export interface PlannedObb {
+    file_name: string,
+    size: number
+}
+
+export interface PatchDryRun {
+    type: 'PatchDryRun',
+    version: string,
+    // null if libunity.so isn't needed, since only the manifest would be patched
+    libunity_available: boolean | null,
+    patch_kind: PatchKind,
+    manifest_modified: boolean,
+    replaced_files: string[],
+    added_files: string[],
+    removed_files: string[],
+    obbs: PlannedObb[],
+    working_dir: string,
+    required_space: number,
+    available_space: number,
+    vanilla_backup_size: number | null
+}
+
+export interface PatchedApkFile {
+    type: 'PatchedApkFile',
+    output: string,
+    size: number,
+    input_crc32: number,
+    crc32: number,
+    signer_sha256: string,
+    reproducibility_digest: string,
+    patch_kind: PatchKind,
+    manifest_modified: boolean,
+    written_files: string[],
+    removed_files: string[]
+}
+
+export interface CrashSummary {
+    time: string | null,
+    signal: string | null,
+    abort_message: string | null,
+    faulting_library: string | null,
+    top_frames: string[]
+}
+
+export type CrashDiagnosisKind = "LibUnityCrash" | "ModloaderCrash" | "StorageAccessDenied" | { ModCrash: { library: string } };
+
+export type CrashRemediation = "Repatch" | "QuickFix" | "ApplyLegacyStorageFallback" | "DisableMod";
+
+export interface CrashDiagnosis {
+    type: 'CrashDiagnosis',
+    crash: CrashSummary | null,
+    diagnosis: CrashDiagnosisKind | null,
+    remediation: CrashRemediation | null
+}
+
+export type IoOp = "Copy" | "Open" | "Remove" | "CreateDir" | "Rename" | "ReadDir" | "Link" | "Map";
+
+export interface IoFailure {
+    type: 'IoFailure',
+    operation: IoOp,
+    path: string,
+    secondary_path: string | null,
+    // Name of the Rust `std::io::ErrorKind`, e.g. "PermissionDenied", "StorageFull" or "NotFound"
+    kind: string,
+    message: string
+}
+
+export interface SetupFacts {
+    app_info: AppInfo | null,
+    version_supported: boolean | null,
+    downgrade_versions: string[],
+    modloader_up_to_date: boolean,
+    mod_data_present: boolean,
+    missing_core_mods: string[] | null,
+    storage_permission_granted: boolean | null,
+    capabilities: { [capability in Capability]?: CapabilitySupport },
+    storage_cleaners: StorageCleaner[],
+    mass_deletions: MassDeletion[],
+    metadata_health: { [index: string]: MetadataHealth }
+}
+
+export type StorageCleaner = { App: { package_id: string, name: string } } |
+    { AutoClean: { setting: string, name: string } };
+
+export interface MassDeletion {
+    path: string,
+    previous_count: number,
+    current_count: number
+}
+
+export type Capability = "AppopsUid" | "AppopsPackage";
+
+export type CapabilitySupport = "Supported" | "Unsupported" | "Unknown";
+
+export type SetupStep = "InstallGame" |
+    { DowngradeRequired: { to: string } } |
+    "UnsupportedVersion" |
+    "PatchRequired" |
+    "ModloaderMissing" |
+    { CoreModsMissing: { list: string[] } } |
+    "PermissionRepairNeeded" |
+    "ReadyToPlay";
+
+export interface SetupStatus {
+    type: 'SetupStatus',
+    facts: SetupFacts,
+    next_step: SetupStep
+}
+
+export type IntegrityClass = "LikelyCorrupt" | "LikelyModifiedInstall" | "Indeterminate";
+
+export type SignerKind = { KnownModified: { name: string } } | "Unknown" | "Unavailable";
+
+export interface IntegrityEvidence {
+    signer_sha256: string | null,
+    signer: SignerKind,
+    installer: string | null,
+    modification_markers: string[],
+    size_matches: boolean | null
+}
+
+export interface IntegrityCheckFailed {
+    type: 'IntegrityCheckFailed',
+    file_name: string,
+    class: IntegrityClass,
+    evidence: IntegrityEvidence
+}
+
+export type EntryCategory = { NativeLibs: { abi: string } } | "Assets" | "Resources" | "Dex" | "Signing" | "PatcherAdded" | "Other";
+
+export interface CategoryTotals {
+    category: EntryCategory,
+    entries: number,
+    compressed_len: number,
+    uncompressed_len: number
+}
+
+export interface ApkCompositionInfo {
+    total_len: number,
+    categories: CategoryTotals[],
+    headers_len: number,
+    padding_len: number,
+    signing_block_len: number,
+    cent_dir_len: number
+}
+
+export interface ChangedEntry {
+    name: string,
+    category: EntryCategory,
+    len: number
+}
+
+export interface CompositionDelta {
+    size_change: number,
+    added_entries: ChangedEntry[],
+    removed_entries: ChangedEntry[],
+    replaced_entries: string[],
+    replaced_change: number,
+    recompressed_entries: number,
+    recompression_change: number,
+    padding_change: number,
+    signing_block_change: number,
+    cent_dir_change: number
+}
+
+export interface ApkComposition {
+    type: 'ApkComposition',
+    path: string,
+    composition: ApkCompositionInfo,
+    comparison: CompositionDelta | null
+}
+
+export type FileCompression = 'Deflate' | 'Store' | { Unsupported: number };
+
+export interface DosDateTime {
+    year: number,
+    month: number,
+    day: number,
+    hour: number,
+    minute: number,
+    second: number
+}
+
+export interface EntryInfo {
+    name: string,
+    compressed_len: number,
+    uncompressed_len: number,
+    compression_method: FileCompression,
+    crc32: number,
+    last_modified: DosDateTime
+}
+
+export interface ApkEntries {
+    type: 'ApkEntries',
+    path: string,
+    // Sorted by name.
+    entries: EntryInfo[]
+}
+
+export interface Manifest {
+    type: 'Manifest',
+    path: string,
+    // Indented text XML, which isn't guaranteed to be valid XML.
+    xml: string
+}
+
+export interface DowngradeOption {
+    to_version: string,
+    // More than 1 if the version can only be reached by applying several diffs one after another.
+    hops: number,
+    // Null if the diff index doesn't give the size of every diff.
+    download_size: number | null,
+    // Null if the core mod index couldn't be fetched.
+    core_mods_available: boolean | null
+}
+
+export interface DowngradeOptions {
+    type: 'DowngradeOptions',
+    from_version: string,
+    options: DowngradeOption[]
+}
+
+export interface InsufficientStorage {
+    type: 'InsufficientStorage',
+    purpose: string,
+    path: string,
+    // In bytes.
+    required: number,
+    available: number
+}
+
+export type PmFailureKind = "InsufficientStorage" | "VersionDowngrade" | "SignatureMismatch" | "InvalidApk" | "Blocked" | "NotInstalled" | "Other";
+
+export interface PackageManagerFailed {
+    type: 'PackageManagerFailed',
+    command: string,
+    kind: PmFailureKind,
+    // The failure code printed by `pm`, e.g. `INSTALL_FAILED_INSUFFICIENT_STORAGE`.
+    code: string | null,
+    output: string,
+    hint: string
+}
+
+export interface DiffDownload {
+    file_name: string,
+    diff_name: string,
+    // Null if the diff index doesn't give the size of the diff.
+    size: number | null
+}
+
+export interface DownloadConfirmationNeeded {
+    type: 'DownloadConfirmationNeeded',
+    total_size: number,
+    downloads: DiffDownload[]
+}
+
+// Sent after a patch fails because the installed APK would be signed with a different certificate to the one it was last signed with.
+export interface SignerChangeConfirmationNeeded {
+    type: 'SignerChangeConfirmationNeeded',
+    previous_sha256: string,
+    signer_sha256: string
+}
+
 export type ImportResult = ImportedMod | ImportedFileCopy | ImportedSong;
 
 export interface ModStatus {
@@ -91,19 +559,34 @@ export interface LogMsg {
     level: LogLevel
 }
 
-export type Response = LogMsg | ModStatus | Mods | ImportResult | FixedPlayerData;
+export type Response = LogMsg | ModStatus | Mods | Patched | ImportResult | RepairedObbs | FixedPlayerData | RestoredVanilla | BackedUpPlayerData | PlayerDataBackups | RestoredPlayerData | RepositoryIdentityChanged | TrustedRepositoryIdentity | DiffMirrors | AppliedLegacyStorage | CrashDiagnosis | ExportedApk | PatchDryRun | PatchedApkFile | IoFailure | SetupStatus | IntegrityCheckFailed | ApkComposition | Manifest | ApkEntries | DowngradeOptions | DownloadConfirmationNeeded | SignerChangeConfirmationNeeded | PackageManagerFailed | InsufficientStorage;
 
 export interface CoreModsInfo {
     supported_versions: string[],
     downgrade_versions: string[],
     all_core_mods_installed: boolean,
+    metadata_health: { [index: string]: MetadataHealth }
+}
+
+export interface MetadataHealth {
+    parsed: number,
+    // The game version (or versions, for diffs) of each entry that could not be read.
+    skipped: string[]
 }
 
 export type ModLoader = "Scotland2" | "QuestLoader" | "Unknown";
 
+export type StorageStrategy = "ManageExternalStorage" | "Legacy";
+
+export type BuildVariant = "OfficialStore" | "PreviouslyModded" | "UnknownSideload";
+
 export interface AppInfo {
+    package_id: string,
     version: string,
-    loader_installed: ModLoader | null
+    version_code: number | null,
+    loader_installed: ModLoader | null,
+    storage_strategy: StorageStrategy,
+    build_variant: BuildVariant
 }
 
 export type LogLevel = "Error" | "Warn" | "Info" | "Debug" | "Trace";
\ No newline at end of file
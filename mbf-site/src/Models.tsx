@@ -4,7 +4,8 @@ interface Mod {
     description: string | null,
     version: string,
     is_enabled: boolean,
-    game_version: string | null
+    game_version: string | null,
+    conflicts_with: string[]
 }
 
 interface CoreMod {
@@ -16,6 +17,48 @@ interface CoreMod {
 interface ManifestMod {
     add_permissions: string[],
     add_features: string[],
+    legacy_storage?: boolean,
+    application_override?: ApplicationOverride,
+    // Permissions to remove from the manifest, which are never added even if in `add_permissions`.
+    remove_permissions?: string[],
+    remove_attributes?: AttributeRemoval[],
+    add_intent_filters?: IntentFilter[],
+    meta_data?: MetaData[],
+    // Package names and intent actions to make visible to the app on Android 11+
+    query_packages?: string[],
+    query_intent_actions?: string[],
+    // Overrides for the versionName and versionCode of the patched APK
+    version_name?: string,
+    version_code?: number
+}
+
+interface MetaData {
+    // The android:name of the meta-data element within the application element
+    name: string,
+    value: string | number | boolean
+}
+
+interface IntentFilter {
+    // The android:name of the activity to add the filter to
+    activity: string,
+    actions?: string[],
+    categories?: string[],
+    data_scheme?: string,
+    data_host?: string
+}
+
+interface AttributeRemoval {
+    // The name of the element, e.g. "application"
+    element: string,
+    // The name of the attribute without its namespace, e.g. "requestLegacyExternalStorage"
+    attribute: string
+}
+
+interface ApplicationOverride {
+    // Full name of the Application subclass, e.g. "com.example.EarlyApplication"
+    class_name: string,
+    // Path to a DEX file on the quest containing the class.
+    dex_path: string
 }
 
 interface VersionedCoreMods {
@@ -29,7 +72,11 @@ export type {
     CoreMod,
     VersionedCoreMods,
     CoreModIndex,
-    ManifestMod
+    ManifestMod,
+    ApplicationOverride,
+    AttributeRemoval,
+    IntentFilter,
+    MetaData
 }
 
 // Removes the build number, i.e. `_<big number>` suffix from the given game version.